@@ -0,0 +1,308 @@
+//! Request and response types of the Snap API.
+//!
+//! The server serializes these and the clients deserialize them, so both
+//! sides agree on the payloads. With the `openapi` feature they also
+//! describe themselves in the OpenAPI document of the server.
+
+use std::collections::BTreeMap;
+
+/// Successful response, with the payload under `data`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ApiResponse<T> {
+    pub data: T,
+}
+
+/// One page of snaps. `next_cursor` is `None` on the last page.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Page {
+    pub data: Vec<SnapInfo>,
+    pub next_cursor: Option<String>,
+}
+
+/// RFC 7807 problem details, sent as `application/problem+json`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ProblemResponse {
+    #[serde(rename = "type")]
+    pub uri: Option<String>,
+    pub title: Option<String>,
+    /// Status code and reason, as in `"404 Not Found"`.
+    pub status: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl std::fmt::Display for ProblemResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let title = self.title.as_deref().unwrap_or("Unknown problem");
+        match &self.detail {
+            Some(detail) => write!(f, "{title}: {detail}"),
+            None => write!(f, "{title}"),
+        }
+    }
+}
+
+/// Body of "POST /snaps" sent as JSON.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CreateSnap {
+    pub message: String,
+    /// Id of the snap replied to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+}
+
+/// Body of "PATCH /snaps/{id}".
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct EditSnap {
+    pub message: String,
+}
+
+/// The snap just posted.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SnapCreated {
+    pub id: String,
+    pub author_id: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    pub attachments: Vec<AttachmentInfo>,
+}
+
+/// A snap with its counts and the entities found in its message.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SnapInfo {
+    pub id: String,
+    pub author_id: String,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<String>,
+    pub reply_count: usize,
+    /// Users who reacted with each kind.
+    pub reactions: BTreeMap<ReactionKind, usize>,
+    /// Hashtags and mentions of the message.
+    pub entities: Vec<Entity>,
+    pub attachments: Vec<AttachmentInfo>,
+    /// Only set on tombstones, which have an empty message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    /// Only set on snaps hidden by a moderator. Their message is empty
+    /// unless a moderator is asking.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hidden_at: Option<String>,
+}
+
+/// Image attached to a snap.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AttachmentInfo {
+    pub hash: String,
+    pub content_type: String,
+    pub size: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Where the contents are served.
+    pub url: String,
+    pub thumbnails: Vec<ThumbnailInfo>,
+}
+
+/// Smaller rendition of an attachment.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ThumbnailInfo {
+    pub max_dimension: u32,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub url: String,
+}
+
+/// Consistent copy of the whole store kept by the server.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SnapshotInfo {
+    /// File name, to download it from `/admin/snapshots/{name}`.
+    pub name: String,
+    pub created_at: String,
+    /// Journal entries in the snapshot.
+    pub entries: usize,
+    pub bytes: u64,
+    /// SHA-256 of the entries, as in the footer of the file.
+    pub sha256: String,
+}
+
+/// Whether a server takes writes or follows another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationRole {
+    Leader,
+    /// Read-only copy of the leader, writes are redirected to it.
+    Follower,
+}
+
+/// How far a server is behind its leader.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ReplicationStatus {
+    pub role: ReplicationRole,
+    /// URL of the leader, on followers.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader: Option<String>,
+    /// Changes applied to the store of this server.
+    pub offset: u64,
+    /// Changes in the store of the leader, when last heard of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_offset: Option<u64>,
+    /// Changes still to apply, 0 on the leader. Unknown until the
+    /// follower first hears from the leader.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lag: Option<u64>,
+    /// Whether the follower is streaming the changes of the leader.
+    pub connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_contact_at: Option<String>,
+    /// Why the follower last lost the leader.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What an [Entity] refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+    /// `#tag`, matched ignoring case.
+    Hashtag,
+    /// `@username` of a user.
+    Mention,
+}
+
+/// Hashtag or mention found in a message.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Entity {
+    pub kind: EntityKind,
+    /// Lowercase text without the `#` or `@`.
+    pub text: String,
+    /// Offset in characters of the `#` or `@`.
+    pub start: usize,
+    /// Offset in characters right after the entity.
+    pub end: usize,
+}
+
+/// Reaction a user can leave on a snap, from a fixed set of emoji.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ReactionKind {
+    Like,
+    Love,
+    Laugh,
+    Wow,
+    Sad,
+    Angry,
+}
+
+impl ReactionKind {
+    /// Every kind, in display order.
+    pub const ALL: [ReactionKind; 6] = [
+        ReactionKind::Like,
+        ReactionKind::Love,
+        ReactionKind::Laugh,
+        ReactionKind::Wow,
+        ReactionKind::Sad,
+        ReactionKind::Angry,
+    ];
+
+    /// Name used in paths and JSON.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReactionKind::Like => "like",
+            ReactionKind::Love => "love",
+            ReactionKind::Laugh => "laugh",
+            ReactionKind::Wow => "wow",
+            ReactionKind::Sad => "sad",
+            ReactionKind::Angry => "angry",
+        }
+    }
+
+    pub fn emoji(&self) -> &'static str {
+        match self {
+            ReactionKind::Like => "\u{1F44D}",
+            ReactionKind::Love => "\u{2764}\u{FE0F}",
+            ReactionKind::Laugh => "\u{1F602}",
+            ReactionKind::Wow => "\u{1F62E}",
+            ReactionKind::Sad => "\u{1F622}",
+            ReactionKind::Angry => "\u{1F620}",
+        }
+    }
+}
+
+impl std::fmt::Display for ReactionKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for ReactionKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ReactionKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == s)
+            .ok_or_else(|| {
+                let names = ReactionKind::ALL.map(|kind| format!("\"{kind}\""));
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+#[cfg(test)]
+mod api_test {
+    use super::*;
+
+    #[test]
+    fn reaction_kinds_round_trip() {
+        for kind in ReactionKind::ALL {
+            assert_eq!(kind.to_string().parse::<ReactionKind>(), Ok(kind));
+        }
+        assert!("thumbs".parse::<ReactionKind>().unwrap_err().contains("\"like\""));
+    }
+
+    #[test]
+    fn snap_info_round_trips() {
+        let json = serde_json::json!({
+            "id": "1",
+            "author_id": "2",
+            "message": "Hi @bob",
+            "reply_count": 0,
+            "reactions": {"like": 2, "wow": 1},
+            "entities": [{"kind": "mention", "text": "bob", "start": 3, "end": 7}],
+            "attachments": [],
+        });
+        let info = serde_json::from_value::<SnapInfo>(json.clone()).unwrap();
+        assert_eq!(info.reactions[&ReactionKind::Like], 2);
+        assert_eq!(info.entities[0].kind, EntityKind::Mention);
+        assert_eq!(info.edited_at, None);
+        assert_eq!(serde_json::to_value(&info).unwrap(), json);
+    }
+
+    #[test]
+    fn problems_display_title_and_detail() {
+        let problem = ProblemResponse {
+            uri: Some("about:blank".to_string()),
+            title: Some("Snap not found".to_string()),
+            status: Some("404 Not Found".to_string()),
+            detail: Some("No snap has id 1".to_string()),
+        };
+        assert_eq!(problem.to_string(), "Snap not found: No snap has id 1");
+    }
+}
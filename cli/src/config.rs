@@ -0,0 +1,161 @@
+use std::fmt;
+use std::path::PathBuf;
+use snap_client::Credentials;
+
+/// Server used when nothing else is configured.
+pub const DEFAULT_URL: &str = "http://localhost:8080";
+
+/// Where to find the server and how to authenticate, read from
+/// `~/.config/snap/config.toml` or the file in `SNAP_CLI_CONFIG`.
+/// `SNAP_URL`, `SNAP_TOKEN` and `SNAP_API_KEY` override the file, and
+/// the command line flags override both.
+#[derive(Debug, Default, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CliConfig {
+    /// Base URL of the server.
+    pub url: Option<String>,
+    /// Access token from `POST /login`.
+    pub token: Option<String>,
+    /// Key from `POST /api-keys`, used when there is no token.
+    pub api_key: Option<String>,
+}
+
+/// Reasons the configuration can't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file couldn't be read.
+    Io { path: PathBuf, error: std::io::Error },
+    /// The configuration file isn't valid TOML or has unknown keys.
+    Parse { path: PathBuf, error: toml::de::Error },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "can't read config file {}: {error}", path.display())
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "invalid config file {}: {error}", path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl CliConfig {
+    /// Load the configuration from `file`, or the usual places, and the
+    /// variables of `env`. A missing file is only an error when it was
+    /// asked for explicitly.
+    pub fn load<F>(file: Option<PathBuf>, env: F) -> Result<CliConfig, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let explicit = file.or_else(|| env("SNAP_CLI_CONFIG").map(PathBuf::from));
+        let mut config = match explicit {
+            Some(path) => CliConfig::from_file(path)?,
+            None => match default_path(&env) {
+                Some(path) if path.exists() => CliConfig::from_file(path)?,
+                _ => CliConfig::default(),
+            },
+        };
+        if let Some(url) = env("SNAP_URL") {
+            config.url = Some(url);
+        }
+        if let Some(token) = env("SNAP_TOKEN") {
+            config.token = Some(token);
+        }
+        if let Some(api_key) = env("SNAP_API_KEY") {
+            config.api_key = Some(api_key);
+        }
+        Ok(config)
+    }
+
+    /// Parse a TOML configuration file.
+    pub fn from_file(path: PathBuf) -> Result<CliConfig, ConfigError> {
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) => return Err(ConfigError::Io { path, error }),
+        };
+        toml::from_str(&text).map_err(|error| ConfigError::Parse { path, error })
+    }
+
+    /// Base URL of the server.
+    pub fn url(&self) -> &str {
+        self.url.as_deref().unwrap_or(DEFAULT_URL)
+    }
+
+    /// Credentials to send, the token winning over the API key.
+    pub fn credentials(&self) -> Option<Credentials> {
+        match (&self.token, &self.api_key) {
+            (Some(token), _) => Some(Credentials::Bearer(token.clone())),
+            (None, Some(key)) => Some(Credentials::ApiKey(key.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// `snap/config.toml` under `XDG_CONFIG_HOME`, or under `~/.config`.
+fn default_path<F>(env: &F) -> Option<PathBuf>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let base = env("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("snap").join("config.toml"))
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn env_from(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<HashMap<String, String>>();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn defaults_without_file_or_env() {
+        let config = CliConfig::load(None, env_from(&[("HOME", "/nonexistent")])).unwrap();
+        assert_eq!(config, CliConfig::default());
+        assert_eq!(config.url(), DEFAULT_URL);
+        assert_eq!(config.credentials(), None);
+    }
+
+    #[test]
+    fn env_overrides_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snap").join("config.toml");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, "url = \"http://file:1\"\napi_key = \"snap_key\"\n").unwrap();
+
+        let home = dir.path().to_str().unwrap();
+        let config = CliConfig::load(None, env_from(&[("XDG_CONFIG_HOME", home)])).unwrap();
+        assert_eq!(config.url(), "http://file:1");
+        assert_eq!(config.credentials(), Some(Credentials::ApiKey("snap_key".to_string())));
+
+        let env = env_from(&[("XDG_CONFIG_HOME", home), ("SNAP_URL", "http://env:2"), ("SNAP_TOKEN", "jwt")]);
+        let config = CliConfig::load(None, env).unwrap();
+        assert_eq!(config.url(), "http://env:2");
+        assert_eq!(config.credentials(), Some(Credentials::Bearer("jwt".to_string())));
+    }
+
+    #[test]
+    fn explicit_files_must_exist_and_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.toml");
+        let error = CliConfig::load(Some(missing), env_from(&[])).unwrap_err();
+        assert!(matches!(error, ConfigError::Io { .. }), "{error}");
+
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "server = \"http://x\"\n").unwrap();
+        let env = env_from(&[("SNAP_CLI_CONFIG", path.to_str().unwrap())]);
+        let error = CliConfig::load(None, env).unwrap_err();
+        assert!(matches!(error, ConfigError::Parse { .. }), "{error}");
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use clap::Parser;
+use futures_util::StreamExt;
+use snap_client::{ClientError, CreateSnap, FeedEvent, SnapClient};
+use config::CliConfig;
+use output::{Format, Printer};
+
+mod config;
+mod output;
+
+/// Snaps asked for in each page. The server allows up to 100 by default.
+const PAGE_SIZE: usize = 100;
+
+/// Command line flags of the client.
+#[derive(Debug, clap::Parser)]
+#[command(name = "snap", version, about = "Command line client of the Snap API")]
+struct Cli {
+    /// Path to a TOML configuration file (env: SNAP_CLI_CONFIG).
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Base URL of the server (env: SNAP_URL).
+    #[arg(long, global = true, value_name = "URL")]
+    url: Option<String>,
+
+    /// Access token from `POST /login` (env: SNAP_TOKEN).
+    #[arg(long, global = true, value_name = "TOKEN")]
+    token: Option<String>,
+
+    /// API key, used when there is no token (env: SNAP_API_KEY).
+    #[arg(long, global = true, value_name = "KEY")]
+    api_key: Option<String>,
+
+    /// Output format, `json` for export and `table` otherwise.
+    #[arg(long, short, global = true, value_enum)]
+    output: Option<Format>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Post a snap.
+    Post {
+        message: String,
+        /// Id of the snap replied to.
+        #[arg(long, value_name = "ID")]
+        reply_to: Option<String>,
+    },
+    /// List snaps, from the most recent to the oldest.
+    Ls {
+        /// Show only this many snaps.
+        #[arg(long, short = 'n', value_name = "COUNT")]
+        limit: Option<usize>,
+    },
+    /// Show a snap.
+    Get {
+        id: String,
+    },
+    /// Delete a snap.
+    Rm {
+        id: String,
+    },
+    /// Show the latest snaps, oldest first.
+    Tail {
+        /// Snaps to show.
+        #[arg(long, short = 'n', value_name = "COUNT", default_value_t = 10)]
+        lines: usize,
+        /// Keep showing new snaps as they are posted.
+        #[arg(long, short)]
+        follow: bool,
+    },
+    /// Write every snap, to a file or the standard output.
+    Export {
+        /// File to write instead of the standard output.
+        #[arg(long, short, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+    /// Have the server snapshot its store, then download the snapshot.
+    /// Administrators only.
+    Snapshot {
+        /// File to write instead of the standard output.
+        #[arg(long, short, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+}
+
+/// Exit codes, so scripts can tell failures apart.
+mod exit {
+    /// Bad flags or configuration, as with clap.
+    pub const USAGE: u8 = 2;
+    /// 404 from the server.
+    pub const NOT_FOUND: u8 = 3;
+    /// 401 or 403 from the server.
+    pub const DENIED: u8 = 4;
+    /// 4xx from the server for a request it won't take.
+    pub const INVALID: u8 = 5;
+    /// 429 from the server, even after the retries.
+    pub const RATE_LIMITED: u8 = 6;
+    /// 5xx from the server.
+    pub const SERVER: u8 = 7;
+    /// The server couldn't be reached or sent something unexpected.
+    pub const UNAVAILABLE: u8 = 8;
+    /// Output couldn't be written.
+    pub const IO: u8 = 9;
+}
+
+/// Reasons a command can fail.
+#[derive(Debug)]
+enum CliError {
+    Client(ClientError),
+    Io(io::Error),
+}
+
+impl CliError {
+    fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Client(error) => match error {
+                ClientError::InvalidUrl(_) => exit::USAGE,
+                ClientError::Problem { status: 404, .. } => exit::NOT_FOUND,
+                ClientError::Problem { status: 401 | 403, .. } => exit::DENIED,
+                ClientError::Problem { status: 429, .. } => exit::RATE_LIMITED,
+                ClientError::Problem { status: 500.., .. } => exit::SERVER,
+                ClientError::Problem { .. } => exit::INVALID,
+                ClientError::Connection(_) | ClientError::Decode(_) => exit::UNAVAILABLE,
+            },
+            CliError::Io(_) => exit::IO,
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Client(e) => write!(f, "{e}"),
+            CliError::Io(e) => write!(f, "can't write output: {e}"),
+        }
+    }
+}
+
+impl From<ClientError> for CliError {
+    fn from(error: ClientError) -> Self {
+        CliError::Client(error)
+    }
+}
+
+impl From<io::Error> for CliError {
+    fn from(error: io::Error) -> Self {
+        CliError::Io(error)
+    }
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let mut config = match CliConfig::load(cli.config.clone(), |key| env::var(key).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(exit::USAGE);
+        }
+    };
+    config.url = cli.url.or(config.url);
+    if cli.token.is_some() || cli.api_key.is_some() {
+        config.token = cli.token;
+        config.api_key = cli.api_key;
+    }
+
+    let client = match SnapClient::new(config.url()) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(exit::USAGE);
+        }
+    };
+    let client = match config.credentials() {
+        Some(credentials) => client.with_credentials(credentials),
+        None => client,
+    };
+
+    match run(&client, cli.command, cli.output).await {
+        Ok(()) => ExitCode::SUCCESS,
+        // The reader went away, as with `snap ls | head`.
+        Err(CliError::Io(e)) if e.kind() == io::ErrorKind::BrokenPipe => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
+
+/// Run `command`, printing in `format` or the default of the command.
+async fn run(client: &SnapClient, command: Command, format: Option<Format>) -> Result<(), CliError> {
+    let mut printer = Printer::new(format.unwrap_or(Format::Table), io::stdout());
+    match command {
+        Command::Post { message, reply_to } => {
+            let created = client.create(&CreateSnap { message, in_reply_to: reply_to }).await?;
+            printer.print(&created)?;
+        }
+        Command::Ls { limit } => {
+            let page_size = limit.unwrap_or(PAGE_SIZE).clamp(1, PAGE_SIZE);
+            let mut snaps = Box::pin(client.list(page_size).take(limit.unwrap_or(usize::MAX)));
+            while let Some(snap) = snaps.next().await {
+                printer.print(&snap?)?;
+            }
+        }
+        Command::Get { id } => {
+            printer.print(&client.get(&id).await?)?;
+        }
+        Command::Rm { id } => {
+            client.delete(&id).await?;
+        }
+        Command::Tail { lines, follow } => {
+            // Listen first, so no snap falls between the page and the feed.
+            let mut feed = if follow {
+                Some(Box::pin(client.stream().await?))
+            } else {
+                None
+            };
+            let latest = match lines {
+                0 => Vec::new(),
+                lines => client.list_page(None, lines).await?.data,
+            };
+            for snap in latest.iter().rev() {
+                printer.print(snap)?;
+            }
+            let shown = latest.into_iter().map(|snap| snap.id).collect::<HashSet<String>>();
+            while let Some(event) = match &mut feed {
+                Some(feed) => feed.next().await,
+                None => None,
+            } {
+                match event? {
+                    FeedEvent::Snap(snap) if !shown.contains(&snap.id) => printer.print(&*snap)?,
+                    FeedEvent::Snap(_) => {}
+                    FeedEvent::Lagged(missed) => eprintln!("warning: missed up to {missed} snaps"),
+                }
+            }
+        }
+        Command::Export { file } => {
+            let out: Box<dyn Write> = match file {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout()),
+            };
+            let mut printer = Printer::new(format.unwrap_or(Format::Json), out);
+            let mut snaps = Box::pin(client.list(PAGE_SIZE));
+            while let Some(snap) = snaps.next().await {
+                printer.print(&snap?)?;
+            }
+        }
+        Command::Snapshot { file } => {
+            let info = client.create_snapshot().await?;
+            let mut out: Box<dyn Write> = match file {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(io::stdout()),
+            };
+            let mut chunks = Box::pin(client.download_snapshot(&info.name).await?);
+            while let Some(chunk) = chunks.next().await {
+                out.write_all(&chunk?)?;
+            }
+            out.flush()?;
+            eprintln!("snapshot {}: {} entries, {} bytes, sha256 {}", info.name, info.entries, info.bytes, info.sha256);
+        }
+    }
+    Ok(())
+}
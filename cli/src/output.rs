@@ -0,0 +1,188 @@
+use std::io::{self, Write};
+use snap_client::{SnapCreated, SnapInfo};
+
+/// How records are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Aligned columns for people, with long messages cut.
+    Table,
+    /// One JSON object per line, as sent by the server.
+    Json,
+    /// Comma separated values with a header line.
+    Csv,
+}
+
+/// Something printed as one row or one JSON object.
+pub trait Record: serde::Serialize {
+    /// Name and width of each column of the table. The last one takes
+    /// whatever is left, so its width is ignored.
+    const COLUMNS: &'static [(&'static str, usize)];
+
+    /// Value of each column, in the order of [Record::COLUMNS].
+    fn fields(&self) -> Vec<String>;
+}
+
+/// Longest message shown in a table before cutting it.
+const MESSAGE_WIDTH: usize = 60;
+
+impl Record for SnapInfo {
+    const COLUMNS: &'static [(&'static str, usize)] = &[
+        ("ID", 36),
+        ("AUTHOR", 36),
+        ("REPLIES", 7),
+        ("REACTIONS", 9),
+        ("MESSAGE", 0),
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.author_id.clone(),
+            self.reply_count.to_string(),
+            self.reactions.values().sum::<usize>().to_string(),
+            self.message.clone(),
+        ]
+    }
+}
+
+impl Record for SnapCreated {
+    const COLUMNS: &'static [(&'static str, usize)] = &[
+        ("ID", 36),
+        ("AUTHOR", 36),
+        ("IN REPLY TO", 36),
+        ("MESSAGE", 0),
+    ];
+
+    fn fields(&self) -> Vec<String> {
+        vec![
+            self.id.clone(),
+            self.author_id.clone(),
+            self.in_reply_to.clone().unwrap_or_default(),
+            self.message.clone(),
+        ]
+    }
+}
+
+/// Prints records in a [Format], with the header before the first one.
+pub struct Printer<W: Write> {
+    format: Format,
+    out: W,
+    header_printed: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(format: Format, out: W) -> Printer<W> {
+        Printer { format, out, header_printed: false }
+    }
+
+    pub fn print<R: Record>(&mut self, record: &R) -> io::Result<()> {
+        match self.format {
+            Format::Json => {
+                serde_json::to_writer(&mut self.out, record)?;
+                writeln!(self.out)?;
+            }
+            Format::Csv => {
+                if !self.header_printed {
+                    let header = R::COLUMNS.iter().map(|(name, _)| csv_field(&name.to_lowercase()));
+                    writeln!(self.out, "{}", header.collect::<Vec<String>>().join(","))?;
+                }
+                let fields = record.fields().iter().map(|field| csv_field(field)).collect::<Vec<String>>();
+                writeln!(self.out, "{}", fields.join(","))?;
+            }
+            Format::Table => {
+                if !self.header_printed {
+                    let header = R::COLUMNS.iter().map(|(name, _)| name.to_string()).collect();
+                    writeln!(self.out, "{}", table_row::<R>(header))?;
+                }
+                writeln!(self.out, "{}", table_row::<R>(record.fields()))?;
+            }
+        }
+        self.header_printed = true;
+        // Rows of followed feeds should show up right away.
+        self.out.flush()
+    }
+}
+
+/// `fields` padded to the widths of the columns of `R`.
+fn table_row<R: Record>(fields: Vec<String>) -> String {
+    let last = fields.len() - 1;
+    fields.into_iter()
+        .zip(R::COLUMNS)
+        .enumerate()
+        .map(|(i, (field, (_, width)))| {
+            if i == last {
+                cut(&field.replace('\n', " "), MESSAGE_WIDTH)
+            } else {
+                format!("{field:<width$}")
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("  ")
+}
+
+/// `text` with up to `width` characters, ending in `…` when cut.
+fn cut(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string()
+    }
+    let mut cut = text.chars().take(width - 1).collect::<String>();
+    cut.push('…');
+    cut
+}
+
+/// `field` quoted when it has commas, quotes or line breaks.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod output_test {
+    use super::*;
+
+    fn created(message: &str) -> SnapCreated {
+        SnapCreated {
+            id: "1".to_string(),
+            author_id: "2".to_string(),
+            message: message.to_string(),
+            in_reply_to: None,
+            attachments: Vec::new(),
+        }
+    }
+
+    fn printed(format: Format, records: &[SnapCreated]) -> String {
+        let mut printer = Printer::new(format, Vec::new());
+        for record in records {
+            printer.print(record).unwrap();
+        }
+        String::from_utf8(printer.out).unwrap()
+    }
+
+    #[test]
+    fn csv_quotes_when_needed() {
+        let out = printed(Format::Csv, &[created("plain"), created("a, \"b\"\nc")]);
+        assert_eq!(out, "id,author,in reply to,message\n1,2,,plain\n1,2,,\"a, \"\"b\"\"\nc\"\n");
+    }
+
+    #[test]
+    fn json_prints_one_object_per_line() {
+        let out = printed(Format::Json, &[created("a"), created("b")]);
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert_eq!(lines.len(), 2);
+        let value = serde_json::from_str::<serde_json::Value>(lines[1]).unwrap();
+        assert_eq!(value["message"], "b");
+    }
+
+    #[test]
+    fn tables_align_and_cut_messages() {
+        let out = printed(Format::Table, &[created(&"x".repeat(100))]);
+        let lines = out.lines().collect::<Vec<&str>>();
+        assert!(lines[0].starts_with("ID  "));
+        assert_eq!(lines[0].find("AUTHOR"), lines[1].find('2'));
+        assert!(lines[1].ends_with('…'));
+        assert_eq!(lines[1].rsplit("  ").next().unwrap().chars().count(), MESSAGE_WIDTH);
+    }
+}
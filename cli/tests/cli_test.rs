@@ -0,0 +1,141 @@
+use std::process::Stdio;
+use std::time::Duration;
+use serde_json::Value;
+use snap_app_demo::{context, router, shutdown, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::process::Command;
+
+/// Base URL of a new server and an access token of its one user.
+async fn server() -> (String, String) {
+    let context = context::AppContext::default();
+    let mut repo = state::MockSnapRepository::new();
+    let user = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+    let token = context.auth.issue(&user.id()).access_token;
+    let app: axum::Router = router::get_router_with_context(context).with_state(repo);
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(shutdown::serve_with_drain(listener, app, std::future::pending(), Duration::ZERO, Duration::from_secs(1)));
+    (format!("http://{address}"), token)
+}
+
+/// The `snap` binary, with an environment holding only `vars`.
+fn snap(vars: &[(&str, &str)], args: &[&str]) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_snap"));
+    command.env_clear().envs(vars.iter().copied()).args(args);
+    command
+}
+
+/// Run `snap` and return its exit code and standard output.
+async fn run(vars: &[(&str, &str)], args: &[&str]) -> (i32, String) {
+    let output = snap(vars, args).output().await.unwrap();
+    (output.status.code().unwrap(), String::from_utf8(output.stdout).unwrap())
+}
+
+#[tokio::test]
+async fn post_get_ls_and_rm() {
+    let (url, token) = server().await;
+    let env = [("SNAP_URL", url.as_str()), ("SNAP_TOKEN", token.as_str())];
+
+    let (code, out) = run(&env, &["post", "First", "-o", "json"]).await;
+    assert_eq!(code, 0);
+    let created = serde_json::from_str::<Value>(&out).unwrap();
+    let id = created["id"].as_str().unwrap().to_string();
+    run(&env, &["post", "Second, with a comma"]).await;
+
+    let (code, out) = run(&env, &["get", &id, "-o", "json"]).await;
+    assert_eq!(code, 0);
+    assert_eq!(serde_json::from_str::<Value>(&out).unwrap()["message"], "First");
+
+    let (code, out) = run(&env, &["ls", "--output", "csv"]).await;
+    assert_eq!(code, 0);
+    let lines = out.lines().collect::<Vec<&str>>();
+    assert_eq!(lines[0], "id,author,replies,reactions,message");
+    assert!(lines[1].ends_with(",\"Second, with a comma\""));
+    assert!(lines[2].starts_with(&id));
+
+    let (_, out) = run(&env, &["ls", "-n", "1"]).await;
+    assert_eq!(out.lines().count(), 2);
+    assert!(out.lines().next().unwrap().starts_with("ID "));
+
+    let (code, _) = run(&env, &["rm", &id]).await;
+    assert_eq!(code, 0);
+    let (code, _) = run(&env, &["get", &id]).await;
+    assert_eq!(code, 3);
+}
+
+#[tokio::test]
+async fn problems_have_exit_codes() {
+    let (url, token) = server().await;
+    let (code, _) = run(&[("SNAP_URL", &url)], &["post", "Anonymous"]).await;
+    assert_eq!(code, 4);
+
+    let long = "a".repeat(1000);
+    let (code, _) = run(&[("SNAP_URL", &url), ("SNAP_TOKEN", &token)], &["post", &long]).await;
+    assert_eq!(code, 5);
+
+    let (code, _) = run(&[("SNAP_URL", "not a url")], &["ls"]).await;
+    assert_eq!(code, 2);
+}
+
+#[tokio::test]
+async fn unreachable_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    drop(listener);
+    let (code, _) = run(&[("SNAP_URL", &url)], &["ls"]).await;
+    assert_eq!(code, 8);
+}
+
+#[tokio::test]
+async fn config_file_and_flags() {
+    let (url, token) = server().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("snap.toml");
+    std::fs::write(&path, format!("url = \"{url}\"\ntoken = \"{token}\"\n")).unwrap();
+    let path = path.to_str().unwrap();
+
+    let (code, _) = run(&[], &["--config", path, "post", "From the file"]).await;
+    assert_eq!(code, 0);
+    let (code, _) = run(&[("SNAP_CLI_CONFIG", path)], &["post", "From the env"]).await;
+    assert_eq!(code, 0);
+    // Flags win over the file.
+    let (code, _) = run(&[("SNAP_CLI_CONFIG", path)], &["post", "Bad token", "--token", "nope"]).await;
+    assert_eq!(code, 4);
+
+    let export = dir.path().join("export.ndjson");
+    let (code, out) = run(&[("SNAP_CLI_CONFIG", path)], &["export", "--file", export.to_str().unwrap()]).await;
+    assert_eq!(code, 0);
+    assert!(out.is_empty());
+    let exported = std::fs::read_to_string(&export).unwrap();
+    let messages = exported.lines()
+        .map(|line| serde_json::from_str::<Value>(line).unwrap()["message"].as_str().unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(messages, ["From the env", "From the file"]);
+}
+
+#[tokio::test]
+async fn tail_follows_the_feed() {
+    let (url, token) = server().await;
+    let env = [("SNAP_URL", url.as_str()), ("SNAP_TOKEN", token.as_str())];
+    run(&env, &["post", "Old"]).await;
+    run(&env, &["post", "Older than the tail"]).await;
+
+    let mut tail = snap(&env, &["tail", "-n", "1", "-f", "-o", "json"])
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(tail.stdout.take().unwrap()).lines();
+    let message = |line: String| serde_json::from_str::<Value>(&line).unwrap()["message"].clone();
+
+    let next = tokio::time::timeout(Duration::from_secs(10), lines.next_line());
+    assert_eq!(message(next.await.unwrap().unwrap().unwrap()), "Older than the tail");
+
+    run(&env, &["post", "Live"]).await;
+    let next = tokio::time::timeout(Duration::from_secs(10), lines.next_line());
+    assert_eq!(message(next.await.unwrap().unwrap().unwrap()), "Live");
+}
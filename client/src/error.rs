@@ -0,0 +1,48 @@
+use snap_api::ProblemResponse;
+
+/// Reasons a call to the API can fail.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The base URL can't be used, only `http://` ones are supported.
+    InvalidUrl(String),
+    /// The server couldn't be reached or the connection broke.
+    Connection(String),
+    /// The server answered with an error status, described by `problem`.
+    Problem {
+        status: u16,
+        problem: ProblemResponse,
+    },
+    /// The response didn't have the expected shape.
+    Decode(String),
+}
+
+impl ClientError {
+    /// Status code of the response, for [ClientError::Problem].
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ClientError::Problem { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// Problem details sent by the server, for [ClientError::Problem].
+    pub fn problem(&self) -> Option<&ProblemResponse> {
+        match self {
+            ClientError::Problem { problem, .. } => Some(problem),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::InvalidUrl(url) => write!(f, "invalid base URL {url}"),
+            ClientError::Connection(e) => write!(f, "connection failed: {e}"),
+            ClientError::Problem { status, problem } => write!(f, "{status} {problem}"),
+            ClientError::Decode(e) => write!(f, "unexpected response: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
@@ -0,0 +1,103 @@
+use futures_util::Stream;
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use snap_api::SnapInfo;
+use crate::ClientError;
+
+/// Event of the live feed of snaps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeedEvent {
+    /// A snap was just posted.
+    Snap(Box<SnapInfo>),
+    /// The client fell behind and missed up to this many snaps.
+    Lagged(u64),
+}
+
+/// Server-sent event, before its data is decoded.
+#[derive(Debug, PartialEq)]
+struct RawEvent {
+    name: String,
+    data: String,
+}
+
+/// Splits the bytes of an event stream into events.
+#[derive(Default)]
+struct EventParser {
+    buffer: Vec<u8>,
+}
+
+impl EventParser {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend(bytes.iter().filter(|&&byte| byte != b'\r'));
+    }
+
+    /// Next complete event, skipping comments such as keep-alives.
+    fn next_event(&mut self) -> Option<RawEvent> {
+        loop {
+            let end = self.buffer.windows(2).position(|pair| pair == b"\n\n")?;
+            let block = self.buffer.drain(..end + 2).collect::<Vec<u8>>();
+            let block = String::from_utf8_lossy(&block);
+            let mut name = "message".to_string();
+            let mut data = Vec::new();
+            for line in block.lines() {
+                let (field, value) = line.split_once(':').unwrap_or((line, ""));
+                let value = value.strip_prefix(' ').unwrap_or(value);
+                match field {
+                    "event" => name = value.to_string(),
+                    "data" => data.push(value),
+                    _ => {}
+                }
+            }
+            if !data.is_empty() {
+                return Some(RawEvent { name, data: data.join("\n") })
+            }
+        }
+    }
+}
+
+/// Events of the feed read from `body`, until the server closes it.
+pub(crate) fn events(body: Incoming) -> impl Stream<Item = Result<FeedEvent, ClientError>> {
+    let state = Some((body, EventParser::default()));
+    futures_util::stream::unfold(state, |state| async move {
+        let (mut body, mut parser) = state?;
+        loop {
+            if let Some(event) = parser.next_event() {
+                let decoded = match event.name.as_str() {
+                    "snap" => serde_json::from_str::<SnapInfo>(&event.data)
+                        .map(|snap| FeedEvent::Snap(Box::new(snap)))
+                        .map_err(|e| ClientError::Decode(e.to_string())),
+                    "lagged" => event.data
+                        .parse()
+                        .map(FeedEvent::Lagged)
+                        .map_err(|_| ClientError::Decode(format!("bad lagged count {}", event.data))),
+                    _ => continue,
+                };
+                return Some((decoded, Some((body, parser))))
+            }
+            match body.frame().await? {
+                Ok(frame) => {
+                    if let Some(bytes) = frame.data_ref() {
+                        parser.push(bytes);
+                    }
+                }
+                Err(e) => return Some((Err(ClientError::Connection(e.to_string())), None)),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod feed_test {
+    use super::*;
+
+    #[test]
+    fn parser_splits_events_across_chunks() {
+        let mut parser = EventParser::default();
+        parser.push(b":\n\nevent: snap\nid: 1\nda");
+        assert_eq!(parser.next_event(), None);
+        parser.push(b"ta: {}\n\nevent: lagged\r\ndata: 3\r\n\r\n");
+        assert_eq!(parser.next_event(), Some(RawEvent { name: "snap".to_string(), data: "{}".to_string() }));
+        assert_eq!(parser.next_event(), Some(RawEvent { name: "lagged".to_string(), data: "3".to_string() }));
+        assert_eq!(parser.next_event(), None);
+    }
+}
@@ -0,0 +1,337 @@
+//! Typed async client of the Snap API.
+//!
+//! [SnapClient] sends the requests and decodes the responses with the
+//! same types the server uses, from the `snap_api` crate. Problem
+//! responses become [ClientError::Problem], and requests are retried
+//! following a [RetryPolicy].
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use futures_util::{Stream, StreamExt};
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Bytes, Incoming};
+use hyper::header;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use serde::de::DeserializeOwned;
+
+pub use snap_api::{
+    ApiResponse,
+    CreateSnap,
+    Page,
+    ProblemResponse,
+    ReplicationRole,
+    ReplicationStatus,
+    SnapCreated,
+    SnapInfo,
+    SnapshotInfo,
+};
+pub use error::ClientError;
+pub use feed::FeedEvent;
+pub use retry::RetryPolicy;
+
+mod error;
+mod feed;
+mod retry;
+
+/// How the client authenticates its requests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    /// Access token from `POST /login`.
+    Bearer(String),
+    /// Key from `POST /api-keys`.
+    ApiKey(String),
+}
+
+impl Credentials {
+    /// Value of the `Authorization` header.
+    fn header(&self) -> String {
+        match self {
+            Credentials::Bearer(token) => format!("Bearer {token}"),
+            Credentials::ApiKey(key) => format!("ApiKey {key}"),
+        }
+    }
+}
+
+/// Client of a Snap server.
+#[derive(Clone)]
+pub struct SnapClient {
+    base_url: String,
+    credentials: Option<Credentials>,
+    retry: RetryPolicy,
+    http: Client<HttpConnector, Full<Bytes>>,
+}
+
+impl SnapClient {
+    /// Client of the server at `base_url`, such as `http://localhost:3000`,
+    /// without credentials and with the default [RetryPolicy].
+    pub fn new(base_url: &str) -> Result<SnapClient, ClientError> {
+        let base_url = base_url.trim_end_matches('/');
+        let valid = base_url.parse::<hyper::Uri>()
+            .is_ok_and(|uri| uri.scheme_str() == Some("http") && uri.host().is_some());
+        if !valid {
+            return Err(ClientError::InvalidUrl(base_url.to_string()))
+        }
+        Ok(SnapClient {
+            base_url: base_url.to_string(),
+            credentials: None,
+            retry: RetryPolicy::default(),
+            http: Client::builder(TokioExecutor::new()).build_http(),
+        })
+    }
+
+    /// Same client authenticating with `credentials`.
+    pub fn with_credentials(mut self, credentials: Credentials) -> SnapClient {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Same client retrying as told by `retry`.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> SnapClient {
+        self.retry = retry;
+        self
+    }
+
+    /// Post a snap, written by the authenticated user.
+    pub async fn create(&self, snap: &CreateSnap) -> Result<SnapCreated, ClientError> {
+        let body = serde_json::to_vec(snap).map_err(|e| ClientError::Decode(e.to_string()))?;
+        self.call(Method::POST, "/snaps", Some(body)).await
+    }
+
+    /// The snap with this `id`.
+    pub async fn get(&self, id: &str) -> Result<SnapInfo, ClientError> {
+        self.call(Method::GET, &format!("/snaps/{}", encode(id)), None).await
+    }
+
+    /// Delete the snap with this `id`, written by the authenticated user.
+    pub async fn delete(&self, id: &str) -> Result<(), ClientError> {
+        let response = self.send(Method::DELETE, &format!("/snaps/{}", encode(id)), None).await?;
+        read_body(response).await.map(|_| ())
+    }
+
+    /// Up to `limit` snaps, from the most recent to the oldest, starting
+    /// after the `next_cursor` of the previous page.
+    pub async fn list_page(&self, cursor: Option<&str>, limit: usize) -> Result<Page, ClientError> {
+        let mut path = format!("/snaps?limit={limit}");
+        if let Some(cursor) = cursor {
+            path.push_str(&format!("&cursor={}", encode(cursor)));
+        }
+        let response = self.send(Method::GET, &path, None).await?;
+        decode(&read_body(response).await?)
+    }
+
+    /// Every snap, from the most recent to the oldest, fetched `page_size`
+    /// at a time as the stream is read. Ends after the first error.
+    pub fn list(&self, page_size: usize) -> impl Stream<Item = Result<SnapInfo, ClientError>> + '_ {
+        struct Pages {
+            cursor: Option<String>,
+            buffered: VecDeque<SnapInfo>,
+            done: bool,
+        }
+
+        let pages = Pages { cursor: None, buffered: VecDeque::new(), done: false };
+        futures_util::stream::unfold(pages, move |mut pages| async move {
+            loop {
+                if let Some(snap) = pages.buffered.pop_front() {
+                    return Some((Ok(snap), pages))
+                }
+                if pages.done {
+                    return None
+                }
+                match self.list_page(pages.cursor.as_deref(), page_size).await {
+                    Ok(page) => {
+                        pages.done = page.next_cursor.is_none();
+                        pages.cursor = page.next_cursor;
+                        pages.buffered.extend(page.data);
+                    }
+                    Err(e) => {
+                        pages.done = true;
+                        return Some((Err(e), pages))
+                    }
+                }
+            }
+        })
+    }
+
+    /// Follow the snaps of every user as they are posted. The stream
+    /// ends when the server closes the connection.
+    pub async fn stream(&self) -> Result<impl Stream<Item = Result<FeedEvent, ClientError>>, ClientError> {
+        let response = self.send(Method::GET, "/snaps/stream", None).await?;
+        if !response.status().is_success() {
+            return Err(problem(response).await)
+        }
+        Ok(feed::events(response.into_body()))
+    }
+
+    /// Have the server write a snapshot of its store. Administrators only.
+    pub async fn create_snapshot(&self) -> Result<SnapshotInfo, ClientError> {
+        self.call(Method::POST, "/admin/snapshots", None).await
+    }
+
+    /// The snapshots kept by the server, the most recent first.
+    pub async fn snapshots(&self) -> Result<Vec<SnapshotInfo>, ClientError> {
+        self.call(Method::GET, "/admin/snapshots", None).await
+    }
+
+    /// The content of the snapshot named `name`, as it is received.
+    pub async fn download_snapshot(&self, name: &str) -> Result<impl Stream<Item = Result<Bytes, ClientError>>, ClientError> {
+        let response = self.send(Method::GET, &format!("/admin/snapshots/{}", encode(name)), None).await?;
+        if !response.status().is_success() {
+            return Err(problem(response).await)
+        }
+        Ok(response.into_body()
+            .into_data_stream()
+            .map(|chunk| chunk.map_err(|e| ClientError::Connection(e.to_string()))))
+    }
+
+    /// Replication role of the server and how far it is behind its leader.
+    pub async fn replication_status(&self) -> Result<ReplicationStatus, ClientError> {
+        self.call(Method::GET, "/replication/status", None).await
+    }
+
+    /// Send a request and decode the `data` of its [ApiResponse].
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<T, ClientError> {
+        let response = self.send(method, path, body).await?;
+        let ApiResponse { data } = decode(&read_body(response).await?)?;
+        Ok(data)
+    }
+
+    /// Send a request to `path`, retrying as told by the [RetryPolicy].
+    /// Writes sent to a follower are redirected to its leader.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> Result<Response<Incoming>, ClientError> {
+        let body = Bytes::from(body.unwrap_or_default());
+        // Only the requests that can be repeated without side effects are
+        // retried when they may have reached the server.
+        let idempotent = method != Method::POST;
+        let mut retry = 0;
+        let mut url = format!("{}{path}", self.base_url);
+        let mut redirects = 0;
+        loop {
+            let mut request = Request::builder()
+                .method(method.clone())
+                .uri(&url)
+                .header(header::ACCEPT, "application/json");
+            if !body.is_empty() {
+                request = request.header(header::CONTENT_TYPE, "application/json");
+            }
+            if let Some(credentials) = &self.credentials {
+                request = request.header(header::AUTHORIZATION, credentials.header());
+            }
+            let request = request.body(Full::new(body.clone()))
+                .map_err(|e| ClientError::InvalidUrl(e.to_string()))?;
+
+            let retry_after = match self.http.request(request).await {
+                Ok(response) => {
+                    let status = response.status();
+                    // Only redirects keeping the method and body are followed.
+                    let redirect = matches!(status, StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT);
+                    let location = response.headers()
+                        .get(header::LOCATION)
+                        .and_then(|value| value.to_str().ok())
+                        .filter(|location| location.starts_with("http://"));
+                    if let (true, Some(location), true) = (redirect, location, redirects < MAX_REDIRECTS) {
+                        url = location.to_string();
+                        redirects += 1;
+                        continue
+                    }
+                    let retryable = status == StatusCode::TOO_MANY_REQUESTS || (idempotent && matches!(
+                        status,
+                        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT,
+                    ));
+                    if !retryable || retry == self.retry.max_retries {
+                        return Ok(response)
+                    }
+                    response.headers()
+                        .get(header::RETRY_AFTER)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.parse().ok())
+                        .map(Duration::from_secs)
+                }
+                Err(e) => {
+                    if !(idempotent || e.is_connect()) || retry == self.retry.max_retries {
+                        return Err(ClientError::Connection(e.to_string()))
+                    }
+                    None
+                }
+            };
+            tokio::time::sleep(self.retry.backoff(retry, retry_after)).await;
+            retry += 1;
+        }
+    }
+}
+
+/// Redirects followed for one request, in case servers send each other back.
+const MAX_REDIRECTS: usize = 3;
+
+/// Body of a successful `response`, or its problem details as an error.
+async fn read_body(response: Response<Incoming>) -> Result<Bytes, ClientError> {
+    if !response.status().is_success() {
+        return Err(problem(response).await)
+    }
+    Ok(response.into_body()
+        .collect()
+        .await
+        .map_err(|e| ClientError::Connection(e.to_string()))?
+        .to_bytes())
+}
+
+/// Error with the problem details of the failed `response`.
+async fn problem(response: Response<Incoming>) -> ClientError {
+    let status = response.status();
+    let body = match response.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return ClientError::Connection(e.to_string()),
+    };
+    // Proxies in between may answer without problem details.
+    let problem = serde_json::from_slice(&body).unwrap_or_else(|_| ProblemResponse {
+        uri: None,
+        title: status.canonical_reason().map(str::to_string),
+        status: Some(status.to_string()),
+        detail: None,
+    });
+    ClientError::Problem { status: status.as_u16(), problem }
+}
+
+fn decode<T: DeserializeOwned>(body: &[u8]) -> Result<T, ClientError> {
+    serde_json::from_slice(body).map_err(|e| ClientError::Decode(e.to_string()))
+}
+
+/// Percent-encode `value` to use it as a path segment or query value.
+fn encode(value: &str) -> String {
+    value.bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (byte as char).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod client_test {
+    use super::*;
+
+    #[test]
+    fn only_http_urls_are_accepted() {
+        assert!(SnapClient::new("http://localhost:3000/").is_ok());
+        assert!(matches!(SnapClient::new("localhost:3000"), Err(ClientError::InvalidUrl(_))));
+        assert!(matches!(SnapClient::new("https://example.com"), Err(ClientError::InvalidUrl(_))));
+    }
+
+    #[test]
+    fn values_are_percent_encoded() {
+        assert_eq!(encode("1700000000_a-b"), "1700000000_a-b");
+        assert_eq!(encode("a/b c"), "a%2Fb%20c");
+    }
+}
@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+/// When and how long to wait before sending a request again.
+///
+/// Requests are retried when the connection can't be made, when the
+/// server is rate limiting (429) and, for the requests that are safe
+/// to repeat, on a broken connection or a 502, 503 or 504 response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one.
+    pub max_retries: u32,
+    /// Wait before the first retry, doubled on each of the next ones.
+    pub initial_backoff: Duration,
+    /// Longest wait between two attempts, `Retry-After` included.
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Send every request only once.
+    pub fn none() -> RetryPolicy {
+        RetryPolicy { max_retries: 0, ..RetryPolicy::default() }
+    }
+
+    /// Wait before the retry number `retry`, counting from 0, unless
+    /// the server said how long with `retry_after`.
+    pub(crate) fn backoff(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        let backoff = retry_after.unwrap_or_else(|| {
+            self.initial_backoff.saturating_mul(2u32.saturating_pow(retry))
+        });
+        backoff.min(self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+#[cfg(test)]
+mod retry_test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(0, None), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2, None), Duration::from_millis(400));
+        assert_eq!(policy.backoff(10, None), Duration::from_secs(5));
+        assert_eq!(policy.backoff(0, Some(Duration::from_secs(2))), Duration::from_secs(2));
+        assert_eq!(policy.backoff(0, Some(Duration::from_secs(60))), Duration::from_secs(5));
+    }
+}
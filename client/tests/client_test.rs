@@ -0,0 +1,192 @@
+use std::time::Duration;
+use futures_util::StreamExt;
+use snap_app_demo::{config, context, rate_limit, router, shutdown, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use snap_client::{ClientError, CreateSnap, Credentials, FeedEvent, ReplicationRole, RetryPolicy, SnapClient};
+use tokio::net::TcpListener;
+
+/// Serve `app` on a free port and return its base URL.
+async fn serve(app: axum::Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(shutdown::serve_with_drain(listener, app, std::future::pending(), Duration::ZERO, Duration::from_secs(1)));
+    format!("http://{address}")
+}
+
+/// Router and access token of its one registered user.
+fn app_with_author(context: context::AppContext) -> (axum::Router, String) {
+    let mut repo = state::MockSnapRepository::new();
+    let user = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+    let token = context.auth.issue(&user.id()).access_token;
+    (router::get_router_with_context(context).with_state(repo), token)
+}
+
+/// Client of a new server, authenticated as its one user.
+async fn client() -> SnapClient {
+    let (app, token) = app_with_author(context::AppContext::default());
+    SnapClient::new(&serve(app).await)
+        .unwrap()
+        .with_credentials(Credentials::Bearer(token))
+}
+
+fn new_snap(message: &str) -> CreateSnap {
+    CreateSnap { message: message.to_string(), in_reply_to: None }
+}
+
+#[tokio::test]
+async fn create_get_and_delete() {
+    let client = client().await;
+    let created = client.create(&new_snap("Hello #rust")).await.unwrap();
+    assert_eq!(created.message, "Hello #rust");
+
+    let snap = client.get(&created.id).await.unwrap();
+    assert_eq!(snap.id, created.id);
+    assert_eq!(snap.author_id, created.author_id);
+    assert_eq!(snap.entities[0].text, "rust");
+
+    client.delete(&created.id).await.unwrap();
+    let error = client.get(&created.id).await.unwrap_err();
+    assert_eq!(error.status(), Some(404));
+    assert!(error.problem().unwrap().title.is_some());
+}
+
+#[tokio::test]
+async fn problems_are_typed() {
+    let client = client().await;
+    let error = client.create(&new_snap(&"a".repeat(1000))).await.unwrap_err();
+    assert_eq!(error.status(), Some(422));
+
+    let anonymous = SnapClient::new(&serve(app_with_author(context::AppContext::default()).0).await).unwrap();
+    let error = anonymous.create(&new_snap("Hi")).await.unwrap_err();
+    assert!(matches!(error, ClientError::Problem { status: 401, .. }), "{error}");
+}
+
+#[tokio::test]
+async fn list_walks_every_page() {
+    let client = client().await;
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        ids.push(client.create(&new_snap(&format!("Snap {i}"))).await.unwrap().id);
+    }
+    ids.reverse();
+
+    let page = client.list_page(None, 2).await.unwrap();
+    assert_eq!(page.data.len(), 2);
+    assert!(page.next_cursor.is_some());
+
+    let listed = client.list(2)
+        .map(|snap| snap.unwrap().id)
+        .collect::<Vec<String>>()
+        .await;
+    assert_eq!(listed, ids);
+}
+
+#[tokio::test]
+async fn stream_follows_new_snaps() {
+    let client = client().await;
+    let stream = client.stream().await.unwrap();
+    let created = client.create(&new_snap("Live")).await.unwrap();
+
+    let mut stream = Box::pin(stream);
+    let event = tokio::time::timeout(Duration::from_secs(5), stream.next())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let FeedEvent::Snap(snap) = event else {
+        panic!("expected a snap, got {event:?}")
+    };
+    assert_eq!(snap.id, created.id);
+    assert_eq!(snap.message, "Live");
+}
+
+#[tokio::test]
+async fn rate_limited_requests_are_retried() {
+    let limits = config::RateLimitConfig {
+        enabled: true,
+        read: config::BucketConfig { burst: 10, per_second: 10.0 },
+        write: config::BucketConfig { burst: 1, per_second: 2.0 },
+    };
+    let (app, token) = app_with_author(context::AppContext::default());
+    let app = app.layer(rate_limit::RateLimitLayer::new(rate_limit::RateLimiter::new(limits)));
+    let client = SnapClient::new(&serve(app).await)
+        .unwrap()
+        .with_credentials(Credentials::Bearer(token));
+
+    client.clone().with_retry(RetryPolicy::none()).create(&new_snap("First")).await.unwrap();
+    let error = client.clone().with_retry(RetryPolicy::none()).create(&new_snap("Denied")).await.unwrap_err();
+    assert_eq!(error.status(), Some(429));
+
+    // Waits for the `Retry-After` of the server.
+    client.create(&new_snap("Retried")).await.unwrap();
+}
+
+#[tokio::test]
+async fn unreachable_servers_fail_after_the_retries() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+
+    let retry = RetryPolicy {
+        max_retries: 2,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(10),
+    };
+    let client = SnapClient::new(&format!("http://{address}")).unwrap().with_retry(retry);
+    let error = client.get("1").await.unwrap_err();
+    assert!(matches!(error, ClientError::Connection(_)), "{error}");
+}
+
+#[tokio::test]
+async fn snapshots_are_downloaded() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut repo = state::MockSnapRepository::new();
+    let admin = repo.create_user(User::new("admin".to_string(), "password")).unwrap().id();
+    let mut config = config::Config::default();
+    config.admin.users = vec![admin.clone()];
+    config.snapshots.path = Some(dir.path().to_path_buf());
+    let context = context::AppContext { config: std::sync::Arc::new(config), ..context::AppContext::default() };
+    let token = context.auth.issue(&admin).access_token;
+    let app = router::get_router_with_context(context).with_state(repo);
+    let client = SnapClient::new(&serve(app).await)
+        .unwrap()
+        .with_credentials(Credentials::Bearer(token));
+
+    let info = client.create_snapshot().await.unwrap();
+    assert_eq!(info.entries, 1);
+    assert_eq!(client.snapshots().await.unwrap()[0].name, info.name);
+    let mut downloaded = Vec::new();
+    let mut chunks = Box::pin(client.download_snapshot(&info.name).await.unwrap());
+    while let Some(chunk) = chunks.next().await {
+        downloaded.extend_from_slice(&chunk.unwrap());
+    }
+    assert_eq!(downloaded, std::fs::read(dir.path().join(&info.name)).unwrap());
+    assert_eq!(info.bytes, downloaded.len() as u64);
+    match client.download_snapshot("snapshot-missing.ndjson").await {
+        Err(ClientError::Problem { status: 404, .. }) => {}
+        _ => panic!("expected a 404"),
+    }
+}
+
+#[tokio::test]
+async fn writes_to_followers_reach_the_leader() {
+    let mut config = config::Config::default();
+    config.auth.secret = Some("a secret shared by the leader and the follower".to_string());
+    let leader_context = context::AppContext::new(config.clone()).unwrap();
+    let (leader, token) = app_with_author(leader_context);
+    let leader = serve(leader).await;
+    config.replication.leader = Some(leader.clone());
+    let (follower, _) = app_with_author(context::AppContext::new(config).unwrap());
+    let follower = SnapClient::new(&serve(follower).await)
+        .unwrap()
+        .with_credentials(Credentials::Bearer(token.clone()));
+
+    let status = follower.replication_status().await.unwrap();
+    assert_eq!(status.role, ReplicationRole::Follower);
+    assert_eq!(status.leader.as_deref(), Some(leader.as_str()));
+
+    let created = follower.create(&new_snap("Hello leader")).await.unwrap();
+    let leader = SnapClient::new(&leader).unwrap().with_credentials(Credentials::Bearer(token));
+    assert_eq!(leader.get(&created.id).await.unwrap().message, "Hello leader");
+}
@@ -0,0 +1,178 @@
+use snap_app_demo::dump::{self, ConflictPolicy, ImportOptions};
+use snap_app_demo::snapshot;
+use snap_app_demo::config::{Cli, Config, StorageBackend};
+use snap_app_demo::state::{self, Repository};
+use clap::Parser;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+/// Command line flags of the administration tool. The storage is found
+/// as with the server, from the same file, variables and flags.
+/// Stop the server before using it on a journal.
+#[derive(Debug, clap::Parser)]
+#[command(name = "snap-admin", version, about = "Export, import and verify the data of a Snap App storage")]
+struct AdminCli {
+    /// Path to a TOML configuration file (env: SNAP_CONFIG).
+    #[arg(long, global = true, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Storage backend, `memory` or `journal` (env: SNAP_STORAGE_BACKEND).
+    #[arg(long, global = true, value_name = "BACKEND")]
+    storage_backend: Option<StorageBackend>,
+
+    /// Path of the journal file (env: SNAP_STORAGE_PATH).
+    #[arg(long, global = true, value_name = "PATH")]
+    storage_path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Write a dump of every snap.
+    Export {
+        /// File to write instead of the standard output.
+        #[arg(long, short, value_name = "PATH")]
+        file: Option<PathBuf>,
+    },
+    /// Load a dump, keeping the ids and times of its snaps.
+    Import {
+        /// Dump to read, `-` for the standard input.
+        file: PathBuf,
+        /// Check the dump and count what would change, storing nothing.
+        #[arg(long)]
+        dry_run: bool,
+        /// What to do with snaps already stored: `fail`, `skip` or `overwrite`.
+        #[arg(long, value_name = "POLICY", default_value_t = ConflictPolicy::Fail)]
+        on_conflict: ConflictPolicy,
+    },
+    /// Check that a snapshot is complete and replays cleanly, without
+    /// opening the storage.
+    Verify {
+        /// Snapshot to check.
+        file: PathBuf,
+    },
+}
+
+fn main() -> ExitCode {
+    let cli = AdminCli::parse();
+    let server_cli = Cli {
+        config: cli.config.clone(),
+        storage_backend: cli.storage_backend,
+        storage_path: cli.storage_path.clone(),
+        ..Cli::default()
+    };
+    let config = match Config::load(&server_cli, |key| env::var(key).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let fan_out = config.timeline.fan_out;
+    if let Command::Verify { file } = &cli.command {
+        return match snapshot::verify(file, fan_out) {
+            Ok((info, _)) => {
+                eprintln!("snapshot {}: {} entries, sha256 {}", info.name, info.entries, info.sha256);
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("error: {e}");
+                ExitCode::FAILURE
+            }
+        }
+    }
+    match config.storage.backend {
+        StorageBackend::Memory => {
+            eprintln!("warning: the memory backend starts empty and forgets everything on exit");
+            run(state::MockSnapRepository::with_fan_out(fan_out), cli.command)
+        }
+        StorageBackend::Journal => {
+            // Validation guarantees a path for the journal backend.
+            let path = config.storage.path.as_ref().unwrap();
+            match state::JournalSnapRepository::open_with_fan_out(path, fan_out) {
+                Ok(repo) => run(repo, cli.command),
+                Err(e) => {
+                    eprintln!("error: can't open journal {}: {e}", path.display());
+                    ExitCode::FAILURE
+                }
+            }
+        }
+    }
+}
+
+/// Run `command` against `repo`, flushing it at the end.
+fn run<S: Repository>(mut repo: S, command: Command) -> ExitCode {
+    match command {
+        Command::Export { file } => {
+            let written = match file {
+                Some(path) => File::create(&path).and_then(|file| export(&repo, BufWriter::new(file))),
+                None => export(&repo, io::stdout().lock()),
+            };
+            match written {
+                Ok(count) => eprintln!("exported {count} snaps"),
+                Err(e) => {
+                    eprintln!("error: can't write the dump: {e}");
+                    return ExitCode::FAILURE;
+                }
+            }
+        }
+        Command::Import { file, dry_run, on_conflict } => {
+            let reader: Box<dyn BufRead> = if file.as_os_str() == "-" {
+                Box::new(io::stdin().lock())
+            } else {
+                match File::open(&file) {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(e) => {
+                        eprintln!("error: can't open {}: {e}", file.display());
+                        return ExitCode::FAILURE;
+                    }
+                }
+            };
+            let options = ImportOptions { on_conflict, dry_run, max_created: None };
+            let imported = dump::import(&mut repo, reader, options, |report| {
+                eprintln!("read {} of {} snaps", report.read, report.total);
+            });
+            // Whatever was stored before a failure is kept, so flush anyway.
+            let flushed = repo.flush();
+            let report = match imported {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("error: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            let verb = if report.dry_run { "would create" } else { "created" };
+            eprintln!(
+                "read {} snaps: {verb} {}, overwrote {}, skipped {}",
+                report.read,
+                report.created,
+                report.overwritten,
+                report.skipped,
+            );
+            if let Err(e) = flushed {
+                eprintln!("error: can't flush the storage: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+        Command::Verify { .. } => unreachable!("snapshots are verified without a storage"),
+    }
+    ExitCode::SUCCESS
+}
+
+/// Write a dump of `repo` to `out` and return the number of snaps.
+fn export<S: Repository, W: Write>(repo: &S, mut out: W) -> io::Result<usize> {
+    let mut lines = 0;
+    for line in dump::export(repo) {
+        out.write_all(line.as_bytes())?;
+        lines += 1;
+    }
+    out.flush()?;
+    // Without the header and the footer.
+    Ok(lines - 2)
+}
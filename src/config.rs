@@ -0,0 +1,1472 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Resolved configuration of the server.
+/// Values are layered: defaults, then the TOML file,
+/// then `SNAP_*` environment variables and finally command line flags.
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub limits: LimitsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub auth: AuthConfig,
+    pub timeline: TimelineConfig,
+    pub tags: TagsConfig,
+    pub notifications: NotificationsConfig,
+    pub webhooks: WebhooksConfig,
+    pub media: MediaConfig,
+    pub admin: AdminConfig,
+    pub moderation: ModerationConfig,
+    pub snapshots: SnapshotsConfig,
+    pub replication: ReplicationConfig,
+    pub tenancy: TenancyConfig,
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address the server listens on.
+    pub bind: SocketAddr,
+    /// Seconds in-flight requests get to finish after a shutdown signal.
+    pub drain_timeout_secs: u64,
+    /// Seconds new connections are still accepted after a shutdown signal,
+    /// with `/readyz` failing, so load balancers stop routing here first.
+    pub readiness_grace_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// File used by persistent backends.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Milliseconds between flushes of persistent backends to disk.
+    pub flush_interval_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// Snaps live in memory and are lost on exit.
+    #[default]
+    Memory,
+    /// Snaps are kept in memory and appended to a journal file.
+    Journal,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// Maximum amount of characters of a snap message.
+    pub max_message_length: usize,
+    /// Maximum size in bytes of a request body.
+    pub max_body_bytes: usize,
+    /// Most levels of replies returned in a conversation thread.
+    pub max_thread_depth: usize,
+}
+
+/// Per-client token buckets, one for reads (GET, HEAD, OPTIONS)
+/// and one for every other method.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub read: BucketConfig,
+    pub write: BucketConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BucketConfig {
+    /// Requests a client can make in a row.
+    pub burst: u32,
+    /// Requests per second given back to the client.
+    pub per_second: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimelineConfig {
+    pub fan_out: FanOut,
+    /// Snaps per page when the client doesn't ask for an amount.
+    pub default_page_size: usize,
+    /// Most snaps a client can ask for in one page.
+    pub max_page_size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TagsConfig {
+    /// Trending tags are counted over the snaps of this many last seconds.
+    pub trending_window_secs: u64,
+    /// Tags returned as trending when the client doesn't ask for an amount.
+    pub trending_limit: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationsConfig {
+    /// Notifications kept for streams that fall behind before they miss some.
+    pub stream_buffer: usize,
+    /// Seconds between keep-alive comments on idle streams.
+    pub keep_alive_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    /// Milliseconds between two looks at the delivery queue.
+    pub poll_interval_ms: u64,
+    /// Deliveries attempted on each look at the queue.
+    pub batch_size: usize,
+    /// Seconds a receiver gets to answer.
+    pub timeout_secs: u64,
+    /// Attempts before a delivery goes to the dead letters.
+    pub max_attempts: u32,
+    /// Milliseconds before the first retry, doubled on each one after.
+    pub initial_backoff_ms: u64,
+    /// Longest wait between two attempts, in milliseconds.
+    pub max_backoff_ms: u64,
+    /// Let webhooks point to loopback, private and link-local addresses,
+    /// for receivers on the same machine or network.
+    pub allow_private_hosts: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MediaConfig {
+    /// Directory of the attachment files. They live in memory when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Maximum size in bytes of one attachment.
+    pub max_bytes: usize,
+    /// Most attachments a snap can have.
+    pub max_attachments: usize,
+    /// Most pixels an image can have, to turn away decompression bombs.
+    pub max_pixels: u64,
+    /// Longest side in pixels of the thumbnails made for every image.
+    pub thumbnail_sizes: Vec<u32>,
+    /// Images processed at the same time, each on a blocking thread.
+    pub workers: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Ids of the users allowed on the `/admin` endpoints, none by default.
+    pub users: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationConfig {
+    /// Ids of the users allowed on the `/moderation` endpoints, besides
+    /// the admins. None by default.
+    pub moderators: Vec<String>,
+    /// Most characters in the reason of a report.
+    pub max_reason_length: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SnapshotsConfig {
+    /// Directory of the snapshots of the store. They can't be taken when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// Seconds between two scheduled snapshots, 0 to only take them on demand.
+    pub interval_secs: u64,
+    /// Snapshots kept in the directory, the oldest are deleted first.
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReplicationConfig {
+    /// Base URL of the leader, e.g. `http://10.0.0.1:8080`. Setting it makes
+    /// this server a read-only follower of that leader.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader: Option<String>,
+    /// Secret shared by the leader and its followers. The change log isn't
+    /// served when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// Milliseconds between two looks at the change log for new changes.
+    pub poll_interval_ms: u64,
+    /// Latest changes kept in memory for the followers by the `memory`
+    /// backend. The `journal` backend reads them from its file instead.
+    pub change_buffer: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenancyConfig {
+    /// Serve a separate store to each tenant instead of a single one.
+    /// With the journal backend, `storage.path` is then a directory
+    /// with a journal per tenant.
+    pub enabled: bool,
+    /// Domain under which `<tenant>.<domain>` hosts name a tenant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// Token of the `/admin/tenants` endpoints.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+    /// Quotas of the tenants created without their own.
+    pub default_quotas: TenantQuotas,
+}
+
+/// Limits of one tenant, none when unset.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantQuotas {
+    /// Most snaps the tenant can store.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_snaps: Option<usize>,
+    /// Most users that can register.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_users: Option<usize>,
+    /// Reads shared by every client of the tenant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read: Option<BucketConfig>,
+    /// Writes shared by every client of the tenant.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write: Option<BucketConfig>,
+}
+
+impl TenantQuotas {
+    /// Check values that parse fine but make no sense, `key` names the quotas.
+    pub fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        for (name, bucket) in [("read", &self.read), ("write", &self.write)] {
+            let Some(bucket) = bucket else {
+                continue
+            };
+            if bucket.burst == 0 {
+                return Err(invalid(&format!("{key}.{name}.burst"), "0", "must be at least 1"));
+            }
+            if !(bucket.per_second > 0.0 && bucket.per_second.is_finite()) {
+                return Err(invalid(
+                    &format!("{key}.{name}.per_second"),
+                    &bucket.per_second.to_string(),
+                    "must be a positive number",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// When home timelines are assembled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FanOut {
+    /// Merge the snaps of followed users on every timeline request.
+    #[default]
+    Read,
+    /// Push every new snap to the timelines of the author's followers.
+    Write,
+}
+
+impl FanOut {
+    /// Name used in metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FanOut::Read => "read",
+            FanOut::Write => "write",
+        }
+    }
+}
+
+/// Signing keys and lifetimes of the bearer tokens.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub algorithm: JwtAlgorithm,
+    /// HS256 shared secret. A random one is made at startup when unset,
+    /// so tokens don't survive restarts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+    /// EdDSA private key, PKCS#8 PEM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub private_key_path: Option<PathBuf>,
+    /// EdDSA public key, SPKI PEM.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key_path: Option<PathBuf>,
+    /// `iss` claim of issued tokens, checked on every request.
+    pub issuer: String,
+    /// Seconds an access token is accepted.
+    pub access_token_ttl_secs: u64,
+    /// Seconds a refresh token can be traded for new tokens.
+    pub refresh_token_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub enum JwtAlgorithm {
+    /// HMAC with SHA-256 and a shared secret.
+    #[default]
+    HS256,
+    /// Ed25519 signatures with a key pair.
+    EdDSA,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// `tracing` filter directives. `RUST_LOG` takes precedence when set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per event.
+    Json,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8080)),
+            drain_timeout_secs: 30,
+            readiness_grace_secs: 5,
+        }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            backend: StorageBackend::default(),
+            path: None,
+            flush_interval_ms: 1000,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_message_length: 280,
+            max_body_bytes: 64 * 1024,
+            max_thread_depth: 32,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled: true,
+            read: BucketConfig { burst: 120, per_second: 20.0 },
+            write: BucketConfig { burst: 20, per_second: 1.0 },
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            algorithm: JwtAlgorithm::default(),
+            secret: None,
+            private_key_path: None,
+            public_key_path: None,
+            issuer: "snap_app_demo".to_string(),
+            access_token_ttl_secs: 15 * 60,
+            refresh_token_ttl_secs: 14 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for TimelineConfig {
+    fn default() -> Self {
+        TimelineConfig {
+            fan_out: FanOut::default(),
+            default_page_size: 20,
+            max_page_size: 100,
+        }
+    }
+}
+
+impl Default for TagsConfig {
+    fn default() -> Self {
+        TagsConfig {
+            trending_window_secs: 3600,
+            trending_limit: 10,
+        }
+    }
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        NotificationsConfig {
+            stream_buffer: 256,
+            keep_alive_secs: 15,
+        }
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        WebhooksConfig {
+            poll_interval_ms: 1000,
+            batch_size: 32,
+            timeout_secs: 10,
+            max_attempts: 8,
+            initial_backoff_ms: 1000,
+            max_backoff_ms: 3_600_000,
+            allow_private_hosts: false,
+        }
+    }
+}
+
+impl Default for MediaConfig {
+    fn default() -> Self {
+        MediaConfig {
+            path: None,
+            max_bytes: 5 * 1024 * 1024,
+            max_attachments: 4,
+            max_pixels: 40_000_000,
+            thumbnail_sizes: vec![160, 640],
+            workers: 2,
+        }
+    }
+}
+
+impl Default for ModerationConfig {
+    fn default() -> Self {
+        ModerationConfig {
+            moderators: Vec::new(),
+            max_reason_length: 500,
+        }
+    }
+}
+
+impl Default for SnapshotsConfig {
+    fn default() -> Self {
+        SnapshotsConfig {
+            path: None,
+            interval_secs: 0,
+            keep: 7,
+        }
+    }
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            leader: None,
+            secret: None,
+            poll_interval_ms: 200,
+            change_buffer: 100_000,
+        }
+    }
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StorageBackend::Memory),
+            "journal" => Ok(StorageBackend::Journal),
+            _ => Err("expected one of \"memory\", \"journal\"".to_string()),
+        }
+    }
+}
+
+impl FromStr for JwtAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HS256" => Ok(JwtAlgorithm::HS256),
+            "EdDSA" => Ok(JwtAlgorithm::EdDSA),
+            _ => Err("expected one of \"HS256\", \"EdDSA\"".to_string()),
+        }
+    }
+}
+
+impl FromStr for FanOut {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(FanOut::Read),
+            "write" => Ok(FanOut::Write),
+            _ => Err("expected one of \"read\", \"write\"".to_string()),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err("expected one of \"text\", \"json\"".to_string()),
+        }
+    }
+}
+
+/// Command line flags of the server.
+#[derive(Debug, Default, clap::Parser)]
+#[command(version, about = "Snap App web API")]
+pub struct Cli {
+    /// Path to a TOML configuration file (env: SNAP_CONFIG).
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, e.g. 0.0.0.0:8080.
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<SocketAddr>,
+
+    /// Seconds in-flight requests get to finish on shutdown.
+    #[arg(long, value_name = "SECS")]
+    pub drain_timeout_secs: Option<u64>,
+
+    /// Seconds connections are still accepted on shutdown, with /readyz failing.
+    #[arg(long, value_name = "SECS")]
+    pub readiness_grace_secs: Option<u64>,
+
+    /// Storage backend: memory or journal.
+    #[arg(long, value_name = "BACKEND")]
+    pub storage_backend: Option<StorageBackend>,
+
+    /// File used by persistent storage backends.
+    #[arg(long, value_name = "PATH")]
+    pub storage_path: Option<PathBuf>,
+
+    /// Milliseconds between flushes of persistent storage backends.
+    #[arg(long, value_name = "MILLIS")]
+    pub flush_interval_ms: Option<u64>,
+
+    /// Maximum amount of characters of a snap message.
+    #[arg(long, value_name = "CHARS")]
+    pub max_message_length: Option<usize>,
+
+    /// Maximum size in bytes of a request body.
+    #[arg(long, value_name = "BYTES")]
+    pub max_body_bytes: Option<usize>,
+
+    /// Most levels of replies returned in a conversation thread.
+    #[arg(long, value_name = "LEVELS")]
+    pub max_thread_depth: Option<usize>,
+
+    /// Turn off per-client rate limiting.
+    #[arg(long)]
+    pub no_rate_limit: bool,
+
+    /// Reads a client can make in a row.
+    #[arg(long, value_name = "REQUESTS")]
+    pub rate_limit_read_burst: Option<u32>,
+
+    /// Reads per second given back to a client.
+    #[arg(long, value_name = "RATE")]
+    pub rate_limit_read_per_second: Option<f64>,
+
+    /// Writes a client can make in a row.
+    #[arg(long, value_name = "REQUESTS")]
+    pub rate_limit_write_burst: Option<u32>,
+
+    /// Writes per second given back to a client.
+    #[arg(long, value_name = "RATE")]
+    pub rate_limit_write_per_second: Option<f64>,
+
+    /// Token signing algorithm: HS256 or EdDSA.
+    #[arg(long, value_name = "ALGORITHM")]
+    pub auth_algorithm: Option<JwtAlgorithm>,
+
+    /// EdDSA private key file (PKCS#8 PEM).
+    #[arg(long, value_name = "PATH")]
+    pub auth_private_key_path: Option<PathBuf>,
+
+    /// EdDSA public key file (SPKI PEM).
+    #[arg(long, value_name = "PATH")]
+    pub auth_public_key_path: Option<PathBuf>,
+
+    /// When timelines are assembled: read or write.
+    #[arg(long, value_name = "STRATEGY")]
+    pub timeline_fan_out: Option<FanOut>,
+
+    /// Directory of the attachment files.
+    #[arg(long, value_name = "PATH")]
+    pub media_path: Option<PathBuf>,
+
+    /// Directory of the snapshots of the store.
+    #[arg(long, value_name = "PATH")]
+    pub snapshots_path: Option<PathBuf>,
+
+    /// Check this snapshot and start from it instead of the current store.
+    #[arg(long, value_name = "PATH")]
+    pub restore_snapshot: Option<PathBuf>,
+
+    /// Follow the leader at this URL instead of taking writes.
+    #[arg(long, value_name = "URL")]
+    pub replication_leader: Option<String>,
+
+    /// Log format: text or json.
+    #[arg(long, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+
+    /// `tracing` filter directives.
+    #[arg(long, value_name = "FILTER")]
+    pub log_filter: Option<String>,
+
+    /// Print the resolved configuration as TOML and exit.
+    #[arg(long)]
+    pub print_config: bool,
+}
+
+/// Errors while resolving the [Config].
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file couldn't be read.
+    Io { path: PathBuf, error: std::io::Error },
+    /// The configuration file isn't valid TOML or has unknown keys.
+    Parse { path: PathBuf, error: toml::de::Error },
+    /// A value from the environment or a validation check is wrong.
+    InvalidValue { key: String, value: String, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io { path, error } => {
+                write!(f, "can't read config file {}: {error}", path.display())
+            }
+            ConfigError::Parse { path, error } => {
+                write!(f, "invalid config file {}: {error}", path.display())
+            }
+            ConfigError::InvalidValue { key, value, reason } => {
+                write!(f, "invalid value {value:?} for {key}: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Resolve the configuration from the file named by `--config` or `SNAP_CONFIG`,
+    /// the environment as seen through `env` and the command line flags.
+    pub fn load<F>(cli: &Cli, env: F) -> Result<Config, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let file = cli.config.clone()
+            .or_else(|| env("SNAP_CONFIG").map(PathBuf::from));
+        let mut config = match file {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(env)?;
+        config.apply_cli(cli);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Parse a TOML configuration file. Missing keys take their default value.
+    pub fn from_file(path: PathBuf) -> Result<Config, ConfigError> {
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) => return Err(ConfigError::Io { path, error }),
+        };
+        toml::from_str(&text).map_err(|error| ConfigError::Parse { path, error })
+    }
+
+    /// Render the configuration as TOML.
+    pub fn to_toml(&self) -> String {
+        toml::to_string_pretty(self).expect("config is always serializable")
+    }
+
+    fn apply_env<F>(&mut self, env: F) -> Result<(), ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        // `PORT` predates the configuration system, keep honoring it.
+        if let Some(port) = env_value::<u16, _>(&env, "PORT")? {
+            self.server.bind.set_port(port);
+        }
+        if let Some(bind) = env_value(&env, "SNAP_BIND")? {
+            self.server.bind = bind;
+        }
+        if let Some(secs) = env_value(&env, "SNAP_DRAIN_TIMEOUT_SECS")? {
+            self.server.drain_timeout_secs = secs;
+        }
+        if let Some(secs) = env_value(&env, "SNAP_READINESS_GRACE_SECS")? {
+            self.server.readiness_grace_secs = secs;
+        }
+        if let Some(backend) = env_value(&env, "SNAP_STORAGE_BACKEND")? {
+            self.storage.backend = backend;
+        }
+        if let Some(path) = env_value(&env, "SNAP_STORAGE_PATH")? {
+            self.storage.path = Some(path);
+        }
+        if let Some(millis) = env_value(&env, "SNAP_FLUSH_INTERVAL_MS")? {
+            self.storage.flush_interval_ms = millis;
+        }
+        if let Some(max) = env_value(&env, "SNAP_MAX_MESSAGE_LENGTH")? {
+            self.limits.max_message_length = max;
+        }
+        if let Some(max) = env_value(&env, "SNAP_MAX_BODY_BYTES")? {
+            self.limits.max_body_bytes = max;
+        }
+        if let Some(max) = env_value(&env, "SNAP_MAX_THREAD_DEPTH")? {
+            self.limits.max_thread_depth = max;
+        }
+        if let Some(enabled) = env_value(&env, "SNAP_RATE_LIMIT_ENABLED")? {
+            self.rate_limit.enabled = enabled;
+        }
+        if let Some(burst) = env_value(&env, "SNAP_RATE_LIMIT_READ_BURST")? {
+            self.rate_limit.read.burst = burst;
+        }
+        if let Some(rate) = env_value(&env, "SNAP_RATE_LIMIT_READ_PER_SECOND")? {
+            self.rate_limit.read.per_second = rate;
+        }
+        if let Some(burst) = env_value(&env, "SNAP_RATE_LIMIT_WRITE_BURST")? {
+            self.rate_limit.write.burst = burst;
+        }
+        if let Some(rate) = env_value(&env, "SNAP_RATE_LIMIT_WRITE_PER_SECOND")? {
+            self.rate_limit.write.per_second = rate;
+        }
+        if let Some(algorithm) = env_value(&env, "SNAP_AUTH_ALGORITHM")? {
+            self.auth.algorithm = algorithm;
+        }
+        if let Some(secret) = env_value(&env, "SNAP_AUTH_SECRET")? {
+            self.auth.secret = Some(secret);
+        }
+        if let Some(path) = env_value(&env, "SNAP_AUTH_PRIVATE_KEY_PATH")? {
+            self.auth.private_key_path = Some(path);
+        }
+        if let Some(path) = env_value(&env, "SNAP_AUTH_PUBLIC_KEY_PATH")? {
+            self.auth.public_key_path = Some(path);
+        }
+        if let Some(issuer) = env_value(&env, "SNAP_AUTH_ISSUER")? {
+            self.auth.issuer = issuer;
+        }
+        if let Some(secs) = env_value(&env, "SNAP_AUTH_ACCESS_TOKEN_TTL_SECS")? {
+            self.auth.access_token_ttl_secs = secs;
+        }
+        if let Some(secs) = env_value(&env, "SNAP_AUTH_REFRESH_TOKEN_TTL_SECS")? {
+            self.auth.refresh_token_ttl_secs = secs;
+        }
+        if let Some(fan_out) = env_value(&env, "SNAP_TIMELINE_FAN_OUT")? {
+            self.timeline.fan_out = fan_out;
+        }
+        if let Some(size) = env_value(&env, "SNAP_TIMELINE_DEFAULT_PAGE_SIZE")? {
+            self.timeline.default_page_size = size;
+        }
+        if let Some(size) = env_value(&env, "SNAP_TIMELINE_MAX_PAGE_SIZE")? {
+            self.timeline.max_page_size = size;
+        }
+        if let Some(secs) = env_value(&env, "SNAP_TRENDING_WINDOW_SECS")? {
+            self.tags.trending_window_secs = secs;
+        }
+        if let Some(limit) = env_value(&env, "SNAP_TRENDING_LIMIT")? {
+            self.tags.trending_limit = limit;
+        }
+        if let Some(size) = env_value(&env, "SNAP_NOTIFICATIONS_STREAM_BUFFER")? {
+            self.notifications.stream_buffer = size;
+        }
+        if let Some(secs) = env_value(&env, "SNAP_NOTIFICATIONS_KEEP_ALIVE_SECS")? {
+            self.notifications.keep_alive_secs = secs;
+        }
+        if let Some(ms) = env_value(&env, "SNAP_WEBHOOKS_POLL_INTERVAL_MS")? {
+            self.webhooks.poll_interval_ms = ms;
+        }
+        if let Some(size) = env_value(&env, "SNAP_WEBHOOKS_BATCH_SIZE")? {
+            self.webhooks.batch_size = size;
+        }
+        if let Some(secs) = env_value(&env, "SNAP_WEBHOOKS_TIMEOUT_SECS")? {
+            self.webhooks.timeout_secs = secs;
+        }
+        if let Some(attempts) = env_value(&env, "SNAP_WEBHOOKS_MAX_ATTEMPTS")? {
+            self.webhooks.max_attempts = attempts;
+        }
+        if let Some(ms) = env_value(&env, "SNAP_WEBHOOKS_INITIAL_BACKOFF_MS")? {
+            self.webhooks.initial_backoff_ms = ms;
+        }
+        if let Some(ms) = env_value(&env, "SNAP_WEBHOOKS_MAX_BACKOFF_MS")? {
+            self.webhooks.max_backoff_ms = ms;
+        }
+        if let Some(allow) = env_value(&env, "SNAP_WEBHOOKS_ALLOW_PRIVATE_HOSTS")? {
+            self.webhooks.allow_private_hosts = allow;
+        }
+        if let Some(path) = env_value(&env, "SNAP_MEDIA_PATH")? {
+            self.media.path = Some(path);
+        }
+        if let Some(max) = env_value(&env, "SNAP_MEDIA_MAX_BYTES")? {
+            self.media.max_bytes = max;
+        }
+        if let Some(max) = env_value(&env, "SNAP_MEDIA_MAX_ATTACHMENTS")? {
+            self.media.max_attachments = max;
+        }
+        if let Some(max) = env_value(&env, "SNAP_MEDIA_MAX_PIXELS")? {
+            self.media.max_pixels = max;
+        }
+        if let Some(sizes) = env_value::<String, _>(&env, "SNAP_MEDIA_THUMBNAIL_SIZES")? {
+            self.media.thumbnail_sizes = sizes.split(',')
+                .filter(|size| !size.trim().is_empty())
+                .map(|size| size.trim().parse::<u32>())
+                .collect::<Result<_, _>>()
+                .map_err(|e| invalid("SNAP_MEDIA_THUMBNAIL_SIZES", &sizes, &e.to_string()))?;
+        }
+        if let Some(workers) = env_value(&env, "SNAP_MEDIA_WORKERS")? {
+            self.media.workers = workers;
+        }
+        if let Some(users) = env_value::<String, _>(&env, "SNAP_ADMIN_USERS")? {
+            self.admin.users = users.split(',')
+                .map(|user| user.trim().to_string())
+                .filter(|user| !user.is_empty())
+                .collect();
+        }
+        if let Some(users) = env_value::<String, _>(&env, "SNAP_MODERATION_MODERATORS")? {
+            self.moderation.moderators = users.split(',')
+                .map(|user| user.trim().to_string())
+                .filter(|user| !user.is_empty())
+                .collect();
+        }
+        if let Some(max) = env_value(&env, "SNAP_MODERATION_MAX_REASON_LENGTH")? {
+            self.moderation.max_reason_length = max;
+        }
+        if let Some(path) = env_value(&env, "SNAP_SNAPSHOTS_PATH")? {
+            self.snapshots.path = Some(path);
+        }
+        if let Some(secs) = env_value(&env, "SNAP_SNAPSHOTS_INTERVAL_SECS")? {
+            self.snapshots.interval_secs = secs;
+        }
+        if let Some(keep) = env_value(&env, "SNAP_SNAPSHOTS_KEEP")? {
+            self.snapshots.keep = keep;
+        }
+        if let Some(leader) = env_value(&env, "SNAP_REPLICATION_LEADER")? {
+            self.replication.leader = Some(leader);
+        }
+        if let Some(secret) = env_value(&env, "SNAP_REPLICATION_SECRET")? {
+            self.replication.secret = Some(secret);
+        }
+        if let Some(ms) = env_value(&env, "SNAP_REPLICATION_POLL_INTERVAL_MS")? {
+            self.replication.poll_interval_ms = ms;
+        }
+        if let Some(changes) = env_value(&env, "SNAP_REPLICATION_CHANGE_BUFFER")? {
+            self.replication.change_buffer = changes;
+        }
+        if let Some(enabled) = env_value(&env, "SNAP_TENANCY_ENABLED")? {
+            self.tenancy.enabled = enabled;
+        }
+        if let Some(domain) = env_value(&env, "SNAP_TENANCY_DOMAIN")? {
+            self.tenancy.domain = Some(domain);
+        }
+        if let Some(token) = env_value(&env, "SNAP_TENANCY_ADMIN_TOKEN")? {
+            self.tenancy.admin_token = Some(token);
+        }
+        if let Some(format) = env_value(&env, "SNAP_LOG_FORMAT")? {
+            self.log.format = format;
+        }
+        if let Some(filter) = env_value(&env, "SNAP_LOG_FILTER")? {
+            self.log.filter = Some(filter);
+        }
+        Ok(())
+    }
+
+    fn apply_cli(&mut self, cli: &Cli) {
+        if let Some(bind) = cli.bind {
+            self.server.bind = bind;
+        }
+        if let Some(secs) = cli.drain_timeout_secs {
+            self.server.drain_timeout_secs = secs;
+        }
+        if let Some(secs) = cli.readiness_grace_secs {
+            self.server.readiness_grace_secs = secs;
+        }
+        if let Some(backend) = cli.storage_backend {
+            self.storage.backend = backend;
+        }
+        if let Some(path) = &cli.storage_path {
+            self.storage.path = Some(path.clone());
+        }
+        if let Some(millis) = cli.flush_interval_ms {
+            self.storage.flush_interval_ms = millis;
+        }
+        if let Some(max) = cli.max_message_length {
+            self.limits.max_message_length = max;
+        }
+        if let Some(max) = cli.max_body_bytes {
+            self.limits.max_body_bytes = max;
+        }
+        if let Some(max) = cli.max_thread_depth {
+            self.limits.max_thread_depth = max;
+        }
+        if cli.no_rate_limit {
+            self.rate_limit.enabled = false;
+        }
+        if let Some(burst) = cli.rate_limit_read_burst {
+            self.rate_limit.read.burst = burst;
+        }
+        if let Some(rate) = cli.rate_limit_read_per_second {
+            self.rate_limit.read.per_second = rate;
+        }
+        if let Some(burst) = cli.rate_limit_write_burst {
+            self.rate_limit.write.burst = burst;
+        }
+        if let Some(rate) = cli.rate_limit_write_per_second {
+            self.rate_limit.write.per_second = rate;
+        }
+        if let Some(algorithm) = cli.auth_algorithm {
+            self.auth.algorithm = algorithm;
+        }
+        if let Some(path) = &cli.auth_private_key_path {
+            self.auth.private_key_path = Some(path.clone());
+        }
+        if let Some(path) = &cli.auth_public_key_path {
+            self.auth.public_key_path = Some(path.clone());
+        }
+        if let Some(fan_out) = cli.timeline_fan_out {
+            self.timeline.fan_out = fan_out;
+        }
+        if let Some(path) = &cli.media_path {
+            self.media.path = Some(path.clone());
+        }
+        if let Some(path) = &cli.snapshots_path {
+            self.snapshots.path = Some(path.clone());
+        }
+        if let Some(leader) = &cli.replication_leader {
+            self.replication.leader = Some(leader.clone());
+        }
+        if let Some(format) = cli.log_format {
+            self.log.format = format;
+        }
+        if let Some(filter) = &cli.log_filter {
+            self.log.filter = Some(filter.clone());
+        }
+    }
+
+    /// Check values that parse fine but make no sense together.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.limits.max_message_length == 0 {
+            return Err(invalid("limits.max_message_length", "0", "must be at least 1"));
+        }
+        if self.limits.max_body_bytes == 0 {
+            return Err(invalid("limits.max_body_bytes", "0", "must be at least 1"));
+        }
+        if self.limits.max_thread_depth == 0 {
+            return Err(invalid("limits.max_thread_depth", "0", "must be at least 1"));
+        }
+        for (name, bucket) in [("read", &self.rate_limit.read), ("write", &self.rate_limit.write)] {
+            if bucket.burst == 0 {
+                return Err(invalid(&format!("rate_limit.{name}.burst"), "0", "must be at least 1"));
+            }
+            if !(bucket.per_second > 0.0 && bucket.per_second.is_finite()) {
+                return Err(invalid(
+                    &format!("rate_limit.{name}.per_second"),
+                    &bucket.per_second.to_string(),
+                    "must be a positive number",
+                ));
+            }
+        }
+        if self.auth.access_token_ttl_secs == 0 {
+            return Err(invalid("auth.access_token_ttl_secs", "0", "must be at least 1"));
+        }
+        if self.auth.refresh_token_ttl_secs == 0 {
+            return Err(invalid("auth.refresh_token_ttl_secs", "0", "must be at least 1"));
+        }
+        match self.auth.algorithm {
+            JwtAlgorithm::HS256 => {
+                // The secret itself isn't echoed back in the error.
+                if self.auth.secret.as_ref().is_some_and(|s| s.len() < MIN_SECRET_BYTES) {
+                    return Err(invalid(
+                        "auth.secret",
+                        "<redacted>",
+                        &format!("must have at least {MIN_SECRET_BYTES} bytes"),
+                    ));
+                }
+            }
+            JwtAlgorithm::EdDSA => {
+                if self.auth.private_key_path.is_none() || self.auth.public_key_path.is_none() {
+                    return Err(invalid(
+                        "auth.private_key_path",
+                        "",
+                        "a key pair is required when auth.algorithm is \"EdDSA\"",
+                    ));
+                }
+            }
+        }
+        if self.timeline.max_page_size == 0 {
+            return Err(invalid("timeline.max_page_size", "0", "must be at least 1"));
+        }
+        if !(1..=self.timeline.max_page_size).contains(&self.timeline.default_page_size) {
+            return Err(invalid(
+                "timeline.default_page_size",
+                &self.timeline.default_page_size.to_string(),
+                "must be between 1 and timeline.max_page_size",
+            ));
+        }
+        if self.tags.trending_window_secs == 0 {
+            return Err(invalid("tags.trending_window_secs", "0", "must be at least 1"));
+        }
+        if !(1..=self.timeline.max_page_size).contains(&self.tags.trending_limit) {
+            return Err(invalid(
+                "tags.trending_limit",
+                &self.tags.trending_limit.to_string(),
+                "must be between 1 and timeline.max_page_size",
+            ));
+        }
+        if self.notifications.stream_buffer == 0 {
+            return Err(invalid("notifications.stream_buffer", "0", "must be at least 1"));
+        }
+        if self.notifications.keep_alive_secs == 0 {
+            return Err(invalid("notifications.keep_alive_secs", "0", "must be at least 1"));
+        }
+        let webhooks = &self.webhooks;
+        for (key, value) in [
+            ("webhooks.poll_interval_ms", webhooks.poll_interval_ms),
+            ("webhooks.batch_size", webhooks.batch_size as u64),
+            ("webhooks.timeout_secs", webhooks.timeout_secs),
+            ("webhooks.max_attempts", u64::from(webhooks.max_attempts)),
+            ("webhooks.initial_backoff_ms", webhooks.initial_backoff_ms),
+        ] {
+            if value == 0 {
+                return Err(invalid(key, "0", "must be at least 1"));
+            }
+        }
+        if webhooks.max_backoff_ms < webhooks.initial_backoff_ms {
+            return Err(invalid(
+                "webhooks.max_backoff_ms",
+                &webhooks.max_backoff_ms.to_string(),
+                "must be at least webhooks.initial_backoff_ms",
+            ));
+        }
+        if self.media.max_bytes == 0 {
+            return Err(invalid("media.max_bytes", "0", "must be at least 1"));
+        }
+        if self.media.max_attachments == 0 {
+            return Err(invalid("media.max_attachments", "0", "must be at least 1"));
+        }
+        if self.media.max_pixels == 0 {
+            return Err(invalid("media.max_pixels", "0", "must be at least 1"));
+        }
+        if self.media.thumbnail_sizes.contains(&0) {
+            return Err(invalid("media.thumbnail_sizes", "0", "sizes must be at least 1"));
+        }
+        if self.media.workers == 0 {
+            return Err(invalid("media.workers", "0", "must be at least 1"));
+        }
+        if let Some(user) = self.admin.users.iter().find(|user| uuid::Uuid::parse_str(user).is_err()) {
+            return Err(invalid("admin.users", user, "must be user ids"));
+        }
+        if let Some(user) = self.moderation.moderators.iter().find(|user| uuid::Uuid::parse_str(user).is_err()) {
+            return Err(invalid("moderation.moderators", user, "must be user ids"));
+        }
+        if self.moderation.max_reason_length == 0 {
+            return Err(invalid("moderation.max_reason_length", "0", "must be at least 1"));
+        }
+        if self.snapshots.keep == 0 {
+            return Err(invalid("snapshots.keep", "0", "must be at least 1"));
+        }
+        if self.snapshots.interval_secs > 0 && self.snapshots.path.is_none() {
+            return Err(invalid(
+                "snapshots.path",
+                "",
+                "a path is required when snapshots.interval_secs is set",
+            ));
+        }
+        if self.replication.poll_interval_ms == 0 {
+            return Err(invalid("replication.poll_interval_ms", "0", "must be at least 1"));
+        }
+        if self.replication.change_buffer == 0 {
+            return Err(invalid("replication.change_buffer", "0", "must be at least 1"));
+        }
+        if self.replication.secret.as_ref().is_some_and(|s| s.len() < MIN_SECRET_BYTES) {
+            return Err(invalid(
+                "replication.secret",
+                "<redacted>",
+                &format!("must have at least {MIN_SECRET_BYTES} bytes"),
+            ));
+        }
+        if let Some(leader) = &self.replication.leader {
+            if self.replication.secret.is_none() {
+                return Err(invalid(
+                    "replication.secret",
+                    "",
+                    "a secret is required when replication.leader is set",
+                ));
+            }
+            if !leader.starts_with("http://") || leader.parse::<hyper::Uri>().is_err() {
+                return Err(invalid("replication.leader", leader, "must be an http:// URL"));
+            }
+        }
+        if self.tenancy.enabled {
+            let long_enough = match &self.tenancy.admin_token {
+                Some(token) => token.len() >= MIN_SECRET_BYTES,
+                None => false,
+            };
+            if !long_enough {
+                return Err(invalid(
+                    "tenancy.admin_token",
+                    "<redacted>",
+                    &format!("a token of at least {MIN_SECRET_BYTES} bytes is required when tenancy.enabled is set"),
+                ));
+            }
+            if self.replication.leader.is_some() {
+                return Err(invalid("replication.leader", "", "followers can't serve several tenants"));
+            }
+            if self.snapshots.interval_secs > 0 {
+                return Err(invalid(
+                    "snapshots.interval_secs",
+                    &self.snapshots.interval_secs.to_string(),
+                    "scheduled snapshots aren't taken for tenants",
+                ));
+            }
+        }
+        self.tenancy.default_quotas.validate("tenancy.default_quotas")?;
+        if self.storage.flush_interval_ms == 0 {
+            return Err(invalid("storage.flush_interval_ms", "0", "must be at least 1"));
+        }
+        if self.storage.backend == StorageBackend::Journal && self.storage.path.is_none() {
+            return Err(invalid(
+                "storage.path",
+                "",
+                "a path is required when storage.backend is \"journal\"",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Shortest HS256 secret accepted, as long as the hash output.
+const MIN_SECRET_BYTES: usize = 32;
+
+fn invalid(key: &str, value: &str, reason: &str) -> ConfigError {
+    ConfigError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Read and parse the environment variable `key`, if set.
+fn env_value<T, F>(env: &F, key: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+    F: Fn(&str) -> Option<String>,
+{
+    match env(key) {
+        Some(value) => value.parse::<T>()
+            .map(Some)
+            .map_err(|e| invalid(key, &value, &e.to_string())),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod config_test {
+    use super::*;
+    use clap::Parser;
+    use std::collections::HashMap;
+
+    fn env_from(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars = vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<HashMap<String, String>>();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn defaults_without_sources() {
+        let config = Config::load(&Cli::default(), env_from(&[])).unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(config.server.bind.to_string(), "0.0.0.0:8080");
+    }
+
+    #[test]
+    fn cli_overrides_env_overrides_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snap.toml");
+        std::fs::write(&path, r#"
+            [server]
+            bind = "127.0.0.1:1000"
+
+            [limits]
+            max_message_length = 10
+            max_body_bytes = 100
+        "#).unwrap();
+
+        let cli = Cli::try_parse_from([
+            "snap_app_demo",
+            "--config", path.to_str().unwrap(),
+            "--bind", "127.0.0.1:3000",
+            "--readiness-grace-secs", "0",
+        ]).unwrap();
+        let env = env_from(&[
+            ("SNAP_BIND", "127.0.0.1:2000"),
+            ("SNAP_READINESS_GRACE_SECS", "10"),
+            ("SNAP_MAX_MESSAGE_LENGTH", "20"),
+        ]);
+        let config = Config::load(&cli, env).unwrap();
+
+        assert_eq!(config.server.bind.to_string(), "127.0.0.1:3000");
+        assert_eq!(config.server.readiness_grace_secs, 0);
+        assert_eq!(config.limits.max_message_length, 20);
+        assert_eq!(config.limits.max_body_bytes, 100);
+    }
+
+    #[test]
+    fn legacy_port_variable() {
+        let config = Config::load(&Cli::default(), env_from(&[("PORT", "3000")])).unwrap();
+        assert_eq!(config.server.bind.to_string(), "0.0.0.0:3000");
+    }
+
+    #[test]
+    fn invalid_env_value_names_the_variable() {
+        let err = Config::load(&Cli::default(), env_from(&[("SNAP_STORAGE_BACKEND", "mongo")]))
+            .unwrap_err();
+        assert!(err.to_string().contains("SNAP_STORAGE_BACKEND"));
+    }
+
+    #[test]
+    fn unknown_file_keys_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snap.toml");
+        std::fs::write(&path, "[server]\nport = 80\n").unwrap();
+
+        let err = Config::from_file(path).unwrap_err();
+        assert!(matches!(err, ConfigError::Parse { .. }));
+    }
+
+    #[test]
+    fn journal_backend_requires_a_path() {
+        let env = env_from(&[("SNAP_STORAGE_BACKEND", "journal")]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+
+        let env = env_from(&[
+            ("SNAP_STORAGE_BACKEND", "journal"),
+            ("SNAP_STORAGE_PATH", "snaps.journal"),
+        ]);
+        assert!(Config::load(&Cli::default(), env).is_ok());
+    }
+
+    #[test]
+    fn rate_limit_budgets_from_file_and_flags() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snap.toml");
+        std::fs::write(&path, r#"
+            [rate_limit.write]
+            burst = 5
+            per_second = 0.5
+        "#).unwrap();
+
+        let cli = Cli::try_parse_from([
+            "snap_app_demo",
+            "--config", path.to_str().unwrap(),
+            "--no-rate-limit",
+        ]).unwrap();
+        let config = Config::load(&cli, env_from(&[])).unwrap();
+
+        assert!(!config.rate_limit.enabled);
+        assert_eq!(config.rate_limit.write, BucketConfig { burst: 5, per_second: 0.5 });
+        assert_eq!(config.rate_limit.read, RateLimitConfig::default().read);
+
+        let env = env_from(&[("SNAP_RATE_LIMIT_READ_PER_SECOND", "0")]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+    }
+
+    #[test]
+    fn auth_keys_are_checked() {
+        let env = env_from(&[("SNAP_AUTH_SECRET", "too short")]);
+        let err = Config::load(&Cli::default(), env).unwrap_err();
+        assert!(err.to_string().contains("auth.secret"));
+        assert!(!err.to_string().contains("too short"));
+
+        let env = env_from(&[("SNAP_AUTH_ALGORITHM", "EdDSA")]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+
+        let cli = Cli::try_parse_from([
+            "snap_app_demo",
+            "--auth-algorithm", "EdDSA",
+            "--auth-private-key-path", "private.pem",
+            "--auth-public-key-path", "public.pem",
+        ]).unwrap();
+        let config = Config::load(&cli, env_from(&[])).unwrap();
+        assert_eq!(config.auth.algorithm, JwtAlgorithm::EdDSA);
+    }
+
+    #[test]
+    fn timeline_settings() {
+        let cli = Cli::try_parse_from(["snap_app_demo", "--timeline-fan-out", "write"]).unwrap();
+        let config = Config::load(&cli, env_from(&[])).unwrap();
+        assert_eq!(config.timeline.fan_out, FanOut::Write);
+
+        let env = env_from(&[("SNAP_TIMELINE_DEFAULT_PAGE_SIZE", "500")]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+    }
+
+    #[test]
+    fn notification_settings() {
+        let env = env_from(&[("SNAP_NOTIFICATIONS_KEEP_ALIVE_SECS", "30")]);
+        let config = Config::load(&Cli::default(), env).unwrap();
+        assert_eq!(config.notifications.keep_alive_secs, 30);
+
+        let env = env_from(&[("SNAP_NOTIFICATIONS_STREAM_BUFFER", "0")]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+    }
+
+    #[test]
+    fn webhook_settings() {
+        let env = env_from(&[("SNAP_WEBHOOKS_MAX_ATTEMPTS", "3")]);
+        let config = Config::load(&Cli::default(), env).unwrap();
+        assert_eq!(config.webhooks.max_attempts, 3);
+        assert!(!config.webhooks.allow_private_hosts);
+        let env = env_from(&[("SNAP_WEBHOOKS_ALLOW_PRIVATE_HOSTS", "true")]);
+        assert!(Config::load(&Cli::default(), env).unwrap().webhooks.allow_private_hosts);
+
+        let env = env_from(&[("SNAP_WEBHOOKS_MAX_BACKOFF_MS", "10")]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+        let env = env_from(&[("SNAP_WEBHOOKS_BATCH_SIZE", "0")]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+    }
+
+    #[test]
+    fn media_settings() {
+        let env = env_from(&[("SNAP_MEDIA_PATH", "media"), ("SNAP_MEDIA_MAX_ATTACHMENTS", "2")]);
+        let config = Config::load(&Cli::default(), env).unwrap();
+        assert_eq!(config.media.path, Some(PathBuf::from("media")));
+        assert_eq!(config.media.max_attachments, 2);
+        assert_eq!(config.media.thumbnail_sizes, vec![160, 640]);
+
+        let env = env_from(&[("SNAP_MEDIA_THUMBNAIL_SIZES", "96, 480,1080"), ("SNAP_MEDIA_WORKERS", "4")]);
+        let config = Config::load(&Cli::default(), env).unwrap();
+        assert_eq!(config.media.thumbnail_sizes, vec![96, 480, 1080]);
+        assert_eq!(config.media.workers, 4);
+        let env = env_from(&[("SNAP_MEDIA_THUMBNAIL_SIZES", "")]);
+        assert!(Config::load(&Cli::default(), env).unwrap().media.thumbnail_sizes.is_empty());
+
+        for (key, value) in [
+            ("SNAP_MEDIA_MAX_BYTES", "0"),
+            ("SNAP_MEDIA_MAX_PIXELS", "0"),
+            ("SNAP_MEDIA_THUMBNAIL_SIZES", "160,big"),
+            ("SNAP_MEDIA_THUMBNAIL_SIZES", "0"),
+            ("SNAP_MEDIA_WORKERS", "0"),
+        ] {
+            assert!(Config::load(&Cli::default(), env_from(&[(key, value)])).is_err(), "{key}={value}");
+        }
+    }
+
+    #[test]
+    fn admin_users() {
+        assert!(Config::load(&Cli::default(), env_from(&[])).unwrap().admin.users.is_empty());
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let env = env_from(&[("SNAP_ADMIN_USERS", &format!(" {id},"))]);
+        assert_eq!(Config::load(&Cli::default(), env).unwrap().admin.users, vec![id.clone()]);
+
+        let env = env_from(&[("SNAP_ADMIN_USERS", &format!("{id},alice"))]);
+        assert!(Config::load(&Cli::default(), env).is_err());
+    }
+
+    #[test]
+    fn moderation_settings() {
+        let config = Config::load(&Cli::default(), env_from(&[])).unwrap();
+        assert_eq!(config.moderation, ModerationConfig::default());
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let env = env_from(&[("SNAP_MODERATION_MODERATORS", &id), ("SNAP_MODERATION_MAX_REASON_LENGTH", "80")]);
+        let config = Config::load(&Cli::default(), env).unwrap();
+        assert_eq!(config.moderation.moderators, vec![id]);
+        assert_eq!(config.moderation.max_reason_length, 80);
+
+        for (key, value) in [
+            ("SNAP_MODERATION_MODERATORS", "alice"),
+            ("SNAP_MODERATION_MAX_REASON_LENGTH", "0"),
+        ] {
+            assert!(Config::load(&Cli::default(), env_from(&[(key, value)])).is_err(), "{key}={value}");
+        }
+    }
+
+    #[test]
+    fn snapshot_settings() {
+        let config = Config::load(&Cli::default(), env_from(&[])).unwrap();
+        assert_eq!(config.snapshots, SnapshotsConfig::default());
+
+        let env = env_from(&[("SNAP_SNAPSHOTS_PATH", "snapshots"), ("SNAP_SNAPSHOTS_INTERVAL_SECS", "3600")]);
+        let cli = Cli { snapshots_path: Some(PathBuf::from("elsewhere")), ..Cli::default() };
+        let config = Config::load(&cli, env).unwrap();
+        assert_eq!(config.snapshots.path, Some(PathBuf::from("elsewhere")));
+        assert_eq!(config.snapshots.interval_secs, 3600);
+
+        for vars in [
+            [("SNAP_SNAPSHOTS_INTERVAL_SECS", "60"), ("SNAP_SNAPSHOTS_KEEP", "7")],
+            [("SNAP_SNAPSHOTS_PATH", "snapshots"), ("SNAP_SNAPSHOTS_KEEP", "0")],
+        ] {
+            assert!(Config::load(&Cli::default(), env_from(&vars)).is_err(), "{vars:?}");
+        }
+    }
+
+    #[test]
+    fn replication_settings() {
+        let secret = "s".repeat(MIN_SECRET_BYTES);
+        let env = env_from(&[("SNAP_REPLICATION_SECRET", &secret)]);
+        let cli = Cli { replication_leader: Some("http://127.0.0.1:8080".to_string()), ..Cli::default() };
+        let config = Config::load(&cli, env).unwrap();
+        assert_eq!(config.replication.leader.as_deref(), Some("http://127.0.0.1:8080"));
+
+        for vars in [
+            vec![("SNAP_REPLICATION_LEADER", "http://127.0.0.1:8080")],
+            vec![("SNAP_REPLICATION_LEADER", "https://leader"), ("SNAP_REPLICATION_SECRET", secret.as_str())],
+            vec![("SNAP_REPLICATION_SECRET", "short")],
+            vec![("SNAP_REPLICATION_POLL_INTERVAL_MS", "0")],
+            vec![("SNAP_REPLICATION_CHANGE_BUFFER", "0")],
+        ] {
+            assert!(Config::load(&Cli::default(), env_from(&vars)).is_err(), "{vars:?}");
+        }
+    }
+
+    #[test]
+    fn tenancy_settings() {
+        let token = "t".repeat(MIN_SECRET_BYTES);
+        let env = env_from(&[("SNAP_TENANCY_ENABLED", "true"), ("SNAP_TENANCY_ADMIN_TOKEN", &token)]);
+        let config = Config::load(&Cli::default(), env).unwrap();
+        assert!(config.tenancy.enabled);
+        assert_eq!(config.tenancy.default_quotas, TenantQuotas::default());
+
+        for vars in [
+            vec![("SNAP_TENANCY_ENABLED", "true")],
+            vec![("SNAP_TENANCY_ENABLED", "true"), ("SNAP_TENANCY_ADMIN_TOKEN", "short")],
+            vec![
+                ("SNAP_TENANCY_ENABLED", "true"),
+                ("SNAP_TENANCY_ADMIN_TOKEN", token.as_str()),
+                ("SNAP_SNAPSHOTS_PATH", "snapshots"),
+                ("SNAP_SNAPSHOTS_INTERVAL_SECS", "60"),
+            ],
+        ] {
+            assert!(Config::load(&Cli::default(), env_from(&vars)).is_err(), "{vars:?}");
+        }
+
+        let quotas = TenantQuotas {
+            write: Some(BucketConfig { burst: 0, per_second: 1.0 }),
+            ..TenantQuotas::default()
+        };
+        assert!(quotas.validate("quotas").is_err());
+    }
+
+    #[test]
+    fn printed_config_parses_back() {
+        let mut config = Config::default();
+        config.storage.backend = StorageBackend::Journal;
+        config.storage.path = Some(PathBuf::from("snaps.journal"));
+        config.log.format = LogFormat::Json;
+
+        let parsed: Config = toml::from_str(&config.to_toml()).unwrap();
+        assert_eq!(parsed, config);
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+use crate::auth::Authenticator;
+use crate::config::{Config, ConfigError};
+use crate::media::{self, BlobStore, MemoryBlobStore, Pipeline};
+use crate::metrics::Metrics;
+use crate::notifications::Notifier;
+use crate::projections::Projector;
+use crate::replication::Replication;
+use crate::state::StorageError;
+
+/// Services shared by every handler, next to the repository state.
+#[derive(Clone)]
+pub struct AppContext {
+    pub config: Arc<Config>,
+    pub readiness: Readiness,
+    pub metrics: Metrics,
+    pub auth: Authenticator,
+    pub notifier: Notifier,
+    /// Read models built from the snap log.
+    pub projections: Projector,
+    /// How far a follower is behind its leader.
+    pub replication: Replication,
+    /// Contents of the snap attachments.
+    pub blobs: Arc<dyn BlobStore>,
+    /// Turns uploads into attachments stored in `blobs`.
+    pub pipeline: Pipeline,
+}
+
+impl AppContext {
+    /// Context for `config`. Readiness starts as [Phase::Ready].
+    /// Fails if the token signing keys can't be loaded or the media
+    /// directory can't be created.
+    pub fn new(config: Config) -> Result<AppContext, ConfigError> {
+        let auth = Authenticator::from_config(&config.auth)?;
+        let notifier = Notifier::new(config.notifications.stream_buffer);
+        let blobs = media::open(&config.media).map_err(|e| ConfigError::Io {
+            path: config.media.path.clone().unwrap_or_default(),
+            error: match e {
+                StorageError::Io(error) => error,
+                e => std::io::Error::other(e.to_string()),
+            },
+        })?;
+        let pipeline = Pipeline::new(blobs.clone(), config.media.clone());
+        Ok(AppContext {
+            config: Arc::new(config),
+            readiness: Readiness::default(),
+            metrics: Metrics::default(),
+            auth,
+            notifier,
+            projections: Projector::default(),
+            replication: Replication::default(),
+            blobs,
+            pipeline,
+        })
+    }
+}
+
+impl Default for AppContext {
+    fn default() -> Self {
+        let blobs: Arc<dyn BlobStore> = Arc::new(MemoryBlobStore::default());
+        AppContext {
+            config: Arc::default(),
+            readiness: Readiness::default(),
+            metrics: Metrics::default(),
+            auth: Authenticator::default(),
+            notifier: Notifier::default(),
+            projections: Projector::default(),
+            replication: Replication::default(),
+            pipeline: Pipeline::new(blobs.clone(), Default::default()),
+            blobs,
+        }
+    }
+}
+
+/// Lifecycle phase of the server, as reported by `GET /readyz`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Starting,
+    Ready,
+    ShuttingDown,
+}
+
+/// Shared, cheaply clonable handle on the current [Phase].
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    phase: Arc<AtomicU8>,
+}
+
+impl Readiness {
+    pub fn new(phase: Phase) -> Readiness {
+        Readiness {
+            phase: Arc::new(AtomicU8::new(phase as u8)),
+        }
+    }
+
+    pub fn phase(&self) -> Phase {
+        match self.phase.load(Ordering::Acquire) {
+            0 => Phase::Starting,
+            1 => Phase::Ready,
+            _ => Phase::ShuttingDown,
+        }
+    }
+
+    pub fn set(&self, phase: Phase) {
+        self.phase.store(phase as u8, Ordering::Release);
+    }
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness::new(Phase::Ready)
+    }
+}
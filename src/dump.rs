@@ -0,0 +1,546 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::io::BufRead;
+use std::str::FromStr;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use crate::models::{self, Snap};
+use crate::state::{SnapAppState, SnapCreationError};
+
+/// Name in the header of every dump.
+pub const FORMAT: &str = "snap-dump";
+
+/// Version of the dumps written. Only dumps of this version are imported.
+pub const VERSION: u32 = 1;
+
+/// Snaps between two progress reports of [import].
+pub const PROGRESS_EVERY: usize = 1000;
+
+/// First line of a dump.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// Snaps in the dump.
+    snaps: usize,
+}
+
+/// Last line of a dump, telling it wasn't cut short.
+#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Footer {
+    snaps: usize,
+    /// SHA-256 of the checksums of every snap, one per line.
+    sha256: String,
+}
+
+/// What to do with a snap of the dump whose id is already stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// Stop the import.
+    #[default]
+    Fail,
+    /// Keep the stored snap.
+    Skip,
+    /// Replace the stored snap with the one in the dump.
+    Overwrite,
+}
+
+impl fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictPolicy::Fail => write!(f, "fail"),
+            ConflictPolicy::Skip => write!(f, "skip"),
+            ConflictPolicy::Overwrite => write!(f, "overwrite"),
+        }
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(ConflictPolicy::Fail),
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            _ => Err("expected one of \"fail\", \"skip\", \"overwrite\"".to_string()),
+        }
+    }
+}
+
+/// How to import a dump.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ImportOptions {
+    pub on_conflict: ConflictPolicy,
+    /// Check the whole dump and count what would change, storing nothing.
+    pub dry_run: bool,
+    /// Most new snaps the import can store, none when unset.
+    pub max_created: Option<usize>,
+}
+
+/// What an import did, or would do on a dry run.
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct ImportReport {
+    /// Snaps the header announced.
+    pub total: usize,
+    /// Snaps read so far.
+    pub read: usize,
+    pub created: usize,
+    pub overwritten: usize,
+    pub skipped: usize,
+    pub dry_run: bool,
+}
+
+/// Reasons an import stops. Snaps stored before the failure stay,
+/// a dry run first tells whether the whole dump would go through.
+#[derive(Debug)]
+pub enum ImportError {
+    /// The dump couldn't be read.
+    Io(std::io::Error),
+    /// A line isn't a record or comes out of place.
+    Malformed { line: usize, reason: String },
+    /// The dump was written in another version of the format.
+    UnsupportedVersion(u32),
+    /// A snap doesn't match its checksum.
+    Checksum { line: usize },
+    /// The dump ended early or its footer doesn't match its contents.
+    Truncated(String),
+    /// A snap is already stored and the policy is [ConflictPolicy::Fail].
+    Conflict { line: usize, id: String },
+    /// The backend refused a snap.
+    Restore { line: usize, id: String, error: SnapCreationError },
+    /// A snap would go over [ImportOptions::max_created] or the snap
+    /// limit of the store.
+    TooMany { line: usize, max: usize },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "can't read the dump: {e}"),
+            ImportError::Malformed { line, reason } => write!(f, "line {line}: {reason}"),
+            ImportError::UnsupportedVersion(version) => {
+                write!(f, "dump version {version} isn't supported, expected {VERSION}")
+            }
+            ImportError::Checksum { line } => write!(f, "line {line}: checksum mismatch"),
+            ImportError::Truncated(reason) => write!(f, "incomplete dump: {reason}"),
+            ImportError::Conflict { line, id } => write!(f, "line {line}: snap {id} already exists"),
+            ImportError::Restore { line, id, error } => match error {
+                SnapCreationError::ParentNotFound => {
+                    write!(f, "line {line}: snap {id} replies to a snap that isn't stored")
+                }
+                error => write!(f, "line {line}: can't store snap {id}: {error:?}"),
+            },
+            ImportError::TooMany { line, max } => write!(f, "line {line}: can't store more than {max} new snaps"),
+        }
+    }
+}
+
+impl std::error::Error for ImportError {}
+
+impl From<std::io::Error> for ImportError {
+    fn from(error: std::io::Error) -> Self {
+        ImportError::Io(error)
+    }
+}
+
+/// Lines of a dump of every snap of `repo`, tombstones included, from the
+/// oldest to the most recent. Each line ends with a line break.
+pub fn export<S: SnapAppState>(repo: &S) -> Export {
+    let snaps = repo.all_snaps();
+    Export {
+        header: Some(Header {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            created_at: chrono::Utc::now(),
+            snaps: snaps.len(),
+        }),
+        count: snaps.len(),
+        snaps: snaps.into_iter(),
+        digest: Some(Sha256::new()),
+    }
+}
+
+/// Lines of a dump, see [export].
+pub struct Export {
+    header: Option<Header>,
+    snaps: std::vec::IntoIter<Snap>,
+    count: usize,
+    /// Taken to write the footer.
+    digest: Option<Sha256>,
+}
+
+impl Iterator for Export {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        if let Some(header) = self.header.take() {
+            return Some(record("header", serde_json::to_value(header)))
+        }
+        let digest = self.digest.as_mut()?;
+        match self.snaps.next() {
+            Some(snap) => {
+                let snap = serde_json::to_value(snap).expect("snaps are always serializable");
+                let sha256 = checksum(&snap);
+                digest.update(format!("{sha256}\n"));
+                let mut line = serde_json::json!({ "type": "snap", "sha256": sha256, "snap": snap }).to_string();
+                line.push('\n');
+                Some(line)
+            }
+            None => {
+                let digest = self.digest.take()?;
+                let footer = Footer { snaps: self.count, sha256: models::hex(&digest.finalize()) };
+                Some(record("footer", serde_json::to_value(footer)))
+            }
+        }
+    }
+}
+
+/// Line of a header or footer record.
+fn record(kind: &str, fields: serde_json::Result<Value>) -> String {
+    let mut value = fields.expect("records are always serializable");
+    value["type"] = Value::from(kind);
+    let mut line = value.to_string();
+    line.push('\n');
+    line
+}
+
+/// SHA-256 of `value` written with its keys sorted, so it doesn't
+/// depend on the order of the fields on the line.
+fn checksum(value: &Value) -> String {
+    fn canonical(value: &Value, out: &mut String) {
+        match value {
+            Value::Object(map) => {
+                let mut keys = map.keys().collect::<Vec<&String>>();
+                keys.sort();
+                out.push('{');
+                for (i, key) in keys.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push_str(&Value::from(key.as_str()).to_string());
+                    out.push(':');
+                    canonical(&map[key], out);
+                }
+                out.push('}');
+            }
+            Value::Array(values) => {
+                out.push('[');
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    canonical(value, out);
+                }
+                out.push(']');
+            }
+            value => out.push_str(&value.to_string()),
+        }
+    }
+
+    let mut text = String::new();
+    canonical(value, &mut text);
+    models::hex(&Sha256::digest(text.as_bytes()))
+}
+
+/// Where an [Importer] is in the dump.
+#[derive(Debug, PartialEq, Eq)]
+enum Expect {
+    Header,
+    Snaps,
+    End,
+}
+
+/// Imports a dump fed one line at a time, so it can come from a file
+/// or from a request body without holding all of it.
+pub struct Importer {
+    options: ImportOptions,
+    report: ImportReport,
+    line: usize,
+    expect: Expect,
+    digest: Sha256,
+    /// Ids of the snaps read, standing in for the store on dry runs.
+    seen: HashSet<String>,
+}
+
+impl Importer {
+    pub fn new(options: ImportOptions) -> Importer {
+        Importer {
+            options,
+            report: ImportReport { dry_run: options.dry_run, ..ImportReport::default() },
+            line: 0,
+            expect: Expect::Header,
+            digest: Sha256::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// What was imported so far.
+    pub fn report(&self) -> &ImportReport {
+        &self.report
+    }
+
+    /// Check and import the next line of the dump into `repo`.
+    pub fn line<S: SnapAppState>(&mut self, repo: &mut S, line: &str) -> Result<(), ImportError> {
+        self.line += 1;
+        let line_number = self.line;
+        if line.trim().is_empty() {
+            return Ok(())
+        }
+        let malformed = |reason: String| ImportError::Malformed { line: line_number, reason };
+        let mut value = serde_json::from_str::<Value>(line).map_err(|e| malformed(e.to_string()))?;
+        let kind = value.get("type").and_then(Value::as_str).unwrap_or_default().to_string();
+        match (&self.expect, kind.as_str()) {
+            (Expect::Header, "header") => {
+                let header = serde_json::from_value::<Header>(value).map_err(|e| malformed(e.to_string()))?;
+                if header.format != FORMAT {
+                    return Err(malformed(format!("not a {FORMAT} file")))
+                }
+                if header.version != VERSION {
+                    return Err(ImportError::UnsupportedVersion(header.version))
+                }
+                self.report.total = header.snaps;
+                self.expect = Expect::Snaps;
+                Ok(())
+            }
+            (Expect::Header, _) => Err(malformed(format!("expected the {FORMAT} header"))),
+            (Expect::Snaps, "snap") => {
+                let sha256 = value.get("sha256").and_then(Value::as_str).unwrap_or_default().to_string();
+                let snap = value.get_mut("snap").map(Value::take).unwrap_or_default();
+                if checksum(&snap) != sha256 {
+                    return Err(ImportError::Checksum { line: line_number })
+                }
+                self.digest.update(format!("{sha256}\n"));
+                let snap = serde_json::from_value::<Snap>(snap).map_err(|e| malformed(e.to_string()))?;
+                self.store(repo, snap)
+            }
+            (Expect::Snaps, "footer") => {
+                let footer = serde_json::from_value::<Footer>(value).map_err(|e| malformed(e.to_string()))?;
+                let digest = models::hex(&std::mem::take(&mut self.digest).finalize());
+                if footer.snaps != self.report.read || self.report.total != self.report.read {
+                    return Err(ImportError::Truncated(format!(
+                        "announced {} snaps but has {}",
+                        self.report.total,
+                        self.report.read,
+                    )))
+                }
+                if footer.sha256 != digest {
+                    return Err(ImportError::Checksum { line: line_number })
+                }
+                self.expect = Expect::End;
+                Ok(())
+            }
+            (Expect::Snaps, kind) => Err(malformed(format!("unexpected record {kind:?}"))),
+            (Expect::End, _) => Err(malformed("records after the footer".to_string())),
+        }
+    }
+
+    /// Store `snap` following the conflict policy, or only count it on dry runs.
+    fn store<S: SnapAppState>(&mut self, repo: &mut S, snap: Snap) -> Result<(), ImportError> {
+        let id = snap.id();
+        self.report.read += 1;
+        let exists = repo.get_snap(&id).is_some() || self.seen.contains(&id);
+        if exists {
+            match self.options.on_conflict {
+                ConflictPolicy::Fail => return Err(ImportError::Conflict { line: self.line, id }),
+                ConflictPolicy::Skip => {
+                    self.report.skipped += 1;
+                    return Ok(())
+                }
+                ConflictPolicy::Overwrite => {}
+            }
+        }
+        if let Some(max) = self.options.max_created {
+            if !exists && self.report.created >= max {
+                return Err(ImportError::TooMany { line: self.line, max })
+            }
+        }
+        if self.options.dry_run {
+            let parent_found = match snap.in_reply_to() {
+                Some(parent) => repo.get_snap(&parent).is_some() || self.seen.contains(&parent),
+                None => true,
+            };
+            if !parent_found {
+                let error = SnapCreationError::ParentNotFound;
+                return Err(ImportError::Restore { line: self.line, id, error })
+            }
+        } else {
+            match repo.restore(snap) {
+                Ok(_) => {}
+                // Snaps stored meanwhile by others count too.
+                Err(SnapCreationError::QuotaExceeded) => {
+                    return Err(ImportError::TooMany { line: self.line, max: self.report.created })
+                }
+                Err(error) => return Err(ImportError::Restore { line: self.line, id, error }),
+            }
+        }
+        if exists {
+            self.report.overwritten += 1;
+        } else {
+            self.report.created += 1;
+        }
+        self.seen.insert(id);
+        Ok(())
+    }
+
+    /// Check the dump ended with its footer and tell what was imported.
+    pub fn finish(self) -> Result<ImportReport, ImportError> {
+        match self.expect {
+            Expect::End => Ok(self.report),
+            Expect::Header => Err(ImportError::Truncated("the dump is empty".to_string())),
+            Expect::Snaps => Err(ImportError::Truncated("the footer is missing".to_string())),
+        }
+    }
+}
+
+/// Import the dump read from `reader` into `repo`, calling `progress`
+/// every [PROGRESS_EVERY] snaps.
+pub fn import<S, R, F>(
+    repo: &mut S,
+    reader: R,
+    options: ImportOptions,
+    mut progress: F,
+) -> Result<ImportReport, ImportError>
+where
+    S: SnapAppState,
+    R: BufRead,
+    F: FnMut(&ImportReport),
+{
+    let mut importer = Importer::new(options);
+    for line in reader.lines() {
+        let read = importer.report().read;
+        importer.line(repo, &line?)?;
+        if importer.report().read / PROGRESS_EVERY != read / PROGRESS_EVERY {
+            progress(importer.report());
+        }
+    }
+    importer.finish()
+}
+
+#[cfg(test)]
+mod dump_test {
+    use super::*;
+    use crate::models::User;
+    use crate::state::{MockSnapRepository, UserRepository};
+
+    /// Repository with a deleted snap kept for its reply, the reply and
+    /// another snap.
+    fn repo() -> MockSnapRepository {
+        let mut repo = MockSnapRepository::new();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap().id();
+        let first = repo.post(&author, "First").unwrap();
+        repo.reply(&author, &first.id(), "Reply").unwrap();
+        repo.post(&author, "Last").unwrap();
+        repo.delete(&first.id()).unwrap();
+        repo
+    }
+
+    fn import_lines(
+        repo: &mut MockSnapRepository,
+        lines: &[String],
+        options: ImportOptions,
+    ) -> Result<ImportReport, ImportError> {
+        import(repo, lines.concat().as_bytes(), options, |_| {})
+    }
+
+    #[test]
+    fn export_then_import_keeps_every_snap() {
+        let source = repo();
+        let lines = export(&source).collect::<Vec<String>>();
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|line| line.ends_with('\n')));
+
+        let mut target = MockSnapRepository::new();
+        let report = import_lines(&mut target, &lines, ImportOptions::default()).unwrap();
+        assert_eq!((report.total, report.read, report.created), (3, 3, 3));
+        let values = |repo: &MockSnapRepository| serde_json::to_value(repo.all_snaps()).unwrap();
+        assert_eq!(values(&target), values(&source));
+        assert_eq!(export(&target).skip(1).collect::<Vec<String>>(), lines[1..]);
+    }
+
+    #[test]
+    fn dry_runs_store_nothing() {
+        let lines = export(&repo()).collect::<Vec<String>>();
+        let mut target = MockSnapRepository::new();
+        let options = ImportOptions { dry_run: true, ..ImportOptions::default() };
+        let report = import_lines(&mut target, &lines, options).unwrap();
+        assert_eq!(report.created, 3);
+        assert!(report.dry_run);
+        assert!(target.all_snaps().is_empty());
+    }
+
+    #[test]
+    fn conflicts_follow_the_policy() {
+        let mut source = repo();
+        let lines = export(&source).collect::<Vec<String>>();
+
+        let error = import_lines(&mut source, &lines, ImportOptions::default()).unwrap_err();
+        assert!(matches!(error, ImportError::Conflict { line: 2, .. }), "{error}");
+
+        let skip = ImportOptions { on_conflict: ConflictPolicy::Skip, ..ImportOptions::default() };
+        assert_eq!(import_lines(&mut source, &lines, skip).unwrap().skipped, 3);
+
+        let overwrite = ImportOptions { on_conflict: ConflictPolicy::Overwrite, ..ImportOptions::default() };
+        let report = import_lines(&mut source, &lines, overwrite).unwrap();
+        assert_eq!((report.created, report.overwritten), (0, 3));
+    }
+
+    #[test]
+    fn imports_stop_at_max_created() {
+        let mut source = repo();
+        let lines = export(&source).collect::<Vec<String>>();
+        let mut target = MockSnapRepository::new();
+        let options = ImportOptions { max_created: Some(2), ..ImportOptions::default() };
+        let error = import_lines(&mut target, &lines, options).unwrap_err();
+        assert!(matches!(error, ImportError::TooMany { line: 4, max: 2 }), "{error}");
+        assert_eq!(target.all_snaps().len(), 2);
+
+        // Overwritten snaps aren't new.
+        let overwrite = ImportOptions { on_conflict: ConflictPolicy::Overwrite, max_created: Some(0), ..options };
+        assert_eq!(import_lines(&mut source, &lines, overwrite).unwrap().overwritten, 3);
+    }
+
+    #[test]
+    fn damaged_dumps_are_refused() {
+        let lines = export(&repo()).collect::<Vec<String>>();
+        let mut target = MockSnapRepository::new();
+        let options = ImportOptions { dry_run: true, ..ImportOptions::default() };
+
+        let mut edited = lines.clone();
+        edited[3] = edited[3].replace("Last", "Forged");
+        let error = import_lines(&mut target, &edited, options).unwrap_err();
+        assert!(matches!(error, ImportError::Checksum { line: 4 }), "{error}");
+
+        let error = import_lines(&mut target, &lines[..4], options).unwrap_err();
+        assert!(matches!(error, ImportError::Truncated(_)), "{error}");
+
+        let mut skipped = lines.clone();
+        skipped.remove(3);
+        let error = import_lines(&mut target, &skipped, options).unwrap_err();
+        assert!(matches!(error, ImportError::Truncated(_)), "{error}");
+
+        let mut newer = lines.clone();
+        newer[0] = newer[0].replace("\"version\":1", "\"version\":2");
+        let error = import_lines(&mut target, &newer, options).unwrap_err();
+        assert!(matches!(error, ImportError::UnsupportedVersion(2)), "{error}");
+
+        let error = import_lines(&mut target, &lines[1..], options).unwrap_err();
+        assert!(matches!(error, ImportError::Malformed { line: 1, .. }), "{error}");
+    }
+
+    #[test]
+    fn replies_need_their_parent() {
+        let lines = export(&repo()).collect::<Vec<String>>();
+        let mut target = MockSnapRepository::new();
+        // Without the parent, the reply can't be stored.
+        let mut orphan = lines.clone();
+        orphan.remove(1);
+        let options = ImportOptions { dry_run: true, ..ImportOptions::default() };
+        let error = import_lines(&mut target, &orphan, options).unwrap_err();
+        assert!(matches!(error, ImportError::Restore { line: 2, .. }), "{error}");
+        let error = import_lines(&mut target, &orphan, ImportOptions::default()).unwrap_err();
+        assert!(matches!(error, ImportError::Restore { line: 2, .. }), "{error}");
+    }
+}
@@ -0,0 +1,101 @@
+pub use snap_api::{Entity, EntityKind};
+
+/// Find the hashtags and mentions of `message`, in order.
+/// They have to start the message or follow a character that can't be
+/// part of a word, so `a@b.com` or `x#1` are left alone. Hashtags need
+/// at least one character that isn't a digit.
+pub fn extract(message: &str) -> Vec<Entity> {
+    let chars = message.chars().collect::<Vec<char>>();
+    let mut entities = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let (kind, is_part): (EntityKind, fn(char) -> bool) = match chars[i] {
+            '#' => (EntityKind::Hashtag, is_word),
+            '@' => (EntityKind::Mention, is_username),
+            _ => {
+                i += 1;
+                continue
+            }
+        };
+        let end = (i + 1..chars.len())
+            .find(|&j| !is_part(chars[j]))
+            .unwrap_or(chars.len());
+        let body = &chars[i + 1..end];
+        let at_boundary = i == 0 || !is_word(chars[i - 1]);
+        let valid = match kind {
+            EntityKind::Hashtag => body.iter().any(|c| !c.is_numeric()),
+            EntityKind::Mention => !body.is_empty(),
+        };
+        if at_boundary && valid {
+            entities.push(Entity {
+                kind,
+                text: body.iter().collect::<String>().to_lowercase(),
+                start: i,
+                end,
+            });
+        }
+        i = end.max(i + 1);
+    }
+    entities
+}
+
+/// Lowercase tags with the leading `#` dropped, as they are indexed.
+pub fn hashtags(message: &str) -> Vec<String> {
+    let mut tags = extract(message)
+        .into_iter()
+        .filter(|entity| entity.kind == EntityKind::Hashtag)
+        .map(|entity| entity.text)
+        .collect::<Vec<String>>();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Characters allowed in usernames.
+fn is_username(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod entities_test {
+    use super::*;
+
+    fn texts(message: &str) -> Vec<(EntityKind, String)> {
+        extract(message).into_iter().map(|entity| (entity.kind, entity.text)).collect()
+    }
+
+    #[test]
+    fn finds_hashtags_and_mentions() {
+        let entities = extract("Ping @Alice about #Incident_42!");
+        assert_eq!(entities, vec![
+            Entity { kind: EntityKind::Mention, text: "alice".to_string(), start: 5, end: 11 },
+            Entity { kind: EntityKind::Hashtag, text: "incident_42".to_string(), start: 18, end: 30 },
+        ]);
+    }
+
+    #[test]
+    fn offsets_count_characters() {
+        let entities = extract("¡Olé #fútbol");
+        assert_eq!(entities[0].text, "fútbol");
+        assert_eq!((entities[0].start, entities[0].end), (5, 12));
+    }
+
+    #[test]
+    fn skips_what_isnt_an_entity() {
+        assert!(texts("mail me at a@b.com").is_empty());
+        assert!(texts("issue#12 and #123 and # and @").is_empty());
+        assert_eq!(texts("#a#b @c@d"), vec![
+            (EntityKind::Hashtag, "a".to_string()),
+            (EntityKind::Mention, "c".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn hashtags_are_unique() {
+        assert_eq!(hashtags("#Rust is #rust, @not_a_tag #go"), vec!["go", "rust"]);
+    }
+}
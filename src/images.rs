@@ -0,0 +1,473 @@
+use std::fmt;
+use std::io::Cursor;
+use image::codecs::jpeg::JpegEncoder;
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, Limits};
+use crate::config::MediaConfig;
+use crate::media;
+
+/// Quality of re-encoded JPEG images and thumbnails.
+const JPEG_QUALITY: u8 = 85;
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// The contents aren't one of the supported media types.
+    Unsupported,
+    /// The image has more pixels than allowed, likely a decompression bomb.
+    TooManyPixels { width: u32, height: u32, max: u64 },
+    /// The contents can't be decoded.
+    Corrupt(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Unsupported => write!(f, "unsupported media type"),
+            ImageError::TooManyPixels { width, height, max } => {
+                write!(f, "image of {width}x{height} pixels is over the limit of {max} pixels")
+            }
+            ImageError::Corrupt(reason) => write!(f, "invalid image: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+impl From<image::ImageError> for ImageError {
+    fn from(e: image::ImageError) -> Self {
+        ImageError::Corrupt(e.to_string())
+    }
+}
+
+/// An encoded image with its media type and size in pixels.
+#[derive(Debug, Clone)]
+pub struct Rendition {
+    pub bytes: Vec<u8>,
+    pub content_type: &'static str,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// An uploaded image ready to be stored: the original without its
+/// metadata, and one thumbnail for each configured size smaller than it.
+#[derive(Debug, Clone)]
+pub struct Processed {
+    pub original: Rendition,
+    /// Thumbnails alongside the size of the box they fit in.
+    pub thumbnails: Vec<(u32, Rendition)>,
+}
+
+/// Check, clean and shrink an uploaded image. Fails when the contents
+/// aren't a supported image or have more pixels than `config.max_pixels`,
+/// before anything is decoded.
+pub fn process(bytes: &[u8], config: &MediaConfig) -> Result<Processed, ImageError> {
+    let content_type = media::sniff(bytes).ok_or(ImageError::Unsupported)?;
+    let format = ImageFormat::from_mime_type(content_type).ok_or(ImageError::Unsupported)?;
+    let (width, height) = ImageReader::with_format(Cursor::new(bytes), format).into_dimensions()?;
+    let pixels = u64::from(width) * u64::from(height);
+    if pixels > config.max_pixels {
+        return Err(ImageError::TooManyPixels { width, height, max: config.max_pixels })
+    }
+
+    let mut original = Rendition {
+        bytes: strip_metadata(bytes, content_type)?,
+        content_type,
+        width,
+        height,
+    };
+    let sizes = config.thumbnail_sizes
+        .iter()
+        .copied()
+        .filter(|size| *size < width.max(height))
+        .collect::<Vec<u32>>();
+    let rotated = format == ImageFormat::Jpeg && jpeg_orientation(bytes) != Orientation::NoTransforms;
+    if sizes.is_empty() && !rotated {
+        return Ok(Processed { original, thumbnails: Vec::new() })
+    }
+
+    let mut image = decode(bytes, format, width, height, config)?;
+    if rotated {
+        // The orientation lived in the EXIF data just stripped, so the
+        // pixels themselves have to be turned.
+        image.apply_orientation(jpeg_orientation(bytes));
+        original = encode(&image, format)?;
+    }
+    let thumbnails = sizes.into_iter()
+        .map(|size| Ok((size, encode(&image.thumbnail(size, size), format)?)))
+        .collect::<Result<Vec<_>, ImageError>>()?;
+    Ok(Processed { original, thumbnails })
+}
+
+/// Decode an image already known to be `width` by `height` pixels,
+/// refusing to allocate more than those pixels need.
+fn decode(bytes: &[u8], format: ImageFormat, width: u32, height: u32, config: &MediaConfig) -> Result<DynamicImage, ImageError> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(width);
+    limits.max_image_height = Some(height);
+    // Room for 16 bit RGBA plus the decoder's own buffers.
+    limits.max_alloc = Some(config.max_pixels.saturating_mul(16));
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    Ok(reader.decode()?)
+}
+
+/// Encode `image` as a JPEG when it came from one, as a PNG otherwise.
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Rendition, ImageError> {
+    let mut bytes = Vec::new();
+    let content_type = if format == ImageFormat::Jpeg {
+        let encoder = JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY);
+        DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        "image/jpeg"
+    } else {
+        image.write_to(Cursor::new(&mut bytes), ImageFormat::Png)?;
+        "image/png"
+    };
+    Ok(Rendition { bytes, content_type, width: image.width(), height: image.height() })
+}
+
+/// Orientation recorded in the EXIF data of a JPEG, if any.
+fn jpeg_orientation(bytes: &[u8]) -> Orientation {
+    ImageReader::with_format(Cursor::new(bytes), ImageFormat::Jpeg)
+        .into_decoder()
+        .and_then(|mut decoder| decoder.orientation())
+        .unwrap_or(Orientation::NoTransforms)
+}
+
+/// Copy of an image of type `content_type` without the metadata that may
+/// identify its author or place, like EXIF, XMP, text chunks and comments.
+/// The pixels aren't touched, only the containers are rewritten.
+pub fn strip_metadata(bytes: &[u8], content_type: &str) -> Result<Vec<u8>, ImageError> {
+    match content_type {
+        "image/jpeg" => strip_jpeg(bytes),
+        "image/png" => strip_png(bytes),
+        "image/gif" => strip_gif(bytes),
+        "image/webp" => strip_webp(bytes),
+        _ => Err(ImageError::Unsupported),
+    }
+}
+
+fn truncated() -> ImageError {
+    ImageError::Corrupt("truncated image".to_string())
+}
+
+/// `len` bytes of `bytes` from `at`.
+fn slice(bytes: &[u8], at: usize, len: usize) -> Result<&[u8], ImageError> {
+    bytes.get(at..at.checked_add(len).ok_or_else(truncated)?).ok_or_else(truncated)
+}
+
+/// Drop the APP1 to APP15 segments but ICC profiles (APP2) and Adobe color
+/// transforms (APP14), and the comments.
+fn strip_jpeg(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let mut out = slice(bytes, 0, 2)?.to_vec();
+    let mut at = 2;
+    loop {
+        let marker = slice(bytes, at, 2)?;
+        if marker[0] != 0xff {
+            return Err(ImageError::Corrupt("expected a JPEG marker".to_string()))
+        }
+        match marker[1] {
+            // Fill byte before a marker.
+            0xff => {
+                at += 1;
+                continue
+            },
+            // Markers without a payload.
+            0x01 | 0xd0..=0xd7 => {
+                out.extend_from_slice(marker);
+                at += 2;
+                continue
+            },
+            // Start of scan, or end of image: the rest is image data.
+            0xda | 0xd9 => {
+                out.extend_from_slice(&bytes[at..]);
+                return Ok(out)
+            },
+            _ => {},
+        }
+        let length = slice(bytes, at + 2, 2)?;
+        let length = usize::from(u16::from_be_bytes([length[0], length[1]]));
+        // The length counts its own two bytes.
+        if length < 2 {
+            return Err(ImageError::Corrupt("JPEG segment shorter than its length".to_string()))
+        }
+        let segment = slice(bytes, at, 2 + length)?;
+        let payload = &segment[4..];
+        let keep = match marker[1] {
+            0xe2 => payload.starts_with(b"ICC_PROFILE\0"),
+            0xee => payload.starts_with(b"Adobe"),
+            0xe1..=0xef | 0xfe => false,
+            _ => true,
+        };
+        if keep {
+            out.extend_from_slice(segment);
+        }
+        at += 2 + length;
+    }
+}
+
+/// Keep the critical chunks and the ancillary ones needed to render the
+/// image, including APNG animations.
+fn strip_png(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+    const RENDERING: [&[u8; 4]; 11] = [
+        b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"iCCP", b"sBIT", b"bKGD", b"pHYs", b"acTL", b"fcTL", b"fdAT",
+    ];
+    let mut out = slice(bytes, 0, 8)?.to_vec();
+    let mut at = 8;
+    loop {
+        let header = slice(bytes, at, 8)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = &header[4..8];
+        // Length, type, data and CRC.
+        let chunk = slice(bytes, at, length.checked_add(12).ok_or_else(truncated)?)?;
+        if kind[0].is_ascii_uppercase() || RENDERING.iter().any(|rendering| kind == *rendering) {
+            out.extend_from_slice(chunk);
+        }
+        at += chunk.len();
+        if kind == b"IEND" {
+            return Ok(out)
+        }
+    }
+}
+
+/// Drop comments and application extensions other than the ones
+/// controlling animation loops.
+fn strip_gif(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+    /// Length of the color table announced by a `packed` fields byte.
+    fn color_table(packed: u8) -> usize {
+        if packed & 0x80 == 0 { 0 } else { 3 << ((packed & 0x07) + 1) }
+    }
+    /// Position after the data sub-blocks starting at `at`.
+    fn sub_blocks(bytes: &[u8], mut at: usize) -> Result<usize, ImageError> {
+        loop {
+            let size = usize::from(slice(bytes, at, 1)?[0]);
+            at += 1 + size;
+            if size == 0 {
+                return Ok(at)
+            }
+        }
+    }
+
+    let screen = slice(bytes, 0, 13)?;
+    let mut at = 13 + color_table(screen[10]);
+    let mut out = slice(bytes, 0, at)?.to_vec();
+    loop {
+        match slice(bytes, at, 1)?[0] {
+            0x21 => {
+                let label = slice(bytes, at + 1, 1)?[0];
+                let end = sub_blocks(bytes, at + 2)?;
+                let keep = match label {
+                    0xfe => false,
+                    0xff => {
+                        let application = slice(bytes, at + 2, 12)?;
+                        application[0] == 11 && matches!(&application[1..], b"NETSCAPE2.0" | b"ANIMEXTS1.0")
+                    },
+                    _ => true,
+                };
+                if keep {
+                    out.extend_from_slice(&bytes[at..end]);
+                }
+                at = end;
+            },
+            0x2c => {
+                let descriptor = slice(bytes, at, 10)?;
+                // Skip the local color table and the LZW code size.
+                let end = sub_blocks(bytes, at + 10 + color_table(descriptor[9]) + 1)?;
+                out.extend_from_slice(&bytes[at..end]);
+                at = end;
+            },
+            0x3b => {
+                out.push(0x3b);
+                return Ok(out)
+            },
+            _ => return Err(ImageError::Corrupt("unknown GIF block".to_string())),
+        }
+    }
+}
+
+/// Drop the EXIF and XMP chunks and clear their flags in the extended header.
+fn strip_webp(bytes: &[u8]) -> Result<Vec<u8>, ImageError> {
+    const EXIF_FLAG: u8 = 0x08;
+    const XMP_FLAG: u8 = 0x04;
+    let mut out = slice(bytes, 0, 12)?.to_vec();
+    let mut at = 12;
+    while at < bytes.len() {
+        let header = slice(bytes, at, 8)?;
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        // Chunks are padded to an even length.
+        let padded = length.checked_add(length % 2).ok_or_else(truncated)?;
+        let chunk = match slice(bytes, at, 8 + padded) {
+            Ok(chunk) => chunk,
+            // Some encoders leave out the padding of the last chunk.
+            Err(_) => slice(bytes, at, 8 + length)?,
+        };
+        match &header[..4] {
+            b"EXIF" | b"XMP " => {},
+            b"VP8X" => {
+                let start = out.len();
+                out.extend_from_slice(chunk);
+                if let Some(flags) = out.get_mut(start + 8) {
+                    *flags &= !(EXIF_FLAG | XMP_FLAG);
+                }
+            },
+            _ => out.extend_from_slice(chunk),
+        }
+        at += chunk.len();
+    }
+    let riff_size = u32::try_from(out.len() - 8).map_err(|_| truncated())?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+#[cfg(test)]
+mod images_test {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    fn config() -> MediaConfig {
+        MediaConfig { thumbnail_sizes: vec![8, 32], ..MediaConfig::default() }
+    }
+
+    fn encoded(format: ImageFormat, width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_fn(width, height, |x, y| Rgba([x as u8, y as u8, 128, 255]));
+        let mut bytes = Vec::new();
+        let image = match format {
+            ImageFormat::Jpeg => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).to_rgb8()),
+            _ => DynamicImage::ImageRgba8(image),
+        };
+        image.write_to(Cursor::new(&mut bytes), format).unwrap();
+        bytes
+    }
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    /// A JPEG with an EXIF segment holding a GPS tag and `orientation`.
+    fn jpeg_with_exif(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+        let jpeg = encoded(ImageFormat::Jpeg, width, height);
+        // Big endian TIFF with IFD0 holding Orientation and a GPS IFD pointer.
+        let mut tiff = b"MM\0\x2a\0\0\0\x08\0\x02".to_vec();
+        tiff.extend([0x01, 0x12, 0, 3, 0, 0, 0, 1]);
+        tiff.extend(orientation.to_be_bytes());
+        tiff.extend([0, 0]);
+        tiff.extend([0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0x26]);
+        tiff.extend([0, 0, 0, 0]);
+        tiff.extend(b"\0\0GPSLatitude");
+        let mut app1 = b"Exif\0\0".to_vec();
+        app1.extend(tiff);
+        let mut bytes = jpeg[..2].to_vec();
+        bytes.extend([0xff, 0xe1]);
+        bytes.extend(((app1.len() + 2) as u16).to_be_bytes());
+        bytes.extend(app1);
+        bytes.extend([0xff, 0xfe, 0, 9]);
+        bytes.extend(b"secret!");
+        bytes.extend(&jpeg[2..]);
+        bytes
+    }
+
+    #[test]
+    fn jpeg_metadata_is_stripped() {
+        let bytes = jpeg_with_exif(40, 20, 1);
+        assert!(contains(&bytes, b"GPSLatitude"));
+        let processed = process(&bytes, &config()).unwrap();
+        let original = &processed.original;
+        assert!(!contains(&original.bytes, b"Exif"));
+        assert!(!contains(&original.bytes, b"secret!"));
+        assert_eq!((original.width, original.height), (40, 20));
+        assert_eq!(image::load_from_memory(&original.bytes).unwrap().width(), 40);
+
+        let sizes = processed.thumbnails.iter()
+            .map(|(size, thumbnail)| (*size, thumbnail.width, thumbnail.height, thumbnail.content_type))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![(8, 8, 4, "image/jpeg"), (32, 32, 16, "image/jpeg")]);
+    }
+
+    #[test]
+    fn jpeg_segments_too_short_are_corrupt() {
+        let jpeg = encoded(ImageFormat::Jpeg, 8, 8);
+        // Right before the start of scan, past the frame header.
+        let mut sos = 2;
+        while jpeg[sos + 1] != 0xda {
+            sos += 2 + usize::from(u16::from_be_bytes([jpeg[sos + 2], jpeg[sos + 3]]));
+        }
+        for segment in [[0xff, 0xfe, 0, 0], [0xff, 0xe1, 0, 1]] {
+            let mut bytes = jpeg[..sos].to_vec();
+            bytes.extend(segment);
+            bytes.extend(&jpeg[sos..]);
+            assert!(matches!(strip_metadata(&bytes, "image/jpeg"), Err(ImageError::Corrupt(_))));
+            assert!(matches!(process(&bytes, &config()), Err(ImageError::Corrupt(_))));
+        }
+    }
+
+    #[test]
+    fn rotated_jpegs_are_turned() {
+        // Orientation 6: the camera was turned 90 degrees clockwise.
+        let processed = process(&jpeg_with_exif(40, 20, 6), &config()).unwrap();
+        let original = &processed.original;
+        assert_eq!((original.width, original.height), (20, 40));
+        assert!(!contains(&original.bytes, b"GPSLatitude"));
+        assert_eq!(image::load_from_memory(&original.bytes).unwrap().height(), 40);
+    }
+
+    #[test]
+    fn png_text_chunks_are_stripped() {
+        let png = encoded(ImageFormat::Png, 16, 16);
+        // tEXt chunk right after IHDR, with a CRC nobody checks once removed.
+        let mut bytes = png[..33].to_vec();
+        bytes.extend(13u32.to_be_bytes());
+        bytes.extend(b"tEXtAuthor\0Alice!");
+        bytes.extend([0; 4]);
+        bytes.extend(&png[33..]);
+
+        let stripped = strip_metadata(&bytes, "image/png").unwrap();
+        assert_eq!(stripped, png);
+        // Only the 8 pixel thumbnail is smaller than the image.
+        let thumbnails = process(&png, &config()).unwrap().thumbnails;
+        assert_eq!(thumbnails.iter().map(|(size, _)| *size).collect::<Vec<_>>(), vec![8]);
+    }
+
+    #[test]
+    fn gif_comments_are_stripped() {
+        let gif = encoded(ImageFormat::Gif, 4, 4);
+        let mut bytes = gif[..gif.len() - 1].to_vec();
+        bytes.extend(b"\x21\xfe\x07secret!\x00");
+        bytes.extend(b"\x21\xff\x0bXMP DataXMP\x03abc\x00");
+        bytes.push(0x3b);
+
+        let stripped = strip_metadata(&bytes, "image/gif").unwrap();
+        assert_eq!(stripped, gif);
+    }
+
+    #[test]
+    fn webp_exif_is_stripped() {
+        let mut vp8l = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([1, 2, 3])))
+            .write_to(Cursor::new(&mut vp8l), ImageFormat::WebP)
+            .unwrap();
+        // Extended header flagging EXIF, the image, and an odd sized EXIF chunk.
+        let mut bytes = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0\x08\0\0\0\x01\0\0\x01\0\0".to_vec();
+        bytes.extend(&vp8l[12..]);
+        bytes.extend(b"EXIF\x03\0\0\0GPS\0");
+        let size = (bytes.len() - 8) as u32;
+        bytes[4..8].copy_from_slice(&size.to_le_bytes());
+
+        let stripped = strip_webp(&bytes).unwrap();
+        assert!(!contains(&stripped, b"EXIF"));
+        assert_eq!(stripped[20], 0);
+        assert_eq!(u32::from_le_bytes(stripped[4..8].try_into().unwrap()) as usize, stripped.len() - 8);
+        assert_eq!(image::load_from_memory(&stripped).unwrap().width(), 2);
+    }
+
+    #[test]
+    fn decompression_bombs_are_rejected() {
+        let config = MediaConfig { max_pixels: 100, ..config() };
+        let bytes = encoded(ImageFormat::Png, 11, 10);
+        assert!(matches!(
+            process(&bytes, &config),
+            Err(ImageError::TooManyPixels { width: 11, height: 10, max: 100 }),
+        ));
+        assert!(matches!(process(b"not an image", &config), Err(ImageError::Unsupported)));
+        let truncated = &encoded(ImageFormat::Png, 4, 4)[..40];
+        assert!(matches!(process(truncated, &config), Err(ImageError::Corrupt(_))));
+    }
+}
@@ -1,3 +1,19 @@
+pub mod auth;
+pub mod config;
+pub mod dump;
+pub mod context;
+pub mod entities;
+pub mod images;
+pub mod media;
+pub mod metrics;
+pub mod notifications;
+pub mod projections;
+pub mod rate_limit;
+pub mod replication;
 pub mod router;
+pub mod shutdown;
+pub mod snapshot;
 pub mod state;
-mod models;
\ No newline at end of file
+pub mod tenancy;
+pub mod webhooks;
+pub mod models;
\ No newline at end of file
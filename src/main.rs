@@ -1,5 +1,18 @@
-use snap_app_demo::{router, state};
+use snap_app_demo::{media, replication, router, shutdown, snapshot, state, tenancy, webhooks};
+use snap_app_demo::context::{AppContext, Phase, Readiness};
+use snap_app_demo::metrics::Metrics;
+use snap_app_demo::notifications::Notifier;
+use snap_app_demo::projections::Projector;
+use snap_app_demo::rate_limit::{RateLimitLayer, RateLimiter};
+use snap_app_demo::auth::Authenticator;
+use snap_app_demo::config::{Cli, Config, JwtAlgorithm, LogConfig, LogFormat, StorageBackend};
+use snap_app_demo::state::Repository;
+use clap::Parser;
 use std::env;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 use tower_http::trace::TraceLayer;
 use tracing_subscriber::{
     util::SubscriberInitExt,
@@ -7,35 +20,370 @@ use tracing_subscriber::{
 };
 
 #[tokio::main]
-pub async fn main() {
-    // Subscriber for logs.
-    // See: https://docs.rs/tracing-core/0.1.32/tracing_core/subscriber/trait.Subscriber.html
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                // axum logs rejections from built-in extractors with the `axum::rejection`
-                // target, at `TRACE` level. `axum::rejection=trace` enables showing those events
-                format!(
-                    "{}=debug,tower_http=debug,axum::rejection=trace",
-                    env!("CARGO_CRATE_NAME")
-                )
-                    .into()
-            }),
-        )
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router()
-        .with_state(state)
+pub async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match Config::load(&cli, |key| env::var(key).ok()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    if cli.print_config {
+        print!("{}", config.to_toml());
+        return ExitCode::SUCCESS;
+    }
+
+    init_tracing(&config.log);
+
+    let fan_out = config.timeline.fan_out;
+    if config.tenancy.enabled {
+        if cli.restore_snapshot.is_some() {
+            eprintln!("error: snapshots can't be restored with tenancy enabled");
+            return ExitCode::from(2);
+        }
+        return match config.storage.backend {
+            StorageBackend::Memory => {
+                let change_buffer = config.replication.change_buffer;
+                serve_tenants(&config, None, move |_| {
+                    Ok(state::MockSnapRepository::with_fan_out(fan_out).with_change_buffer(change_buffer))
+                }).await
+            }
+            StorageBackend::Journal => {
+                // Each tenant has a journal in the storage directory, next to the registry.
+                let dir = config.storage.path.clone().unwrap();
+                if let Err(e) = std::fs::create_dir_all(&dir) {
+                    eprintln!("error: can't create {}: {e}", dir.display());
+                    return ExitCode::FAILURE;
+                }
+                let registry = dir.join("tenants.json");
+                serve_tenants(&config, Some(registry), move |id| {
+                    state::JournalSnapRepository::open_with_fan_out(dir.join(format!("{id}.journal")), fan_out)
+                }).await
+            }
+        };
+    }
+    match config.storage.backend {
+        StorageBackend::Memory => match &cli.restore_snapshot {
+            None => {
+                let repo = state::MockSnapRepository::with_fan_out(fan_out)
+                    .with_change_buffer(config.replication.change_buffer);
+                serve(repo, &config).await
+            }
+            Some(snapshot) => match snapshot::verify(snapshot, fan_out) {
+                Ok((info, repo)) => {
+                    tracing::info!(snapshot = %info.name, entries = info.entries, "snapshot restored");
+                    serve(repo.with_change_buffer(config.replication.change_buffer), &config).await
+                }
+                Err(e) => {
+                    eprintln!("error: can't restore {}: {e}", snapshot.display());
+                    ExitCode::FAILURE
+                }
+            },
+        },
+        StorageBackend::Journal => {
+            // Validation guarantees a path for the journal backend.
+            let path = config.storage.path.as_ref().unwrap();
+            if let Some(snapshot) = &cli.restore_snapshot {
+                // The journal is only swapped once the snapshot replays cleanly.
+                match snapshot::restore_journal(snapshot, path, fan_out) {
+                    Ok(info) => tracing::info!(snapshot = %info.name, entries = info.entries, "snapshot restored"),
+                    Err(e) => {
+                        eprintln!("error: can't restore {}: {e}", snapshot.display());
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            match state::JournalSnapRepository::open_with_fan_out(path, fan_out) {
+                Ok(repo) => serve(repo, &config).await,
+                Err(e) => {
+                    eprintln!("error: can't open journal {}: {e}", path.display());
+                    ExitCode::FAILURE
+                }
+            }
+        }
+    }
+}
+
+/// Subscriber for logs.
+/// See: https://docs.rs/tracing-core/0.1.32/tracing_core/subscriber/trait.Subscriber.html
+fn init_tracing(log: &LogConfig) {
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        // axum logs rejections from built-in extractors with the `axum::rejection`
+        // target, at `TRACE` level. `axum::rejection=trace` enables showing those events
+        log.filter.clone().unwrap_or_else(|| format!(
+            "{}=debug,tower_http=debug,axum::rejection=trace",
+            env!("CARGO_CRATE_NAME")
+        ))
+            .into()
+    });
+    let registry = tracing_subscriber::registry().with(filter);
+    match log.format {
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).init(),
+        LogFormat::Json => registry.with(tracing_subscriber::fmt::layer().json()).init(),
+    }
+}
+
+/// Serve the app on the configured address with `state` as the repository
+/// until SIGINT or SIGTERM, then drain connections, stop background tasks
+/// and flush the repository.
+async fn serve<S: Repository + Clone + Send + Sync + 'static>(
+    state: S,
+    config: &Config,
+) -> ExitCode {
+    let auth = match Authenticator::from_config(&config.auth) {
+        Ok(auth) => auth,
+        Err(e) => {
+            eprintln!("error: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    if config.auth.algorithm == JwtAlgorithm::HS256 && config.auth.secret.is_none() {
+        tracing::warn!("no auth.secret configured, issued tokens won't survive a restart");
+    }
+    let readiness = Readiness::new(Phase::Starting);
+    let notifier = Notifier::new(config.notifications.stream_buffer);
+    let metrics = Metrics::new();
+    let blobs = match media::open(&config.media) {
+        Ok(blobs) => blobs,
+        Err(e) => {
+            eprintln!("error: can't open the media directory: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Built before taking traffic so the first reads don't wait on it.
+    let projections = Projector::default();
+    let status = projections.replay(&state);
+    tracing::info!(events = status.offset, snaps = status.snaps, "projections built");
+    let replication = replication::Replication::default();
+    let context = AppContext {
+        config: Arc::new(config.clone()),
+        readiness: readiness.clone(),
+        metrics: metrics.clone(),
+        auth,
+        notifier: notifier.clone(),
+        projections,
+        replication: replication.clone(),
+        pipeline: media::Pipeline::new(blobs.clone(), config.media.clone()),
+        blobs,
+    };
+    let rate_limiter = RateLimiter::new(config.rate_limit.clone()).with_api_keys(state.clone());
+    let limiter = config.rate_limit.enabled.then(|| rate_limiter.clone());
+    let app = router::get_router_with_limiter(context, limiter)
+        .with_state(state.clone())
         .layer(TraceLayer::new_for_http());
 
-    let mut addr = env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    addr.insert_str(0, "0.0.0.0:");
-    let listener = tokio::net::TcpListener::bind(addr)
-        .await
-        .unwrap();
+    let listener = match tokio::net::TcpListener::bind(config.server.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: can't listen on {}: {e}", config.server.bind);
+            return ExitCode::FAILURE;
+        }
+    };
+    tracing::debug!("LISTENING ON {}", listener.local_addr().unwrap());
+
+    let mut background = shutdown::BackgroundTasks::new();
+    let flushed = state.clone();
+    background.spawn_periodic(
+        "storage flush",
+        Duration::from_millis(config.storage.flush_interval_ms),
+        move || {
+            if let Err(e) = flushed.flush() {
+                tracing::error!("periodic storage flush failed: {e}");
+            }
+        },
+    );
+
+    readiness.set(Phase::Ready);
+    let shutdown_signal = async {
+        shutdown::signal().await;
+        readiness.set(Phase::ShuttingDown);
+        // Streams never finish on their own, end them before draining.
+        notifier.close();
+    };
+    if let (Some(dir), true) = (config.snapshots.path.clone(), config.snapshots.interval_secs > 0) {
+        let snapshotted = state.clone();
+        let keep = config.snapshots.keep;
+        background.spawn_periodic_async(
+            "scheduled snapshots",
+            Duration::from_secs(config.snapshots.interval_secs),
+            move || {
+                let (repo, dir) = (snapshotted.clone(), dir.clone());
+                async move {
+                    let taken = tokio::task::spawn_blocking(move || {
+                        let info = snapshot::take(&repo, &dir)?;
+                        Ok::<_, snapshot::SnapshotError>((info, snapshot::prune(&dir, keep)?))
+                    }).await;
+                    match taken {
+                        Ok(Ok((info, pruned))) => {
+                            tracing::info!(snapshot = %info.name, entries = info.entries, pruned = pruned.len(), "scheduled snapshot taken");
+                        }
+                        Ok(Err(e)) => tracing::error!("scheduled snapshot failed: {e}"),
+                        Err(e) => tracing::error!("scheduled snapshot panicked: {e}"),
+                    }
+                }
+            },
+        );
+    }
+    background.spawn_periodic("rate limit pruning", Duration::from_secs(60), move || {
+        rate_limiter.prune();
+    });
+    if let Some(leader) = &config.replication.leader {
+        // The leader delivers the webhooks, followers get the outcome.
+        tracing::info!(leader, offset = state.change_count(), "following the leader");
+        background.spawn(
+            "replication",
+            replication::follow(state.clone(), config.replication.clone(), replication),
+        );
+    } else {
+        let dispatcher = webhooks::Dispatcher::new(state.clone(), config.webhooks.clone(), metrics);
+        background.spawn_periodic_async(
+            "webhook deliveries",
+            Duration::from_millis(config.webhooks.poll_interval_ms),
+            move || {
+                let mut dispatcher = dispatcher.clone();
+                async move {
+                    dispatcher.run_once().await;
+                }
+            },
+        );
+    }
+
+    let readiness_grace = Duration::from_secs(config.server.readiness_grace_secs);
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout_secs);
+    let served = shutdown::serve_with_drain(listener, app, shutdown_signal, readiness_grace, drain_timeout).await;
+    let mut exit_code = match served {
+        shutdown::ServeOutcome::Drained => {
+            tracing::info!("all connections drained");
+            ExitCode::SUCCESS
+        }
+        shutdown::ServeOutcome::TimedOut => {
+            tracing::warn!("drain timeout reached, dropping open connections");
+            ExitCode::SUCCESS
+        }
+        shutdown::ServeOutcome::Failed(e) => {
+            tracing::error!("server stopped unexpectedly: {e}");
+            ExitCode::FAILURE
+        }
+    };
+
+    background.shutdown().await;
+    if let Err(e) = state.flush() {
+        tracing::error!("final storage flush failed: {e}");
+        exit_code = ExitCode::FAILURE;
+    }
+    exit_code
+}
+
+/// Serve the tenants listed in `registry`, each with a store opened with
+/// `open`, until SIGINT or SIGTERM, then drain connections, stop
+/// background tasks and flush every store.
+async fn serve_tenants<S, F>(config: &Config, registry: Option<PathBuf>, open: F) -> ExitCode
+where
+    S: Repository + Clone + Send + Sync + 'static,
+    F: Fn(&str) -> Result<S, state::StorageError> + Send + Sync + 'static,
+{
+    let readiness = Readiness::new(Phase::Starting);
+    let shared = AppContext {
+        readiness: readiness.clone(),
+        metrics: Metrics::for_tenants(),
+        ..AppContext::default()
+    };
+    let tenants = match tenancy::Tenants::new(config.clone(), shared, registry, open) {
+        Ok(tenants) => tenants,
+        Err(e) => {
+            eprintln!("error: can't open the tenants: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    tracing::info!(tenants = tenants.all().len(), "tenants opened");
+    let mut app = router::get_tenant_router(tenants.clone());
+    // Requests to a tenant are charged by its own limiter, which knows its API keys.
+    let rate_limiter = RateLimiter::new(config.rate_limit.clone());
+    if config.rate_limit.enabled {
+        app = app.route_layer(RateLimitLayer::new(rate_limiter.clone()));
+    }
+    let app = app.layer(TraceLayer::new_for_http());
+
+    let listener = match tokio::net::TcpListener::bind(config.server.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("error: can't listen on {}: {e}", config.server.bind);
+            return ExitCode::FAILURE;
+        }
+    };
     tracing::debug!("LISTENING ON {}", listener.local_addr().unwrap());
 
-    axum::serve(listener, app).await.unwrap();
+    let mut background = shutdown::BackgroundTasks::new();
+    let flushed = tenants.clone();
+    background.spawn_periodic(
+        "storage flush",
+        Duration::from_millis(config.storage.flush_interval_ms),
+        move || {
+            for (tenant, scope) in flushed.all() {
+                if let Err(e) = scope.repo.flush() {
+                    tracing::error!(tenant = %tenant.id, "periodic storage flush failed: {e}");
+                }
+            }
+        },
+    );
+    let pruned = tenants.clone();
+    background.spawn_periodic("rate limit pruning", Duration::from_secs(60), move || {
+        rate_limiter.prune();
+        for (_, scope) in pruned.all() {
+            scope.prune();
+        }
+    });
+    let delivering = tenants.clone();
+    background.spawn_periodic_async(
+        "webhook deliveries",
+        Duration::from_millis(config.webhooks.poll_interval_ms),
+        move || {
+            let tenants = delivering.all();
+            async move {
+                for (_, mut scope) in tenants {
+                    scope.dispatcher.run_once().await;
+                }
+            }
+        },
+    );
+
+    readiness.set(Phase::Ready);
+    let closed = tenants.clone();
+    let shutdown_signal = async {
+        shutdown::signal().await;
+        readiness.set(Phase::ShuttingDown);
+        // Streams never finish on their own, end them before draining.
+        for (_, scope) in closed.all() {
+            scope.context.notifier.close();
+        }
+    };
+
+    let readiness_grace = Duration::from_secs(config.server.readiness_grace_secs);
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout_secs);
+    let served = shutdown::serve_with_drain(listener, app, shutdown_signal, readiness_grace, drain_timeout).await;
+    let mut exit_code = match served {
+        shutdown::ServeOutcome::Drained => {
+            tracing::info!("all connections drained");
+            ExitCode::SUCCESS
+        }
+        shutdown::ServeOutcome::TimedOut => {
+            tracing::warn!("drain timeout reached, dropping open connections");
+            ExitCode::SUCCESS
+        }
+        shutdown::ServeOutcome::Failed(e) => {
+            tracing::error!("server stopped unexpectedly: {e}");
+            ExitCode::FAILURE
+        }
+    };
+
+    background.shutdown().await;
+    for (tenant, scope) in tenants.all() {
+        if let Err(e) = scope.repo.flush() {
+            tracing::error!(tenant = %tenant.id, "final storage flush failed: {e}");
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+    exit_code
 }
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use sha2::{Digest, Sha256};
+use tokio::sync::Semaphore;
+use crate::config::MediaConfig;
+use crate::images::{self, ImageError};
+use crate::models::{self, Attachment, Thumbnail};
+use crate::state::StorageError;
+
+/// Media types accepted as attachments, recognized by [sniff].
+pub const SUPPORTED_TYPES: [&str; 4] = ["image/jpeg", "image/png", "image/gif", "image/webp"];
+
+/// Storage of attachment contents, addressed by their SHA-256 hash.
+pub trait BlobStore: Send + Sync {
+    /// Store `bytes` and return their hash. Storing the same bytes
+    /// twice keeps one copy.
+    fn put(&self, bytes: &[u8]) -> Result<String, StorageError>;
+
+    /// Contents stored under `hash`, if any.
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, StorageError>;
+
+    /// Whether anything is stored under `hash`.
+    fn contains(&self, hash: &str) -> Result<bool, StorageError>;
+
+    /// Drop the contents stored under `hash`, if any.
+    fn remove(&self, hash: &str) -> Result<(), StorageError>;
+}
+
+/// Blob store for `config`: files under `media.path`, or memory when unset.
+pub fn open(config: &MediaConfig) -> Result<Arc<dyn BlobStore>, StorageError> {
+    match &config.path {
+        Some(path) => Ok(Arc::new(FsBlobStore::open(path)?)),
+        None => Ok(Arc::new(MemoryBlobStore::default())),
+    }
+}
+
+/// Lowercase hex SHA-256 of `bytes`.
+pub fn hash(bytes: &[u8]) -> String {
+    models::hex(&Sha256::digest(bytes))
+}
+
+/// Whether `hash` looks like one returned by [hash].
+pub fn is_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Media type of `bytes` from their magic numbers, if it's one of
+/// [SUPPORTED_TYPES]. What the client claims isn't trusted.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+/// Part of a blob asked for by a `Range` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// No usable range, the whole blob is sent.
+    Full,
+    /// Bytes from `start` to `end`, both included.
+    Partial { start: u64, end: u64 },
+    /// The range starts past the end of the blob.
+    Unsatisfiable,
+}
+
+/// Range of a blob of `len` bytes asked by the `Range` header `value`.
+/// Only single `bytes` ranges are honored, anything else gets the
+/// whole blob as allowed by RFC 9110.
+pub fn byte_range(value: Option<&str>, len: u64) -> ByteRange {
+    let Some(spec) = value.and_then(|value| value.trim().strip_prefix("bytes=")) else {
+        return ByteRange::Full
+    };
+    if spec.contains(',') {
+        return ByteRange::Full
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full
+    };
+    match (start.parse::<u64>(), end.parse::<u64>()) {
+        // `bytes=-N`: the last N bytes.
+        (Err(_), Ok(suffix)) if start.is_empty() => match suffix {
+            0 => ByteRange::Unsatisfiable,
+            _ if len == 0 => ByteRange::Unsatisfiable,
+            _ => ByteRange::Partial { start: len.saturating_sub(suffix), end: len - 1 },
+        },
+        (Ok(start), _) if start >= len => ByteRange::Unsatisfiable,
+        (Ok(start), Err(_)) if end.is_empty() => ByteRange::Partial { start, end: len - 1 },
+        (Ok(start), Ok(end)) if start <= end => ByteRange::Partial { start, end: end.min(len - 1) },
+        _ => ByteRange::Full,
+    }
+}
+
+#[derive(Debug)]
+pub enum MediaError {
+    Image(ImageError),
+    Storage(StorageError),
+}
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MediaError::Image(e) => write!(f, "{e}"),
+            MediaError::Storage(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+impl From<ImageError> for MediaError {
+    fn from(e: ImageError) -> Self {
+        MediaError::Image(e)
+    }
+}
+
+impl From<StorageError> for MediaError {
+    fn from(e: StorageError) -> Self {
+        MediaError::Storage(e)
+    }
+}
+
+/// An attachment stored by [Pipeline::store], with the hashes of the
+/// blobs that weren't in the store before it.
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub attachment: Attachment,
+    pub created: Vec<String>,
+}
+
+/// Turns uploads into attachments: strips their metadata, makes their
+/// thumbnails and stores everything in a blob store. Images are processed
+/// on blocking threads, at most `media.workers` at a time, so large
+/// uploads can't starve the async executor nor exhaust the memory.
+#[derive(Clone)]
+pub struct Pipeline {
+    blobs: Arc<dyn BlobStore>,
+    config: Arc<MediaConfig>,
+    workers: Arc<Semaphore>,
+}
+
+impl Pipeline {
+    pub fn new(blobs: Arc<dyn BlobStore>, config: MediaConfig) -> Pipeline {
+        Pipeline {
+            blobs,
+            workers: Arc::new(Semaphore::new(config.workers)),
+            config: Arc::new(config),
+        }
+    }
+
+    /// Process the uploaded `bytes` and store them with their thumbnails,
+    /// waiting for a free worker first.
+    pub async fn store<B>(&self, bytes: B) -> Result<Upload, MediaError>
+    where
+        B: AsRef<[u8]> + Send + 'static,
+    {
+        let permit = self.workers.clone().acquire_owned().await.expect("the worker pool is never closed");
+        let pipeline = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            pipeline.store_blocking(bytes.as_ref())
+        })
+            .await
+            .expect("image processing doesn't panic")
+    }
+
+    /// Drop the blobs `created` by uploads whose snap couldn't be posted.
+    /// Blobs that were already stored may belong to other snaps and stay.
+    pub fn discard(&self, created: &[String]) {
+        for hash in created {
+            if let Err(e) = self.blobs.remove(hash) {
+                tracing::warn!("can't remove unused blob {hash}: {e}");
+            }
+        }
+    }
+
+    fn store_blocking(&self, bytes: &[u8]) -> Result<Upload, MediaError> {
+        let processed = images::process(bytes, &self.config)?;
+        let mut created = Vec::new();
+        let result = self.put_all(processed, &mut created);
+        if result.is_err() {
+            self.discard(&created);
+        }
+        result.map(|attachment| Upload { attachment, created })
+    }
+
+    fn put_all(&self, processed: images::Processed, created: &mut Vec<String>) -> Result<Attachment, MediaError> {
+        let mut put = |bytes: &[u8]| -> Result<String, StorageError> {
+            let existed = self.blobs.contains(&hash(bytes))?;
+            let hash = self.blobs.put(bytes)?;
+            if !existed {
+                created.push(hash.clone());
+            }
+            Ok(hash)
+        };
+        let thumbnails = processed.thumbnails
+            .into_iter()
+            .map(|(max_dimension, thumbnail)| Ok(Thumbnail {
+                max_dimension,
+                hash: put(&thumbnail.bytes)?,
+                content_type: thumbnail.content_type.to_string(),
+                width: thumbnail.width,
+                height: thumbnail.height,
+            }))
+            .collect::<Result<Vec<Thumbnail>, StorageError>>()?;
+        let original = processed.original;
+        Ok(Attachment {
+            hash: put(&original.bytes)?,
+            content_type: original.content_type.to_string(),
+            size: original.bytes.len() as u64,
+            width: original.width,
+            height: original.height,
+            thumbnails,
+        })
+    }
+}
+
+/// Blobs kept in memory, lost on exit.
+#[derive(Clone, Default)]
+pub struct MemoryBlobStore {
+    blobs: Arc<Mutex<HashMap<String, Arc<[u8]>>>>,
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put(&self, bytes: &[u8]) -> Result<String, StorageError> {
+        let hash = hash(bytes);
+        self.blobs
+            .lock()
+            .unwrap()
+            .entry(hash.clone())
+            .or_insert_with(|| Arc::from(bytes));
+        Ok(hash)
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        Ok(self.blobs.lock().unwrap().get(hash).map(|blob| blob.to_vec()))
+    }
+
+    fn contains(&self, hash: &str) -> Result<bool, StorageError> {
+        Ok(self.blobs.lock().unwrap().contains_key(hash))
+    }
+
+    fn remove(&self, hash: &str) -> Result<(), StorageError> {
+        self.blobs.lock().unwrap().remove(hash);
+        Ok(())
+    }
+}
+
+/// Blobs kept as files under a root directory, at `ab/cd/abcd...`
+/// after the first bytes of their hash so no directory grows too large.
+#[derive(Debug, Clone)]
+pub struct FsBlobStore {
+    root: PathBuf,
+}
+
+impl FsBlobStore {
+    /// Store under `root`, created if missing.
+    pub fn open(root: &Path) -> Result<FsBlobStore, StorageError> {
+        fs::create_dir_all(root)?;
+        Ok(FsBlobStore { root: root.to_path_buf() })
+    }
+
+    fn path(&self, hash: &str) -> PathBuf {
+        self.root.join(&hash[..2]).join(&hash[2..4]).join(hash)
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, bytes: &[u8]) -> Result<String, StorageError> {
+        let hash = hash(bytes);
+        let path = self.path(&hash);
+        if path.exists() {
+            return Ok(hash)
+        }
+        let dir = path.parent().expect("blob paths have a parent");
+        fs::create_dir_all(dir)?;
+        // Written aside and renamed so readers never see half a blob.
+        let partial = dir.join(format!("{hash}.{}.partial", uuid::Uuid::new_v4()));
+        let mut file = fs::File::create(&partial)?;
+        let written = file.write_all(bytes)
+            .and_then(|()| file.sync_all())
+            .and_then(|()| fs::rename(&partial, &path));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&partial);
+            return Err(e.into())
+        }
+        Ok(hash)
+    }
+
+    fn get(&self, hash: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        if !is_hash(hash) {
+            return Ok(None)
+        }
+        match fs::read(self.path(hash)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn contains(&self, hash: &str) -> Result<bool, StorageError> {
+        Ok(is_hash(hash) && self.path(hash).exists())
+    }
+
+    fn remove(&self, hash: &str) -> Result<(), StorageError> {
+        if !is_hash(hash) {
+            return Ok(())
+        }
+        match fs::remove_file(self.path(hash)) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod media_test {
+    use super::*;
+
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+    #[test]
+    fn sniffing_ignores_names() {
+        assert_eq!(sniff(PNG), Some("image/png"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"GIF89a\x01\0\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>"), None);
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WAVEfmt "), None);
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(byte_range(None, 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=0-3"), 10), ByteRange::Partial { start: 0, end: 3 });
+        assert_eq!(byte_range(Some("bytes=4-"), 10), ByteRange::Partial { start: 4, end: 9 });
+        assert_eq!(byte_range(Some("bytes=-3"), 10), ByteRange::Partial { start: 7, end: 9 });
+        assert_eq!(byte_range(Some("bytes=-30"), 10), ByteRange::Partial { start: 0, end: 9 });
+        assert_eq!(byte_range(Some("bytes=5-100"), 10), ByteRange::Partial { start: 5, end: 9 });
+        assert_eq!(byte_range(Some("bytes=10-"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=-0"), 10), ByteRange::Unsatisfiable);
+        assert_eq!(byte_range(Some("bytes=0-1,4-5"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("bytes=5-2"), 10), ByteRange::Full);
+        assert_eq!(byte_range(Some("items=0-1"), 10), ByteRange::Full);
+    }
+
+    #[test]
+    fn fs_store_is_content_addressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FsBlobStore::open(dir.path()).unwrap();
+        let hash = store.put(PNG).unwrap();
+        assert!(is_hash(&hash));
+        assert_eq!(store.put(PNG).unwrap(), hash);
+        assert!(dir.path().join(&hash[..2]).join(&hash[2..4]).join(&hash).is_file());
+        assert_eq!(store.get(&hash).unwrap().as_deref(), Some(PNG));
+        assert_eq!(store.get(&"0".repeat(64)).unwrap(), None);
+        assert_eq!(store.get("../../etc/passwd").unwrap(), None);
+        assert!(store.contains(&hash).unwrap());
+        store.remove(&hash).unwrap();
+        assert!(!store.contains(&hash).unwrap());
+        store.remove(&hash).unwrap();
+
+        let memory = MemoryBlobStore::default();
+        assert_eq!(memory.put(PNG).unwrap(), hash);
+        assert_eq!(memory.get(&hash).unwrap().as_deref(), Some(PNG));
+        memory.remove(&hash).unwrap();
+        assert_eq!(memory.get(&hash).unwrap(), None);
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Instant;
+use axum::extract::{MatchedPath, Request};
+use axum::http::Method;
+use axum::middleware::Next;
+use axum::response::Response;
+use prometheus::{
+    exponential_buckets,
+    Encoder,
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    IntGauge,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+use crate::config::FanOut;
+
+/// Label of the requests that matched no route.
+const UNMATCHED_ROUTE: &str = "<unmatched>";
+
+/// Label of the requests with a method outside of [method_label].
+const OTHER_METHOD: &str = "other";
+
+/// Response extension naming the kind of RFC 7807 problem returned,
+/// so it can be counted without parsing the body.
+#[derive(Debug, Clone, Copy)]
+pub struct ProblemKind(pub &'static str);
+
+/// Prometheus collectors of the app, registered on their own [Registry].
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_latency: HistogramVec,
+    /// Unset when shared by tenants, each has its own count.
+    snaps: Option<IntGauge>,
+    open_streams: IntGauge,
+    snap_creation_errors: IntCounterVec,
+    problems: IntCounterVec,
+    repository_latency: HistogramVec,
+    webhook_attempts: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests handled."),
+            &["method", "route", "status"],
+        ).unwrap();
+        let http_latency = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency."),
+            &["method", "route", "status"],
+        ).unwrap();
+        let snaps = IntGauge::new("snaps", "Snaps currently stored.").unwrap();
+        let open_streams = IntGauge::new(
+            "open_streams",
+            "Streaming connections (SSE or WebSocket) currently open.",
+        ).unwrap();
+        let snap_creation_errors = IntCounterVec::new(
+            Opts::new("snap_creation_errors_total", "Failed snap creations by error."),
+            &["error"],
+        ).unwrap();
+        let problems = IntCounterVec::new(
+            Opts::new("problem_responses_total", "RFC 7807 problem responses by kind."),
+            &["kind", "status"],
+        ).unwrap();
+
+        // In-memory operations take micro to milliseconds.
+        let repository_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "repository_operation_duration_seconds",
+                "Latency of repository operations by timeline fan-out strategy.",
+            ).buckets(exponential_buckets(1e-6, 4.0, 10).unwrap()),
+            &["operation", "fan_out"],
+        ).unwrap();
+
+        let webhook_attempts = IntCounterVec::new(
+            Opts::new("webhook_delivery_attempts_total", "Webhook delivery attempts by outcome."),
+            &["outcome"],
+        ).unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_latency.clone())).unwrap();
+        registry.register(Box::new(open_streams.clone())).unwrap();
+        registry.register(Box::new(snap_creation_errors.clone())).unwrap();
+        registry.register(Box::new(problems.clone())).unwrap();
+        registry.register(Box::new(repository_latency.clone())).unwrap();
+        registry.register(Box::new(webhook_attempts.clone())).unwrap();
+
+        registry.register(Box::new(snaps.clone())).unwrap();
+
+        Metrics {
+            registry,
+            http_requests,
+            http_latency,
+            snaps: Some(snaps),
+            open_streams,
+            snap_creation_errors,
+            problems,
+            repository_latency,
+            webhook_attempts,
+        }
+    }
+
+    /// Metrics shared by every tenant of a server, without the snaps
+    /// gauge: tenants report their own count in their usage.
+    pub fn for_tenants() -> Metrics {
+        let mut metrics = Metrics::new();
+        if let Some(snaps) = metrics.snaps.take() {
+            metrics.registry.unregister(Box::new(snaps)).unwrap();
+        }
+        metrics
+    }
+
+    /// Record a failed snap creation. `error` names the variant.
+    pub fn snap_creation_failed(&self, error: &str) {
+        self.snap_creation_errors.with_label_values(&[error]).inc();
+    }
+
+    /// Record a webhook delivery attempt. `outcome` is `delivered`,
+    /// `retried` or `dead`.
+    pub fn webhook_attempted(&self, outcome: &str) {
+        self.webhook_attempts.with_label_values(&[outcome]).inc();
+    }
+
+    /// Run the repository `operation` in `f`, recording its latency
+    /// under the timeline strategy in use.
+    pub fn time_repository<T>(&self, operation: &str, fan_out: FanOut, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.repository_latency
+            .with_label_values(&[operation, fan_out.as_str()])
+            .observe(start.elapsed().as_secs_f64());
+        result
+    }
+
+    /// Count a streaming connection as open until the guard is dropped.
+    pub fn stream_opened(&self) -> StreamGuard {
+        self.open_streams.inc();
+        StreamGuard { gauge: self.open_streams.clone() }
+    }
+
+    /// Render every metric in the Prometheus text format,
+    /// with `snap_count` as the current value of the snaps gauge, if any.
+    pub fn render(&self, snap_count: usize) -> String {
+        if let Some(snaps) = &self.snaps {
+            snaps.set(snap_count as i64);
+        }
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics are always encodable");
+        String::from_utf8(buffer).expect("the text format is UTF-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps the open streams gauge up while alive.
+pub struct StreamGuard {
+    gauge: IntGauge,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.gauge.dec();
+    }
+}
+
+/// Middleware counting requests and their latency by matched route and status,
+/// and problem responses by kind.
+pub async fn track(metrics: Metrics, request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = method_label(request.method());
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| UNMATCHED_ROUTE.to_string());
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let labels = [method, route.as_str(), status.as_str()];
+    metrics.http_requests.with_label_values(&labels).inc();
+    metrics.http_latency.with_label_values(&labels).observe(start.elapsed().as_secs_f64());
+    if let Some(ProblemKind(kind)) = response.extensions().get::<ProblemKind>() {
+        metrics.problems.with_label_values(&[kind, status.as_str()]).inc();
+    }
+    response
+}
+
+/// Label of `method`. Clients can send any method, so only the standard
+/// ones get their own label, like routes.
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        _ => OTHER_METHOD,
+    }
+}
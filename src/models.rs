@@ -1,29 +1,104 @@
+use std::collections::BTreeSet;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
-#[derive(Debug, Clone)]
+pub use snap_api::ReactionKind;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct Snap {
     id: Uuid,
+    /// Snaps journaled before authorship existed get the nil UUID.
+    #[serde(default)]
+    author_id: Uuid,
     message: String,
     timestamp: chrono::DateTime<chrono::Utc>,
+    /// Time of the last edit, if the message was ever changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edited_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Snap this one replies to, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    in_reply_to: Option<Uuid>,
+    /// Time of deletion of a snap kept as a tombstone for its replies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Time a moderator hid the snap from everyone else.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hidden_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Media attached to the snap, in the order they were sent.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    attachments: Vec<Attachment>,
 }
 
 impl Snap {
-    /// Create new Snap with a message.
+    /// Create new Snap with a message written by the user `author_id`.
     /// Will set current time in utc as the timestamp
     /// of the snap.
-    pub fn new(message: String) -> Snap {
+    pub fn new(message: String, author_id: Uuid) -> Snap {
         Snap {
             id: Uuid::new_v4(),
+            author_id,
             message,
             timestamp: chrono::Utc::now(),
+            edited_at: None,
+            in_reply_to: None,
+            deleted_at: None,
+            hidden_at: None,
+            attachments: Vec::new(),
         }
     }
 
+    /// Same snap carrying `attachments`.
+    pub fn with_attachments(self, attachments: Vec<Attachment>) -> Snap {
+        Snap { attachments, ..self }
+    }
+
+    /// Same as [Snap::new] for a reply to the snap `in_reply_to`.
+    pub fn reply(message: String, author_id: Uuid, in_reply_to: Uuid) -> Snap {
+        Snap { in_reply_to: Some(in_reply_to), ..Snap::new(message, author_id) }
+    }
+
+    /// Turn the snap into a tombstone deleted at `at`, dropping its message
+    /// and attachments but keeping its place in the conversation.
+    pub fn tombstone(&mut self, at: chrono::DateTime<chrono::Utc>) {
+        self.message.clear();
+        self.attachments.clear();
+        self.deleted_at = Some(at);
+    }
+
+    /// Hide the snap at `at`, or show it again with `None`.
+    pub fn set_hidden(&mut self, at: Option<chrono::DateTime<chrono::Utc>>) {
+        self.hidden_at = at;
+    }
+
+    /// Replace the message, recording the time of the edit.
+    pub fn edit(&mut self, message: String) {
+        self.message = message;
+        self.edited_at = Some(chrono::Utc::now());
+    }
+
     /// Getter for the snap id.
     pub fn id(&self) -> String {
         self.id.to_string()
     }
 
+    /// Getter for the snap id as an [Uuid].
+    pub fn uuid(&self) -> Uuid {
+        self.id
+    }
+
+    /// Getter for the id of the user who wrote the snap.
+    pub fn author_id(&self) -> String {
+        self.author_id.to_string()
+    }
+
+    /// Getter for the id of the author as an [Uuid].
+    pub fn author_uuid(&self) -> Uuid {
+        self.author_id
+    }
+
     /// Getter for the snap message.
     pub fn message(&self) -> &str {
         &self.message
@@ -33,6 +108,827 @@ impl Snap {
     pub fn timestamp(&self) -> &chrono::DateTime<chrono::Utc> {
         &self.timestamp
     }
+
+    /// Getter for the time of the last edit.
+    pub fn edited_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.edited_at.as_ref()
+    }
+
+    /// Getter for the id of the snap this one replies to.
+    pub fn in_reply_to(&self) -> Option<String> {
+        self.in_reply_to.map(|id| id.to_string())
+    }
+
+    /// Getter for the time of deletion of a tombstone.
+    pub fn deleted_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.deleted_at.as_ref()
+    }
+
+    /// Whether the snap is a tombstone.
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    /// Getter for the time a moderator hid the snap.
+    pub fn hidden_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.hidden_at.as_ref()
+    }
+
+    /// Whether a moderator hid the snap.
+    pub fn is_hidden(&self) -> bool {
+        self.hidden_at.is_some()
+    }
+
+    /// Getter for the media attached to the snap.
+    pub fn attachments(&self) -> &[Attachment] {
+        &self.attachments
+    }
+}
+
+/// Media file attached to a snap, stored in a blob store under its hash.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Attachment {
+    /// Lowercase hex SHA-256 of the contents.
+    pub hash: String,
+    /// Media type found by sniffing the contents.
+    pub content_type: String,
+    /// Size in bytes.
+    pub size: u64,
+    /// Size in pixels, after turning the image upright.
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    /// Smaller copies of the image, from the smallest.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+/// Smaller copy of an attached image, stored in the same blob store.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Thumbnail {
+    /// Size of the box the thumbnail fits in, from `media.thumbnail_sizes`.
+    pub max_dimension: u32,
+    pub hash: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct User {
+    id: Uuid,
+    username: String,
+    /// Argon2id hash in PHC string format.
+    password_hash: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl User {
+    /// Create a new User, hashing its password with a random salt.
+    /// Hashing is slow on purpose, avoid calling it on an async executor thread.
+    pub fn new(username: String, password: &str) -> User {
+        let salt = SaltString::generate(&mut OsRng);
+        let password_hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .expect("argon2 accepts any password with a generated salt")
+            .to_string();
+        User {
+            id: Uuid::new_v4(),
+            username,
+            password_hash,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    /// Getter for the user id.
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// Getter for the user id as an [Uuid].
+    pub fn uuid(&self) -> Uuid {
+        self.id
+    }
+
+    /// Getter for the username.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Getter for the time the user registered.
+    pub fn created_at(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.created_at
+    }
+
+    /// Whether `password` matches the stored hash.
+    /// Slow on purpose like [User::new].
+    pub fn verify_password(&self, password: &str) -> bool {
+        match PasswordHash::new(&self.password_hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// What an [ApiKey] may do. Each scope includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Authenticated reads.
+    Read,
+    /// Posting, editing and deleting the owner's snaps.
+    Write,
+    /// Managing the owner's API keys.
+    Admin,
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Scope::Read => write!(f, "read"),
+            Scope::Write => write!(f, "write"),
+            Scope::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// What a [Notification] tells its recipient about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// The recipient was mentioned in a snap.
+    Mention,
+    /// A snap of the recipient got a reply.
+    Reply,
+    /// A snap of the recipient got a reaction.
+    Reaction,
+}
+
+/// Something another user did with the recipient or their snaps.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Notification {
+    id: Uuid,
+    /// Recipient of the notification.
+    user_id: Uuid,
+    kind: NotificationKind,
+    /// User who caused the notification.
+    actor_id: Uuid,
+    /// Snap mentioning the recipient, the reply, or the snap reacted to.
+    snap_id: Uuid,
+    /// Only set on reaction notifications.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reaction: Option<ReactionKind>,
+    created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    read_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Notification {
+    /// Create an unread notification for `user_id` about `snap_id`,
+    /// caused by `actor_id` right now.
+    pub fn new(kind: NotificationKind, user_id: Uuid, actor_id: Uuid, snap_id: Uuid) -> Notification {
+        Notification {
+            id: Uuid::new_v4(),
+            user_id,
+            kind,
+            actor_id,
+            snap_id,
+            reaction: None,
+            created_at: chrono::Utc::now(),
+            read_at: None,
+        }
+    }
+
+    /// Same as [Notification::new] for a reaction of `actor_id`.
+    pub fn reaction(user_id: Uuid, actor_id: Uuid, snap_id: Uuid, reaction: ReactionKind) -> Notification {
+        Notification {
+            reaction: Some(reaction),
+            ..Notification::new(NotificationKind::Reaction, user_id, actor_id, snap_id)
+        }
+    }
+
+    /// Mark the notification as read at `at`. Reading twice keeps the first time.
+    pub fn mark_read(&mut self, at: chrono::DateTime<chrono::Utc>) {
+        self.read_at.get_or_insert(at);
+    }
+
+    /// Getter for the notification id.
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// Getter for the id of the recipient.
+    pub fn user_id(&self) -> String {
+        self.user_id.to_string()
+    }
+
+    /// Getter for the notification kind.
+    pub fn kind(&self) -> NotificationKind {
+        self.kind
+    }
+
+    /// Getter for the id of the user who caused the notification.
+    pub fn actor_id(&self) -> String {
+        self.actor_id.to_string()
+    }
+
+    /// Getter for the id of the snap the notification is about.
+    pub fn snap_id(&self) -> String {
+        self.snap_id.to_string()
+    }
+
+    /// Getter for the reaction of a reaction notification.
+    pub fn reaction_kind(&self) -> Option<ReactionKind> {
+        self.reaction
+    }
+
+    /// Getter for the time the notification was created.
+    pub fn created_at(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.created_at
+    }
+
+    /// Getter for the time the recipient read the notification.
+    pub fn read_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.read_at.as_ref()
+    }
+
+    /// Whether the recipient read the notification.
+    pub fn is_read(&self) -> bool {
+        self.read_at.is_some()
+    }
+}
+
+/// Kinds of notifications a user wants to get. All are on by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct NotificationPreferences {
+    pub mentions: bool,
+    pub replies: bool,
+    pub reactions: bool,
+}
+
+impl NotificationPreferences {
+    /// Whether notifications of `kind` are on.
+    pub fn allows(&self, kind: NotificationKind) -> bool {
+        match kind {
+            NotificationKind::Mention => self.mentions,
+            NotificationKind::Reply => self.replies,
+            NotificationKind::Reaction => self.reactions,
+        }
+    }
+}
+
+impl Default for NotificationPreferences {
+    fn default() -> Self {
+        NotificationPreferences {
+            mentions: true,
+            replies: true,
+            reactions: true,
+        }
+    }
+}
+
+/// Long-lived credential of a user for non-interactive clients.
+/// Only a SHA-256 digest of the secret is kept.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApiKey {
+    id: Uuid,
+    owner_id: Uuid,
+    name: String,
+    scope: Scope,
+    /// Hex SHA-256 of the secret part of the key.
+    secret_hash: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Prefix of every API key, to make them easy to spot in logs and configs.
+const API_KEY_PREFIX: &str = "snap_";
+
+impl ApiKey {
+    /// Create a key for the user `owner_id`. Returns the key alongside
+    /// the full token for the client, which can't be recovered later.
+    pub fn new(owner_id: Uuid, name: String, scope: Scope) -> (ApiKey, String) {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        let secret = hex(&secret);
+        let key = ApiKey {
+            id: Uuid::new_v4(),
+            owner_id,
+            name,
+            scope,
+            secret_hash: hex(&Sha256::digest(secret.as_bytes())),
+            created_at: chrono::Utc::now(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+        let token = format!("{API_KEY_PREFIX}{}_{secret}", key.id.simple());
+        (key, token)
+    }
+
+    /// Split a token made by [ApiKey::new] into the key id and its secret.
+    pub fn parse_token(token: &str) -> Option<(String, &str)> {
+        let (id, secret) = token.strip_prefix(API_KEY_PREFIX)?.split_once('_')?;
+        let id = Uuid::try_parse(id).ok()?;
+        Some((id.to_string(), secret))
+    }
+
+    /// Whether `secret` is the secret of this key, in constant time.
+    pub fn verify_secret(&self, secret: &str) -> bool {
+        let digest = hex(&Sha256::digest(secret.as_bytes()));
+        digest.len() == self.secret_hash.len()
+            && digest.bytes()
+                .zip(self.secret_hash.bytes())
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// Mark the key as unusable from now on. Revoking twice keeps the first time.
+    pub fn revoke(&mut self) {
+        self.revoked_at.get_or_insert_with(chrono::Utc::now);
+    }
+
+    /// Record a use of the key at `time`.
+    pub fn touch(&mut self, time: chrono::DateTime<chrono::Utc>) {
+        self.last_used_at = Some(time);
+    }
+
+    /// Getter for the key id.
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// Getter for the id of the user owning the key.
+    pub fn owner_id(&self) -> String {
+        self.owner_id.to_string()
+    }
+
+    /// Getter for the name given by the owner.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Getter for the key scope.
+    pub fn scope(&self) -> Scope {
+        self.scope
+    }
+
+    /// Getter for the time the key was created.
+    pub fn created_at(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.created_at
+    }
+
+    /// Getter for the time the key was last used.
+    pub fn last_used_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.last_used_at.as_ref()
+    }
+
+    /// Getter for the time the key was revoked.
+    pub fn revoked_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.revoked_at.as_ref()
+    }
+}
+
+/// Change to a snap that webhooks can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub enum WebhookEvent {
+    #[serde(rename = "snap.created")]
+    SnapCreated,
+    #[serde(rename = "snap.edited")]
+    SnapEdited,
+    #[serde(rename = "snap.deleted")]
+    SnapDeleted,
+}
+
+impl WebhookEvent {
+    /// Every event, in lifecycle order.
+    pub const ALL: [WebhookEvent; 3] = [
+        WebhookEvent::SnapCreated,
+        WebhookEvent::SnapEdited,
+        WebhookEvent::SnapDeleted,
+    ];
+
+    /// Name used in JSON and in the `X-Snap-Event` header.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookEvent::SnapCreated => "snap.created",
+            WebhookEvent::SnapEdited => "snap.edited",
+            WebhookEvent::SnapDeleted => "snap.deleted",
+        }
+    }
+}
+
+impl std::fmt::Display for WebhookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for WebhookEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        WebhookEvent::ALL
+            .into_iter()
+            .find(|event| event.as_str() == s)
+            .ok_or_else(|| {
+                let names = WebhookEvent::ALL.map(|event| format!("\"{event}\""));
+                format!("expected one of {}", names.join(", "))
+            })
+    }
+}
+
+/// Subscription of a user to snap events, delivered by HTTP POST to `url`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Webhook {
+    id: Uuid,
+    owner_id: Uuid,
+    url: String,
+    events: BTreeSet<WebhookEvent>,
+    /// Key of the HMAC-SHA256 signatures, kept as is to sign deliveries.
+    secret: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Prefix of every webhook secret.
+const WEBHOOK_SECRET_PREFIX: &str = "whsec_";
+
+impl Webhook {
+    /// Create a webhook for the user `owner_id` with a random secret.
+    pub fn new(owner_id: Uuid, url: String, events: BTreeSet<WebhookEvent>) -> Webhook {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        Webhook {
+            id: Uuid::new_v4(),
+            owner_id,
+            url,
+            events,
+            secret: format!("{WEBHOOK_SECRET_PREFIX}{}", hex(&secret)),
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    /// Whether deliveries of `event` go to this webhook.
+    pub fn subscribes_to(&self, event: WebhookEvent) -> bool {
+        self.events.contains(&event)
+    }
+
+    /// Replace the URL deliveries are sent to.
+    pub fn set_url(&mut self, url: String) {
+        self.url = url;
+    }
+
+    /// Replace the events the webhook subscribes to.
+    pub fn set_events(&mut self, events: BTreeSet<WebhookEvent>) {
+        self.events = events;
+    }
+
+    /// Getter for the webhook id.
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// Getter for the webhook id as an [Uuid].
+    pub fn uuid(&self) -> Uuid {
+        self.id
+    }
+
+    /// Getter for the id of the user owning the webhook.
+    pub fn owner_id(&self) -> String {
+        self.owner_id.to_string()
+    }
+
+    /// Getter for the URL deliveries are sent to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Getter for the events the webhook subscribes to.
+    pub fn events(&self) -> &BTreeSet<WebhookEvent> {
+        &self.events
+    }
+
+    /// Getter for the signing secret.
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    /// Getter for the time the webhook was created.
+    pub fn created_at(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.created_at
+    }
+}
+
+/// Where a [Delivery] is in its lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryStatus {
+    /// Waiting for its first attempt or for a retry.
+    Pending,
+    /// The receiver answered with a 2xx status.
+    Delivered,
+    /// Every attempt failed, it won't be retried unless asked to.
+    Dead,
+}
+
+impl std::str::FromStr for DeliveryStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(DeliveryStatus::Pending),
+            "delivered" => Ok(DeliveryStatus::Delivered),
+            "dead" => Ok(DeliveryStatus::Dead),
+            _ => Err("expected one of \"pending\", \"delivered\", \"dead\"".to_string()),
+        }
+    }
+}
+
+/// Outcome of one attempt to send a [Delivery].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct DeliveryAttempt {
+    pub at: chrono::DateTime<chrono::Utc>,
+    /// HTTP status of the response, if one came back.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// Why the attempt failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// One event to send to one webhook, with every attempt made so far.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Delivery {
+    id: Uuid,
+    webhook_id: Uuid,
+    event: WebhookEvent,
+    /// JSON body, kept as sent so retries carry the same signed bytes.
+    payload: String,
+    status: DeliveryStatus,
+    attempts: Vec<DeliveryAttempt>,
+    /// Attempts made before the last requeue, not counted against the maximum.
+    #[serde(default)]
+    requeued_after: usize,
+    created_at: chrono::DateTime<chrono::Utc>,
+    /// Only set while pending.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    next_attempt_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Delivery {
+    /// Create a pending delivery of `payload`, due right away.
+    pub fn new(webhook_id: Uuid, event: WebhookEvent, payload: String) -> Delivery {
+        let now = chrono::Utc::now();
+        Delivery {
+            id: Uuid::new_v4(),
+            webhook_id,
+            event,
+            payload,
+            status: DeliveryStatus::Pending,
+            attempts: Vec::new(),
+            requeued_after: 0,
+            created_at: now,
+            next_attempt_at: Some(now),
+        }
+    }
+
+    /// Record a successful attempt.
+    pub fn succeed(&mut self, attempt: DeliveryAttempt) {
+        self.attempts.push(attempt);
+        self.status = DeliveryStatus::Delivered;
+        self.next_attempt_at = None;
+    }
+
+    /// Record a failed attempt, to retry at `retry_at` or never with `None`.
+    pub fn fail(&mut self, attempt: DeliveryAttempt, retry_at: Option<chrono::DateTime<chrono::Utc>>) {
+        self.attempts.push(attempt);
+        self.status = match retry_at {
+            Some(_) => DeliveryStatus::Pending,
+            None => DeliveryStatus::Dead,
+        };
+        self.next_attempt_at = retry_at;
+    }
+
+    /// Give a dead delivery another round of attempts, starting at `at`.
+    pub fn requeue(&mut self, at: chrono::DateTime<chrono::Utc>) {
+        self.status = DeliveryStatus::Pending;
+        self.requeued_after = self.attempts.len();
+        self.next_attempt_at = Some(at);
+    }
+
+    /// Attempts made since the delivery was queued or last requeued.
+    pub fn round_attempts(&self) -> usize {
+        self.attempts.len() - self.requeued_after
+    }
+
+    /// Getter for the delivery id.
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// Getter for the id of the webhook the delivery goes to.
+    pub fn webhook_id(&self) -> String {
+        self.webhook_id.to_string()
+    }
+
+    /// Getter for the event delivered.
+    pub fn event(&self) -> WebhookEvent {
+        self.event
+    }
+
+    /// Getter for the JSON body.
+    pub fn payload(&self) -> &str {
+        &self.payload
+    }
+
+    /// Getter for the delivery status.
+    pub fn status(&self) -> DeliveryStatus {
+        self.status
+    }
+
+    /// Getter for the attempts made so far, oldest first.
+    pub fn attempts(&self) -> &[DeliveryAttempt] {
+        &self.attempts
+    }
+
+    /// Getter for the time the delivery was queued.
+    pub fn created_at(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.created_at
+    }
+
+    /// Getter for the time of the next attempt of a pending delivery.
+    pub fn next_attempt_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.next_attempt_at.as_ref()
+    }
+}
+
+/// Complaint of a user about a snap, open until a moderator acts on it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Report {
+    id: Uuid,
+    snap_id: Uuid,
+    reporter_id: Uuid,
+    reason: String,
+    created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    resolved_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Report {
+    /// Create an open report of `reporter_id` about `snap_id`, made right now.
+    pub fn new(snap_id: Uuid, reporter_id: Uuid, reason: String) -> Report {
+        Report {
+            id: Uuid::new_v4(),
+            snap_id,
+            reporter_id,
+            reason,
+            created_at: chrono::Utc::now(),
+            resolved_at: None,
+        }
+    }
+
+    /// Close the report at `at`. Resolving twice keeps the first time.
+    pub fn resolve(&mut self, at: chrono::DateTime<chrono::Utc>) {
+        self.resolved_at.get_or_insert(at);
+    }
+
+    /// Getter for the report id.
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// Getter for the id of the snap reported.
+    pub fn snap_id(&self) -> String {
+        self.snap_id.to_string()
+    }
+
+    /// Getter for the id of the user who made the report.
+    pub fn reporter_id(&self) -> String {
+        self.reporter_id.to_string()
+    }
+
+    /// Getter for the reason given by the reporter.
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// Getter for the time the report was made.
+    pub fn created_at(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.created_at
+    }
+
+    /// Getter for the time a moderator acted on the report.
+    pub fn resolved_at(&self) -> Option<&chrono::DateTime<chrono::Utc>> {
+        self.resolved_at.as_ref()
+    }
+
+    /// Whether the report still waits for a moderator.
+    pub fn is_open(&self) -> bool {
+        self.resolved_at.is_none()
+    }
+}
+
+/// What a moderator can do with a snap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Hide the snap from everyone but the moderators.
+    Hide,
+    /// Show a hidden snap again, or keep a reported one as it is.
+    Restore,
+    /// Delete the snap as its author would.
+    Delete,
+}
+
+impl std::str::FromStr for ModerationAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hide" => Ok(ModerationAction::Hide),
+            "restore" => Ok(ModerationAction::Restore),
+            "delete" => Ok(ModerationAction::Delete),
+            _ => Err("expected one of \"hide\", \"restore\", \"delete\"".to_string()),
+        }
+    }
+}
+
+/// Record of a moderation action, kept in the audit log.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditEntry {
+    id: Uuid,
+    moderator_id: Uuid,
+    snap_id: Uuid,
+    action: ModerationAction,
+    justification: String,
+    at: chrono::DateTime<chrono::Utc>,
+    /// Open reports closed by the action.
+    #[serde(default)]
+    reports: usize,
+}
+
+impl AuditEntry {
+    /// Create the record of `moderator_id` taking `action` on `snap_id` right now.
+    pub fn new(moderator_id: Uuid, snap_id: Uuid, action: ModerationAction, justification: String) -> AuditEntry {
+        AuditEntry {
+            id: Uuid::new_v4(),
+            moderator_id,
+            snap_id,
+            action,
+            justification,
+            at: chrono::Utc::now(),
+            reports: 0,
+        }
+    }
+
+    /// Same entry closing `reports` open reports.
+    pub fn with_reports(self, reports: usize) -> AuditEntry {
+        AuditEntry { reports, ..self }
+    }
+
+    /// Getter for the entry id.
+    pub fn id(&self) -> String {
+        self.id.to_string()
+    }
+
+    /// Getter for the id of the moderator who acted.
+    pub fn moderator_id(&self) -> String {
+        self.moderator_id.to_string()
+    }
+
+    /// Getter for the id of the snap moderated.
+    pub fn snap_id(&self) -> String {
+        self.snap_id.to_string()
+    }
+
+    /// Getter for the action taken.
+    pub fn action(&self) -> ModerationAction {
+        self.action
+    }
+
+    /// Getter for the justification given by the moderator.
+    pub fn justification(&self) -> &str {
+        &self.justification
+    }
+
+    /// Getter for the time of the action.
+    pub fn at(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.at
+    }
+
+    /// Getter for the number of reports closed by the action.
+    pub fn reports(&self) -> usize {
+        self.reports
+    }
+}
+
+/// Lowercase hex encoding of `bytes`.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
 
 #[cfg(test)]
@@ -42,10 +938,88 @@ mod models_test {
     #[test]
     fn create_snap() {
         let message = "Test Snap";
-        let snap = Snap::new(message.to_string());
+        let author = Uuid::new_v4();
+        let snap = Snap::new(message.to_string(), author);
         let id = snap.id();
 
         assert!(Uuid::parse_str(&id).is_ok());
         assert_eq!(snap.message(), message);
+        assert_eq!(snap.author_id(), author.to_string());
+        assert!(snap.edited_at().is_none());
+    }
+
+    #[test]
+    fn edit_snap() {
+        let mut snap = Snap::new("Before".to_string(), Uuid::new_v4());
+        let id = snap.id();
+        snap.edit("After".to_string());
+
+        assert_eq!(snap.id(), id);
+        assert_eq!(snap.message(), "After");
+        assert!(snap.edited_at().unwrap() >= snap.timestamp());
+    }
+
+    #[test]
+    fn reply_and_tombstone() {
+        let parent = Snap::new("Parent".to_string(), Uuid::new_v4());
+        let mut reply = Snap::reply("Reply".to_string(), Uuid::new_v4(), parent.id);
+        assert_eq!(reply.in_reply_to(), Some(parent.id()));
+        assert!(!reply.is_deleted());
+
+        reply.tombstone(chrono::Utc::now());
+        assert!(reply.is_deleted());
+        assert_eq!(reply.message(), "");
+        assert_eq!(reply.in_reply_to(), Some(parent.id()));
+    }
+
+    #[test]
+    fn resolve_report_once() {
+        let mut report = Report::new(Uuid::new_v4(), Uuid::new_v4(), "spam".to_string());
+        assert!(report.is_open());
+
+        let at = chrono::Utc::now();
+        report.resolve(at);
+        report.resolve(at + chrono::Duration::seconds(1));
+        assert!(!report.is_open());
+        assert_eq!(report.resolved_at(), Some(&at));
+        assert_eq!("hide".parse::<ModerationAction>(), Ok(ModerationAction::Hide));
+        assert!("ban".parse::<ModerationAction>().is_err());
+    }
+
+    #[test]
+    fn create_user_hashes_password() {
+        let user = User::new("alice".to_string(), "correct horse");
+
+        assert!(Uuid::parse_str(&user.id()).is_ok());
+        assert_eq!(user.username(), "alice");
+        assert!(!user.password_hash.contains("correct horse"));
+        assert!(user.verify_password("correct horse"));
+        assert!(!user.verify_password("battery staple"));
+    }
+
+    #[test]
+    fn api_key_tokens() {
+        let owner = Uuid::new_v4();
+        let (mut key, token) = ApiKey::new(owner, "bot".to_string(), Scope::Write);
+
+        assert!(token.starts_with("snap_"));
+        assert!(!key.secret_hash.contains(&token[38..]));
+        let (id, secret) = ApiKey::parse_token(&token).unwrap();
+        assert_eq!(id, key.id());
+        assert!(key.verify_secret(secret));
+        assert!(!key.verify_secret("guess"));
+        assert!(ApiKey::parse_token("snap_nope").is_none());
+        assert!(ApiKey::parse_token("other_prefix").is_none());
+
+        key.revoke();
+        let revoked_at = *key.revoked_at().unwrap();
+        key.revoke();
+        assert_eq!(key.revoked_at(), Some(&revoked_at));
+    }
+
+    #[test]
+    fn scopes_include_lower_ones() {
+        assert!(Scope::Admin > Scope::Write);
+        assert!(Scope::Write > Scope::Read);
     }
 }
@@ -0,0 +1,166 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use tokio::sync::{broadcast, watch};
+use crate::config::NotificationsConfig;
+use crate::entities::{self, EntityKind};
+use crate::models::{Notification, NotificationKind, ReactionKind, Snap};
+use crate::state::Repository;
+
+/// Publishes stored notifications to the open streams of their recipients,
+/// and new snaps to the live feed.
+#[derive(Clone)]
+pub struct Notifier {
+    sender: broadcast::Sender<Notification>,
+    snaps: broadcast::Sender<Snap>,
+    closed: Arc<watch::Sender<bool>>,
+}
+
+impl Notifier {
+    /// Notifier keeping up to `buffer` notifications, and as many snaps,
+    /// for slow streams.
+    pub fn new(buffer: usize) -> Notifier {
+        let (sender, _) = broadcast::channel(buffer);
+        let (snaps, _) = broadcast::channel(buffer);
+        let (closed, _) = watch::channel(false);
+        Notifier { sender, snaps, closed: Arc::new(closed) }
+    }
+
+    /// Send `notification` to the streams of its recipient, if any.
+    pub fn publish(&self, notification: Notification) {
+        // Nobody listening is fine, the notification is already stored.
+        let _ = self.sender.send(notification);
+    }
+
+    /// Start listening to the notifications of `user_id`.
+    pub fn subscribe(&self, user_id: &str) -> Subscription {
+        Subscription {
+            user_id: user_id.to_string(),
+            notifications: self.sender.subscribe(),
+            closed: self.closed.subscribe(),
+        }
+    }
+
+    /// Send the new `snap` to the live feed.
+    pub fn publish_snap(&self, snap: Snap) {
+        let _ = self.snaps.send(snap);
+    }
+
+    /// Start following the live feed of new snaps.
+    pub fn feed(&self) -> Feed {
+        Feed {
+            snaps: self.snaps.subscribe(),
+            closed: self.closed.subscribe(),
+        }
+    }
+
+    /// End every subscription, so streams don't hold the server on shutdown.
+    pub fn close(&self) {
+        self.closed.send_replace(true);
+    }
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Notifier::new(NotificationsConfig::default().stream_buffer)
+    }
+}
+
+/// Notifications of one user, as they are published.
+pub struct Subscription {
+    user_id: String,
+    notifications: broadcast::Receiver<Notification>,
+    closed: watch::Receiver<bool>,
+}
+
+impl Subscription {
+    /// Wait for the next notification of the user. Gives `Err` with an upper
+    /// bound of the notifications missed when the subscription fell behind,
+    /// and `None` once the [Notifier] is closed.
+    pub async fn recv(&mut self) -> Option<Result<Notification, u64>> {
+        loop {
+            tokio::select! {
+                _ = self.closed.wait_for(|closed| *closed) => return None,
+                received = self.notifications.recv() => match received {
+                    Ok(notification) if notification.user_id() == self.user_id => {
+                        return Some(Ok(notification))
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) => return Some(Err(missed)),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+            }
+        }
+    }
+}
+
+/// Snaps of every user, as they are posted.
+pub struct Feed {
+    snaps: broadcast::Receiver<Snap>,
+    closed: watch::Receiver<bool>,
+}
+
+impl Feed {
+    /// Wait for the next snap, with the same results as [Subscription::recv].
+    pub async fn recv(&mut self) -> Option<Result<Snap, u64>> {
+        tokio::select! {
+            _ = self.closed.wait_for(|closed| *closed) => None,
+            received = self.snaps.recv() => match received {
+                Ok(snap) => Some(Ok(snap)),
+                Err(broadcast::error::RecvError::Lagged(missed)) => Some(Err(missed)),
+                Err(broadcast::error::RecvError::Closed) => None,
+            },
+        }
+    }
+}
+
+/// Store and publish the notifications caused by the new `snap`: one for
+/// the author of the snap it replies to, and one for each other user it
+/// mentions. Nobody is notified of their own snaps.
+pub fn snap_posted<S: Repository>(repo: &mut S, notifier: &Notifier, snap: &Snap) {
+    let author = snap.author_uuid();
+    let mut notified = BTreeSet::from([author]);
+    if let Some(parent) = snap.in_reply_to().and_then(|id| repo.get_snap(&id)) {
+        if notified.insert(parent.author_uuid()) {
+            let notification = Notification::new(
+                NotificationKind::Reply,
+                parent.author_uuid(),
+                author,
+                snap.uuid(),
+            );
+            deliver(repo, notifier, notification);
+        }
+    }
+    let mentioned = entities::extract(snap.message())
+        .into_iter()
+        .filter(|entity| entity.kind == EntityKind::Mention)
+        .filter_map(|entity| repo.get_user_by_username(&entity.text))
+        .collect::<Vec<_>>();
+    for user in mentioned {
+        if notified.insert(user.uuid()) {
+            let notification = Notification::new(NotificationKind::Mention, user.uuid(), author, snap.uuid());
+            deliver(repo, notifier, notification);
+        }
+    }
+}
+
+/// Store and publish the notification of `user_id` reacting with `kind`
+/// to `snap`, unless it's their own snap.
+pub fn reacted<S: Repository>(repo: &mut S, notifier: &Notifier, snap: &Snap, user_id: &str, kind: ReactionKind) {
+    let Some(user) = repo.get_user(user_id) else {
+        return
+    };
+    if user.uuid() != snap.author_uuid() {
+        let notification = Notification::reaction(snap.author_uuid(), user.uuid(), snap.uuid(), kind);
+        deliver(repo, notifier, notification);
+    }
+}
+
+/// Store `notification` and publish it if its recipient wants it.
+/// Failures are only logged, the action notified about already happened.
+fn deliver<S: Repository>(repo: &mut S, notifier: &Notifier, notification: Notification) {
+    match repo.notify(notification.clone()) {
+        Ok(true) => notifier.publish(notification),
+        Ok(false) => {}
+        Err(e) => tracing::warn!("can't store notification {}: {e:?}", notification.id()),
+    }
+}
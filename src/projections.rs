@@ -0,0 +1,496 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use crate::state::{LoggedEvent, SnapAppState, SnapEvent, TimelineCursor, Visibility};
+
+/// Events read from the log at a time while catching up.
+const BATCH: usize = 1000;
+
+/// Read model built by applying the snap log in order.
+pub trait Projection {
+    fn apply(&mut self, event: &SnapEvent);
+}
+
+/// Live snaps from the most recent to the oldest, overall and by author.
+/// Hidden snaps only show to moderators.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    all: BTreeSet<TimelineCursor>,
+    by_author: HashMap<String, BTreeSet<TimelineCursor>>,
+    /// Author and cursor of each live snap, to find it when it goes away.
+    live: HashMap<String, (String, TimelineCursor)>,
+    hidden: HashSet<String>,
+}
+
+impl Timeline {
+    /// Ids of up to `limit` snaps older than `before` shown to `visibility`,
+    /// the most recent first.
+    pub fn page(&self, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<String> {
+        older(&self.all, before, limit, |id| shown(&self.hidden, id, visibility))
+    }
+
+    /// Same as [Timeline::page] for the snaps of `author_id`.
+    pub fn by_author(
+        &self,
+        author_id: &str,
+        before: Option<&TimelineCursor>,
+        limit: usize,
+        visibility: Visibility,
+    ) -> Vec<String> {
+        match self.by_author.get(author_id) {
+            Some(cursors) => older(cursors, before, limit, |id| shown(&self.hidden, id, visibility)),
+            None => Vec::new(),
+        }
+    }
+
+    fn remove(&mut self, id: &str) {
+        self.hidden.remove(id);
+        if let Some((author, cursor)) = self.live.remove(id) {
+            self.all.remove(&cursor);
+            if let Some(cursors) = self.by_author.get_mut(&author) {
+                cursors.remove(&cursor);
+                if cursors.is_empty() {
+                    self.by_author.remove(&author);
+                }
+            }
+        }
+    }
+}
+
+impl Projection for Timeline {
+    fn apply(&mut self, event: &SnapEvent) {
+        match event {
+            SnapEvent::SnapCreated { snap }
+            | SnapEvent::SnapRestored { snap }
+            | SnapEvent::SnapUnhidden { snap } => {
+                self.remove(&snap.id());
+                if !snap.is_deleted() {
+                    let cursor = TimelineCursor::from(snap);
+                    self.all.insert(cursor.clone());
+                    self.by_author.entry(snap.author_id()).or_default().insert(cursor.clone());
+                    self.live.insert(snap.id(), (snap.author_id(), cursor));
+                    if snap.is_hidden() {
+                        self.hidden.insert(snap.id());
+                    }
+                }
+            }
+            // Edits keep the place of the snap.
+            SnapEvent::SnapEdited { .. } => {}
+            SnapEvent::SnapHidden { id, .. } => {
+                if self.live.contains_key(id) {
+                    self.hidden.insert(id.clone());
+                }
+            }
+            SnapEvent::SnapDeleted { id, .. } => self.remove(id),
+        }
+    }
+}
+
+/// Number of live snaps, overall, by author and replying to each snap.
+/// Hidden snaps still count, as they do in [SnapAppState::snap_count].
+#[derive(Debug, Default)]
+pub struct Counts {
+    by_author: HashMap<String, usize>,
+    replies: HashMap<String, usize>,
+    /// Author and parent of each live snap.
+    live: HashMap<String, (String, Option<String>)>,
+}
+
+impl Counts {
+    pub fn snaps(&self) -> usize {
+        self.live.len()
+    }
+
+    pub fn authors(&self) -> usize {
+        self.by_author.len()
+    }
+
+    pub fn by_author(&self, author_id: &str) -> usize {
+        self.by_author.get(author_id).copied().unwrap_or(0)
+    }
+
+    /// Live replies to the snap `id`.
+    pub fn replies(&self, id: &str) -> usize {
+        self.replies.get(id).copied().unwrap_or(0)
+    }
+
+    fn remove(&mut self, id: &str) {
+        let Some((author, parent)) = self.live.remove(id) else { return };
+        decrement(&mut self.by_author, &author);
+        if let Some(parent) = parent {
+            decrement(&mut self.replies, &parent);
+        }
+    }
+}
+
+impl Projection for Counts {
+    fn apply(&mut self, event: &SnapEvent) {
+        match event {
+            SnapEvent::SnapCreated { snap } | SnapEvent::SnapRestored { snap } => {
+                self.remove(&snap.id());
+                if !snap.is_deleted() {
+                    *self.by_author.entry(snap.author_id()).or_default() += 1;
+                    if let Some(parent) = snap.in_reply_to() {
+                        *self.replies.entry(parent.clone()).or_default() += 1;
+                    }
+                    self.live.insert(snap.id(), (snap.author_id(), snap.in_reply_to()));
+                }
+            }
+            SnapEvent::SnapEdited { .. } | SnapEvent::SnapHidden { .. } | SnapEvent::SnapUnhidden { .. } => {}
+            SnapEvent::SnapDeleted { id, .. } => self.remove(id),
+        }
+    }
+}
+
+/// Live snaps by the words of their message, for full text search.
+/// Hidden snaps only show to moderators.
+#[derive(Debug, Default)]
+pub struct SearchIndex {
+    postings: HashMap<String, BTreeSet<TimelineCursor>>,
+    /// Cursor and words of each live snap, to unindex it.
+    documents: HashMap<String, (TimelineCursor, BTreeSet<String>)>,
+    hidden: HashSet<String>,
+}
+
+impl SearchIndex {
+    /// Ids of up to `limit` snaps older than `before` with every word of
+    /// `query` shown to `visibility`, the most recent first. A query
+    /// without words matches nothing.
+    pub fn search(&self, query: &str, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<String> {
+        let words = terms(query);
+        let mut postings = Vec::with_capacity(words.len());
+        for word in &words {
+            match self.postings.get(word) {
+                Some(cursors) => postings.push(cursors),
+                None => return Vec::new(),
+            }
+        }
+        // Walk the rarest word, checking the others.
+        postings.sort_by_key(|cursors| cursors.len());
+        let Some((rarest, others)) = postings.split_first() else {
+            return Vec::new()
+        };
+        let candidates = match before {
+            Some(before) => rarest.range(..before),
+            None => rarest.range(..),
+        };
+        candidates.rev()
+            .filter(|cursor| others.iter().all(|cursors| cursors.contains(cursor)))
+            .filter(|cursor| shown(&self.hidden, cursor.id(), visibility))
+            .take(limit)
+            .map(|cursor| cursor.id().to_string())
+            .collect()
+    }
+
+    /// Number of different words indexed.
+    pub fn terms(&self) -> usize {
+        self.postings.len()
+    }
+
+    fn index(&mut self, id: String, cursor: TimelineCursor, message: &str) {
+        let words = terms(message);
+        for word in &words {
+            self.postings.entry(word.clone()).or_default().insert(cursor.clone());
+        }
+        self.documents.insert(id, (cursor, words));
+    }
+
+    fn remove(&mut self, id: &str) -> Option<TimelineCursor> {
+        self.hidden.remove(id);
+        let (cursor, words) = self.documents.remove(id)?;
+        for word in words {
+            if let Some(cursors) = self.postings.get_mut(&word) {
+                cursors.remove(&cursor);
+                if cursors.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+        Some(cursor)
+    }
+}
+
+impl Projection for SearchIndex {
+    fn apply(&mut self, event: &SnapEvent) {
+        match event {
+            SnapEvent::SnapCreated { snap }
+            | SnapEvent::SnapRestored { snap }
+            | SnapEvent::SnapUnhidden { snap } => {
+                self.remove(&snap.id());
+                if !snap.is_deleted() {
+                    self.index(snap.id(), TimelineCursor::from(snap), snap.message());
+                    if snap.is_hidden() {
+                        self.hidden.insert(snap.id());
+                    }
+                }
+            }
+            SnapEvent::SnapEdited { id, message, .. } => {
+                let hidden = self.hidden.contains(id);
+                if let Some(cursor) = self.remove(id) {
+                    self.index(id.clone(), cursor, message);
+                    if hidden {
+                        self.hidden.insert(id.clone());
+                    }
+                }
+            }
+            SnapEvent::SnapHidden { id, .. } => {
+                if self.documents.contains_key(id) {
+                    self.hidden.insert(id.clone());
+                }
+            }
+            SnapEvent::SnapDeleted { id, .. } => {
+                self.remove(id);
+            }
+        }
+    }
+}
+
+/// Lowercase words of `text`: runs of letters, digits and underscores,
+/// so `#Rust` and `rust,` are both `rust`.
+pub fn terms(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Every projection, alongside the offset of the next event to apply.
+#[derive(Debug, Default)]
+pub struct Projections {
+    pub timeline: Timeline,
+    pub counts: Counts,
+    pub search: SearchIndex,
+    offset: u64,
+}
+
+impl Projections {
+    /// Offset of the next event to apply, i.e. how many were applied.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Apply `logged` unless it was already applied.
+    pub fn apply(&mut self, logged: &LoggedEvent) {
+        if logged.offset < self.offset {
+            return
+        }
+        self.timeline.apply(&logged.event);
+        self.counts.apply(&logged.event);
+        self.search.apply(&logged.event);
+        self.offset = logged.offset + 1;
+    }
+
+    /// Apply every event of `repo` past the offset and return how many.
+    pub fn catch_up<S: SnapAppState + ?Sized>(&mut self, repo: &S) -> u64 {
+        let start = self.offset;
+        loop {
+            let events = repo.events(self.offset, BATCH);
+            for logged in &events {
+                self.apply(logged);
+            }
+            if events.len() < BATCH {
+                return self.offset - start
+            }
+        }
+    }
+
+    pub fn status(&self) -> ProjectionStatus {
+        ProjectionStatus {
+            offset: self.offset,
+            snaps: self.counts.snaps(),
+            authors: self.counts.authors(),
+            terms: self.search.terms(),
+        }
+    }
+}
+
+/// Where the projections are in the snap log and what they hold.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct ProjectionStatus {
+    /// Events applied.
+    pub offset: u64,
+    /// Live snaps.
+    pub snaps: usize,
+    /// Users with live snaps.
+    pub authors: usize,
+    /// Different words in the search index.
+    pub terms: usize,
+}
+
+/// Shared, cheaply clonable handle on the [Projections] of the server.
+#[derive(Debug, Clone, Default)]
+pub struct Projector {
+    projections: Arc<RwLock<Projections>>,
+}
+
+impl Projector {
+    /// Bring the projections up to date with `repo`, then read them with `f`.
+    pub fn read<S, T, F>(&self, repo: &S, f: F) -> T
+    where
+        S: SnapAppState + ?Sized,
+        F: FnOnce(&Projections) -> T,
+    {
+        let behind = {
+            let projections = self.projections.read().unwrap();
+            projections.offset() < repo.event_count()
+        };
+        if behind {
+            self.projections.write().unwrap().catch_up(repo);
+        }
+        f(&self.projections.read().unwrap())
+    }
+
+    /// Build new projections from offset zero and swap them in. Reads keep
+    /// using the old ones until the new ones are caught up.
+    pub fn replay<S: SnapAppState + ?Sized>(&self, repo: &S) -> ProjectionStatus {
+        let mut fresh = Projections::default();
+        fresh.catch_up(repo);
+        let mut projections = self.projections.write().unwrap();
+        // Events logged while replaying.
+        fresh.catch_up(repo);
+        *projections = fresh;
+        projections.status()
+    }
+}
+
+/// Ids of up to `limit` of `cursors` before `before` passing `keep`,
+/// the most recent first.
+fn older<F>(cursors: &BTreeSet<TimelineCursor>, before: Option<&TimelineCursor>, limit: usize, keep: F) -> Vec<String>
+where
+    F: Fn(&str) -> bool,
+{
+    let newer = match before {
+        Some(before) => cursors.range(..before),
+        None => cursors.range(..),
+    };
+    newer.rev()
+        .filter(|cursor| keep(cursor.id()))
+        .take(limit)
+        .map(|cursor| cursor.id().to_string())
+        .collect()
+}
+
+/// Whether the snap `id` shows to `visibility`, given the `hidden` ones.
+fn shown(hidden: &HashSet<String>, id: &str, visibility: Visibility) -> bool {
+    visibility == Visibility::Moderator || !hidden.contains(id)
+}
+
+fn decrement(counts: &mut HashMap<String, usize>, key: &str) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod projections_test {
+    use super::*;
+    use uuid::Uuid;
+    use crate::models::{AuditEntry, ModerationAction, User};
+    use crate::state::{MockSnapRepository, ModerationRepository, UserRepository};
+
+    fn repo_with_author() -> (MockSnapRepository, String) {
+        let mut repo = MockSnapRepository::new();
+        let user = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        (repo, user.id())
+    }
+
+    #[test]
+    fn every_change_is_logged_in_order() {
+        let (mut repo, author) = repo_with_author();
+        let first = repo.post(&author, "First").unwrap();
+        repo.edit(&first.id(), "First, edited").unwrap();
+        repo.delete(&first.id()).unwrap();
+        assert_eq!(repo.event_count(), 3);
+
+        let events = repo.events(0, 10);
+        assert_eq!(events.iter().map(|logged| logged.offset).collect::<Vec<u64>>(), vec![0, 1, 2]);
+        assert!(matches!(&events[0].event, SnapEvent::SnapCreated { snap } if snap.message() == "First"));
+        assert!(matches!(&events[1].event, SnapEvent::SnapEdited { message, .. } if message == "First, edited"));
+        assert!(matches!(&events[2].event, SnapEvent::SnapDeleted { id, .. } if *id == first.id()));
+        assert_eq!(repo.events(1, 1).len(), 1);
+        assert!(repo.events(7, 10).is_empty());
+    }
+
+    #[test]
+    fn projections_follow_the_log() {
+        let (mut repo, author) = repo_with_author();
+        let first = repo.post(&author, "Hello #Rust world").unwrap();
+        let reply = repo.reply(&author, &first.id(), "Hello again").unwrap();
+        let last = repo.post(&author, "Goodbye").unwrap();
+
+        let mut projections = Projections::default();
+        assert_eq!(projections.catch_up(&repo), 3);
+        assert_eq!(projections.timeline.page(None, 10, Visibility::Public), vec![last.id(), reply.id(), first.id()]);
+        assert_eq!(projections.timeline.page(Some(&TimelineCursor::from(&reply)), 10, Visibility::Public), vec![first.id()]);
+        assert_eq!(projections.counts.replies(&first.id()), 1);
+        assert_eq!(projections.counts.by_author(&author), 3);
+        assert_eq!(projections.search.search("hello", None, 10, Visibility::Public), vec![reply.id(), first.id()]);
+        assert_eq!(projections.search.search("rust HELLO", None, 10, Visibility::Public), vec![first.id()]);
+
+        repo.edit(&first.id(), "Bye #rust").unwrap();
+        repo.delete(&reply.id()).unwrap();
+        assert_eq!(projections.catch_up(&repo), 2);
+        assert_eq!(projections.catch_up(&repo), 0);
+        assert!(projections.search.search("hello", None, 10, Visibility::Public).is_empty());
+        assert_eq!(projections.search.search("bye", None, 10, Visibility::Public), vec![first.id()]);
+        assert_eq!(projections.counts.replies(&first.id()), 0);
+        assert_eq!(projections.timeline.by_author(&author, None, 10, Visibility::Public), vec![last.id(), first.id()]);
+        assert_eq!(projections.status(), ProjectionStatus { offset: 5, snaps: 2, authors: 1, terms: 3 });
+    }
+
+    #[test]
+    fn hidden_snaps_only_show_to_moderators() {
+        let (mut repo, author) = repo_with_author();
+        let kept = repo.post(&author, "Hello #rust").unwrap();
+        let hidden = repo.post(&author, "Hello there").unwrap();
+        let mut projections = Projections::default();
+        projections.catch_up(&repo);
+
+        let moderator = Uuid::new_v4();
+        repo.moderate(AuditEntry::new(moderator, hidden.uuid(), ModerationAction::Hide, "rude".to_string())).unwrap();
+        projections.catch_up(&repo);
+        assert_eq!(projections.timeline.page(None, 10, Visibility::Public), vec![kept.id()]);
+        assert_eq!(projections.search.search("hello", None, 10, Visibility::Public), vec![kept.id()]);
+        assert_eq!(projections.counts.by_author(&author), 2);
+        assert_eq!(projections.timeline.page(None, 10, Visibility::Moderator), vec![hidden.id(), kept.id()]);
+        assert_eq!(projections.timeline.by_author(&author, None, 10, Visibility::Public), vec![kept.id()]);
+        assert_eq!(projections.search.search("hello", None, 10, Visibility::Moderator), vec![hidden.id(), kept.id()]);
+        assert!(repo.edit(&hidden.id(), "Hello again").is_err());
+
+        repo.moderate(AuditEntry::new(moderator, hidden.uuid(), ModerationAction::Restore, "fine".to_string())).unwrap();
+        projections.catch_up(&repo);
+        assert_eq!(projections.timeline.page(None, 10, Visibility::Public), vec![hidden.id(), kept.id()]);
+        assert_eq!(projections.search.search("hello", None, 10, Visibility::Public), vec![hidden.id(), kept.id()]);
+    }
+
+    #[test]
+    fn replaying_rebuilds_the_same_projections() {
+        let (mut repo, author) = repo_with_author();
+        let kept = repo.post(&author, "Kept").unwrap();
+        let gone = repo.post(&author, "Gone").unwrap();
+        repo.delete(&gone.id()).unwrap();
+
+        let projector = Projector::default();
+        let before = projector.read(&repo, Projections::status);
+        assert_eq!(projector.replay(&repo), before);
+        assert_eq!(projector.read(&repo, |projections| projections.timeline.page(None, 10, Visibility::Public)), vec![kept.id()]);
+    }
+
+    #[test]
+    fn journals_replay_their_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+        let mut repo = crate::state::JournalSnapRepository::open(&path).unwrap();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap().id();
+        let snap = repo.post(&author, "Persisted").unwrap();
+        repo.edit(&snap.id(), "Persisted, edited").unwrap();
+        repo.flush().unwrap();
+        let logged = repo.events(0, 10);
+
+        let reopened = crate::state::JournalSnapRepository::open(&path).unwrap();
+        let replayed = reopened.events(0, 10);
+        assert_eq!(serde_json::to_value(&replayed).unwrap(), serde_json::to_value(&logged).unwrap());
+    }
+}
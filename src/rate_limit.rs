@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::response::Response;
+use tower_layer::Layer;
+use tower_service::Service;
+use crate::auth;
+use crate::config::{BucketConfig, RateLimitConfig};
+use crate::models::ApiKey;
+use crate::router::problem;
+use crate::state::ApiKeyRepository;
+
+/// Who a request is charged to. Requests with an `ApiKey` the limiter
+/// can verify are charged to the key, the rest to the client IP.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    Ip(IpAddr),
+    ApiKey(String),
+    /// Every client of a tenant, for the budgets they share.
+    Tenant(String),
+    /// The peer address is unknown, e.g. when served without connect info.
+    Unknown,
+}
+
+/// Budget a request is charged against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Budget {
+    Read,
+    Write,
+}
+
+impl Budget {
+    fn of(method: &Method) -> Budget {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => Budget::Read,
+            _ => Budget::Write,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+/// Outcome of charging a request to its bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset_secs: u64,
+    /// Seconds until a request would be allowed, when denied.
+    pub retry_after_secs: u64,
+}
+
+/// The API key in an `Authorization` header value, if it's valid.
+type KeyVerifier = Arc<dyn Fn(&str) -> Option<ApiKey> + Send + Sync>;
+
+/// Token buckets per client and budget.
+#[derive(Clone)]
+pub struct RateLimiter {
+    config: Arc<RateLimitConfig>,
+    buckets_mtx: Arc<Mutex<HashMap<(ClientKey, Budget), Bucket>>>,
+    api_keys: Option<KeyVerifier>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            config: Arc::new(config),
+            buckets_mtx: Arc::new(Mutex::new(HashMap::new())),
+            api_keys: None,
+        }
+    }
+
+    /// Give each API key in `repo` its own budget. Keys it doesn't accept,
+    /// and every key without this, are charged to the client IP.
+    pub fn with_api_keys<S>(mut self, repo: S) -> RateLimiter
+    where
+        S: ApiKeyRepository + Send + Sync + 'static,
+    {
+        self.api_keys = Some(Arc::new(move |value| auth::verified_api_key(&repo, value)));
+        self
+    }
+
+    /// Take a token from the bucket of `key` for requests with `method`.
+    pub fn check(&self, key: &ClientKey, method: &Method) -> Decision {
+        self.check_at(key, method, Instant::now())
+    }
+
+    fn check_at(&self, key: &ClientKey, method: &Method, now: Instant) -> Decision {
+        let budget = Budget::of(method);
+        let bucket_config = self.bucket_config(budget);
+        let burst = bucket_config.burst as f64;
+
+        let mut buckets = self.buckets_mtx
+            .lock()
+            .unwrap();
+        let bucket = buckets.entry((key.clone(), budget))
+            .or_insert(Bucket { tokens: burst, refilled_at: now });
+
+        let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * bucket_config.per_second).min(burst);
+        bucket.refilled_at = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let retry_after = if allowed {
+            0.0
+        } else {
+            (1.0 - bucket.tokens) / bucket_config.per_second
+        };
+        Decision {
+            allowed,
+            limit: bucket_config.burst,
+            remaining: bucket.tokens.floor() as u32,
+            reset_secs: ((burst - bucket.tokens) / bucket_config.per_second).ceil() as u64,
+            retry_after_secs: retry_after.ceil() as u64,
+        }
+    }
+
+    /// Who `request` is charged to, see [ClientKey]. A verified API key is
+    /// left in its extensions for the [auth::AuthUser] extractor.
+    fn client_key(&self, request: &mut Request) -> ClientKey {
+        // Unverified keys would let a client pick a fresh budget each time.
+        let api_key = match (&self.api_keys, request.headers().get(header::AUTHORIZATION)) {
+            (Some(verify), Some(value)) => value.to_str().ok().and_then(|value| verify(value)),
+            _ => None,
+        };
+        if let Some(key) = api_key {
+            let id = key.id();
+            request.extensions_mut().insert(auth::VerifiedApiKey(key));
+            return ClientKey::ApiKey(id);
+        }
+        match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(addr)) => ClientKey::Ip(addr.ip()),
+            None => ClientKey::Unknown,
+        }
+    }
+
+    /// Forget buckets that refilled completely, they behave like new ones.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets_mtx
+            .lock()
+            .unwrap();
+        buckets.retain(|(_, budget), bucket| {
+            let bucket_config = self.bucket_config(*budget);
+            let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens + elapsed * bucket_config.per_second < bucket_config.burst as f64
+        });
+    }
+
+    fn bucket_config(&self, budget: Budget) -> &BucketConfig {
+        match budget {
+            Budget::Read => &self.config.read,
+            Budget::Write => &self.config.write,
+        }
+    }
+}
+
+/// Tower layer applying a [RateLimiter] to every request.
+/// Requests over budget get Too Many Requests (429).
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: RateLimiter,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: RateLimiter) -> RateLimitLayer {
+        RateLimitLayer { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: RateLimiter,
+}
+
+impl<S> Service<Request> for RateLimitService<S>
+where
+    S: Service<Request, Response = Response> + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request) -> Self::Future {
+        let key = self.limiter.client_key(&mut request);
+        let decision = self.limiter.check(&key, request.method());
+        if !decision.allowed {
+            tracing::debug!("rate limited {:?}", key);
+            let response = denied(&decision);
+            return Box::pin(async move { Ok(response) });
+        }
+
+        let future = self.inner.call(request);
+        Box::pin(async move {
+            let mut response = future.await?;
+            add_headers(response.headers_mut(), &decision);
+            Ok(response)
+        })
+    }
+}
+
+/// Set the `RateLimit-*` headers, plus `Retry-After` on denials.
+fn add_headers(headers: &mut HeaderMap, decision: &Decision) {
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(decision.reset_secs));
+    if !decision.allowed {
+        headers.insert("retry-after", HeaderValue::from(decision.retry_after_secs));
+    }
+}
+
+/// Too Many Requests (429) response, with its headers, for a denied request.
+pub(crate) fn denied(decision: &Decision) -> Response {
+    let mut response = too_many_requests(decision);
+    add_headers(response.headers_mut(), decision);
+    response
+}
+
+/// RFC 7807 compliant response for a client over its budget.
+fn too_many_requests(decision: &Decision) -> Response {
+    problem(
+        "rate_limited",
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many requests".to_string(),
+        format!("Rate limit exceeded, retry in {} seconds", decision.retry_after_secs),
+    )
+}
+
+#[cfg(test)]
+mod rate_limit_test {
+    use super::*;
+    use std::time::Duration;
+
+    fn limiter() -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            enabled: true,
+            read: BucketConfig { burst: 3, per_second: 1.0 },
+            write: BucketConfig { burst: 1, per_second: 0.5 },
+        })
+    }
+
+    #[test]
+    fn bucket_empties_and_refills() {
+        let limiter = limiter();
+        let key = ClientKey::Ip([127, 0, 0, 1].into());
+        let start = Instant::now();
+
+        for remaining in [2, 1, 0] {
+            let decision = limiter.check_at(&key, &Method::GET, start);
+            assert!(decision.allowed);
+            assert_eq!(decision.remaining, remaining);
+        }
+        let denied = limiter.check_at(&key, &Method::GET, start);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after_secs, 1);
+
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.check_at(&key, &Method::GET, later).allowed);
+    }
+
+    #[test]
+    fn read_and_write_budgets_are_separate() {
+        let limiter = limiter();
+        let key = ClientKey::Ip([127, 0, 0, 1].into());
+        let now = Instant::now();
+
+        assert!(limiter.check_at(&key, &Method::POST, now).allowed);
+        let denied = limiter.check_at(&key, &Method::POST, now);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after_secs, 2);
+
+        assert!(limiter.check_at(&key, &Method::GET, now).allowed);
+    }
+
+    #[test]
+    fn clients_are_separate() {
+        let limiter = limiter();
+        let now = Instant::now();
+        let a = ClientKey::Ip([10, 0, 0, 1].into());
+        let b = ClientKey::ApiKey("bot".to_string());
+
+        assert!(limiter.check_at(&a, &Method::POST, now).allowed);
+        assert!(!limiter.check_at(&a, &Method::POST, now).allowed);
+        assert!(limiter.check_at(&b, &Method::POST, now).allowed);
+    }
+
+    #[test]
+    fn verified_api_keys_are_left_for_the_extractor() {
+        let mut repo = crate::state::MockSnapRepository::new();
+        let (key, token) = ApiKey::new(uuid::Uuid::new_v4(), "bot".to_string(), crate::models::Scope::Read);
+        repo.create_api_key(key.clone()).unwrap();
+        let limiter = limiter().with_api_keys(repo);
+        let request = |value: String| Request::builder()
+            .header(header::AUTHORIZATION, value)
+            .body(axum::body::Body::empty())
+            .unwrap();
+
+        let mut valid = request(format!("ApiKey {token}"));
+        assert_eq!(limiter.client_key(&mut valid), ClientKey::ApiKey(key.id()));
+        let verified = valid.extensions().get::<auth::VerifiedApiKey>().unwrap();
+        assert_eq!(verified.0.id(), key.id());
+
+        let mut wrong = request("ApiKey snap_wrong_secret".to_string());
+        assert_eq!(limiter.client_key(&mut wrong), ClientKey::Unknown);
+        assert!(wrong.extensions().get::<auth::VerifiedApiKey>().is_none());
+    }
+}
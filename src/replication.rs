@@ -0,0 +1,311 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use http_body_util::{BodyExt, Empty};
+use hyper::StatusCode;
+use hyper::body::Bytes;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use sha2::{Digest, Sha256};
+use snap_api::{ReplicationRole, ReplicationStatus};
+use crate::config::ReplicationConfig;
+use crate::state::{ChangeLog, StorageError};
+
+/// Header carrying `replication.secret` on the requests of the followers.
+pub const SECRET_HEADER: &str = "X-Snap-Replication-Secret";
+
+/// Longest wait between two frames of the change stream. The leader sends
+/// a [Frame::Head] at least this often, followers give up after three.
+pub const HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// Longest wait between two attempts to reach the leader.
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+/// One line of the NDJSON stream served by the leader on
+/// `GET /replication/changes`.
+#[derive(Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Frame {
+    /// The change at `offset` of the log, as a journal line.
+    Change { offset: u64, change: String },
+    /// Number of changes in the log of the leader.
+    Head { offset: u64 },
+}
+
+impl Frame {
+    /// The frame as a line of the stream.
+    pub fn to_line(&self) -> String {
+        let mut line = serde_json::to_string(self).expect("frames are always serializable");
+        line.push('\n');
+        line
+    }
+}
+
+/// Whether `given` is the replication secret, taking the same time
+/// whatever the bytes that differ.
+pub fn secret_matches(secret: &str, given: &str) -> bool {
+    let (secret, given) = (Sha256::digest(secret.as_bytes()), Sha256::digest(given.as_bytes()));
+    secret.iter().zip(given.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Shared, cheaply clonable view of how the follower is doing.
+#[derive(Debug, Clone, Default)]
+pub struct Replication {
+    state: Arc<Mutex<FollowerState>>,
+}
+
+#[derive(Debug, Default)]
+struct FollowerState {
+    leader_offset: Option<u64>,
+    connected: bool,
+    last_contact_at: Option<chrono::DateTime<chrono::Utc>>,
+    error: Option<String>,
+}
+
+impl Replication {
+    /// Status of a server with `offset` changes in its store.
+    pub fn status(&self, config: &ReplicationConfig, offset: u64) -> ReplicationStatus {
+        let Some(leader) = &config.leader else {
+            return ReplicationStatus {
+                role: ReplicationRole::Leader,
+                leader: None,
+                offset,
+                leader_offset: None,
+                lag: Some(0),
+                connected: false,
+                last_contact_at: None,
+                error: None,
+            }
+        };
+        let state = self.state.lock().unwrap();
+        ReplicationStatus {
+            role: ReplicationRole::Follower,
+            leader: Some(leader.clone()),
+            offset,
+            leader_offset: state.leader_offset,
+            lag: state.leader_offset.map(|head| head.saturating_sub(offset)),
+            connected: state.connected,
+            last_contact_at: state.last_contact_at.map(|at| at.to_rfc3339()),
+            error: state.error.clone(),
+        }
+    }
+
+    /// The leader answered and has at least `head` changes.
+    fn contact(&self, head: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.leader_offset = Some(state.leader_offset.map_or(head, |offset| offset.max(head)));
+        state.connected = true;
+        state.last_contact_at = Some(chrono::Utc::now());
+        state.error = None;
+    }
+
+    /// The stream of the leader ended, because of `error` if any.
+    fn disconnected(&self, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.connected = false;
+        if error.is_some() {
+            state.error = error;
+        }
+    }
+
+    /// A new stream starts, the leader may have restarted since the last one.
+    fn reconnecting(&self) {
+        self.state.lock().unwrap().leader_offset = None;
+    }
+}
+
+/// Reasons a follower loses the stream of the leader.
+#[derive(Debug)]
+pub enum FollowError {
+    /// The leader couldn't be reached.
+    Connection(String),
+    /// The leader turned the follower away.
+    Refused(StatusCode),
+    /// The follower has changes the leader doesn't, they won't ever agree.
+    Diverged,
+    /// The leader no longer keeps the changes the follower needs next.
+    Behind,
+    /// Nothing came from the leader for a while.
+    Silent,
+    /// A line of the stream isn't a [Frame].
+    Protocol(String),
+    /// A change couldn't be applied.
+    Storage(StorageError),
+}
+
+impl fmt::Display for FollowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FollowError::Connection(e) => write!(f, "can't reach the leader: {e}"),
+            FollowError::Refused(status) => write!(f, "the leader answered {status}"),
+            FollowError::Diverged => write!(f, "diverged, the store has changes the leader doesn't"),
+            FollowError::Behind => write!(f, "fell behind, the leader no longer keeps the changes the store needs"),
+            FollowError::Silent => write!(f, "no news from the leader for {}s", (HEARTBEAT * 3).as_secs()),
+            FollowError::Protocol(e) => write!(f, "invalid change stream: {e}"),
+            FollowError::Storage(e) => write!(f, "can't apply a change: {e}"),
+        }
+    }
+}
+
+impl From<StorageError> for FollowError {
+    fn from(e: StorageError) -> Self {
+        FollowError::Storage(e)
+    }
+}
+
+/// Apply the changes of the leader configured in `config` to `repo`, as
+/// they come, reconnecting when the stream ends. Never returns.
+pub async fn follow<S: ChangeLog>(mut repo: S, config: ReplicationConfig, replication: Replication) {
+    // Validation guarantees both for followers.
+    let (Some(leader), Some(secret)) = (config.leader.as_deref(), config.secret.as_deref()) else {
+        return
+    };
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let initial_backoff = Duration::from_millis(config.poll_interval_ms);
+    let mut backoff = initial_backoff;
+    loop {
+        replication.reconnecting();
+        match stream_changes(&client, &mut repo, leader, secret, &replication).await {
+            Ok(()) => {
+                tracing::info!("the leader ended the change stream");
+                replication.disconnected(None);
+                backoff = initial_backoff;
+            }
+            Err(e) => {
+                tracing::warn!(offset = repo.change_count(), "replication stopped: {e}");
+                replication.disconnected(Some(e.to_string()));
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Apply the changes streamed by `leader` from the last one in `repo`
+/// until the stream ends.
+async fn stream_changes<S: ChangeLog>(
+    client: &Client<HttpConnector, Empty<Bytes>>,
+    repo: &mut S,
+    leader: &str,
+    secret: &str,
+    replication: &Replication,
+) -> Result<(), FollowError> {
+    let uri = format!("{}/replication/changes?from={}", leader.trim_end_matches('/'), repo.change_count());
+    let request = hyper::Request::get(uri)
+        .header(SECRET_HEADER, secret)
+        .body(Empty::new())
+        .map_err(|e| FollowError::Connection(e.to_string()))?;
+    let response = tokio::time::timeout(HEARTBEAT * 3, client.request(request))
+        .await
+        .map_err(|_| FollowError::Silent)?
+        .map_err(|e| FollowError::Connection(e.to_string()))?;
+    match response.status() {
+        StatusCode::OK => {}
+        StatusCode::CONFLICT => return Err(FollowError::Diverged),
+        StatusCode::GONE => return Err(FollowError::Behind),
+        status => return Err(FollowError::Refused(status)),
+    }
+    tracing::info!(leader, offset = repo.change_count(), "following the leader");
+
+    let mut body = response.into_body();
+    let mut pending = Vec::new();
+    loop {
+        let frame = match tokio::time::timeout(HEARTBEAT * 3, body.frame()).await {
+            Err(_) => return Err(FollowError::Silent),
+            Ok(None) => return Ok(()),
+            Ok(Some(Err(e))) => return Err(FollowError::Connection(e.to_string())),
+            Ok(Some(Ok(frame))) => frame,
+        };
+        let Some(data) = frame.data_ref() else {
+            continue
+        };
+        pending.extend_from_slice(data);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line = pending.drain(..=end).collect::<Vec<u8>>();
+            apply_frame(repo, &line, replication)?;
+        }
+    }
+}
+
+/// Apply one line of the change stream to `repo`.
+fn apply_frame<S: ChangeLog>(repo: &mut S, line: &[u8], replication: &Replication) -> Result<(), FollowError> {
+    let frame = serde_json::from_slice::<Frame>(line)
+        .map_err(|e| FollowError::Protocol(e.to_string()))?;
+    match frame {
+        Frame::Change { offset, change } => {
+            repo.apply_change(offset, &change)?;
+            replication.contact(offset + 1);
+        }
+        Frame::Head { offset } => {
+            if offset < repo.change_count() {
+                return Err(FollowError::Diverged)
+            }
+            replication.contact(offset);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod replication_test {
+    use super::*;
+    use crate::models::User;
+    use crate::state::{MockSnapRepository, SnapAppState, UserRepository};
+
+    #[test]
+    fn frames_are_lines() {
+        let frame = Frame::Change { offset: 3, change: "{\"op\":\"x\"}".to_string() };
+        let line = frame.to_line();
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+        assert_eq!(serde_json::from_str::<Frame>(&line).unwrap(), frame);
+    }
+
+    #[test]
+    fn secrets_are_compared() {
+        assert!(secret_matches("secret", "secret"));
+        assert!(!secret_matches("secret", "secreT"));
+        assert!(!secret_matches("secret", ""));
+    }
+
+    #[test]
+    fn followers_apply_each_change_once() {
+        let mut leader = MockSnapRepository::new();
+        let author = leader.create_user(User::new("author".to_string(), "password")).unwrap();
+        let snap = leader.post(&author.id(), "hello").unwrap();
+        leader.delete(&snap.id()).unwrap();
+
+        let mut follower = MockSnapRepository::new();
+        let replication = Replication::default();
+        let frames = leader.changes(0, 10)
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .map(|(offset, change)| Frame::Change { offset: offset as u64, change }.to_line())
+            .collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        // Frames sent again after a reconnection are skipped.
+        for line in frames.iter().chain(&frames) {
+            apply_frame(&mut follower, line.as_bytes(), &replication).unwrap();
+        }
+        assert_eq!(follower.change_count(), 3);
+        assert!(follower.get_user(&author.id()).is_some());
+        assert!(follower.get_snap(&snap.id()).is_none());
+
+        let head = Frame::Head { offset: 5 }.to_line();
+        apply_frame(&mut follower, head.as_bytes(), &replication).unwrap();
+        let status = replication.status(
+            &ReplicationConfig { leader: Some("http://leader".to_string()), ..ReplicationConfig::default() },
+            follower.change_count(),
+        );
+        assert_eq!((status.leader_offset, status.lag), (Some(5), Some(2)));
+
+        let gap = Frame::Change { offset: 7, change: leader.changes(0, 1).unwrap().remove(0) }.to_line();
+        assert!(matches!(
+            apply_frame(&mut follower, gap.as_bytes(), &replication),
+            Err(FollowError::Storage(StorageError::OutOfOrder { expected: 3, offset: 7 })),
+        ));
+        let behind = Frame::Head { offset: 1 }.to_line();
+        assert!(matches!(apply_frame(&mut follower, behind.as_bytes(), &replication), Err(FollowError::Diverged)));
+    }
+}
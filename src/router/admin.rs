@@ -0,0 +1,414 @@
+use std::convert::Infallible;
+use axum::body::Body;
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    Query,
+    State,
+    rejection::QueryRejection
+};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::dump::{self, ConflictPolicy, ImportError, ImportOptions, ImportReport, Importer};
+use crate::models::Scope;
+use crate::projections::{ProjectionStatus, Projections};
+use crate::snapshot::{self, SnapshotError, SnapshotInfo};
+use crate::state::Repository;
+use super::{ApiResponse, ProblemResponse, handle_bad_query, problem};
+use super::tenants::snaps_left;
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct ImportQuery {
+    /// Check the dump and count what would change, storing nothing.
+    #[serde(default)]
+    dry_run: bool,
+    /// What to do with snaps already stored: `fail`, `skip` or `overwrite`.
+    #[serde(default)]
+    on_conflict: ConflictPolicy,
+}
+
+/// axum handler for "GET /admin/export" which streams a dump of every
+/// snap, tombstones included, as NDJSON.
+#[utoipa::path(
+    get,
+    path = "/admin/export",
+    tag = "admin",
+    operation_id = "export_snaps",
+    summary = "Dump every snap",
+    description = "One JSON record per line: a header, every snap with its checksum, then a footer.",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The dump", body = String, content_type = "application/x-ndjson"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not an administrator", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn export_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+) -> Response {
+    if let Err(response) = require_admin(&user, &context) {
+        return *response;
+    }
+    let lines = dump::export(&repo).map(Ok::<String, Infallible>);
+    let filename = format!("snaps-{}.ndjson", chrono::Utc::now().format("%Y%m%dT%H%M%SZ"));
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{filename}\"")),
+        ],
+        Body::from_stream(futures_util::stream::iter(lines)),
+    ).into_response()
+}
+
+/// axum handler for "POST /admin/import" which reads a dump from the
+/// body, one line at a time, and stores its snaps with their ids.
+#[utoipa::path(
+    post,
+    path = "/admin/import",
+    tag = "admin",
+    operation_id = "import_snaps",
+    summary = "Load a dump",
+    description = "Snaps stored before a failure stay. A dry run tells first whether the whole dump would go through.",
+    params(ImportQuery),
+    request_body(content = String, content_type = "application/x-ndjson"),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "What was imported, or would be on a dry run", body = ApiResponse<ImportReport>),
+        (status = BAD_REQUEST, description = "Invalid query or dump", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not an administrator, or the dump goes over the snap quota of the tenant", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "A snap is already stored and `on_conflict` is `fail`", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "A snap can't be stored", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn import_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    query: Result<Query<ImportQuery>, QueryRejection>,
+    body: Body,
+) -> Response {
+    if let Err(response) = require_admin(&user, &context) {
+        return *response;
+    }
+    let query = match query {
+        Ok(Query(query)) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let options = ImportOptions {
+        on_conflict: query.on_conflict,
+        dry_run: query.dry_run,
+        max_created: snaps_left(&repo),
+    };
+    let mut importer = Importer::new(options);
+    let mut chunks = body.into_data_stream();
+    let mut pending = Vec::new();
+    loop {
+        let chunk = match chunks.next().await {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => return import_problem(ImportError::Io(std::io::Error::other(e))),
+            None => break,
+        };
+        pending.extend_from_slice(&chunk);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line = pending.drain(..=end).collect::<Vec<u8>>();
+            if let Err(e) = import_line(&mut importer, &mut repo, &line, &user) {
+                return import_problem(e);
+            }
+        }
+    }
+    if !pending.is_empty() {
+        if let Err(e) = import_line(&mut importer, &mut repo, &pending, &user) {
+            return import_problem(e);
+        }
+    }
+    match importer.finish() {
+        Ok(report) => {
+            tracing::info!(user = %user.id, ?report, "import finished");
+            Json::from(ApiResponse { data: report }).into_response()
+        }
+        Err(e) => import_problem(e),
+    }
+}
+
+/// Feed one line of the body to `importer`, logging the progress.
+fn import_line<S: Repository>(
+    importer: &mut Importer,
+    repo: &mut S,
+    line: &[u8],
+    user: &AuthUser,
+) -> Result<(), ImportError> {
+    let read = importer.report().read;
+    // Invalid UTF-8 is replaced, and then fails the checksum.
+    importer.line(repo, &String::from_utf8_lossy(line))?;
+    let report = importer.report();
+    if report.read / dump::PROGRESS_EVERY != read / dump::PROGRESS_EVERY {
+        tracing::info!(user = %user.id, read = report.read, total = report.total, "importing snaps");
+    }
+    Ok(())
+}
+
+fn import_problem(error: ImportError) -> Response {
+    let (kind, status, title) = match &error {
+        ImportError::Conflict { .. } => ("snap_conflict", StatusCode::CONFLICT, "Snap already exists"),
+        ImportError::Restore { .. } => ("snap_creation", StatusCode::UNPROCESSABLE_ENTITY, "Snap not stored"),
+        ImportError::TooMany { .. } => ("quota_exceeded", StatusCode::FORBIDDEN, "Quota exceeded"),
+        _ => ("invalid_dump", StatusCode::BAD_REQUEST, "Invalid dump"),
+    };
+    problem(kind, status, title.to_string(), error.to_string())
+}
+
+/// axum handler for "POST /admin/snapshots" which writes a consistent
+/// snapshot of the whole store to the snapshots directory while the
+/// server keeps taking writes.
+#[utoipa::path(
+    post,
+    path = "/admin/snapshots",
+    tag = "admin",
+    operation_id = "create_snapshot",
+    summary = "Snapshot the store",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = CREATED, description = "The new snapshot", body = ApiResponse<SnapshotInfo>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not an administrator", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "No snapshots directory is configured", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = INTERNAL_SERVER_ERROR, description = "The snapshot couldn't be written", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snapshots_post_handler<S: Repository + Clone + Send + Sync + 'static>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+) -> Response {
+    let dir = match snapshots_dir(&user, &context) {
+        Ok(dir) => dir,
+        Err(response) => return *response,
+    };
+    // Journals are copied, which takes a while for big ones.
+    let taken = tokio::task::spawn_blocking(move || {
+        let info = snapshot::take(&repo, &dir)?;
+        snapshot::prune(&dir, context.config.snapshots.keep)?;
+        Ok::<SnapshotInfo, SnapshotError>(info)
+    }).await;
+    match taken {
+        Ok(Ok(info)) => {
+            tracing::info!(user = %user.id, snapshot = %info.name, entries = info.entries, "snapshot taken");
+            (StatusCode::CREATED, Json::from(ApiResponse { data: info })).into_response()
+        }
+        Ok(Err(e)) => snapshot_problem(e),
+        Err(e) => snapshot_problem(SnapshotError::Io(std::io::Error::other(e))),
+    }
+}
+
+/// axum handler for "GET /admin/snapshots" which lists the snapshots
+/// kept, the most recent first.
+#[utoipa::path(
+    get,
+    path = "/admin/snapshots",
+    tag = "admin",
+    operation_id = "list_snapshots",
+    summary = "List the snapshots",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The snapshots, the most recent first", body = ApiResponse<Vec<SnapshotInfo>>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not an administrator", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "No snapshots directory is configured", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snapshots_get_handler(
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+) -> Response {
+    let dir = match snapshots_dir(&user, &context) {
+        Ok(dir) => dir,
+        Err(response) => return *response,
+    };
+    match snapshot::list(&dir) {
+        Ok(snapshots) => Json::from(ApiResponse { data: snapshots }).into_response(),
+        Err(e) => snapshot_problem(e),
+    }
+}
+
+/// axum handler for "GET /admin/snapshots/:name" which downloads a snapshot.
+#[utoipa::path(
+    get,
+    path = "/admin/snapshots/{name}",
+    tag = "admin",
+    operation_id = "get_snapshot",
+    summary = "Download a snapshot",
+    params(("name" = String, Path, description = "Name of the snapshot")),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The snapshot file", body = String, content_type = "application/x-ndjson"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not an administrator", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snapshot has this name", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "No snapshots directory is configured", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snapshot_get_handler(
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path(name): Path<String>,
+) -> Response {
+    let dir = match snapshots_dir(&user, &context) {
+        Ok(dir) => dir,
+        Err(response) => return *response,
+    };
+    let file = match snapshot::path(&dir, &name) {
+        Ok(path) => tokio::fs::File::open(path).await,
+        Err(e) => return snapshot_problem(e),
+    };
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => return snapshot_problem(SnapshotError::Io(e)),
+    };
+    let chunks = futures_util::stream::unfold(file, |mut file| async move {
+        let mut chunk = vec![0; 64 * 1024];
+        match tokio::io::AsyncReadExt::read(&mut file, &mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                Some((Ok(chunk), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-ndjson".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{name}\"")),
+        ],
+        Body::from_stream(chunks),
+    ).into_response()
+}
+
+/// axum handler for "GET /admin/projections" which brings the read
+/// models up to date with the snap log and tells what they hold.
+#[utoipa::path(
+    get,
+    path = "/admin/projections",
+    tag = "admin",
+    operation_id = "get_projections",
+    summary = "Status of the read models",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "Events applied and what the projections hold", body = ApiResponse<ProjectionStatus>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not an administrator", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn projections_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+) -> Response {
+    if let Err(response) = require_admin(&user, &context) {
+        return *response
+    }
+    let status = context.projections.read(&repo, Projections::status);
+    Json::from(ApiResponse { data: status }).into_response()
+}
+
+/// axum handler for "POST /admin/projections/replay" which rebuilds the
+/// read models from offset zero of the snap log, then swaps them in.
+#[utoipa::path(
+    post,
+    path = "/admin/projections/replay",
+    tag = "admin",
+    operation_id = "replay_projections",
+    summary = "Rebuild the read models from the snap log",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The rebuilt projections", body = ApiResponse<ProjectionStatus>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not an administrator", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn projections_replay_post_handler<S: Repository + Clone + Send + Sync + 'static>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+) -> Response {
+    if let Err(response) = require_admin(&user, &context) {
+        return *response
+    }
+    let started = std::time::Instant::now();
+    let projector = context.projections.clone();
+    let replayed = tokio::task::spawn_blocking(move || projector.replay(&repo)).await;
+    match replayed {
+        Ok(status) => {
+            tracing::info!(
+                user = %user.id,
+                events = status.offset,
+                took_ms = started.elapsed().as_millis() as u64,
+                "projections replayed",
+            );
+            Json::from(ApiResponse { data: status }).into_response()
+        }
+        Err(e) => {
+            tracing::error!("projection replay failed: {e}");
+            problem(
+                "replay_failed",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Replay failed".to_string(),
+                "The projections couldn't be rebuilt, the old ones are kept".to_string(),
+            )
+        }
+    }
+}
+
+/// Snapshots directory of the configuration, if `user` is an administrator.
+fn snapshots_dir(user: &AuthUser, context: &AppContext) -> Result<std::path::PathBuf, Box<Response>> {
+    require_admin(user, context)?;
+    context.config.snapshots.path.clone().ok_or_else(|| Box::new(problem(
+        "snapshots_disabled",
+        StatusCode::CONFLICT,
+        "Snapshots disabled".to_string(),
+        "No snapshots.path is configured".to_string(),
+    )))
+}
+
+fn snapshot_problem(error: SnapshotError) -> Response {
+    match error {
+        SnapshotError::NotFound(name) => problem(
+            "snapshot_not_found",
+            StatusCode::NOT_FOUND,
+            "Snapshot not found".to_string(),
+            format!("No snapshot named {name}"),
+        ),
+        e => {
+            tracing::error!("snapshot failed: {e}");
+            problem(
+                "snapshot_failed",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Snapshot failed".to_string(),
+                e.to_string(),
+            )
+        }
+    }
+}
+
+/// Fail unless `user` is one of the administrators of the configuration,
+/// with credentials allowing [Scope::Admin].
+pub(super) fn require_admin(user: &AuthUser, context: &AppContext) -> Result<(), Box<Response>> {
+    user.require(Scope::Admin).map_err(|e| Box::new(e.into_response()))?;
+    if !context.config.admin.users.contains(&user.id) {
+        return Err(Box::new(problem(
+            "not_admin",
+            StatusCode::FORBIDDEN,
+            "Not an administrator".to_string(),
+            "Only the users in admin.users can do this".to_string(),
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use axum::extract::{
+    Json,
+    Path,
+    State,
+    rejection::JsonRejection
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use uuid::Uuid;
+use crate::auth::AuthUser;
+use crate::models::{ApiKey, Scope};
+use crate::state::{ApiKeyError, Repository};
+use super::{ApiResponse, ProblemResponse, handle_bad_json, problem};
+
+/// Longest name accepted for a key.
+const MAX_NAME_LENGTH: usize = 64;
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct CreateApiKey {
+    name: String,
+    scope: Scope,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct ApiKeyInfo {
+    id: String,
+    name: String,
+    scope: Scope,
+    created_at: String,
+    last_used_at: Option<String>,
+    revoked_at: Option<String>,
+    /// Only sent once, when the key is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+}
+
+impl From<&ApiKey> for ApiKeyInfo {
+    fn from(key: &ApiKey) -> Self {
+        ApiKeyInfo {
+            id: key.id(),
+            name: key.name().to_string(),
+            scope: key.scope(),
+            created_at: key.created_at().to_rfc3339(),
+            last_used_at: key.last_used_at().map(|time| time.to_rfc3339()),
+            revoked_at: key.revoked_at().map(|time| time.to_rfc3339()),
+            key: None,
+        }
+    }
+}
+
+/// axum handler for "POST /api-keys" which creates a key for the
+/// authenticated user. The response has the only copy of the secret key.
+#[utoipa::path(
+    post,
+    path = "/api-keys",
+    tag = "api_keys",
+    operation_id = "create_api_key",
+    summary = "Create an API key",
+    request_body = CreateApiKey,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = CREATED, description = "The new key, with the only copy of the secret", body = ApiResponse<ApiKeyInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid name", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn api_keys_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    extractor: Result<Json<CreateApiKey>, JsonRejection>,
+) -> Response {
+    if let Err(e) = user.require(Scope::Admin) {
+        return e.into_response();
+    }
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    let length = payload.name.trim().chars().count();
+    if length == 0 || length > MAX_NAME_LENGTH {
+        return problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid name".to_string(),
+            format!("Name must have between 1 and {MAX_NAME_LENGTH} characters"),
+        );
+    }
+    let owner = match Uuid::parse_str(&user.id) {
+        Ok(owner) if repo.get_user(&user.id).is_some() => owner,
+        _ => return problem(
+            "author_not_found",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown user".to_string(),
+            "No user has the authenticated id".to_string(),
+        ),
+    };
+
+    let (key, token) = ApiKey::new(owner, payload.name.trim().to_string(), payload.scope);
+    match repo.create_api_key(key) {
+        Ok(key) => {
+            let info = ApiKeyInfo { key: Some(token), ..ApiKeyInfo::from(&key) };
+            (StatusCode::CREATED, Json::from(ApiResponse { data: info })).into_response()
+        },
+        Err(e) => map_api_key_error(e, ""),
+    }
+}
+
+/// axum handler for "GET /api-keys" which lists the keys of the
+/// authenticated user, revoked ones included.
+#[utoipa::path(
+    get,
+    path = "/api-keys",
+    tag = "api_keys",
+    operation_id = "list_api_keys",
+    summary = "List your API keys",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "Every key, revoked ones included", body = ApiResponse<Vec<ApiKeyInfo>>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn api_keys_get_handler<S: Repository>(
+    State(repo): State<S>,
+    user: AuthUser,
+) -> Response {
+    if let Err(e) = user.require(Scope::Admin) {
+        return e.into_response();
+    }
+    let keys = repo.get_api_keys_by_owner(&user.id)
+        .iter()
+        .map(ApiKeyInfo::from)
+        .collect::<Vec<ApiKeyInfo>>();
+    (StatusCode::OK, Json::from(ApiResponse { data: keys })).into_response()
+}
+
+/// axum handler for "DELETE /api-keys/{id}" which revokes a key.
+/// Keys of other users are reported as Not Found (404).
+#[utoipa::path(
+    delete,
+    path = "/api-keys/{id}",
+    tag = "api_keys",
+    operation_id = "revoke_api_key",
+    summary = "Revoke an API key",
+    params(
+        ("id" = String, Path, description = "Id of the key"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The key was revoked"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "You have no API key with the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn api_key_delete_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(e) = user.require(Scope::Admin) {
+        return e.into_response();
+    }
+    match repo.get_api_key(&id) {
+        Some(key) if key.owner_id() == user.id => {},
+        _ => return map_api_key_error(ApiKeyError::NotFound, &id),
+    }
+    match repo.revoke_api_key(&id) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => map_api_key_error(e, &id),
+    }
+}
+
+/// Parse [ApiKeyError] into an RFC 7807 compliant error response.
+fn map_api_key_error(error: ApiKeyError, id: &str) -> Response {
+    match error {
+        ApiKeyError::NotFound => problem(
+            "api_key_not_found",
+            StatusCode::NOT_FOUND,
+            "API key not found".to_string(),
+            format!("You have no API key with the id {id}"),
+        ),
+        _ => problem(
+            "api_key",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
@@ -0,0 +1,200 @@
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    Query,
+    State,
+    rejection::QueryRejection
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::state::{FollowError, Repository};
+use super::{ApiResponse, Page, PageQuery, ProblemResponse, page_params, page_response, problem};
+use super::moderation::visibility;
+use super::users::{UserInfo, user_not_found};
+
+/// axum handler for "PUT /users/{id}/follow" which makes the
+/// authenticated user follow another. Following twice is a no-op.
+#[utoipa::path(
+    put,
+    path = "/users/{id}/follow",
+    tag = "follows",
+    operation_id = "follow",
+    summary = "Follow a user",
+    params(
+        ("id" = String, Path, description = "Id of the user"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The user is followed, or already was"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No user has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Users can't follow themselves", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn follow_put_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Response {
+    let fan_out = context.config.timeline.fan_out;
+    let result = context.metrics.time_repository("follow", fan_out, || repo.follow(&user.id, &id));
+    match result {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => map_follow_error(e, &id),
+    }
+}
+
+/// axum handler for "DELETE /users/{id}/follow" which makes the
+/// authenticated user stop following another.
+#[utoipa::path(
+    delete,
+    path = "/users/{id}/follow",
+    tag = "follows",
+    operation_id = "unfollow",
+    summary = "Stop following a user",
+    params(
+        ("id" = String, Path, description = "Id of the user"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The user isn't followed anymore"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No user has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn follow_delete_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Response {
+    let fan_out = context.config.timeline.fan_out;
+    let result = context.metrics.time_repository("unfollow", fan_out, || repo.unfollow(&user.id, &id));
+    match result {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => map_follow_error(e, &id),
+    }
+}
+
+/// axum handler for "GET /users/{id}/followers" which lists
+/// the users following a user.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/followers",
+    tag = "follows",
+    operation_id = "list_followers",
+    summary = "List the followers of a user",
+    params(
+        ("id" = String, Path, description = "Id of the user"),
+    ),
+    responses(
+        (status = OK, description = "The followers", body = ApiResponse<Vec<UserInfo>>),
+        (status = NOT_FOUND, description = "No user has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn followers_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Path(id): Path<String>,
+) -> Response {
+    if repo.get_user(&id).is_none() {
+        return user_not_found(&id);
+    }
+    users_response(&repo, repo.followers(&id))
+}
+
+/// axum handler for "GET /users/{id}/following" which lists
+/// the users a user follows.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/following",
+    tag = "follows",
+    operation_id = "list_following",
+    summary = "List the users a user follows",
+    params(
+        ("id" = String, Path, description = "Id of the user"),
+    ),
+    responses(
+        (status = OK, description = "The followed users", body = ApiResponse<Vec<UserInfo>>),
+        (status = NOT_FOUND, description = "No user has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn following_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Path(id): Path<String>,
+) -> Response {
+    if repo.get_user(&id).is_none() {
+        return user_not_found(&id);
+    }
+    users_response(&repo, repo.following(&id))
+}
+
+/// axum handler for "GET /timeline" which returns the snaps of the
+/// authenticated user and the users they follow, from the most recent
+/// to the oldest, one page at a time.
+#[utoipa::path(
+    get,
+    path = "/timeline",
+    tag = "follows",
+    operation_id = "get_timeline",
+    summary = "Page through the home timeline",
+    params(
+        PageQuery,
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "Snaps of the user and the users they follow, from the most recent", body = Page),
+        (status = BAD_REQUEST, description = "Malformed query or cursor", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn timeline_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    query: Result<Query<PageQuery>, QueryRejection>,
+) -> Response {
+    let config = &context.config.timeline;
+    let (cursor, limit) = match page_params(query, config) {
+        Ok(params) => params,
+        Err(response) => return *response,
+    };
+    let visibility = visibility(Some(&user), &context);
+    // One extra snap tells whether there is a next page.
+    let snaps = context.metrics.time_repository("timeline", config.fan_out, || {
+        repo.timeline(&user.id, cursor.as_ref(), limit + 1, visibility)
+    });
+    page_response(&repo, snaps, limit)
+}
+
+/// List the users with these ids, skipping unknown ones.
+fn users_response<S: Repository>(repo: &S, ids: Vec<String>) -> Response {
+    let users = ids.iter()
+        .filter_map(|id| repo.get_user(id))
+        .map(|user| UserInfo::from(&user))
+        .collect::<Vec<UserInfo>>();
+    (StatusCode::OK, Json::from(ApiResponse { data: users })).into_response()
+}
+
+/// Parse [FollowError] into an RFC 7807 compliant error response.
+fn map_follow_error(error: FollowError, id: &str) -> Response {
+    match error {
+        FollowError::UserNotFound => user_not_found(id),
+        FollowError::SelfFollow => problem(
+            "self_follow",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Can't follow yourself".to_string(),
+            "Users can't follow themselves".to_string(),
+        ),
+        FollowError::StorageError(_) => problem(
+            "follow",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
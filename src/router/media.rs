@@ -0,0 +1,100 @@
+use axum::extract::{Extension, Path};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use crate::context::AppContext;
+use crate::media::{self, ByteRange};
+use super::{ProblemResponse, problem};
+
+/// Blobs never change under the same hash, so clients may keep them for a year.
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+/// axum handler for "GET /media/{hash}" which serves the contents of an
+/// attachment. Honors single `Range` requests and `If-None-Match`.
+#[utoipa::path(
+    get,
+    path = "/media/{hash}",
+    tag = "media",
+    operation_id = "get_media",
+    summary = "Download an attachment or thumbnail",
+    params(
+        ("hash" = String, Path, description = "Lowercase hex SHA-256 of the file"),
+        ("Range" = Option<String>, Header, description = "Single `bytes` range"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a cached copy"),
+    ),
+    responses(
+        (status = OK, description = "The whole file", content_type = "image/*"),
+        (status = PARTIAL_CONTENT, description = "The range asked for with `Range`", content_type = "image/*"),
+        (status = NOT_MODIFIED, description = "The `If-None-Match` ETag matches"),
+        (status = NOT_FOUND, description = "No file has the hash", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = RANGE_NOT_SATISFIABLE, description = "The range starts after the end of the file"),
+    ),
+)]
+pub(super) async fn media_get_handler(
+    Extension(context): Extension<AppContext>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+) -> Response {
+    if !media::is_hash(&hash) {
+        return media_not_found(&hash);
+    }
+    let bytes = match context.blobs.get(&hash) {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return media_not_found(&hash),
+        Err(e) => {
+            tracing::error!("can't read attachment {hash}: {e}");
+            return problem(
+                "media_storage",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Unknown error".to_string(),
+                "Can't read the attachment".to_string(),
+            );
+        }
+    };
+    let etag = format!("\"{hash}\"");
+    let content_type = media::sniff(&bytes).unwrap_or("application/octet-stream");
+    let common = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, CACHE_CONTROL.to_string()),
+        (header::ACCEPT_RANGES, "bytes".to_string()),
+        (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+    ];
+
+    let if_none_match = headers.get(header::IF_NONE_MATCH).and_then(|value| value.to_str().ok());
+    if if_none_match.is_some_and(|tags| tags.split(',').any(|tag| matches!(tag.trim(), "*") || tag.trim() == etag)) {
+        return (StatusCode::NOT_MODIFIED, common).into_response();
+    }
+
+    let len = bytes.len() as u64;
+    let range = headers.get(header::RANGE).and_then(|value| value.to_str().ok());
+    match media::byte_range(range, len) {
+        ByteRange::Full => (
+            StatusCode::OK,
+            common,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            bytes,
+        ).into_response(),
+        ByteRange::Partial { start, end } => (
+            StatusCode::PARTIAL_CONTENT,
+            common,
+            [
+                (header::CONTENT_TYPE, content_type.to_string()),
+                (header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}")),
+            ],
+            bytes[start as usize..=end as usize].to_vec(),
+        ).into_response(),
+        ByteRange::Unsatisfiable => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            common,
+            [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+        ).into_response(),
+    }
+}
+
+fn media_not_found(hash: &str) -> Response {
+    problem(
+        "media_not_found",
+        StatusCode::NOT_FOUND,
+        "Media not found".to_string(),
+        format!("No attachment has the hash {hash}"),
+    )
+}
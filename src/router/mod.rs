@@ -0,0 +1,432 @@
+use axum::handler::Handler;
+use axum::routing;
+use axum::extract::{
+    DefaultBodyLimit,
+    Extension,
+    Json,
+    Query,
+    rejection::{JsonRejection, QueryRejection}
+};
+use axum::http::{header, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use utoipa_swagger_ui::SwaggerUi;
+use crate::auth;
+use crate::config::TimelineConfig;
+use crate::context::AppContext;
+use crate::metrics::{self, ProblemKind};
+use crate::models::Snap;
+use crate::rate_limit::{RateLimitLayer, RateLimiter};
+use crate::state::{Repository, SnapAppState, TimelineCursor};
+use crate::tenancy::Tenants;
+use snap_api::{ApiResponse, Page, ProblemResponse};
+
+mod admin;
+mod api_keys;
+mod follows;
+mod media;
+mod moderation;
+mod notifications;
+mod openapi;
+mod probes;
+mod reactions;
+mod replication;
+mod search;
+mod snaps;
+mod tags;
+mod tenants;
+mod users;
+mod webhooks;
+
+/// Query of the endpoints paginated with [TimelineCursor].
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct PageQuery {
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+    /// Snaps in the page.
+    limit: Option<usize>,
+}
+
+/// Instantiate a router for the app needing a state of type [S].
+/// To use it, the method [axum::Router<S>::with_state] must be called on it.
+pub fn get_router<S: Repository + Clone + Send + Sync + 'static>() -> axum::Router<S> {
+    get_router_with_context(AppContext::default())
+}
+
+/// Same as [get_router] but using the configuration and services in `context`.
+pub fn get_router_with_context<S: Repository + Clone + Send + Sync + 'static>(
+    context: AppContext,
+) -> axum::Router<S> {
+    get_router_with_limiter(context, None)
+}
+
+/// Same as [get_router_with_context], charging each request to `limiter`
+/// when given. Requests it refuses are still counted in the metrics.
+pub fn get_router_with_limiter<S: Repository + Clone + Send + Sync + 'static>(
+    context: AppContext,
+    limiter: Option<RateLimiter>,
+) -> axum::Router<S> {
+    // Snaps with attachments may take every attachment on top of the usual body.
+    let media = &context.config.media;
+    let max_snap_bytes = media.max_attachments
+        .saturating_mul(media.max_bytes)
+        .saturating_add(context.config.limits.max_body_bytes);
+    let router = axum::Router::new()
+        .fallback(
+            fallback_handler
+        )
+        .route(
+            "/healthz",
+            routing::get(probes::healthz_handler),
+        )
+        .route(
+            "/readyz",
+            routing::get(probes::readyz_handler::<S>),
+        )
+        .route(
+            "/metrics",
+            routing::get(probes::metrics_handler::<S>),
+        )
+        .route(
+            "/snaps",
+            routing::get(snaps::snaps_get_handler::<S>)
+                .post(snaps::snaps_post_handler::<S>.layer(DefaultBodyLimit::max(max_snap_bytes))),
+        )
+        .route(
+            "/snaps/stream",
+            routing::get(snaps::snaps_stream_handler::<S>),
+        )
+        .route(
+            "/snaps/:id",
+            routing::get(snaps::snap_get_handler::<S>)
+                .patch(snaps::snap_patch_handler::<S>)
+                .delete(snaps::snap_delete_handler::<S>),
+        )
+        .route(
+            "/snaps/:id/reactions/:kind",
+            routing::get(reactions::reactors_get_handler::<S>)
+                .put(reactions::reaction_put_handler::<S>)
+                .delete(reactions::reaction_delete_handler::<S>),
+        )
+        .route(
+            "/snaps/:id/thread",
+            routing::get(snaps::snap_thread_get_handler::<S>),
+        )
+        .route(
+            "/snaps/:id/reports",
+            routing::post(moderation::reports_post_handler::<S>),
+        )
+        .route(
+            "/moderation/queue",
+            routing::get(moderation::queue_get_handler::<S>),
+        )
+        .route(
+            "/moderation/snaps/:id/:action",
+            routing::post(moderation::moderate_post_handler::<S>),
+        )
+        .route(
+            "/moderation/audit",
+            routing::get(moderation::audit_get_handler::<S>),
+        )
+        .route(
+            "/media/:hash",
+            routing::get(media::media_get_handler),
+        )
+        .route(
+            "/users",
+            routing::post(users::users_post_handler::<S>),
+        )
+        .route(
+            "/users/:id",
+            routing::get(users::user_get_handler::<S>),
+        )
+        .route(
+            "/users/:id/snaps",
+            routing::get(users::user_snaps_get_handler::<S>),
+        )
+        .route(
+            "/users/:id/follow",
+            routing::put(follows::follow_put_handler::<S>)
+                .delete(follows::follow_delete_handler::<S>),
+        )
+        .route(
+            "/users/:id/followers",
+            routing::get(follows::followers_get_handler::<S>),
+        )
+        .route(
+            "/users/:id/following",
+            routing::get(follows::following_get_handler::<S>),
+        )
+        .route(
+            "/timeline",
+            routing::get(follows::timeline_get_handler::<S>),
+        )
+        .route(
+            "/search",
+            routing::get(search::search_get_handler::<S>),
+        )
+        .route(
+            "/tags/trending",
+            routing::get(tags::trending_get_handler::<S>),
+        )
+        .route(
+            "/tags/:tag/snaps",
+            routing::get(tags::tag_snaps_get_handler::<S>),
+        )
+        .route(
+            "/notifications",
+            routing::get(notifications::notifications_get_handler::<S>),
+        )
+        .route(
+            "/notifications/read",
+            routing::post(notifications::notifications_read_post_handler::<S>),
+        )
+        .route(
+            "/notifications/preferences",
+            routing::get(notifications::preferences_get_handler::<S>)
+                .patch(notifications::preferences_patch_handler::<S>),
+        )
+        .route(
+            "/notifications/stream",
+            routing::get(notifications::notifications_stream_handler),
+        )
+        .route(
+            "/login",
+            routing::post(users::login_post_handler::<S>),
+        )
+        .route(
+            "/login/refresh",
+            routing::post(users::refresh_post_handler::<S>),
+        )
+        .route(
+            "/api-keys",
+            routing::get(api_keys::api_keys_get_handler::<S>)
+                .post(api_keys::api_keys_post_handler::<S>),
+        )
+        .route(
+            "/api-keys/:id",
+            routing::delete(api_keys::api_key_delete_handler::<S>),
+        )
+        .route(
+            "/webhooks",
+            routing::get(webhooks::webhooks_get_handler::<S>)
+                .post(webhooks::webhooks_post_handler::<S>),
+        )
+        .route(
+            "/webhooks/:id",
+            routing::get(webhooks::webhook_get_handler::<S>)
+                .patch(webhooks::webhook_patch_handler::<S>)
+                .delete(webhooks::webhook_delete_handler::<S>),
+        )
+        .route(
+            "/webhooks/:id/deliveries",
+            routing::get(webhooks::deliveries_get_handler::<S>),
+        )
+        .route(
+            "/webhooks/:id/deliveries/:delivery_id/retry",
+            routing::post(webhooks::delivery_retry_post_handler::<S>),
+        )
+        .route(
+            "/admin/export",
+            routing::get(admin::export_get_handler::<S>),
+        )
+        .route(
+            "/admin/import",
+            routing::post(admin::import_post_handler::<S>),
+        )
+        .route(
+            "/admin/snapshots",
+            routing::get(admin::snapshots_get_handler)
+                .post(admin::snapshots_post_handler::<S>),
+        )
+        .route(
+            "/admin/projections",
+            routing::get(admin::projections_get_handler::<S>),
+        )
+        .route(
+            "/admin/projections/replay",
+            routing::post(admin::projections_replay_post_handler::<S>),
+        )
+        .route(
+            "/admin/snapshots/:name",
+            routing::get(admin::snapshot_get_handler),
+        )
+        .route(
+            "/replication/changes",
+            routing::get(replication::changes_get_handler::<S>),
+        )
+        .route(
+            "/replication/status",
+            routing::get(replication::status_get_handler::<S>),
+        )
+        .merge(
+            SwaggerUi::new("/docs").url("/openapi.json", openapi::document()),
+        );
+    // Followers only take the writes the leader sends them.
+    let router = match context.config.replication.leader.clone() {
+        Some(leader) => router.layer(middleware::from_fn(move |request, next| {
+            replication::redirect_writes(leader.clone(), request, next)
+        })),
+        None => router,
+    };
+    let router = router
+        .layer(DefaultBodyLimit::max(context.config.limits.max_body_bytes))
+        .layer(middleware::from_fn({
+            let auth = context.auth.clone();
+            move |request, next| auth::authenticate(auth.clone(), request, next)
+        }));
+    let router = match limiter {
+        Some(limiter) => router.layer(RateLimitLayer::new(limiter)),
+        None => router,
+    };
+    router
+        .layer(middleware::from_fn({
+            let metrics = context.metrics.clone();
+            move |request, next| metrics::track(metrics.clone(), request, next)
+        }))
+        .layer(Extension(context))
+}
+
+/// Router of a server hosting `tenants`: the `/admin/tenants` routes and
+/// the routes of [get_router_with_context] for each tenant, which requests
+/// name with a `/t/<tenant>` path prefix, the `X-Snap-Tenant` header or a
+/// subdomain of `tenancy.domain`.
+pub fn get_tenant_router<S: Repository + Clone + Send + Sync + 'static>(tenants: Tenants<S>) -> axum::Router {
+    axum::Router::new()
+        .fallback(
+            tenants::dispatch::<S>
+        )
+        .route(
+            "/healthz",
+            routing::get(probes::healthz_handler),
+        )
+        .route(
+            "/admin/tenants",
+            routing::get(tenants::tenants_get_handler::<S>)
+                .post(tenants::tenants_post_handler::<S>),
+        )
+        .route(
+            "/admin/tenants/:id",
+            routing::get(tenants::tenant_get_handler::<S>)
+                .patch(tenants::tenant_patch_handler::<S>),
+        )
+        .route(
+            "/admin/tenants/:id/suspend",
+            routing::post(tenants::tenant_suspend_post_handler::<S>),
+        )
+        .route(
+            "/admin/tenants/:id/resume",
+            routing::post(tenants::tenant_resume_post_handler::<S>),
+        )
+        .layer(Extension(tenants))
+}
+
+/// axum handler for any request that fails to match the router routes.
+/// This implementation returns HTTP status code Not Found (404).
+async fn fallback_handler(
+    uri: axum::http::Uri
+) -> impl IntoResponse {
+    problem(
+        "not_found",
+        StatusCode::NOT_FOUND,
+        format!("No route {}", uri),
+        "Couldn't find the route".to_string(),
+    )
+}
+
+/// Build an RFC 7807 compliant error response.
+/// `kind` identifies the problem in the metrics.
+pub(crate) fn problem(kind: &'static str, status: StatusCode, title: String, detail: String) -> Response {
+    let response = ProblemResponse {
+        uri: Some("about:blank".to_string()),
+        title: Some(title),
+        status: Some(status.to_string()),
+        detail: Some(detail),
+    };
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/problem+json")],
+        Extension(ProblemKind(kind)),
+        Json::from(response),
+    ).into_response()
+}
+
+/// Cursor and size of the page asked for in `query`, or an error response.
+/// Page sizes follow the timeline settings.
+fn page_params(
+    query: Result<Query<PageQuery>, QueryRejection>,
+    config: &TimelineConfig,
+) -> Result<(Option<TimelineCursor>, usize), Box<Response>> {
+    let Query(query) = query.map_err(|rejection| Box::new(handle_bad_query(&rejection)))?;
+    page_bounds(query.cursor.as_deref(), query.limit, config)
+}
+
+/// Same as [page_params] for a cursor and limit already extracted.
+fn page_bounds(
+    cursor: Option<&str>,
+    limit: Option<usize>,
+    config: &TimelineConfig,
+) -> Result<(Option<TimelineCursor>, usize), Box<Response>> {
+    let limit = limit.unwrap_or(config.default_page_size);
+    if limit == 0 || limit > config.max_page_size {
+        return Err(Box::new(problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid limit".to_string(),
+            format!("Limit must be between 1 and {}", config.max_page_size),
+        )));
+    }
+    let cursor = match cursor.map(str::parse::<TimelineCursor>) {
+        None => None,
+        Some(Ok(cursor)) => Some(cursor),
+        Some(Err(e)) => return Err(Box::new(problem(
+            "invalid_cursor",
+            StatusCode::BAD_REQUEST,
+            "Invalid cursor".to_string(),
+            e,
+        ))),
+    };
+    Ok((cursor, limit))
+}
+
+/// Response with a page of `limit` snaps out of `snaps`, which should
+/// have one extra snap when there is a next page.
+fn page_response<S: SnapAppState>(repo: &S, mut snaps: Vec<Snap>, limit: usize) -> Response {
+    let next_cursor = if snaps.len() > limit {
+        snaps.truncate(limit);
+        snaps.last().map(|snap| TimelineCursor::from(snap).to_string())
+    } else {
+        None
+    };
+    let page = Page {
+        data: snaps::snap_infos(repo, &snaps),
+        next_cursor,
+    };
+    (StatusCode::OK, Json::from(page)).into_response()
+}
+
+/// Parse [QueryRejection] into an RFC 7807 compliant error response.
+fn handle_bad_query(rejection: &QueryRejection) -> Response {
+    problem(
+        "bad_query",
+        StatusCode::BAD_REQUEST,
+        "Problem Parsing Query".to_string(),
+        rejection.body_text(),
+    )
+}
+
+/// Parse [JsonRejection] into an RFC 7807 compliant error response.
+fn handle_bad_json(rejection: &JsonRejection) -> Response {
+    // Oversized bodies keep their own status, anything else is a bad request.
+    let (kind, status) = match rejection.status() {
+        StatusCode::PAYLOAD_TOO_LARGE => ("payload_too_large", StatusCode::PAYLOAD_TOO_LARGE),
+        _ => ("bad_json", StatusCode::BAD_REQUEST),
+    };
+    problem(
+        kind,
+        status,
+        "Problem Parsing Json".to_string(),
+        rejection.body_text(),
+    )
+}
@@ -0,0 +1,403 @@
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    Query,
+    State,
+    rejection::{JsonRejection, QueryRejection}
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use uuid::Uuid;
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::models::{AuditEntry, ModerationAction, Report, Scope, WebhookEvent};
+use crate::state::{ModerationError, Repository, TimelineCursor, Visibility};
+use crate::webhooks;
+use snap_api::SnapInfo;
+use super::{ApiResponse, ProblemResponse, handle_bad_json, handle_bad_query, page_bounds, problem};
+use super::snaps::{snap_info, snap_not_found};
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct CreateReport {
+    /// What is wrong with the snap, up to `moderation.max_reason_length` characters.
+    reason: String,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct Moderate {
+    /// Why the action was taken, kept in the audit log.
+    justification: String,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct QueueQuery {
+    /// Snaps in the queue, up to `timeline.max_page_size`.
+    limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct AuditQuery {
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+    /// Entries in the page.
+    limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct ReportInfo {
+    id: String,
+    snap_id: String,
+    reporter_id: String,
+    reason: String,
+    created_at: String,
+}
+
+impl From<&Report> for ReportInfo {
+    fn from(report: &Report) -> Self {
+        ReportInfo {
+            id: report.id(),
+            snap_id: report.snap_id(),
+            reporter_id: report.reporter_id(),
+            reason: report.reason().to_string(),
+            created_at: report.created_at().to_rfc3339(),
+        }
+    }
+}
+
+/// Reported snap waiting for a moderator, with its open reports.
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct QueueEntry {
+    snap: SnapInfo,
+    report_count: usize,
+    /// Oldest first.
+    reports: Vec<ReportInfo>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct AuditEntryInfo {
+    id: String,
+    moderator_id: String,
+    snap_id: String,
+    action: ModerationAction,
+    justification: String,
+    at: String,
+    /// Open reports closed by the action.
+    reports: usize,
+}
+
+impl From<&AuditEntry> for AuditEntryInfo {
+    fn from(entry: &AuditEntry) -> Self {
+        AuditEntryInfo {
+            id: entry.id(),
+            moderator_id: entry.moderator_id(),
+            snap_id: entry.snap_id(),
+            action: entry.action(),
+            justification: entry.justification().to_string(),
+            at: entry.at().to_rfc3339(),
+            reports: entry.reports(),
+        }
+    }
+}
+
+/// One page of the audit log. `next_cursor` is `None` on the last page.
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct AuditPage {
+    data: Vec<AuditEntryInfo>,
+    next_cursor: Option<String>,
+}
+
+/// axum handler for "POST /snaps/{id}/reports" which reports a snap to
+/// the moderators. Reporting a snap again before a moderator acted on it
+/// changes nothing.
+#[utoipa::path(
+    post,
+    path = "/snaps/{id}/reports",
+    tag = "moderation",
+    operation_id = "report_snap",
+    summary = "Report a snap to the moderators",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+    ),
+    request_body = CreateReport,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = CREATED, description = "The new report", body = ApiResponse<ReportInfo>),
+        (status = OK, description = "You already reported the snap", body = ApiResponse<ReportInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Empty or too long reason", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn reports_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    extractor: Result<Json<CreateReport>, JsonRejection>,
+) -> Response {
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    let reason = payload.reason.trim();
+    if let Err(response) = check_text("reason", reason, context.config.moderation.max_reason_length) {
+        return *response;
+    }
+    let (Ok(snap_id), Ok(reporter_id)) = (Uuid::parse_str(&id), Uuid::parse_str(&user.id)) else {
+        return snap_not_found(&id);
+    };
+    let report = Report::new(snap_id, reporter_id, reason.to_string());
+    match repo.report(report.clone()) {
+        Ok(true) => {
+            let response = ApiResponse { data: ReportInfo::from(&report) };
+            (StatusCode::CREATED, Json::from(response)).into_response()
+        }
+        Ok(false) => {
+            // The open report of the user, which is kept as it was.
+            let open = repo.reports(&id)
+                .into_iter()
+                .find(|open| open.reporter_id() == user.id)
+                .unwrap_or(report);
+            let response = ApiResponse { data: ReportInfo::from(&open) };
+            (StatusCode::OK, Json::from(response)).into_response()
+        }
+        Err(e) => map_moderation_error(e, &id),
+    }
+}
+
+/// axum handler for "GET /moderation/queue" which lists the snaps with
+/// open reports, the most reported first, then the longest waiting.
+#[utoipa::path(
+    get,
+    path = "/moderation/queue",
+    tag = "moderation",
+    operation_id = "moderation_queue",
+    summary = "List the reported snaps waiting for a moderator",
+    params(QueueQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "Reported snaps, the most reported first", body = ApiResponse<Vec<QueueEntry>>),
+        (status = BAD_REQUEST, description = "Malformed query", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not a moderator, or the API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn queue_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    query: Result<Query<QueueQuery>, QueryRejection>,
+) -> Response {
+    if let Err(response) = require_moderator(&user, &context) {
+        return *response;
+    }
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let (_, limit) = match page_bounds(None, query.limit, &context.config.timeline) {
+        Ok(bounds) => bounds,
+        Err(response) => return *response,
+    };
+    let queue = repo.report_queue(limit)
+        .into_iter()
+        .filter_map(|(id, report_count)| {
+            let snap = repo.get_snap(&id)?;
+            Some(QueueEntry {
+                snap: snap_info(&repo, &snap),
+                report_count,
+                reports: repo.reports(&id).iter().map(ReportInfo::from).collect(),
+            })
+        })
+        .collect::<Vec<QueueEntry>>();
+    (StatusCode::OK, Json::from(ApiResponse { data: queue })).into_response()
+}
+
+/// axum handler for "POST /moderation/snaps/{id}/{action}" which hides,
+/// restores or deletes a snap, closes its open reports and records the
+/// action in the audit log. Restoring a snap that isn't hidden dismisses
+/// its reports.
+#[utoipa::path(
+    post,
+    path = "/moderation/snaps/{id}/{action}",
+    tag = "moderation",
+    operation_id = "moderate_snap",
+    summary = "Hide, restore or delete a snap",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+        ("action" = ModerationAction, Path, description = "What to do with the snap"),
+    ),
+    request_body = Moderate,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The audit entry of the action", body = ApiResponse<AuditEntryInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not a moderator, or the API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Unknown action, or empty or too long justification", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn moderate_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path((id, action)): Path<(String, String)>,
+    extractor: Result<Json<Moderate>, JsonRejection>,
+) -> Response {
+    if let Err(response) = require_moderator(&user, &context) {
+        return *response;
+    }
+    let action = match action.parse::<ModerationAction>() {
+        Ok(action) => action,
+        Err(e) => return problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown action".to_string(),
+            e,
+        ),
+    };
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    let justification = payload.justification.trim();
+    if let Err(response) = check_text("justification", justification, context.config.moderation.max_reason_length) {
+        return *response;
+    }
+    let (Ok(snap_id), Ok(moderator_id)) = (Uuid::parse_str(&id), Uuid::parse_str(&user.id)) else {
+        return snap_not_found(&id);
+    };
+    // Kept to tell the webhooks what was deleted.
+    let before = repo.get_snap(&id);
+    let entry = AuditEntry::new(moderator_id, snap_id, action, justification.to_string());
+    match repo.moderate(entry) {
+        Ok(entry) => {
+            if let (ModerationAction::Delete, Some(snap)) = (action, before) {
+                webhooks::enqueue(&mut repo, WebhookEvent::SnapDeleted, &snap);
+            }
+            let response = ApiResponse { data: AuditEntryInfo::from(&entry) };
+            (StatusCode::OK, Json::from(response)).into_response()
+        }
+        Err(e) => map_moderation_error(e, &id),
+    }
+}
+
+/// axum handler for "GET /moderation/audit" which returns what the
+/// moderators did, from the most recent action to the oldest, one page
+/// at a time.
+#[utoipa::path(
+    get,
+    path = "/moderation/audit",
+    tag = "moderation",
+    operation_id = "moderation_audit",
+    summary = "Page through the audit log of the moderators",
+    params(AuditQuery),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "Actions from the most recent", body = AuditPage),
+        (status = BAD_REQUEST, description = "Malformed query or cursor", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not a moderator, or the API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn audit_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    query: Result<Query<AuditQuery>, QueryRejection>,
+) -> Response {
+    if let Err(response) = require_moderator(&user, &context) {
+        return *response;
+    }
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let (cursor, limit) = match page_bounds(query.cursor.as_deref(), query.limit, &context.config.timeline) {
+        Ok(bounds) => bounds,
+        Err(response) => return *response,
+    };
+    // One extra entry tells whether there is a next page.
+    let mut entries = repo.audit_log(cursor.as_ref(), limit + 1);
+    let next_cursor = if entries.len() > limit {
+        entries.truncate(limit);
+        entries.last().map(|entry| TimelineCursor::from(entry).to_string())
+    } else {
+        None
+    };
+    let page = AuditPage {
+        data: entries.iter().map(AuditEntryInfo::from).collect(),
+        next_cursor,
+    };
+    (StatusCode::OK, Json::from(page)).into_response()
+}
+
+/// What `user`, if signed in, is allowed to see of hidden snaps.
+pub(super) fn visibility(user: Option<&AuthUser>, context: &AppContext) -> Visibility {
+    match user {
+        Some(user) if is_moderator(user, context) => Visibility::Moderator,
+        _ => Visibility::Public,
+    }
+}
+
+/// Whether `user` is a moderator or an administrator of the configuration,
+/// with credentials allowing [Scope::Admin].
+fn is_moderator(user: &AuthUser, context: &AppContext) -> bool {
+    let config = &context.config;
+    user.require(Scope::Admin).is_ok()
+        && (config.moderation.moderators.contains(&user.id) || config.admin.users.contains(&user.id))
+}
+
+/// Fail unless `user` is a moderator, see [is_moderator].
+fn require_moderator(user: &AuthUser, context: &AppContext) -> Result<(), Box<Response>> {
+    user.require(Scope::Admin).map_err(|e| Box::new(e.into_response()))?;
+    if !is_moderator(user, context) {
+        return Err(Box::new(problem(
+            "not_moderator",
+            StatusCode::FORBIDDEN,
+            "Not a moderator".to_string(),
+            "Only the users in moderation.moderators or admin.users can do this".to_string(),
+        )));
+    }
+    Ok(())
+}
+
+/// Fail unless `text` has between 1 and `max` characters, `field` naming it.
+fn check_text(field: &str, text: &str, max: usize) -> Result<(), Box<Response>> {
+    let length = text.chars().count();
+    if length == 0 || length > max {
+        return Err(Box::new(problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("Invalid {field}"),
+            format!("The {field} must have between 1 and {max} characters, it has {length}"),
+        )));
+    }
+    Ok(())
+}
+
+/// Parse [ModerationError] into an RFC 7807 compliant error response.
+fn map_moderation_error(error: ModerationError, id: &str) -> Response {
+    match error {
+        ModerationError::SnapNotFound => snap_not_found(id),
+        ModerationError::UserNotFound => problem(
+            "user_not_found",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown user".to_string(),
+            "No user has the id of the credentials".to_string(),
+        ),
+        ModerationError::StorageError(_) => problem(
+            "moderation",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
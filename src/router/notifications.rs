@@ -0,0 +1,290 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use axum::extract::{
+    Extension,
+    Json,
+    Query,
+    State,
+    rejection::{JsonRejection, QueryRejection}
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::Stream;
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::models::{Notification, NotificationKind, NotificationPreferences, ReactionKind};
+use crate::state::{NotificationError, Repository, TimelineCursor};
+use super::{ApiResponse, ProblemResponse, handle_bad_json, handle_bad_query, page_bounds, problem};
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct NotificationsQuery {
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+    /// Notifications in the page.
+    limit: Option<usize>,
+    /// Leave out notifications already read.
+    #[serde(default)]
+    unread: bool,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct MarkRead {
+    /// Notifications to mark, all of them if missing.
+    ids: Option<Vec<String>>,
+}
+
+/// Preferences to change, the missing ones are kept.
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct EditPreferences {
+    mentions: Option<bool>,
+    replies: Option<bool>,
+    reactions: Option<bool>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct NotificationInfo {
+    id: String,
+    kind: NotificationKind,
+    actor_id: String,
+    snap_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reaction: Option<ReactionKind>,
+    created_at: String,
+    read: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_at: Option<String>,
+}
+
+impl From<&Notification> for NotificationInfo {
+    fn from(notification: &Notification) -> Self {
+        NotificationInfo {
+            id: notification.id(),
+            kind: notification.kind(),
+            actor_id: notification.actor_id(),
+            snap_id: notification.snap_id(),
+            reaction: notification.reaction_kind(),
+            created_at: notification.created_at().to_rfc3339(),
+            read: notification.is_read(),
+            read_at: notification.read_at().map(|time| time.to_rfc3339()),
+        }
+    }
+}
+
+/// One page of notifications. `next_cursor` is `None` on the last page.
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct NotificationPage {
+    data: Vec<NotificationInfo>,
+    next_cursor: Option<String>,
+    /// Unread notifications in the whole inbox, not just this page.
+    unread_count: usize,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct ReadResult {
+    /// Notifications that were unread before the request.
+    marked: usize,
+    unread_count: usize,
+}
+
+/// axum handler for "GET /notifications" which returns the notifications
+/// of the authenticated user, from the most recent to the oldest,
+/// one page at a time.
+#[utoipa::path(
+    get,
+    path = "/notifications",
+    tag = "notifications",
+    operation_id = "list_notifications",
+    summary = "Page through the notifications",
+    params(
+        NotificationsQuery,
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "Notifications from the most recent", body = NotificationPage),
+        (status = BAD_REQUEST, description = "Malformed query or cursor", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn notifications_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    query: Result<Query<NotificationsQuery>, QueryRejection>,
+) -> Response {
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let (cursor, limit) = match page_bounds(query.cursor.as_deref(), query.limit, &context.config.timeline) {
+        Ok(params) => params,
+        Err(response) => return *response,
+    };
+    // One extra notification tells whether there is a next page.
+    let mut notifications = repo.notifications(&user.id, cursor.as_ref(), limit + 1, query.unread);
+    let next_cursor = if notifications.len() > limit {
+        notifications.truncate(limit);
+        notifications.last().map(|notification| TimelineCursor::from(notification).to_string())
+    } else {
+        None
+    };
+    let page = NotificationPage {
+        data: notifications.iter().map(NotificationInfo::from).collect(),
+        next_cursor,
+        unread_count: repo.unread_count(&user.id),
+    };
+    (StatusCode::OK, Json::from(page)).into_response()
+}
+
+/// axum handler for "POST /notifications/read" which marks notifications
+/// of the authenticated user as read.
+#[utoipa::path(
+    post,
+    path = "/notifications/read",
+    tag = "notifications",
+    operation_id = "mark_notifications_read",
+    summary = "Mark notifications as read",
+    request_body = MarkRead,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "How many were marked", body = ApiResponse<ReadResult>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn notifications_read_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    extractor: Result<Json<MarkRead>, JsonRejection>,
+) -> Response {
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    match repo.mark_read(&user.id, payload.ids.as_deref()) {
+        Ok(marked) => {
+            let result = ReadResult { marked, unread_count: repo.unread_count(&user.id) };
+            (StatusCode::OK, Json::from(ApiResponse { data: result })).into_response()
+        }
+        Err(e) => map_notification_error(e),
+    }
+}
+
+/// axum handler for "GET /notifications/preferences" which returns the
+/// kinds of notifications the authenticated user gets.
+#[utoipa::path(
+    get,
+    path = "/notifications/preferences",
+    tag = "notifications",
+    operation_id = "get_notification_preferences",
+    summary = "Get the kinds of notifications received",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The preferences", body = ApiResponse<NotificationPreferences>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn preferences_get_handler<S: Repository>(
+    State(repo): State<S>,
+    user: AuthUser,
+) -> Response {
+    let response = ApiResponse { data: repo.notification_preferences(&user.id) };
+    (StatusCode::OK, Json::from(response)).into_response()
+}
+
+/// axum handler for "PATCH /notifications/preferences" which turns kinds
+/// of notifications on or off for the authenticated user. Notifications
+/// of kinds turned off aren't stored nor pushed.
+#[utoipa::path(
+    patch,
+    path = "/notifications/preferences",
+    tag = "notifications",
+    operation_id = "edit_notification_preferences",
+    summary = "Turn kinds of notifications on or off",
+    request_body = EditPreferences,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The new preferences", body = ApiResponse<NotificationPreferences>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn preferences_patch_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    extractor: Result<Json<EditPreferences>, JsonRejection>,
+) -> Response {
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    let current = repo.notification_preferences(&user.id);
+    let preferences = NotificationPreferences {
+        mentions: payload.mentions.unwrap_or(current.mentions),
+        replies: payload.replies.unwrap_or(current.replies),
+        reactions: payload.reactions.unwrap_or(current.reactions),
+    };
+    match repo.set_notification_preferences(&user.id, preferences) {
+        Ok(()) => (StatusCode::OK, Json::from(ApiResponse { data: preferences })).into_response(),
+        Err(e) => map_notification_error(e),
+    }
+}
+
+/// axum handler for "GET /notifications/stream" which pushes the new
+/// notifications of the authenticated user as server-sent events named
+/// `notification`. A `lagged` event tells the client it missed some and
+/// should fetch `GET /notifications` again.
+#[utoipa::path(
+    get,
+    path = "/notifications/stream",
+    tag = "notifications",
+    operation_id = "stream_notifications",
+    summary = "Stream new notifications as server-sent events",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "`notification` events with a notification as data, and `lagged` events with how many were missed", body = String, content_type = "text/event-stream"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn notifications_stream_handler(
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let guard = context.metrics.stream_opened();
+    let subscription = context.notifier.subscribe(&user.id);
+    let events = futures_util::stream::unfold((subscription, guard), |(mut subscription, guard)| async move {
+        let event = match subscription.recv().await? {
+            Ok(notification) => Event::default()
+                .event("notification")
+                .id(notification.id())
+                .json_data(NotificationInfo::from(&notification))
+                .expect("notifications are always serializable"),
+            Err(missed) => Event::default()
+                .event("lagged")
+                .data(missed.to_string()),
+        };
+        Some((Ok(event), (subscription, guard)))
+    });
+    let interval = Duration::from_secs(context.config.notifications.keep_alive_secs);
+    Sse::new(events).keep_alive(KeepAlive::new().interval(interval))
+}
+
+/// Parse [NotificationError] into an RFC 7807 compliant error response.
+fn map_notification_error(error: NotificationError) -> Response {
+    match error {
+        NotificationError::UserNotFound => problem(
+            "author_not_found",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown user".to_string(),
+            "No user has the authenticated id".to_string(),
+        ),
+        NotificationError::StorageError(_) => problem(
+            "notification",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
@@ -0,0 +1,136 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use super::{admin, api_keys, follows, media, moderation, notifications, probes, reactions, replication, search, snaps, tags, tenants, users, webhooks};
+
+/// OpenAPI document of the API, built from the handlers and their payloads.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Snap API",
+        description = "Short messages with replies, reactions, attachments, notifications, webhooks and moderation.",
+    ),
+    paths(
+        probes::healthz_handler,
+        probes::readyz_handler,
+        probes::metrics_handler,
+        snaps::snaps_get_handler,
+        snaps::snaps_post_handler,
+        snaps::snaps_stream_handler,
+        snaps::snap_get_handler,
+        snaps::snap_thread_get_handler,
+        snaps::snap_patch_handler,
+        snaps::snap_delete_handler,
+        reactions::reactors_get_handler,
+        reactions::reaction_put_handler,
+        reactions::reaction_delete_handler,
+        media::media_get_handler,
+        users::users_post_handler,
+        users::user_get_handler,
+        users::user_snaps_get_handler,
+        users::login_post_handler,
+        users::refresh_post_handler,
+        follows::follow_put_handler,
+        follows::follow_delete_handler,
+        follows::followers_get_handler,
+        follows::following_get_handler,
+        follows::timeline_get_handler,
+        search::search_get_handler,
+        tags::trending_get_handler,
+        tags::tag_snaps_get_handler,
+        notifications::notifications_get_handler,
+        notifications::notifications_read_post_handler,
+        notifications::preferences_get_handler,
+        notifications::preferences_patch_handler,
+        notifications::notifications_stream_handler,
+        api_keys::api_keys_get_handler,
+        api_keys::api_keys_post_handler,
+        api_keys::api_key_delete_handler,
+        webhooks::webhooks_get_handler,
+        webhooks::webhooks_post_handler,
+        webhooks::webhook_get_handler,
+        webhooks::webhook_patch_handler,
+        webhooks::webhook_delete_handler,
+        webhooks::deliveries_get_handler,
+        webhooks::delivery_retry_post_handler,
+        moderation::reports_post_handler,
+        moderation::queue_get_handler,
+        moderation::moderate_post_handler,
+        moderation::audit_get_handler,
+        admin::export_get_handler,
+        admin::import_post_handler,
+        admin::snapshots_post_handler,
+        admin::snapshots_get_handler,
+        admin::snapshot_get_handler,
+        admin::projections_get_handler,
+        admin::projections_replay_post_handler,
+        replication::changes_get_handler,
+        replication::status_get_handler,
+        tenants::tenants_get_handler,
+        tenants::tenants_post_handler,
+        tenants::tenant_get_handler,
+        tenants::tenant_patch_handler,
+        tenants::tenant_suspend_post_handler,
+        tenants::tenant_resume_post_handler,
+    ),
+    tags(
+        (name = "snaps", description = "Posting, reading and editing snaps"),
+        (name = "reactions", description = "Emoji reactions on snaps"),
+        (name = "media", description = "Attachments and thumbnails"),
+        (name = "users", description = "Registration and profiles"),
+        (name = "auth", description = "Access and refresh tokens"),
+        (name = "follows", description = "Follows and the home timeline"),
+        (name = "tags", description = "Hashtags"),
+        (name = "notifications", description = "Inbox and live stream of notifications"),
+        (name = "api_keys", description = "Long lived keys for bots and batch jobs"),
+        (name = "webhooks", description = "Signed HTTP callbacks on snap events"),
+        (name = "moderation", description = "Reports of abusive snaps and what moderators did about them"),
+        (name = "admin", description = "Dumps, snapshots and read models of the store, for operators"),
+        (name = "replication", description = "Change log streamed from the leader to its followers"),
+        (name = "tenants", description = "Teams sharing the server, each with its own store"),
+        (name = "probes", description = "Health checks and metrics"),
+    ),
+    modifiers(&Credentials),
+)]
+struct ApiDoc;
+
+/// OpenAPI document of the API, as served at "GET /openapi.json".
+pub(super) fn document() -> utoipa::openapi::OpenApi {
+    let mut document = ApiDoc::openapi();
+    // Taken from the package, which has no license to tell.
+    document.info.license = None;
+    document
+}
+
+/// Adds the ways to authenticate: access tokens and API keys, and the
+/// admin token of the tenants.
+struct Credentials;
+
+impl Modify for Credentials {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some("Access token from `POST /login`"))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "Authorization",
+                "`ApiKey <key>` with a key from `POST /api-keys`",
+            ))),
+        );
+        components.add_security_scheme(
+            "admin_token",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Snap-Admin-Token",
+                "The `tenancy.admin_token` of the server",
+            ))),
+        );
+    }
+}
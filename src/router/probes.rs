@@ -0,0 +1,98 @@
+use axum::extract::{Extension, Json, State};
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use crate::context::{AppContext, Phase};
+use crate::state::SnapAppState;
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct Readiness {
+    phase: Phase,
+    checks: Vec<CheckInfo>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct CheckInfo {
+    name: String,
+    ok: bool,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// axum handler for "GET /healthz". Answering at all means
+/// the process is alive, so it always returns OK (200).
+#[utoipa::path(
+    get,
+    path = "/healthz",
+    tag = "probes",
+    operation_id = "healthz",
+    summary = "Liveness probe",
+    responses(
+        (status = OK, description = "The process is alive", body = Object, example = json!({"status": "ok"})),
+    ),
+)]
+pub(super) async fn healthz_handler() -> impl IntoResponse {
+    (StatusCode::OK, Json::from(serde_json::json!({ "status": "ok" })))
+}
+
+/// axum handler for "GET /readyz" which runs the repository self checks.
+/// Returns Service Unavailable (503) if a check fails or the server
+/// is starting up or shutting down.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "probes",
+    operation_id = "readyz",
+    summary = "Readiness probe running the repository checks",
+    responses(
+        (status = OK, description = "Ready to serve", body = Readiness),
+        (status = SERVICE_UNAVAILABLE, description = "Starting, shutting down or failing a check", body = Readiness),
+    ),
+)]
+pub(super) async fn readyz_handler<S: SnapAppState>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+) -> impl IntoResponse {
+    let phase = context.readiness.phase();
+    let checks = repo.health()
+        .iter()
+        .map(|check|
+            CheckInfo {
+                name: check.name.to_string(),
+                ok: check.ok(),
+                latency_ms: check.latency.as_secs_f64() * 1000.0,
+                error: check.error.clone(),
+            }
+        )
+        .collect::<Vec<CheckInfo>>();
+
+    let status = if phase == Phase::Ready && checks.iter().all(|check| check.ok) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json::from(Readiness { phase, checks }))
+}
+
+/// axum handler for "GET /metrics" which exposes the app
+/// metrics in the Prometheus text format.
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "probes",
+    operation_id = "metrics",
+    summary = "Metrics in the Prometheus text format",
+    responses(
+        (status = OK, description = "Prometheus metrics", body = String, content_type = "text/plain; version=0.0.4"),
+    ),
+)]
+pub(super) async fn metrics_handler<S: SnapAppState>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        context.metrics.render(repo.snap_count()),
+    )
+}
@@ -0,0 +1,164 @@
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    State,
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::models::ReactionKind;
+use crate::notifications;
+use crate::state::{ReactionError, Repository, Visibility};
+use super::{ApiResponse, ProblemResponse, problem};
+use super::moderation::visibility;
+use super::snaps::snap_not_found;
+use super::users::UserInfo;
+
+/// axum handler for "PUT /snaps/{id}/reactions/{kind}" which records
+/// a reaction of the authenticated user and notifies the author.
+/// Reacting twice is a no-op.
+#[utoipa::path(
+    put,
+    path = "/snaps/{id}/reactions/{kind}",
+    tag = "reactions",
+    operation_id = "react",
+    summary = "React to a snap",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+        ("kind" = ReactionKind, Path, description = "Kind of reaction"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The reaction was recorded, or already was"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Unknown kind of reaction", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn reaction_put_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path((id, kind)): Path<(String, String)>,
+) -> Response {
+    let kind = match kind.parse::<ReactionKind>() {
+        Ok(kind) => kind,
+        Err(e) => return unknown_reaction(e),
+    };
+    match repo.react(&id, &user.id, kind) {
+        Ok(added) => {
+            if let Some(snap) = repo.get_snap(&id).filter(|_| added) {
+                notifications::reacted(&mut repo, &context.notifier, &snap, &user.id, kind);
+            }
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => map_reaction_error(e, &id),
+    }
+}
+
+/// axum handler for "DELETE /snaps/{id}/reactions/{kind}" which removes
+/// a reaction of the authenticated user, if there was one.
+#[utoipa::path(
+    delete,
+    path = "/snaps/{id}/reactions/{kind}",
+    tag = "reactions",
+    operation_id = "unreact",
+    summary = "Remove a reaction from a snap",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+        ("kind" = ReactionKind, Path, description = "Kind of reaction"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The reaction is gone"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Unknown kind of reaction", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn reaction_delete_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    Path((id, kind)): Path<(String, String)>,
+) -> Response {
+    let kind = match kind.parse::<ReactionKind>() {
+        Ok(kind) => kind,
+        Err(e) => return unknown_reaction(e),
+    };
+    match repo.unreact(&id, &user.id, kind) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => map_reaction_error(e, &id),
+    }
+}
+
+/// axum handler for "GET /snaps/{id}/reactions/{kind}" which lists the
+/// users who reacted to a snap with a kind, from the first to the last.
+/// Hidden snaps are only found by moderators.
+#[utoipa::path(
+    get,
+    path = "/snaps/{id}/reactions/{kind}",
+    tag = "reactions",
+    operation_id = "list_reactors",
+    summary = "List the users who reacted to a snap with a kind",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+        ("kind" = ReactionKind, Path, description = "Kind of reaction"),
+    ),
+    responses(
+        (status = OK, description = "Users from the first to react to the last", body = ApiResponse<Vec<UserInfo>>),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Unknown kind of reaction", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn reactors_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: Option<AuthUser>,
+    Path((id, kind)): Path<(String, String)>,
+) -> Response {
+    let kind = match kind.parse::<ReactionKind>() {
+        Ok(kind) => kind,
+        Err(e) => return unknown_reaction(e),
+    };
+    let visibility = visibility(user.as_ref(), &context);
+    match repo.get_snap(&id) {
+        Some(snap) if !snap.is_deleted() && (visibility == Visibility::Moderator || !snap.is_hidden()) => {},
+        _ => return snap_not_found(&id),
+    }
+    let users = repo.reactors(&id, kind)
+        .iter()
+        .filter_map(|id| repo.get_user(id))
+        .map(|user| UserInfo::from(&user))
+        .collect::<Vec<UserInfo>>();
+    (StatusCode::OK, Json::from(ApiResponse { data: users })).into_response()
+}
+
+fn unknown_reaction(detail: String) -> Response {
+    problem(
+        "unknown_reaction",
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Unknown reaction".to_string(),
+        detail,
+    )
+}
+
+/// Parse [ReactionError] into an RFC 7807 compliant error response.
+fn map_reaction_error(error: ReactionError, id: &str) -> Response {
+    match error {
+        ReactionError::SnapNotFound => snap_not_found(id),
+        ReactionError::UserNotFound => problem(
+            "author_not_found",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown user".to_string(),
+            "No user has the authenticated id".to_string(),
+        ),
+        ReactionError::StorageError(_) => problem(
+            "reaction",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
@@ -0,0 +1,179 @@
+use std::convert::Infallible;
+use std::time::Instant;
+use axum::body::Body;
+use axum::extract::{
+    Extension,
+    Json,
+    Query,
+    Request,
+    State,
+    rejection::QueryRejection
+};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use crate::context::{AppContext, Phase};
+use crate::replication::{self, Frame, HEARTBEAT, SECRET_HEADER};
+use crate::state::Repository;
+use snap_api::ReplicationStatus;
+use super::{ApiResponse, ProblemResponse, handle_bad_query, problem};
+
+/// Changes sent at most in one go.
+const BATCH: usize = 500;
+
+/// Writes a follower still takes itself, as they only touch its own disk.
+const LOCAL_WRITES: [&str; 2] = ["/admin/snapshots", "/admin/projections/replay"];
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct ChangesQuery {
+    /// Offset of the first change to send, the number the follower has.
+    #[serde(default)]
+    from: u64,
+}
+
+/// axum handler for "GET /replication/changes" which streams the change
+/// log from `from` as NDJSON [Frame]s, then the new changes as they are
+/// made, with a `head` frame after each batch and when idle. Followers
+/// behind the oldest change kept get Gone (410).
+/// Only answers requests with the replication secret.
+#[utoipa::path(
+    get,
+    path = "/replication/changes",
+    tag = "replication",
+    operation_id = "replication_changes",
+    summary = "Stream the change log to a follower",
+    params(ChangesQuery, ("X-Snap-Replication-Secret" = String, Header, description = "The `replication.secret` of the leader")),
+    responses(
+        (status = OK, description = "`change` and `head` frames, one per line, until the leader stops", body = String, content_type = "application/x-ndjson"),
+        (status = BAD_REQUEST, description = "Invalid query", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Replication is off or the secret is wrong", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "The follower has more changes than the leader", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = GONE, description = "The leader no longer keeps the changes from `from`", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn changes_get_handler<S: Repository + Send + 'static>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    headers: HeaderMap,
+    query: Result<Query<ChangesQuery>, QueryRejection>,
+) -> Response {
+    let given = headers.get(SECRET_HEADER).and_then(|value| value.to_str().ok());
+    let allowed = match (&context.config.replication.secret, given) {
+        (Some(secret), Some(given)) => replication::secret_matches(secret, given),
+        _ => false,
+    };
+    if !allowed {
+        return problem(
+            "replication_forbidden",
+            StatusCode::FORBIDDEN,
+            "Replication forbidden".to_string(),
+            "The change log needs the replication secret".to_string(),
+        )
+    }
+    let from = match query {
+        Ok(Query(query)) => query.from,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let head = repo.change_count();
+    if from > head {
+        return problem(
+            "replication_diverged",
+            StatusCode::CONFLICT,
+            "Diverged follower".to_string(),
+            format!("The follower has {from} changes but the leader only {head}"),
+        )
+    }
+    let first = repo.first_change();
+    if from < first {
+        return problem(
+            "replication_behind",
+            StatusCode::GONE,
+            "Follower behind".to_string(),
+            format!("The leader only keeps the changes from {first}, the follower has {from}"),
+        )
+    }
+
+    let guard = context.metrics.stream_opened();
+    let poll_interval = std::time::Duration::from_millis(context.config.replication.poll_interval_ms);
+    // The stream ends on shutdown, so it doesn't hold up the drain.
+    let state = (repo, context.readiness.clone(), from, None::<Instant>, guard);
+    let frames = futures_util::stream::unfold(state, move |(repo, readiness, mut next, mut sent_at, guard)| async move {
+        loop {
+            if readiness.phase() == Phase::ShuttingDown {
+                return None
+            }
+            // A follower that falls behind is turned away when it reconnects.
+            let changes = match repo.changes(next, BATCH) {
+                Ok(changes) => changes,
+                Err(e) => {
+                    tracing::warn!(offset = next, "change stream ended: {e}");
+                    return None
+                }
+            };
+            let idle = match sent_at {
+                Some(at) => at.elapsed() >= HEARTBEAT,
+                None => true,
+            };
+            if !changes.is_empty() || idle {
+                let mut lines = String::new();
+                for change in changes {
+                    lines.push_str(&Frame::Change { offset: next, change }.to_line());
+                    next += 1;
+                }
+                lines.push_str(&Frame::Head { offset: repo.change_count().max(next) }.to_line());
+                sent_at = Some(Instant::now());
+                return Some((Ok::<String, Infallible>(lines), (repo, readiness, next, sent_at, guard)))
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    });
+    (
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(frames),
+    ).into_response()
+}
+
+/// axum handler for "GET /replication/status" which tells whether the
+/// server follows a leader and how many changes it is behind.
+#[utoipa::path(
+    get,
+    path = "/replication/status",
+    tag = "replication",
+    operation_id = "replication_status",
+    summary = "Replication role and lag",
+    responses(
+        (status = OK, description = "Role, offsets and lag", body = ApiResponse<ReplicationStatus>),
+    ),
+)]
+pub(super) async fn status_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+) -> Response {
+    let status = context.replication.status(&context.config.replication, repo.change_count());
+    Json::from(ApiResponse { data: status }).into_response()
+}
+
+/// Middleware answering the writes made to a follower with a Temporary
+/// Redirect (307) to the same URL on the `leader`, keeping the method
+/// and body.
+pub(super) async fn redirect_writes(leader: String, request: Request, next: Next) -> Response {
+    let read = matches!(*request.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    if read || LOCAL_WRITES.contains(&request.uri().path()) {
+        return next.run(request).await
+    }
+    let target = request.uri()
+        .path_and_query()
+        .map_or("/", |path| path.as_str());
+    let location = format!("{}{target}", leader.trim_end_matches('/'));
+    let mut response = problem(
+        "read_only_follower",
+        StatusCode::TEMPORARY_REDIRECT,
+        "Read-only follower".to_string(),
+        format!("Writes go to the leader at {location}"),
+    );
+    if let Ok(location) = HeaderValue::from_str(&location) {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
@@ -0,0 +1,71 @@
+use axum::extract::{
+    Extension,
+    Query,
+    State,
+    rejection::QueryRejection
+};
+use axum::http::StatusCode;
+use axum::response::Response;
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::projections;
+use crate::state::Repository;
+use super::{Page, ProblemResponse, handle_bad_query, page_bounds, page_response, problem};
+use super::moderation::visibility;
+use super::snaps::live_snaps;
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct SearchQuery {
+    /// Words every snap must have, ignoring case and punctuation.
+    q: String,
+    /// `next_cursor` of the previous page.
+    cursor: Option<String>,
+    /// Snaps in the page.
+    limit: Option<usize>,
+}
+
+/// axum handler for "GET /search" which returns the snaps with every
+/// word of the query, from the most recent to the oldest, one page at a time.
+#[utoipa::path(
+    get,
+    path = "/search",
+    tag = "snaps",
+    operation_id = "search_snaps",
+    summary = "Search the snaps by their words",
+    params(SearchQuery),
+    responses(
+        (status = OK, description = "Matching snaps from the most recent", body = Page),
+        (status = BAD_REQUEST, description = "Malformed query or cursor", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "No words to search or limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn search_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: Option<AuthUser>,
+    query: Result<Query<SearchQuery>, QueryRejection>,
+) -> Response {
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    if projections::terms(&query.q).is_empty() {
+        return problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid query".to_string(),
+            "The query needs at least one word".to_string(),
+        );
+    }
+    let (cursor, limit) = match page_bounds(query.cursor.as_deref(), query.limit, &context.config.timeline) {
+        Ok(bounds) => bounds,
+        Err(response) => return *response,
+    };
+    let visibility = visibility(user.as_ref(), &context);
+    // One extra snap tells whether there is a next page.
+    let ids = context.projections.read(&repo, |projections| {
+        projections.search.search(&query.q, cursor.as_ref(), limit + 1, visibility)
+    });
+    page_response(&repo, live_snaps(&repo, &ids, visibility), limit)
+}
@@ -0,0 +1,697 @@
+use std::convert::Infallible;
+use std::time::Duration;
+use axum::body::Bytes;
+use axum::extract::{
+    Extension,
+    FromRequest,
+    Json,
+    Multipart,
+    Path,
+    Query,
+    Request,
+    State,
+    rejection::{JsonRejection, QueryRejection}
+};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::Stream;
+use crate::auth::AuthUser;
+use crate::config::MediaConfig;
+use crate::context::AppContext;
+use crate::entities;
+use crate::models::{Attachment, Snap, WebhookEvent};
+use crate::{media, notifications, webhooks};
+use crate::images::ImageError;
+use crate::media::MediaError;
+use crate::state::{Repository, SnapAppState, SnapCreationError, SnapUpdateError, Visibility};
+use snap_api::{AttachmentInfo, CreateSnap, EditSnap, SnapCreated, SnapInfo, ThumbnailInfo};
+use super::moderation::visibility;
+use super::tenants::quota_exceeded;
+use super::{
+    ApiResponse,
+    Page,
+    PageQuery,
+    ProblemResponse,
+    handle_bad_json,
+    handle_bad_query,
+    page_bounds,
+    page_response,
+    problem,
+};
+
+/// Snap sent to "POST /snaps", either as JSON or as `multipart/form-data`
+/// with `message` and `in_reply_to` fields and `attachments` files.
+#[derive(utoipa::ToSchema)]
+#[schema(as = SnapForm)]
+struct NewSnap {
+    message: String,
+    in_reply_to: Option<String>,
+    #[schema(value_type = Vec<String>, format = Binary, required = false)]
+    attachments: Vec<Bytes>,
+}
+
+impl From<&Attachment> for AttachmentInfo {
+    fn from(attachment: &Attachment) -> Self {
+        AttachmentInfo {
+            hash: attachment.hash.clone(),
+            content_type: attachment.content_type.clone(),
+            size: attachment.size,
+            width: attachment.width,
+            height: attachment.height,
+            url: format!("/media/{}", attachment.hash),
+            thumbnails: attachment.thumbnails
+                .iter()
+                .map(|thumbnail| ThumbnailInfo {
+                    max_dimension: thumbnail.max_dimension,
+                    content_type: thumbnail.content_type.clone(),
+                    width: thumbnail.width,
+                    height: thumbnail.height,
+                    url: format!("/media/{}", thumbnail.hash),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct ThreadQuery {
+    /// Levels of replies under the root, up to `limits.max_thread_depth`.
+    depth: Option<usize>,
+}
+
+/// Info on `snap`, with its reply and reaction counts from `repo`.
+pub(super) fn snap_info<S: SnapAppState>(repo: &S, snap: &Snap) -> SnapInfo {
+    let id = snap.id();
+    SnapInfo {
+        reply_count: repo.reply_count(&id),
+        reactions: repo.reaction_counts(&id),
+        entities: entities::extract(snap.message()),
+        attachments: snap.attachments().iter().map(AttachmentInfo::from).collect(),
+        id: snap.id(),
+        author_id: snap.author_id(),
+        message: snap.message().to_string(),
+        edited_at: snap.edited_at().map(|time| time.to_rfc3339()),
+        in_reply_to: snap.in_reply_to(),
+        deleted_at: snap.deleted_at().map(|time| time.to_rfc3339()),
+        hidden_at: snap.hidden_at().map(|time| time.to_rfc3339()),
+    }
+}
+
+/// Same as [snap_info], leaving out the contents of a hidden snap
+/// unless `visibility` lets a moderator see them.
+fn visible_snap_info<S: SnapAppState>(repo: &S, snap: &Snap, visibility: Visibility) -> SnapInfo {
+    let mut info = snap_info(repo, snap);
+    if snap.is_hidden() && visibility == Visibility::Public {
+        info.message.clear();
+        info.entities.clear();
+        info.attachments.clear();
+    }
+    info
+}
+
+/// Info on each of `snaps`, see [snap_info].
+pub(super) fn snap_infos<S: SnapAppState>(repo: &S, snaps: &[Snap]) -> Vec<SnapInfo> {
+    snaps.iter()
+        .map(|snap| snap_info(repo, snap))
+        .collect()
+}
+
+/// A snap and its replies, up to the requested depth. Snaps at the
+/// last level have no `replies` but still tell their `reply_count`.
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct ThreadNode {
+    #[serde(flatten)]
+    snap: SnapInfo,
+    #[schema(no_recursion)]
+    replies: Vec<ThreadNode>,
+}
+
+/// axum handler for "GET /snaps" which return a list
+/// of snaps in JSON format, from the most recent to the oldest.
+/// Every snap comes in a single page unless a `cursor` or a
+/// `limit` is given. Hidden snaps only show to moderators.
+#[utoipa::path(
+    get,
+    path = "/snaps",
+    tag = "snaps",
+    operation_id = "list_snaps",
+    summary = "List every snap, optionally one page at a time",
+    params(PageQuery),
+    responses(
+        (status = OK, description = "Snaps from the most recent", body = Page),
+        (status = BAD_REQUEST, description = "Malformed query or cursor", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snaps_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: Option<AuthUser>,
+    query: Result<Query<PageQuery>, QueryRejection>,
+) -> Response {
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let visibility = visibility(user.as_ref(), &context);
+    if query.cursor.is_none() && query.limit.is_none() {
+        let ids = context.projections.read(&repo, |projections| {
+            projections.timeline.page(None, usize::MAX, visibility)
+        });
+        let page = Page { data: snap_infos(&repo, &live_snaps(&repo, &ids, visibility)), next_cursor: None };
+        return (StatusCode::OK, Json::from(page)).into_response()
+    }
+    let (cursor, limit) = match page_bounds(query.cursor.as_deref(), query.limit, &context.config.timeline) {
+        Ok(bounds) => bounds,
+        Err(response) => return *response,
+    };
+    // One extra snap tells whether there is a next page.
+    let ids = context.projections.read(&repo, |projections| {
+        projections.timeline.page(cursor.as_ref(), limit + 1, visibility)
+    });
+    page_response(&repo, live_snaps(&repo, &ids, visibility), limit)
+}
+
+/// Snaps with these `ids` in the same order, leaving out the ones
+/// deleted, or hidden from `visibility`, since a projection listed them.
+pub(super) fn live_snaps<S: SnapAppState>(repo: &S, ids: &[String], visibility: Visibility) -> Vec<Snap> {
+    ids.iter()
+        .filter_map(|id| repo.get_snap(id))
+        .filter(|snap| !snap.is_deleted() && (visibility == Visibility::Moderator || !snap.is_hidden()))
+        .collect()
+}
+
+/// axum handler for "GET /snaps/stream" which pushes the snaps of every
+/// user as server-sent events named `snap`, as they are posted. A
+/// `lagged` event tells the client it missed some.
+#[utoipa::path(
+    get,
+    path = "/snaps/stream",
+    tag = "snaps",
+    operation_id = "stream_snaps",
+    summary = "Stream new snaps as server-sent events",
+    responses(
+        (status = OK, description = "`snap` events with a snap as data, and `lagged` events with how many were missed", body = String, content_type = "text/event-stream"),
+    ),
+)]
+pub(super) async fn snaps_stream_handler<S: SnapAppState + Send + 'static>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let guard = context.metrics.stream_opened();
+    let feed = context.notifier.feed();
+    let events = futures_util::stream::unfold((feed, repo, guard), |(mut feed, repo, guard)| async move {
+        let event = match feed.recv().await? {
+            Ok(snap) => Event::default()
+                .event("snap")
+                .id(snap.id())
+                .json_data(snap_info(&repo, &snap))
+                .expect("snaps are always serializable"),
+            Err(missed) => Event::default()
+                .event("lagged")
+                .data(missed.to_string()),
+        };
+        Some((Ok(event), (feed, repo, guard)))
+    });
+    let interval = Duration::from_secs(context.config.notifications.keep_alive_secs);
+    Sse::new(events).keep_alive(KeepAlive::new().interval(interval))
+}
+
+/// axum handler for "POST /snaps" which creates a new
+/// snap in the repository, written by the authenticated user, and
+/// notifies the users it mentions or replies to and the webhooks.
+/// Accepts JSON, or `multipart/form-data` to attach images.
+/// Will return some info on the new snap alongside the status code.
+#[utoipa::path(
+    post,
+    path = "/snaps",
+    tag = "snaps",
+    operation_id = "create_snap",
+    summary = "Post a snap, optionally replying to another or with images",
+    request_body(content((CreateSnap = "application/json"), (NewSnap = "multipart/form-data"))),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = CREATED, description = "The new snap", body = ApiResponse<SnapCreated>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The tenant reached its snap quota", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = PAYLOAD_TOO_LARGE, description = "Body or attachment too large", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNSUPPORTED_MEDIA_TYPE, description = "Attachment of an unsupported type", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Message too long, unknown parent, too many attachments or invalid image", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snaps_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    request: Request,
+) -> Response {
+    let payload = match read_new_snap(request, &context.config.media).await {
+        Ok(payload) => payload,
+        Err(response) => return *response,
+    };
+    let limits = &context.config.limits;
+    let length = payload.message.chars().count();
+    if length > limits.max_message_length {
+        return message_too_long(length, limits.max_message_length);
+    }
+    let (attachments, created) = match store_attachments(&context, payload.attachments).await {
+        Ok(stored) => stored,
+        Err(response) => return *response,
+    };
+    let fan_out = context.config.timeline.fan_out;
+    let result = context.metrics.time_repository("post", fan_out, || {
+        match (&payload.in_reply_to, attachments.is_empty()) {
+            (None, true) => repo.post(&user.id, &payload.message),
+            (Some(parent), true) => repo.reply(&user.id, parent, &payload.message),
+            (parent, false) => repo.post_with_attachments(
+                &user.id,
+                parent.as_deref(),
+                &payload.message,
+                attachments,
+            ),
+        }
+    });
+    match result {
+        Ok(snap) => {
+            notifications::snap_posted(&mut repo, &context.notifier, &snap);
+            context.notifier.publish_snap(snap.clone());
+            webhooks::enqueue(&mut repo, WebhookEvent::SnapCreated, &snap);
+            let payload = SnapCreated {
+                id: snap.id(),
+                author_id: snap.author_id(),
+                message: snap.message().to_string(),
+                in_reply_to: snap.in_reply_to(),
+                attachments: snap.attachments().iter().map(AttachmentInfo::from).collect(),
+            };
+            let response = ApiResponse { data: payload };
+            (StatusCode::CREATED, Json::from(response)).into_response()
+        },
+        Err(e) => {
+            context.pipeline.discard(&created);
+            map_snap_creation_error(e, &context)
+        }
+    }
+}
+
+/// Read the body of "POST /snaps" as multipart when the client says so,
+/// as JSON otherwise.
+async fn read_new_snap(request: Request, config: &MediaConfig) -> Result<NewSnap, Box<Response>> {
+    let multipart = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("multipart/form-data"));
+    if !multipart {
+        return match Json::<CreateSnap>::from_request(request, &()).await {
+            Ok(Json(payload)) => Ok(NewSnap {
+                message: payload.message,
+                in_reply_to: payload.in_reply_to,
+                attachments: Vec::new(),
+            }),
+            Err(rejection) => Err(Box::new(handle_bad_json(&rejection))),
+        }
+    }
+
+    let bad_multipart = |status: StatusCode, detail: String| {
+        let kind = match status {
+            StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+            _ => "bad_multipart",
+        };
+        Box::new(problem(kind, status, "Problem Parsing Multipart".to_string(), detail))
+    };
+    let mut form = Multipart::from_request(request, &())
+        .await
+        .map_err(|rejection| bad_multipart(rejection.status(), rejection.body_text()))?;
+    let mut message = None;
+    let mut in_reply_to = None;
+    let mut files = Vec::new();
+    while let Some(field) = form.next_field()
+        .await
+        .map_err(|e| bad_multipart(e.status(), e.body_text()))?
+    {
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "message" | "in_reply_to" => {
+                let text = field.text()
+                    .await
+                    .map_err(|e| bad_multipart(e.status(), e.body_text()))?;
+                if name == "message" {
+                    message = Some(text);
+                } else if !text.is_empty() {
+                    in_reply_to = Some(text);
+                }
+            },
+            "attachments" => {
+                if files.len() == config.max_attachments {
+                    return Err(Box::new(problem(
+                        "invalid_field",
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        "Too many attachments".to_string(),
+                        format!("A snap can have up to {} attachments", config.max_attachments),
+                    )))
+                }
+                let bytes = field.bytes()
+                    .await
+                    .map_err(|e| bad_multipart(e.status(), e.body_text()))?;
+                if bytes.len() > config.max_bytes {
+                    return Err(Box::new(problem(
+                        "payload_too_large",
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "Attachment too large".to_string(),
+                        format!("Attachments can have up to {} bytes", config.max_bytes),
+                    )))
+                }
+                files.push(bytes);
+            },
+            _ => return Err(bad_multipart(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown field {name:?}, expected message, in_reply_to or attachments"),
+            )),
+        }
+    }
+    let Some(message) = message else {
+        return Err(bad_multipart(StatusCode::BAD_REQUEST, "Missing field \"message\"".to_string()));
+    };
+    Ok(NewSnap { message, in_reply_to, attachments: files })
+}
+
+/// Check each of `files` is an image, clean and shrink it, and put it
+/// in the blob store of `context` with its thumbnails. Returns the
+/// attachments along with the blobs they added to the store.
+async fn store_attachments(context: &AppContext, files: Vec<Bytes>) -> Result<(Vec<Attachment>, Vec<String>), Box<Response>> {
+    let mut attachments = Vec::with_capacity(files.len());
+    let mut created = Vec::new();
+    for bytes in files {
+        let upload = context.pipeline.store(bytes).await.map_err(|e| Box::new(match e {
+            MediaError::Image(ImageError::Unsupported) => problem(
+                "unsupported_media_type",
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "Unsupported media type".to_string(),
+                format!("Attachments must be one of {}", media::SUPPORTED_TYPES.join(", ")),
+            ),
+            MediaError::Image(e @ ImageError::TooManyPixels { .. }) => problem(
+                "image_too_large",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Image too large".to_string(),
+                e.to_string(),
+            ),
+            MediaError::Image(e @ ImageError::Corrupt(_)) => problem(
+                "invalid_image",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Invalid image".to_string(),
+                e.to_string(),
+            ),
+            MediaError::Storage(e) => {
+                tracing::error!("can't store attachment: {e}");
+                problem(
+                    "media_storage",
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unknown error".to_string(),
+                    "Can't store the attachment".to_string(),
+                )
+            },
+        }));
+        match upload {
+            Ok(upload) => {
+                attachments.push(upload.attachment);
+                created.extend(upload.created);
+            },
+            Err(response) => {
+                context.pipeline.discard(&created);
+                return Err(response)
+            },
+        }
+    }
+    Ok((attachments, created))
+}
+
+/// axum handler for "GET /snaps/{id}" which returns a single snap.
+/// Hidden snaps are only found by moderators.
+#[utoipa::path(
+    get,
+    path = "/snaps/{id}",
+    tag = "snaps",
+    operation_id = "get_snap",
+    summary = "Get a snap",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+    ),
+    responses(
+        (status = OK, description = "The snap", body = ApiResponse<SnapInfo>),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snap_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: Option<AuthUser>,
+    Path(id): Path<String>,
+) -> Response {
+    let visibility = visibility(user.as_ref(), &context);
+    match repo.get_snap(&id) {
+        Some(snap) if !snap.is_deleted() && (visibility == Visibility::Moderator || !snap.is_hidden()) => {
+            let response = ApiResponse { data: snap_info(&repo, &snap) };
+            (StatusCode::OK, Json::from(response)).into_response()
+        },
+        _ => snap_not_found(&id),
+    }
+}
+
+/// axum handler for "GET /snaps/{id}/thread" which returns the whole
+/// conversation the snap belongs to, as a tree starting at its root.
+/// Deleted snaps with replies show up as tombstones, and hidden snaps
+/// without their contents unless a moderator asks.
+#[utoipa::path(
+    get,
+    path = "/snaps/{id}/thread",
+    tag = "snaps",
+    operation_id = "get_thread",
+    summary = "Get the whole conversation of a snap",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+        ThreadQuery,
+    ),
+    responses(
+        (status = OK, description = "Tree of snaps from the root of the conversation", body = ApiResponse<ThreadNode>),
+        (status = BAD_REQUEST, description = "Malformed query", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Depth out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snap_thread_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: Option<AuthUser>,
+    Path(id): Path<String>,
+    query: Result<Query<ThreadQuery>, QueryRejection>,
+) -> Response {
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let max = context.config.limits.max_thread_depth;
+    let depth = query.depth.unwrap_or(max);
+    if depth == 0 || depth > max {
+        return problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid depth".to_string(),
+            format!("Depth must be between 1 and {max}"),
+        );
+    }
+    let visibility = visibility(user.as_ref(), &context);
+    let mut root = match repo.get_snap(&id) {
+        Some(snap) if visibility == Visibility::Moderator || !snap.is_hidden() => snap,
+        _ => return snap_not_found(&id),
+    };
+    while let Some(parent) = root.in_reply_to().and_then(|parent| repo.get_snap(&parent)) {
+        root = parent;
+    }
+    let response = ApiResponse { data: thread_node(&repo, &root, depth, visibility) };
+    (StatusCode::OK, Json::from(response)).into_response()
+}
+
+/// Tree of `snap` with `depth` levels of replies below it, as shown to `visibility`.
+fn thread_node<S: SnapAppState>(repo: &S, snap: &Snap, depth: usize, visibility: Visibility) -> ThreadNode {
+    let replies = match depth {
+        0 => Vec::new(),
+        _ => repo.replies(&snap.id())
+            .iter()
+            .map(|reply| thread_node(repo, reply, depth - 1, visibility))
+            .collect(),
+    };
+    ThreadNode {
+        snap: visible_snap_info(repo, snap, visibility),
+        replies,
+    }
+}
+
+/// axum handler for "PATCH /snaps/{id}" which replaces the message
+/// of a snap. Only its author can edit it, others get Forbidden (403).
+/// Hidden snaps can't be edited until a moderator restores them.
+#[utoipa::path(
+    patch,
+    path = "/snaps/{id}",
+    tag = "snaps",
+    operation_id = "edit_snap",
+    summary = "Edit the message of a snap",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+    ),
+    request_body = EditSnap,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The edited snap", body = ApiResponse<SnapInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not the author, or the API key lacks the write scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snap has the id, or it's hidden", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Message too long", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snap_patch_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    extractor: Result<Json<EditSnap>, JsonRejection>,
+) -> Response {
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    let limits = &context.config.limits;
+    let length = payload.message.chars().count();
+    if length > limits.max_message_length {
+        return message_too_long(length, limits.max_message_length);
+    }
+    if let Some(response) = reject_non_author(&repo, &id, &user) {
+        return response;
+    }
+    match repo.edit(&id, &payload.message) {
+        Ok(snap) => {
+            webhooks::enqueue(&mut repo, WebhookEvent::SnapEdited, &snap);
+            let response = ApiResponse { data: snap_info(&repo, &snap) };
+            (StatusCode::OK, Json::from(response)).into_response()
+        },
+        Err(e) => map_snap_update_error(e, &id),
+    }
+}
+
+/// axum handler for "DELETE /snaps/{id}" which removes a snap.
+/// Only its author can delete it, others get Forbidden (403).
+#[utoipa::path(
+    delete,
+    path = "/snaps/{id}",
+    tag = "snaps",
+    operation_id = "delete_snap",
+    summary = "Delete a snap",
+    params(
+        ("id" = String, Path, description = "Id of the snap"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The snap was deleted"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "Not the author, or the API key lacks the write scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No snap has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn snap_delete_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Response {
+    if let Some(response) = reject_non_author(&repo, &id, &user) {
+        return response;
+    }
+    match repo.delete(&id) {
+        Ok(snap) => {
+            webhooks::enqueue(&mut repo, WebhookEvent::SnapDeleted, &snap);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) => map_snap_update_error(e, &id),
+    }
+}
+
+/// Error response unless the snap `id` exists and was written by `user`.
+fn reject_non_author<S: SnapAppState>(repo: &S, id: &str, user: &AuthUser) -> Option<Response> {
+    match repo.get_snap(id) {
+        None => Some(snap_not_found(id)),
+        Some(snap) if snap.is_deleted() => Some(snap_not_found(id)),
+        Some(snap) if snap.author_id() != user.id => Some(problem(
+            "not_author",
+            StatusCode::FORBIDDEN,
+            "Not the author".to_string(),
+            "Only the author of a snap can change it".to_string(),
+        )),
+        Some(_) => None,
+    }
+}
+
+/// Parse [SnapUpdateError] into an RFC 7807 compliant error response.
+fn map_snap_update_error(error: SnapUpdateError, id: &str) -> Response {
+    match error {
+        SnapUpdateError::NotFound => snap_not_found(id),
+        SnapUpdateError::StorageError(_) => problem(
+            "snap_update",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
+
+pub(super) fn snap_not_found(id: &str) -> Response {
+    problem(
+        "snap_not_found",
+        StatusCode::NOT_FOUND,
+        "Snap not found".to_string(),
+        format!("No snap has the id {id}"),
+    )
+}
+
+/// Parse [SnapCreationError] into an RFC 7807 compliant error response.
+fn map_snap_creation_error(error: SnapCreationError, context: &AppContext) -> Response {
+    let variant = match error {
+        SnapCreationError::IdCollisionError => "id_collision",
+        SnapCreationError::AuthorNotFound => "author_not_found",
+        SnapCreationError::ParentNotFound => "parent_not_found",
+        SnapCreationError::QuotaExceeded => "quota_exceeded",
+        SnapCreationError::StorageError(_) => "storage",
+    };
+    context.metrics.snap_creation_failed(variant);
+    match error {
+        SnapCreationError::AuthorNotFound => problem(
+            "author_not_found",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown author".to_string(),
+            "No user has the given author id".to_string(),
+        ),
+        SnapCreationError::ParentNotFound => problem(
+            "parent_not_found",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown parent".to_string(),
+            "The snap replied to doesn't exist or was deleted".to_string(),
+        ),
+        SnapCreationError::QuotaExceeded => quota_exceeded("snap quota"),
+        _ => problem(
+            "snap_creation",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
+
+/// RFC 7807 compliant response for a message over the configured limit.
+fn message_too_long(length: usize, max: usize) -> Response {
+    problem(
+        "message_too_long",
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Message too long".to_string(),
+        format!("Message has {length} characters, the maximum is {max}"),
+    )
+}
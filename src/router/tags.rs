@@ -0,0 +1,119 @@
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    Query,
+    State,
+    rejection::QueryRejection
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::entities;
+use crate::state::{Repository, SnapAppState};
+use super::moderation::visibility;
+use super::{ApiResponse, Page, PageQuery, ProblemResponse, handle_bad_query, page_params, page_response, problem};
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct TrendingQuery {
+    /// Tags in the list, `tags.trending_limit` by default.
+    limit: Option<usize>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct TrendingTag {
+    tag: String,
+    /// Snaps using the tag within the window.
+    count: usize,
+}
+
+/// axum handler for "GET /tags/{tag}/snaps" which returns the snaps
+/// using a hashtag, from the most recent to the oldest, one page at a time.
+/// The tag is matched ignoring case and may keep its leading `#`.
+#[utoipa::path(
+    get,
+    path = "/tags/{tag}/snaps",
+    tag = "tags",
+    operation_id = "list_tag_snaps",
+    summary = "Page through the snaps using a hashtag",
+    params(
+        ("tag" = String, Path, description = "Hashtag, with or without `#`"),
+        PageQuery,
+    ),
+    responses(
+        (status = OK, description = "Snaps from the most recent", body = Page),
+        (status = BAD_REQUEST, description = "Malformed query or cursor", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid tag or limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn tag_snaps_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: Option<AuthUser>,
+    Path(tag): Path<String>,
+    query: Result<Query<PageQuery>, QueryRejection>,
+) -> Response {
+    let tag = tag.trim_start_matches('#').to_lowercase();
+    if entities::hashtags(&format!("#{tag}")) != [tag.as_str()] {
+        return problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid tag".to_string(),
+            "Tags have letters, digits or underscores, and not only digits".to_string(),
+        );
+    }
+    let (cursor, limit) = match page_params(query, &context.config.timeline) {
+        Ok(params) => params,
+        Err(response) => return *response,
+    };
+    // One extra snap tells whether there is a next page.
+    let snaps = repo.get_by_tag(&tag, cursor.as_ref(), limit + 1, visibility(user.as_ref(), &context));
+    page_response(&repo, snaps, limit)
+}
+
+/// axum handler for "GET /tags/trending" which returns the hashtags used
+/// by the most snaps posted within the configured window, most used first.
+#[utoipa::path(
+    get,
+    path = "/tags/trending",
+    tag = "tags",
+    operation_id = "list_trending_tags",
+    summary = "List the most used hashtags",
+    params(
+        TrendingQuery,
+    ),
+    responses(
+        (status = OK, description = "Tags used within the window, most used first", body = ApiResponse<Vec<TrendingTag>>),
+        (status = BAD_REQUEST, description = "Malformed query", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Limit out of bounds", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn trending_get_handler<S: SnapAppState>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    query: Result<Query<TrendingQuery>, QueryRejection>,
+) -> Response {
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let config = &context.config;
+    let max = config.timeline.max_page_size;
+    let limit = query.limit.unwrap_or(config.tags.trending_limit);
+    if limit == 0 || limit > max {
+        return problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid limit".to_string(),
+            format!("Limit must be between 1 and {max}"),
+        );
+    }
+    let window = chrono::TimeDelta::seconds(config.tags.trending_window_secs as i64);
+    let tags = repo.trending_tags(chrono::Utc::now() - window, limit)
+        .into_iter()
+        .map(|(tag, count)| TrendingTag { tag, count })
+        .collect::<Vec<TrendingTag>>();
+    (StatusCode::OK, Json::from(ApiResponse { data: tags })).into_response()
+}
@@ -0,0 +1,368 @@
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    Request,
+    rejection::JsonRejection
+};
+use axum::http::{header, HeaderMap, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use tower_service::Service;
+use crate::config::TenantQuotas;
+use crate::rate_limit;
+use crate::replication::secret_matches;
+use crate::state::Repository;
+use crate::tenancy::{
+    ADMIN_TOKEN_HEADER,
+    TENANT_HEADER,
+    Tenant,
+    TenantError,
+    TenantInfo,
+    TenantUpdate,
+    Tenants,
+};
+use super::{ApiResponse, ProblemResponse, handle_bad_json, problem};
+
+/// Body of "POST /admin/tenants".
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+pub(super) struct CreateTenant {
+    /// Lowercase letters, digits and dashes, as in a host name.
+    id: String,
+    /// Defaults to the id.
+    name: Option<String>,
+    /// Defaults to `tenancy.default_quotas`.
+    quotas: Option<TenantQuotas>,
+}
+
+/// Send `request` to the store of its tenant, named by the `/t/<tenant>`
+/// prefix of its path, the `X-Snap-Tenant` header or the subdomain of
+/// `tenancy.domain` in its host, in that order. Unknown tenants get Not
+/// Found (404), suspended ones Forbidden (403) and tenants over their
+/// budgets Too Many Requests (429).
+pub(super) async fn dispatch<S: Repository + Clone + Send + Sync + 'static>(
+    Extension(tenants): Extension<Tenants<S>>,
+    mut request: Request,
+) -> Response {
+    let Some(id) = tenant_of(&mut request, tenants.config().tenancy.domain.as_deref()) else {
+        return problem(
+            "tenant_not_found",
+            StatusCode::NOT_FOUND,
+            "Unknown tenant".to_string(),
+            format!("Name the tenant with a /t/<tenant> path prefix or the {TENANT_HEADER} header"),
+        )
+    };
+    let Some((tenant, mut scope)) = tenants.get(&id) else {
+        return problem(
+            "tenant_not_found",
+            StatusCode::NOT_FOUND,
+            "Unknown tenant".to_string(),
+            format!("No tenant {id}"),
+        )
+    };
+    if tenant.is_suspended() {
+        return problem(
+            "tenant_suspended",
+            StatusCode::FORBIDDEN,
+            "Tenant suspended".to_string(),
+            format!("The tenant {id} is suspended"),
+        )
+    }
+    if let Err(decision) = scope.charge(&tenant.quotas, request.method(), &id) {
+        return rate_limit::denied(&decision)
+    }
+    match scope.app.call(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    }
+}
+
+/// Id of the tenant `request` is for, removing the `/t/<tenant>` prefix
+/// from its path if that's how it's named.
+fn tenant_of(request: &mut Request, domain: Option<&str>) -> Option<String> {
+    if let Some(rest) = request.uri().path().strip_prefix("/t/") {
+        let (id, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        let id = id.to_string();
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{path}?{query}"),
+            None => path.to_string(),
+        };
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = Some(path_and_query.parse().ok()?);
+        *request.uri_mut() = Uri::from_parts(parts).ok()?;
+        return Some(id)
+    }
+    let headers = request.headers();
+    if let Some(id) = headers.get(TENANT_HEADER).and_then(|value| value.to_str().ok()) {
+        return Some(id.to_string())
+    }
+    let host = headers.get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| request.uri().host())?;
+    let host = host.split(':').next().unwrap_or(host);
+    let subdomain = host.strip_suffix(domain?)?.strip_suffix('.')?;
+    (!subdomain.is_empty() && !subdomain.contains('.')).then(|| subdomain.to_string())
+}
+
+/// Snaps `repo` can still store, none when it has no snap quota.
+pub(super) fn snaps_left<S: Repository>(repo: &S) -> Option<usize> {
+    repo.limits().max_snaps.map(|max| max.saturating_sub(repo.snap_count()))
+}
+
+/// Forbidden (403) for a tenant that reached its `quota`, which the
+/// store enforces on every insert.
+pub(super) fn quota_exceeded(quota: &str) -> Response {
+    problem(
+        "quota_exceeded",
+        StatusCode::FORBIDDEN,
+        "Quota exceeded".to_string(),
+        format!("The tenant reached its {quota}"),
+    )
+}
+
+/// Unauthorized (401) unless `headers` carry `tenancy.admin_token`.
+fn require_admin_token<S: Repository + Clone + Send + Sync + 'static>(tenants: &Tenants<S>, headers: &HeaderMap) -> Result<(), Box<Response>> {
+    let given = headers.get(ADMIN_TOKEN_HEADER).and_then(|value| value.to_str().ok());
+    match (&tenants.config().tenancy.admin_token, given) {
+        (Some(token), Some(given)) if secret_matches(token, given) => Ok(()),
+        _ => Err(Box::new(problem(
+            "unauthorized",
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized".to_string(),
+            format!("The tenants are managed with the {ADMIN_TOKEN_HEADER} header"),
+        ))),
+    }
+}
+
+fn tenant_problem(e: TenantError) -> Response {
+    let (kind, status, title) = match &e {
+        TenantError::InvalidId(_) | TenantError::InvalidQuotas(_) => {
+            ("invalid_field", StatusCode::UNPROCESSABLE_ENTITY, "Invalid tenant")
+        }
+        TenantError::Exists(_) => ("tenant_exists", StatusCode::CONFLICT, "Tenant already exists"),
+        TenantError::NotFound(_) => ("tenant_not_found", StatusCode::NOT_FOUND, "Unknown tenant"),
+        TenantError::Config(_) | TenantError::Storage(_) => {
+            tracing::error!("tenant storage failed: {e}");
+            ("storage_failed", StatusCode::INTERNAL_SERVER_ERROR, "Storage failure")
+        }
+    };
+    problem(kind, status, title.to_string(), e.to_string())
+}
+
+fn info<S: Repository + Clone + Send + Sync + 'static>(tenants: &Tenants<S>, tenant: Tenant) -> TenantInfo {
+    let usage = tenants.get(&tenant.id).map(|(_, scope)| scope.usage()).unwrap_or_default();
+    TenantInfo { tenant, usage }
+}
+
+/// axum handler for "GET /admin/tenants" which lists every tenant and
+/// its usage, ordered by id.
+#[utoipa::path(
+    get,
+    path = "/admin/tenants",
+    tag = "tenants",
+    operation_id = "list_tenants",
+    summary = "List the tenants",
+    security(("admin_token" = [])),
+    responses(
+        (status = OK, description = "Every tenant", body = ApiResponse<Vec<TenantInfo>>),
+        (status = UNAUTHORIZED, description = "Missing or invalid admin token", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn tenants_get_handler<S: Repository + Clone + Send + Sync + 'static>(
+    Extension(tenants): Extension<Tenants<S>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Err(response) = require_admin_token(&tenants, &headers) {
+        return *response;
+    }
+    let infos = tenants.all()
+        .into_iter()
+        .map(|(tenant, scope)| TenantInfo { tenant, usage: scope.usage() })
+        .collect::<Vec<_>>();
+    Json::from(ApiResponse { data: infos }).into_response()
+}
+
+/// axum handler for "POST /admin/tenants" which creates a tenant with
+/// an empty store.
+#[utoipa::path(
+    post,
+    path = "/admin/tenants",
+    tag = "tenants",
+    operation_id = "create_tenant",
+    summary = "Create a tenant",
+    request_body = CreateTenant,
+    security(("admin_token" = [])),
+    responses(
+        (status = CREATED, description = "The new tenant", body = ApiResponse<TenantInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid admin token", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "The id is taken", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid id or quotas", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn tenants_post_handler<S: Repository + Clone + Send + Sync + 'static>(
+    Extension(tenants): Extension<Tenants<S>>,
+    headers: HeaderMap,
+    body: Result<Json<CreateTenant>, JsonRejection>,
+) -> Response {
+    if let Err(response) = require_admin_token(&tenants, &headers) {
+        return *response;
+    }
+    let Json(body) = match body {
+        Ok(body) => body,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    // Opening a journal reads a file.
+    let created = {
+        let tenants = tenants.clone();
+        tokio::task::spawn_blocking(move || tenants.create(&body.id, body.name, body.quotas))
+            .await
+            .expect("creating a tenant doesn't panic")
+    };
+    match created {
+        Ok(tenant) => {
+            tracing::info!(tenant = %tenant.id, "tenant created");
+            (StatusCode::CREATED, Json::from(ApiResponse { data: info(&tenants, tenant) })).into_response()
+        }
+        Err(e) => tenant_problem(e),
+    }
+}
+
+/// axum handler for "GET /admin/tenants/:id" which returns a tenant
+/// and its usage.
+#[utoipa::path(
+    get,
+    path = "/admin/tenants/{id}",
+    tag = "tenants",
+    operation_id = "get_tenant",
+    summary = "Get a tenant",
+    params(("id" = String, Path, description = "Id of the tenant")),
+    security(("admin_token" = [])),
+    responses(
+        (status = OK, description = "The tenant", body = ApiResponse<TenantInfo>),
+        (status = UNAUTHORIZED, description = "Missing or invalid admin token", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No tenant has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn tenant_get_handler<S: Repository + Clone + Send + Sync + 'static>(
+    Extension(tenants): Extension<Tenants<S>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(response) = require_admin_token(&tenants, &headers) {
+        return *response;
+    }
+    match tenants.get(&id) {
+        Some((tenant, scope)) => {
+            Json::from(ApiResponse { data: TenantInfo { tenant, usage: scope.usage() } }).into_response()
+        }
+        None => tenant_problem(TenantError::NotFound(id)),
+    }
+}
+
+/// axum handler for "PATCH /admin/tenants/:id" which renames a tenant
+/// or replaces its quotas.
+#[utoipa::path(
+    patch,
+    path = "/admin/tenants/{id}",
+    tag = "tenants",
+    operation_id = "update_tenant",
+    summary = "Rename a tenant or change its quotas",
+    params(("id" = String, Path, description = "Id of the tenant")),
+    request_body = TenantUpdate,
+    security(("admin_token" = [])),
+    responses(
+        (status = OK, description = "The updated tenant", body = ApiResponse<TenantInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid admin token", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No tenant has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid quotas", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn tenant_patch_handler<S: Repository + Clone + Send + Sync + 'static>(
+    Extension(tenants): Extension<Tenants<S>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    body: Result<Json<TenantUpdate>, JsonRejection>,
+) -> Response {
+    if let Err(response) = require_admin_token(&tenants, &headers) {
+        return *response;
+    }
+    let Json(update) = match body {
+        Ok(body) => body,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    match tenants.update(&id, update) {
+        Ok(tenant) => Json::from(ApiResponse { data: info(&tenants, tenant) }).into_response(),
+        Err(e) => tenant_problem(e),
+    }
+}
+
+/// axum handler for "POST /admin/tenants/:id/suspend" which turns away
+/// every request to a tenant until it's resumed. Its store is kept.
+#[utoipa::path(
+    post,
+    path = "/admin/tenants/{id}/suspend",
+    tag = "tenants",
+    operation_id = "suspend_tenant",
+    summary = "Suspend a tenant",
+    params(("id" = String, Path, description = "Id of the tenant")),
+    security(("admin_token" = [])),
+    responses(
+        (status = OK, description = "The suspended tenant", body = ApiResponse<TenantInfo>),
+        (status = UNAUTHORIZED, description = "Missing or invalid admin token", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No tenant has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn tenant_suspend_post_handler<S: Repository + Clone + Send + Sync + 'static>(
+    Extension(tenants): Extension<Tenants<S>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(response) = require_admin_token(&tenants, &headers) {
+        return *response;
+    }
+    match tenants.suspend(&id) {
+        Ok(tenant) => {
+            tracing::info!(tenant = %tenant.id, "tenant suspended");
+            Json::from(ApiResponse { data: info(&tenants, tenant) }).into_response()
+        }
+        Err(e) => tenant_problem(e),
+    }
+}
+
+/// axum handler for "POST /admin/tenants/:id/resume" which serves a
+/// suspended tenant again.
+#[utoipa::path(
+    post,
+    path = "/admin/tenants/{id}/resume",
+    tag = "tenants",
+    operation_id = "resume_tenant",
+    summary = "Resume a suspended tenant",
+    params(("id" = String, Path, description = "Id of the tenant")),
+    security(("admin_token" = [])),
+    responses(
+        (status = OK, description = "The resumed tenant", body = ApiResponse<TenantInfo>),
+        (status = UNAUTHORIZED, description = "Missing or invalid admin token", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No tenant has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn tenant_resume_post_handler<S: Repository + Clone + Send + Sync + 'static>(
+    Extension(tenants): Extension<Tenants<S>>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(response) = require_admin_token(&tenants, &headers) {
+        return *response;
+    }
+    match tenants.resume(&id) {
+        Ok(tenant) => {
+            tracing::info!(tenant = %tenant.id, "tenant resumed");
+            Json::from(ApiResponse { data: info(&tenants, tenant) }).into_response()
+        }
+        Err(e) => tenant_problem(e),
+    }
+}
@@ -0,0 +1,349 @@
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    State,
+    rejection::JsonRejection
+};
+use std::sync::OnceLock;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use crate::auth::{AuthError, AuthUser, TokenKind, TokenPair};
+use crate::context::AppContext;
+use crate::models::User;
+use crate::state::{Repository, UserCreationError};
+use super::{ApiResponse, ProblemResponse, handle_bad_json, problem};
+use snap_api::SnapInfo;
+use super::moderation::visibility;
+use super::snaps::{live_snaps, snap_infos};
+use super::tenants::quota_exceeded;
+
+/// Shortest password accepted on registration.
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Longest password accepted on registration and login, so a huge one
+/// can't keep a hashing thread busy.
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// User never stored, whose hash is checked when logging in with an
+/// unknown username so it takes as long as a wrong password.
+static NO_USER: OnceLock<User> = OnceLock::new();
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct CreateUser {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct Login {
+    username: String,
+    password: String,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct Refresh {
+    refresh_token: String,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct Tokens {
+    user: UserInfo,
+    access_token: String,
+    refresh_token: String,
+    token_type: &'static str,
+    expires_in: u64,
+}
+
+impl Tokens {
+    fn new(user: &User, tokens: TokenPair) -> Tokens {
+        Tokens {
+            user: UserInfo::from(user),
+            access_token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+            token_type: "Bearer",
+            expires_in: tokens.expires_in,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+pub(super) struct UserInfo {
+    id: String,
+    username: String,
+    created_at: String,
+}
+
+impl From<&User> for UserInfo {
+    fn from(user: &User) -> Self {
+        UserInfo {
+            id: user.id(),
+            username: user.username().to_string(),
+            created_at: user.created_at().to_rfc3339(),
+        }
+    }
+}
+
+/// axum handler for "POST /users" which registers a new user.
+/// Returns Created (201) with the user info, or Conflict (409)
+/// if the username is taken.
+#[utoipa::path(
+    post,
+    path = "/users",
+    tag = "users",
+    operation_id = "create_user",
+    summary = "Register a user",
+    request_body = CreateUser,
+    responses(
+        (status = CREATED, description = "The new user", body = ApiResponse<UserInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The tenant reached its user quota", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "The username is taken", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid username or password", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn users_post_handler<S: Repository + Send + 'static>(
+    State(mut repo): State<S>,
+    extractor: Result<Json<CreateUser>, JsonRejection>,
+) -> Response {
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    if let Err(detail) = validate_username(&payload.username) {
+        return invalid_field("Invalid username", detail);
+    }
+    if payload.password.chars().count() < MIN_PASSWORD_LENGTH {
+        return invalid_field(
+            "Invalid password",
+            format!("Password must have at least {MIN_PASSWORD_LENGTH} characters"),
+        );
+    }
+    if payload.password.chars().count() > MAX_PASSWORD_LENGTH {
+        return password_too_long();
+    }
+    if repo.get_user_by_username(&payload.username).is_some() {
+        return username_taken();
+    }
+
+    // Hashing is slow on purpose, keep it off the async executor.
+    let user = tokio::task::spawn_blocking(move || User::new(payload.username, &payload.password))
+        .await
+        .expect("password hashing doesn't panic");
+
+    match repo.create_user(user) {
+        Ok(user) => {
+            let response = ApiResponse { data: UserInfo::from(&user) };
+            (StatusCode::CREATED, Json::from(response)).into_response()
+        },
+        Err(UserCreationError::UsernameTaken) => username_taken(),
+        Err(UserCreationError::QuotaExceeded) => quota_exceeded("user quota"),
+        Err(_) => problem(
+            "user_creation",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
+
+/// axum handler for "GET /users/{id}" which returns the public info of a user.
+#[utoipa::path(
+    get,
+    path = "/users/{id}",
+    tag = "users",
+    operation_id = "get_user",
+    summary = "Get a user",
+    params(
+        ("id" = String, Path, description = "Id of the user"),
+    ),
+    responses(
+        (status = OK, description = "The user", body = ApiResponse<UserInfo>),
+        (status = NOT_FOUND, description = "No user has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn user_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Path(id): Path<String>,
+) -> Response {
+    match repo.get_user(&id) {
+        Some(user) => {
+            let response = ApiResponse { data: UserInfo::from(&user) };
+            (StatusCode::OK, Json::from(response)).into_response()
+        },
+        None => user_not_found(&id),
+    }
+}
+
+/// axum handler for "GET /users/{id}/snaps" which returns the snaps
+/// written by a user, from the most recent to the oldest.
+#[utoipa::path(
+    get,
+    path = "/users/{id}/snaps",
+    tag = "users",
+    operation_id = "list_user_snaps",
+    summary = "List the snaps of a user",
+    params(
+        ("id" = String, Path, description = "Id of the user"),
+    ),
+    responses(
+        (status = OK, description = "Snaps from the most recent", body = ApiResponse<Vec<SnapInfo>>),
+        (status = NOT_FOUND, description = "No user has the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn user_snaps_get_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: Option<AuthUser>,
+    Path(id): Path<String>,
+) -> Response {
+    if repo.get_user(&id).is_none() {
+        return user_not_found(&id);
+    }
+    let visibility = visibility(user.as_ref(), &context);
+    let ids = context.projections.read(&repo, |projections| {
+        projections.timeline.by_author(&id, None, usize::MAX, visibility)
+    });
+    let response = ApiResponse { data: snap_infos(&repo, &live_snaps(&repo, &ids, visibility)) };
+    (StatusCode::OK, Json::from(response)).into_response()
+}
+
+/// axum handler for "POST /login" which checks a username and password.
+/// Returns the user info with an access and a refresh token on success,
+/// or Unauthorized (401).
+#[utoipa::path(
+    post,
+    path = "/login",
+    tag = "auth",
+    operation_id = "login",
+    summary = "Trade a username and password for tokens",
+    request_body = Login,
+    responses(
+        (status = OK, description = "Access and refresh tokens", body = ApiResponse<Tokens>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Wrong username or password", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Password too long", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn login_post_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    extractor: Result<Json<Login>, JsonRejection>,
+) -> Response {
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    if payload.password.chars().count() > MAX_PASSWORD_LENGTH {
+        return password_too_long();
+    }
+    let user = repo.get_user_by_username(&payload.username);
+
+    // Unknown usernames are checked too, so timing doesn't tell which exist.
+    let password = payload.password;
+    let (user, valid) = tokio::task::spawn_blocking(move || {
+        let no_user = || NO_USER.get_or_init(|| User::new(String::new(), "no password"));
+        let valid = user.as_ref().unwrap_or_else(no_user).verify_password(&password);
+        (user, valid)
+    })
+        .await
+        .expect("password verification doesn't panic");
+
+    let user = match user {
+        Some(user) if valid => user,
+        _ => return invalid_credentials(),
+    };
+    let response = ApiResponse { data: Tokens::new(&user, context.auth.issue(&user.id())) };
+    (StatusCode::OK, Json::from(response)).into_response()
+}
+
+/// axum handler for "POST /login/refresh" which trades a refresh token
+/// for a new pair of tokens. Returns Unauthorized (401) if the token
+/// isn't valid or its user doesn't exist anymore.
+#[utoipa::path(
+    post,
+    path = "/login/refresh",
+    tag = "auth",
+    operation_id = "refresh",
+    summary = "Trade a refresh token for new tokens",
+    request_body = Refresh,
+    responses(
+        (status = OK, description = "Access and refresh tokens", body = ApiResponse<Tokens>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Invalid refresh token", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn refresh_post_handler<S: Repository>(
+    State(repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    extractor: Result<Json<Refresh>, JsonRejection>,
+) -> Response {
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    let claims = match context.auth.verify(&payload.refresh_token, TokenKind::Refresh) {
+        Ok(claims) => claims,
+        Err(e) => return e.into_response(),
+    };
+    let user = match repo.get_user(&claims.sub) {
+        Some(user) => user,
+        None => return AuthError::InvalidToken("unknown user".to_string()).into_response(),
+    };
+    let response = ApiResponse { data: Tokens::new(&user, context.auth.issue(&user.id())) };
+    (StatusCode::OK, Json::from(response)).into_response()
+}
+
+/// Usernames have 3 to 32 ASCII letters, digits or underscores.
+fn validate_username(username: &str) -> Result<(), String> {
+    if !(3..=32).contains(&username.len()) {
+        return Err("Username must have between 3 and 32 characters".to_string());
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err("Username can only have letters, digits and underscores".to_string());
+    }
+    Ok(())
+}
+
+fn invalid_field(title: &str, detail: String) -> Response {
+    problem(
+        "invalid_field",
+        StatusCode::UNPROCESSABLE_ENTITY,
+        title.to_string(),
+        detail,
+    )
+}
+
+fn username_taken() -> Response {
+    problem(
+        "username_taken",
+        StatusCode::CONFLICT,
+        "Username taken".to_string(),
+        "Another user already has this username".to_string(),
+    )
+}
+
+pub(super) fn user_not_found(id: &str) -> Response {
+    problem(
+        "user_not_found",
+        StatusCode::NOT_FOUND,
+        "User not found".to_string(),
+        format!("No user has the id {id}"),
+    )
+}
+
+fn password_too_long() -> Response {
+    invalid_field(
+        "Invalid password",
+        format!("Password must have at most {MAX_PASSWORD_LENGTH} characters"),
+    )
+}
+
+fn invalid_credentials() -> Response {
+    problem(
+        "invalid_credentials",
+        StatusCode::UNAUTHORIZED,
+        "Invalid credentials".to_string(),
+        "Wrong username or password".to_string(),
+    )
+}
@@ -0,0 +1,477 @@
+use std::collections::BTreeSet;
+use axum::extract::{
+    Extension,
+    Json,
+    Path,
+    Query,
+    State,
+    rejection::{JsonRejection, QueryRejection}
+};
+use axum::http::{StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use uuid::Uuid;
+use crate::auth::AuthUser;
+use crate::context::AppContext;
+use crate::models::{Delivery, DeliveryAttempt, DeliveryStatus, Scope, Webhook, WebhookEvent};
+use crate::state::{Repository, WebhookError};
+use crate::webhooks::{self, HostError};
+use super::{ApiResponse, ProblemResponse, handle_bad_json, handle_bad_query, problem};
+
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct CreateWebhook {
+    url: String,
+    events: Vec<String>,
+}
+
+/// Changes to a webhook, the missing fields are kept.
+#[derive(Debug, serde::Deserialize, utoipa::ToSchema)]
+pub(super) struct EditWebhook {
+    url: Option<String>,
+    events: Option<Vec<String>>,
+}
+
+#[derive(Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub(super) struct DeliveriesQuery {
+    /// Only deliveries `pending`, `delivered` or `dead`.
+    status: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct WebhookInfo {
+    id: String,
+    url: String,
+    events: BTreeSet<WebhookEvent>,
+    created_at: String,
+    /// Only sent once, when the webhook is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl From<&Webhook> for WebhookInfo {
+    fn from(webhook: &Webhook) -> Self {
+        WebhookInfo {
+            id: webhook.id(),
+            url: webhook.url().to_string(),
+            events: webhook.events().clone(),
+            created_at: webhook.created_at().to_rfc3339(),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Debug, serde::Serialize, utoipa::ToSchema)]
+struct DeliveryInfo {
+    id: String,
+    event: WebhookEvent,
+    status: DeliveryStatus,
+    attempts: Vec<DeliveryAttempt>,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_attempt_at: Option<String>,
+}
+
+impl From<&Delivery> for DeliveryInfo {
+    fn from(delivery: &Delivery) -> Self {
+        DeliveryInfo {
+            id: delivery.id(),
+            event: delivery.event(),
+            status: delivery.status(),
+            attempts: delivery.attempts().to_vec(),
+            created_at: delivery.created_at().to_rfc3339(),
+            next_attempt_at: delivery.next_attempt_at().map(|time| time.to_rfc3339()),
+        }
+    }
+}
+
+/// axum handler for "POST /webhooks" which subscribes a URL of the
+/// authenticated user to snap events. The response has the only copy
+/// of the signing secret.
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    operation_id = "create_webhook",
+    summary = "Subscribe a URL to snap events",
+    request_body = CreateWebhook,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = CREATED, description = "The new webhook, with the only copy of the signing secret", body = ApiResponse<WebhookInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid URL, a host that isn't public, or invalid events", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn webhooks_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    extractor: Result<Json<CreateWebhook>, JsonRejection>,
+) -> Response {
+    if let Err(e) = user.require(Scope::Admin) {
+        return e.into_response();
+    }
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    let url = match validate_url(&payload.url, &context).await {
+        Ok(url) => url,
+        Err(response) => return *response,
+    };
+    let events = match parse_events(&payload.events) {
+        Ok(events) => events,
+        Err(response) => return *response,
+    };
+    let Ok(owner) = Uuid::parse_str(&user.id) else {
+        return map_webhook_error(WebhookError::OwnerNotFound, "");
+    };
+
+    match repo.create_webhook(Webhook::new(owner, url, events)) {
+        Ok(webhook) => {
+            let info = WebhookInfo {
+                secret: Some(webhook.secret().to_string()),
+                ..WebhookInfo::from(&webhook)
+            };
+            (StatusCode::CREATED, Json::from(ApiResponse { data: info })).into_response()
+        },
+        Err(e) => map_webhook_error(e, ""),
+    }
+}
+
+/// axum handler for "GET /webhooks" which lists the webhooks of the
+/// authenticated user.
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    operation_id = "list_webhooks",
+    summary = "List your webhooks",
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The webhooks", body = ApiResponse<Vec<WebhookInfo>>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn webhooks_get_handler<S: Repository>(
+    State(repo): State<S>,
+    user: AuthUser,
+) -> Response {
+    if let Err(e) = user.require(Scope::Admin) {
+        return e.into_response();
+    }
+    let webhooks = repo.get_webhooks_by_owner(&user.id)
+        .iter()
+        .map(WebhookInfo::from)
+        .collect::<Vec<WebhookInfo>>();
+    (StatusCode::OK, Json::from(ApiResponse { data: webhooks })).into_response()
+}
+
+/// axum handler for "GET /webhooks/{id}" which returns one webhook.
+/// Webhooks of other users are reported as Not Found (404).
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    operation_id = "get_webhook",
+    summary = "Get a webhook",
+    params(
+        ("id" = String, Path, description = "Id of the webhook"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The webhook", body = ApiResponse<WebhookInfo>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "You have no webhook with the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn webhook_get_handler<S: Repository>(
+    State(repo): State<S>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Response {
+    let webhook = match owned_webhook(&repo, &user, &id) {
+        Ok(webhook) => webhook,
+        Err(response) => return *response,
+    };
+    let response = ApiResponse { data: WebhookInfo::from(&webhook) };
+    (StatusCode::OK, Json::from(response)).into_response()
+}
+
+/// axum handler for "PATCH /webhooks/{id}" which changes the URL
+/// or the events of a webhook.
+#[utoipa::path(
+    patch,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    operation_id = "edit_webhook",
+    summary = "Change the URL or events of a webhook",
+    params(
+        ("id" = String, Path, description = "Id of the webhook"),
+    ),
+    request_body = EditWebhook,
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "The edited webhook", body = ApiResponse<WebhookInfo>),
+        (status = BAD_REQUEST, description = "Malformed body", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "You have no webhook with the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Invalid URL, a host that isn't public, or invalid events", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn webhook_patch_handler<S: Repository>(
+    State(mut repo): State<S>,
+    Extension(context): Extension<AppContext>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    extractor: Result<Json<EditWebhook>, JsonRejection>,
+) -> Response {
+    let mut webhook = match owned_webhook(&repo, &user, &id) {
+        Ok(webhook) => webhook,
+        Err(response) => return *response,
+    };
+    let payload = match extractor {
+        Ok(Json(payload)) => payload,
+        Err(rejection) => return handle_bad_json(&rejection),
+    };
+    if let Some(url) = &payload.url {
+        match validate_url(url, &context).await {
+            Ok(url) => webhook.set_url(url),
+            Err(response) => return *response,
+        }
+    }
+    if let Some(events) = &payload.events {
+        match parse_events(events) {
+            Ok(events) => webhook.set_events(events),
+            Err(response) => return *response,
+        }
+    }
+    match repo.update_webhook(webhook) {
+        Ok(webhook) => {
+            let response = ApiResponse { data: WebhookInfo::from(&webhook) };
+            (StatusCode::OK, Json::from(response)).into_response()
+        },
+        Err(e) => map_webhook_error(e, &id),
+    }
+}
+
+/// axum handler for "DELETE /webhooks/{id}" which removes a webhook
+/// alongside its pending deliveries and delivery log.
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    operation_id = "delete_webhook",
+    summary = "Delete a webhook and its deliveries",
+    params(
+        ("id" = String, Path, description = "Id of the webhook"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = NO_CONTENT, description = "The webhook was deleted"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "You have no webhook with the id", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn webhook_delete_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(response) = owned_webhook(&repo, &user, &id) {
+        return *response;
+    }
+    match repo.delete_webhook(&id) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => map_webhook_error(e, &id),
+    }
+}
+
+/// axum handler for "GET /webhooks/{id}/deliveries" which returns the
+/// delivery log of a webhook, the most recent first, with every attempt.
+/// `?status=dead` lists the dead letters.
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    operation_id = "list_deliveries",
+    summary = "List the deliveries of a webhook",
+    params(
+        ("id" = String, Path, description = "Id of the webhook"),
+        DeliveriesQuery,
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = OK, description = "Deliveries from the most recent, with every attempt", body = ApiResponse<Vec<DeliveryInfo>>),
+        (status = BAD_REQUEST, description = "Malformed query", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "You have no webhook with the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = UNPROCESSABLE_ENTITY, description = "Unknown status", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn deliveries_get_handler<S: Repository>(
+    State(repo): State<S>,
+    user: AuthUser,
+    Path(id): Path<String>,
+    query: Result<Query<DeliveriesQuery>, QueryRejection>,
+) -> Response {
+    if let Err(response) = owned_webhook(&repo, &user, &id) {
+        return *response;
+    }
+    let Query(query) = match query {
+        Ok(query) => query,
+        Err(rejection) => return handle_bad_query(&rejection),
+    };
+    let status = match query.status.as_deref().map(str::parse::<DeliveryStatus>) {
+        None => None,
+        Some(Ok(status)) => Some(status),
+        Some(Err(e)) => return problem(
+            "invalid_field",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Invalid status".to_string(),
+            e,
+        ),
+    };
+    let deliveries = repo.deliveries(&id, status)
+        .iter()
+        .map(DeliveryInfo::from)
+        .collect::<Vec<DeliveryInfo>>();
+    (StatusCode::OK, Json::from(ApiResponse { data: deliveries })).into_response()
+}
+
+/// axum handler for "POST /webhooks/{id}/deliveries/{delivery_id}/retry"
+/// which puts a dead letter back in the queue for another round of attempts.
+#[utoipa::path(
+    post,
+    path = "/webhooks/{id}/deliveries/{delivery_id}/retry",
+    tag = "webhooks",
+    operation_id = "retry_delivery",
+    summary = "Requeue a dead delivery",
+    params(
+        ("id" = String, Path, description = "Id of the webhook"),
+        ("delivery_id" = String, Path, description = "Id of the delivery"),
+    ),
+    security(("bearer" = []), ("api_key" = [])),
+    responses(
+        (status = ACCEPTED, description = "The delivery is pending again", body = ApiResponse<DeliveryInfo>),
+        (status = UNAUTHORIZED, description = "Missing or invalid credentials", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = FORBIDDEN, description = "The API key lacks the admin scope", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = NOT_FOUND, description = "No webhook or delivery has the id", body = ProblemResponse, content_type = "application/problem+json"),
+        (status = CONFLICT, description = "The delivery isn't dead", body = ProblemResponse, content_type = "application/problem+json"),
+    ),
+)]
+pub(super) async fn delivery_retry_post_handler<S: Repository>(
+    State(mut repo): State<S>,
+    user: AuthUser,
+    Path((id, delivery_id)): Path<(String, String)>,
+) -> Response {
+    if let Err(response) = owned_webhook(&repo, &user, &id) {
+        return *response;
+    }
+    let mut delivery = match repo.get_delivery(&delivery_id) {
+        Some(delivery) if delivery.webhook_id() == id => delivery,
+        _ => return delivery_not_found(&delivery_id),
+    };
+    if delivery.status() != DeliveryStatus::Dead {
+        return problem(
+            "delivery_not_dead",
+            StatusCode::CONFLICT,
+            "Delivery not dead".to_string(),
+            "Only dead deliveries can be retried".to_string(),
+        );
+    }
+    delivery.requeue(chrono::Utc::now());
+    let info = DeliveryInfo::from(&delivery);
+    match repo.update_delivery(delivery) {
+        Ok(()) => (StatusCode::ACCEPTED, Json::from(ApiResponse { data: info })).into_response(),
+        Err(WebhookError::NotFound) => delivery_not_found(&delivery_id),
+        Err(e) => map_webhook_error(e, &id),
+    }
+}
+
+/// The webhook `id` if it belongs to `user`, or an error response.
+fn owned_webhook<S: Repository>(repo: &S, user: &AuthUser, id: &str) -> Result<Webhook, Box<Response>> {
+    user.require(Scope::Admin).map_err(|e| Box::new(e.into_response()))?;
+    match repo.get_webhook(id) {
+        Some(webhook) if webhook.owner_id() == user.id => Ok(webhook),
+        _ => Err(Box::new(map_webhook_error(WebhookError::NotFound, id))),
+    }
+}
+
+/// `url` trimmed if it's an absolute `http` or `https` URL whose host
+/// is public, or private ones are allowed.
+async fn validate_url(url: &str, context: &AppContext) -> Result<String, Box<Response>> {
+    let invalid = |detail: String| Box::new(problem(
+        "invalid_field",
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Invalid URL".to_string(),
+        detail,
+    ));
+    let url = url.trim();
+    let uri = match url.parse::<Uri>() {
+        Ok(uri) if matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some() => uri,
+        _ => return Err(invalid("The URL must be absolute and use http or https".to_string())),
+    };
+    match webhooks::check_host(&uri, context.config.webhooks.allow_private_hosts).await {
+        Ok(()) => Ok(url.to_string()),
+        Err(HostError::Unresolved) => Err(invalid("The host of the URL can't be resolved".to_string())),
+        Err(HostError::Private) => Err(invalid(
+            "The host of the URL must not be a loopback, private or link-local address".to_string(),
+        )),
+    }
+}
+
+/// Parse a non empty list of event names.
+fn parse_events(names: &[String]) -> Result<BTreeSet<WebhookEvent>, Box<Response>> {
+    let invalid = |detail: String| Box::new(problem(
+        "invalid_field",
+        StatusCode::UNPROCESSABLE_ENTITY,
+        "Invalid events".to_string(),
+        detail,
+    ));
+    if names.is_empty() {
+        return Err(invalid("Subscribe to at least one event".to_string()));
+    }
+    names.iter()
+        .map(|name| name.parse::<WebhookEvent>().map_err(invalid))
+        .collect()
+}
+
+fn delivery_not_found(id: &str) -> Response {
+    problem(
+        "delivery_not_found",
+        StatusCode::NOT_FOUND,
+        "Delivery not found".to_string(),
+        format!("The webhook has no delivery with the id {id}"),
+    )
+}
+
+/// Parse [WebhookError] into an RFC 7807 compliant error response.
+fn map_webhook_error(error: WebhookError, id: &str) -> Response {
+    match error {
+        WebhookError::NotFound => problem(
+            "webhook_not_found",
+            StatusCode::NOT_FOUND,
+            "Webhook not found".to_string(),
+            format!("You have no webhook with the id {id}"),
+        ),
+        WebhookError::OwnerNotFound => problem(
+            "author_not_found",
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Unknown user".to_string(),
+            "No user has the authenticated id".to_string(),
+        ),
+        _ => problem(
+            "webhook",
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unknown error".to_string(),
+            "Can't determine error cause".to_string(),
+        ),
+    }
+}
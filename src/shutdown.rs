@@ -0,0 +1,186 @@
+use std::future::{Future, IntoFuture};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Wait until the process receives SIGINT (Ctrl+C) or SIGTERM.
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install the Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => tracing::info!("received SIGINT"),
+        _ = terminate => tracing::info!("received SIGTERM"),
+    }
+}
+
+/// How the server stopped.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServeOutcome {
+    /// Every in-flight request finished before the drain timeout.
+    Drained,
+    /// Some connections were still open when the drain timeout ran out.
+    /// They are abandoned and end when the process exits.
+    TimedOut,
+    /// The server stopped on its own, without being asked to.
+    Failed(String),
+}
+
+/// Serve `app` until `shutdown` resolves, keep accepting connections for
+/// `readiness_grace`, then stop and give in-flight requests up to
+/// `drain_timeout` to finish. The grace lets load balancers see the
+/// readiness probe fail, which `shutdown` should do, before connections
+/// get refused.
+/// Handlers can extract the peer address with [axum::extract::ConnectInfo].
+pub async fn serve_with_drain<F>(
+    listener: TcpListener,
+    app: axum::Router,
+    shutdown: F,
+    readiness_grace: Duration,
+    drain_timeout: Duration,
+) -> ServeOutcome
+where
+    F: Future<Output = ()>,
+{
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let mut server = tokio::spawn(
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move {
+                let _ = stop_rx.await;
+            })
+            .into_future(),
+    );
+
+    tokio::select! {
+        result = &mut server => return failed(result),
+        _ = shutdown => {}
+    }
+    if !readiness_grace.is_zero() {
+        tracing::info!("accepting connections for {:?} more", readiness_grace);
+        tokio::select! {
+            result = &mut server => return failed(result),
+            _ = tokio::time::sleep(readiness_grace) => {}
+        }
+    }
+
+    tracing::info!("draining connections for up to {:?}", drain_timeout);
+    let _ = stop_tx.send(());
+    match tokio::time::timeout(drain_timeout, &mut server).await {
+        Ok(_) => ServeOutcome::Drained,
+        Err(_) => {
+            server.abort();
+            ServeOutcome::TimedOut
+        }
+    }
+}
+
+/// [ServeOutcome::Failed] for a server that stopped with `result`.
+fn failed(result: Result<std::io::Result<()>, tokio::task::JoinError>) -> ServeOutcome {
+    ServeOutcome::Failed(match result {
+        Ok(Ok(())) => "server exited".to_string(),
+        Ok(Err(e)) => e.to_string(),
+        Err(e) => e.to_string(),
+    })
+}
+
+/// A task spawned by [BackgroundTasks].
+struct BackgroundTask {
+    name: &'static str,
+    stop_tx: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+/// Periodic tasks running next to the server.
+/// They are stopped one at a time, in the order they were spawned.
+pub struct BackgroundTasks {
+    tasks: Vec<BackgroundTask>,
+}
+
+impl BackgroundTasks {
+    pub fn new() -> BackgroundTasks {
+        BackgroundTasks {
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Run `job` every `period` until [BackgroundTasks::shutdown] is called.
+    pub fn spawn_periodic<J>(&mut self, name: &'static str, period: Duration, mut job: J)
+    where
+        J: FnMut() + Send + 'static,
+    {
+        self.spawn_periodic_async(name, period, move || {
+            job();
+            std::future::ready(())
+        });
+    }
+
+    /// Same as [BackgroundTasks::spawn_periodic] for a job that has to wait
+    /// on I/O. A job already running when stopping is awaited, not cancelled.
+    pub fn spawn_periodic_async<J, F>(&mut self, name: &'static str, period: Duration, mut job: J)
+    where
+        J: FnMut() -> F + Send + 'static,
+        F: Future<Output = ()> + Send,
+    {
+        let (stop_tx, mut stop_rx) = oneshot::channel::<()>();
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => job().await,
+                    _ = &mut stop_rx => break,
+                }
+            }
+        });
+        self.tasks.push(BackgroundTask { name, stop_tx, handle });
+    }
+
+    /// Run `task` until it finishes or [BackgroundTasks::shutdown] is called.
+    /// Unlike periodic jobs, a running `task` is cancelled when stopping,
+    /// so it can wait on streams that never end.
+    pub fn spawn<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = oneshot::channel::<()>();
+        let handle = tokio::spawn(async move {
+            tokio::select! {
+                _ = task => {}
+                _ = stop_rx => {}
+            }
+        });
+        self.tasks.push(BackgroundTask { name, stop_tx, handle });
+    }
+
+    /// Stop every task, waiting for each one before stopping the next.
+    pub async fn shutdown(self) {
+        for task in self.tasks {
+            let _ = task.stop_tx.send(());
+            match task.handle.await {
+                Ok(()) => tracing::debug!("background task {} stopped", task.name),
+                Err(e) => tracing::error!("background task {} failed: {e}", task.name),
+            }
+        }
+    }
+}
+
+impl Default for BackgroundTasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,416 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use crate::config::FanOut;
+use crate::models;
+use crate::state::{self, MockSnapRepository, SnapAppState, StorageError};
+
+pub use snap_api::SnapshotInfo;
+
+/// Name in the header of every snapshot.
+pub const FORMAT: &str = "snap-snapshot";
+
+/// Version of the snapshots written. Only snapshots of this version are restored.
+pub const VERSION: u32 = 1;
+
+/// Start and extension of the names of snapshot files.
+const PREFIX: &str = "snapshot-";
+const EXTENSION: &str = ".ndjson";
+
+/// First line of a snapshot.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Last line of a snapshot, telling it wasn't cut short.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Footer {
+    entries: usize,
+    /// SHA-256 of the entry lines, line breaks included.
+    sha256: String,
+}
+
+/// Reasons a snapshot can't be taken, read or restored.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// The store couldn't write its image.
+    Storage(StorageError),
+    /// The file isn't a snapshot or doesn't end with its footer.
+    Invalid(String),
+    /// The snapshot was written in another version of the format.
+    UnsupportedVersion(u32),
+    /// The entries don't match the checksum of the footer.
+    Checksum,
+    /// No snapshot has this name.
+    NotFound(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot I/O error: {e}"),
+            SnapshotError::Storage(e) => write!(f, "{e}"),
+            SnapshotError::Invalid(reason) => write!(f, "invalid snapshot: {reason}"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "snapshot version {version} isn't supported, expected {VERSION}")
+            }
+            SnapshotError::Checksum => write!(f, "the snapshot entries don't match their checksum"),
+            SnapshotError::NotFound(name) => write!(f, "no snapshot named {name}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+impl From<StorageError> for SnapshotError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::Io(e) => SnapshotError::Io(e),
+            e => SnapshotError::Storage(e),
+        }
+    }
+}
+
+/// Writer hashing what goes through it.
+struct Hashing<W> {
+    inner: W,
+    digest: Sha256,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Write a snapshot of `repo` to `out`: a header, the image of the store
+/// in the journal format and a footer. Returns the number of entries and
+/// their checksum.
+pub fn write<S: SnapAppState + ?Sized, W: Write>(repo: &S, mut out: W) -> Result<(usize, String), SnapshotError> {
+    let header = Header { format: FORMAT.to_string(), version: VERSION, created_at: chrono::Utc::now() };
+    line(&mut out, &header)?;
+    let mut hashing = Hashing { inner: out, digest: Sha256::new() };
+    let entries = repo.snapshot(&mut hashing)?;
+    let Hashing { inner: mut out, digest } = hashing;
+    let sha256 = models::hex(&digest.finalize());
+    line(&mut out, &Footer { entries, sha256: sha256.clone() })?;
+    out.flush()?;
+    Ok((entries, sha256))
+}
+
+fn line<W: Write, T: serde::Serialize>(out: &mut W, record: &T) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, record).expect("snapshot records are always serializable");
+    out.write_all(b"\n")
+}
+
+/// Take a snapshot of `repo` into a new file of `dir`, created if needed.
+/// The file only shows up under its name once complete.
+pub fn take<S: SnapAppState + ?Sized>(repo: &S, dir: &Path) -> Result<SnapshotInfo, SnapshotError> {
+    fs::create_dir_all(dir)?;
+    let now = chrono::Utc::now();
+    let name = format!("{PREFIX}{}{EXTENSION}", now.format("%Y%m%dT%H%M%S%.6fZ"));
+    let partial = dir.join(format!(".{name}.partial"));
+    let result = (|| {
+        let mut file = BufWriter::new(File::create(&partial)?);
+        write(repo, &mut file)?;
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&partial, dir.join(&name))?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&partial);
+        return Err(e)
+    }
+    info(dir, &name)
+}
+
+/// Path of the snapshot `name` of `dir`, if it is the name of a snapshot.
+pub fn path(dir: &Path, name: &str) -> Result<PathBuf, SnapshotError> {
+    let valid = name.starts_with(PREFIX)
+        && name.ends_with(EXTENSION)
+        && !name.contains(['/', '\\']);
+    let path = dir.join(name);
+    if !valid || !path.is_file() {
+        return Err(SnapshotError::NotFound(name.to_string()))
+    }
+    Ok(path)
+}
+
+/// Header and footer of the snapshot `name` of `dir`, without checking its entries.
+pub fn info(dir: &Path, name: &str) -> Result<SnapshotInfo, SnapshotError> {
+    let path = path(dir, name)?;
+    let mut file = File::open(&path)?;
+    let bytes = file.metadata()?.len();
+
+    let mut first = String::new();
+    BufReader::new(&mut file).read_line(&mut first)?;
+    let header = header(&first)?;
+
+    // The footer is short, only the end of the file is read.
+    let mut tail = String::new();
+    file.seek(SeekFrom::Start(bytes.saturating_sub(1024)))?;
+    file.read_to_string(&mut tail)?;
+    let last = tail.lines().last().unwrap_or_default();
+    let footer = serde_json::from_str::<Footer>(last)
+        .map_err(|_| SnapshotError::Invalid("the footer is missing".to_string()))?;
+
+    Ok(SnapshotInfo {
+        name: name.to_string(),
+        created_at: header.created_at.to_rfc3339(),
+        entries: footer.entries,
+        bytes,
+        sha256: footer.sha256,
+    })
+}
+
+fn header(line: &str) -> Result<Header, SnapshotError> {
+    let header = serde_json::from_str::<Header>(line)
+        .ok()
+        .filter(|header| header.format == FORMAT)
+        .ok_or_else(|| SnapshotError::Invalid(format!("not a {FORMAT} file")))?;
+    if header.version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(header.version))
+    }
+    Ok(header)
+}
+
+/// Names of the snapshots of `dir`, the most recent first. A missing
+/// directory has none.
+fn names(dir: &Path) -> Result<Vec<String>, SnapshotError> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(PREFIX) && name.ends_with(EXTENSION) {
+            names.push(name);
+        }
+    }
+    // Names hold the time they were taken, so they sort in that order.
+    names.sort_by(|a, b| b.cmp(a));
+    Ok(names)
+}
+
+/// Snapshots of `dir`, the most recent first. Unreadable files are skipped.
+pub fn list(dir: &Path) -> Result<Vec<SnapshotInfo>, SnapshotError> {
+    Ok(names(dir)?.iter().filter_map(|name| info(dir, name).ok()).collect())
+}
+
+/// Delete the snapshots of `dir` but the `keep` most recent ones and
+/// return the names of the deleted ones.
+pub fn prune(dir: &Path, keep: usize) -> Result<Vec<String>, SnapshotError> {
+    let old = names(dir)?.into_iter().skip(keep).collect::<Vec<String>>();
+    for name in &old {
+        fs::remove_file(dir.join(name))?;
+    }
+    Ok(old)
+}
+
+/// Check the snapshot at `path` by replaying it into a new in-memory
+/// store building timelines with `fan_out`, which is returned with its
+/// description.
+pub fn verify(path: &Path, fan_out: FanOut) -> Result<(SnapshotInfo, MockSnapRepository), SnapshotError> {
+    let repo = MockSnapRepository::with_fan_out(fan_out);
+    let info = read_entries(path, |line, number| {
+        state::replay_line(&repo, line, number)?;
+        Ok(())
+    })?;
+    Ok((info, repo))
+}
+
+/// Call `entry` with each entry of the snapshot at `path` and its line
+/// number, then check the footer.
+fn read_entries<F>(path: &Path, mut entry: F) -> Result<SnapshotInfo, SnapshotError>
+where
+    F: FnMut(&str, usize) -> Result<(), SnapshotError>,
+{
+    let file = File::open(path)?;
+    let bytes = file.metadata()?.len();
+    let mut lines = BufReader::new(file).lines();
+    let header = header(&lines.next().transpose()?.unwrap_or_default())?;
+    let mut digest = Sha256::new();
+    let mut entries = 0;
+    // Each line is only known to be an entry once the next one is read,
+    // the last one being the footer.
+    let mut last: Option<(String, usize)> = None;
+    for (index, line) in lines.enumerate() {
+        let line = line?;
+        if let Some((previous, number)) = last.replace((line, index + 2)) {
+            digest.update(previous.as_bytes());
+            digest.update(b"\n");
+            entries += 1;
+            entry(&previous, number)?;
+        }
+    }
+    let footer = last.and_then(|(line, _)| serde_json::from_str::<Footer>(&line).ok());
+    let footer = footer.ok_or_else(|| SnapshotError::Invalid("the footer is missing".to_string()))?;
+    if footer.entries != entries {
+        return Err(SnapshotError::Invalid(format!(
+            "the footer announces {} entries but there are {entries}",
+            footer.entries,
+        )))
+    }
+    if footer.sha256 != models::hex(&digest.finalize()) {
+        return Err(SnapshotError::Checksum)
+    }
+    Ok(SnapshotInfo {
+        name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        created_at: header.created_at.to_rfc3339(),
+        entries,
+        bytes,
+        sha256: footer.sha256,
+    })
+}
+
+/// Check the snapshot at `path` and make its entries the journal at
+/// `journal`. The current journal is kept next to it with a
+/// `.before-restore` suffix. Nothing changes if the check fails.
+pub fn restore_journal(path: &Path, journal: &Path, fan_out: FanOut) -> Result<SnapshotInfo, SnapshotError> {
+    verify(path, fan_out)?;
+    let partial = suffixed(journal, ".restoring");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    let info = read_entries(path, |line, _| {
+        out.write_all(line.as_bytes())?;
+        out.write_all(b"\n")?;
+        Ok(())
+    })?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    if journal.exists() {
+        fs::rename(journal, suffixed(journal, ".before-restore"))?;
+    }
+    fs::rename(&partial, journal)?;
+    Ok(info)
+}
+
+/// `path` with `suffix` added to its file name.
+fn suffixed(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod snapshot_test {
+    use super::*;
+    use crate::models::{ReactionKind, User};
+    use crate::state::{FollowRepository, JournalSnapRepository, UserRepository, Visibility};
+
+    /// Fill `repo` with two users following each other, a tombstone
+    /// kept for its reply and a reaction.
+    fn fill<S: SnapAppState + UserRepository + FollowRepository>(repo: &mut S) {
+        let alice = repo.create_user(User::new("alice".to_string(), "password")).unwrap().id();
+        let bob = repo.create_user(User::new("bob".to_string(), "password")).unwrap().id();
+        repo.follow(&alice, &bob).unwrap();
+        repo.follow(&bob, &alice).unwrap();
+        let first = repo.post(&alice, "First #rust").unwrap();
+        let reply = repo.reply(&bob, &first.id(), "Reply").unwrap();
+        repo.react(&reply.id(), &alice, ReactionKind::Love).unwrap();
+        repo.delete(&first.id()).unwrap();
+    }
+
+    fn check_restored(repo: &MockSnapRepository) {
+        assert_eq!(repo.user_count(), 2);
+        assert_eq!(repo.snap_count(), 1);
+        let reply = repo.get(Visibility::Public).pop().unwrap();
+        assert!(repo.get_snap(&reply.in_reply_to().unwrap()).unwrap().is_deleted());
+        assert_eq!(repo.reaction_counts(&reply.id())[&ReactionKind::Love], 1);
+        let alice = repo.get_user_by_username("alice").unwrap().id();
+        assert_eq!(repo.followers(&alice).len(), 1);
+    }
+
+    #[test]
+    fn memory_snapshots_restore_the_whole_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repo = MockSnapRepository::new();
+        fill(&mut repo);
+
+        let info = take(&repo, dir.path()).unwrap();
+        assert_eq!(info.entries, 7);
+        assert_eq!(list(dir.path()).unwrap(), vec![info.clone()]);
+
+        let (verified, restored) = verify(&dir.path().join(&info.name), FanOut::Write).unwrap();
+        assert_eq!(verified, info);
+        check_restored(&restored);
+    }
+
+    #[test]
+    fn journal_snapshots_copy_the_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repo = JournalSnapRepository::open(dir.path().join("snaps.journal")).unwrap();
+        fill(&mut repo);
+
+        let info = take(&repo, &dir.path().join("snapshots")).unwrap();
+        // Every change, including the deletion, is in the copy.
+        assert_eq!(info.entries, 8);
+
+        let journal = dir.path().join("restored.journal");
+        std::fs::write(&journal, "").unwrap();
+        let snapshot = dir.path().join("snapshots").join(&info.name);
+        restore_journal(&snapshot, &journal, FanOut::Read).unwrap();
+        assert!(dir.path().join("restored.journal.before-restore").exists());
+        let restored = JournalSnapRepository::open(&journal).unwrap();
+        assert_eq!(restored.snap_count(), 1);
+        assert_eq!(restored.user_count(), 2);
+    }
+
+    #[test]
+    fn damaged_snapshots_change_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repo = MockSnapRepository::new();
+        fill(&mut repo);
+        let info = take(&repo, dir.path()).unwrap();
+        let snapshot = dir.path().join(&info.name);
+        let text = std::fs::read_to_string(&snapshot).unwrap();
+        let journal = dir.path().join("snaps.journal");
+        std::fs::write(&journal, "kept\n").unwrap();
+
+        let damaged = dir.path().join("damaged");
+        std::fs::write(&damaged, text.replace("Reply", "Forged")).unwrap();
+        let error = restore_journal(&damaged, &journal, FanOut::Read).unwrap_err();
+        assert!(matches!(error, SnapshotError::Checksum), "{error}");
+
+        let cut = text.lines().take(4).collect::<Vec<&str>>().join("\n");
+        std::fs::write(&damaged, cut).unwrap();
+        let error = restore_journal(&damaged, &journal, FanOut::Read).unwrap_err();
+        assert!(matches!(error, SnapshotError::Invalid(_)), "{error}");
+
+        std::fs::write(&damaged, "{\"op\":\"user_created\"}\n").unwrap();
+        assert!(verify(&damaged, FanOut::Read).is_err());
+        assert_eq!(std::fs::read_to_string(&journal).unwrap(), "kept\n");
+    }
+
+    #[test]
+    fn pruning_keeps_the_most_recent() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = MockSnapRepository::new();
+        let names = (0..3).map(|_| take(&repo, dir.path()).unwrap().name).collect::<Vec<String>>();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        assert_eq!(prune(dir.path(), 2).unwrap(), vec![names[0].clone()]);
+        let left = list(dir.path()).unwrap().into_iter().map(|info| info.name).collect::<Vec<String>>();
+        assert_eq!(left, vec![names[2].clone(), names[1].clone()]);
+        assert!(matches!(path(dir.path(), "../notes.txt"), Err(SnapshotError::NotFound(_))));
+        assert!(matches!(path(dir.path(), "notes.txt"), Err(SnapshotError::NotFound(_))));
+    }
+}
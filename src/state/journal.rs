@@ -0,0 +1,1357 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::config::FanOut;
+use crate::models::{
+    ApiKey,
+    Attachment,
+    AuditEntry,
+    Delivery,
+    DeliveryStatus,
+    Notification,
+    NotificationPreferences,
+    ReactionKind,
+    Report,
+    Snap,
+    User,
+    Webhook,
+    WebhookEvent,
+};
+use super::{
+    ApiKeyError,
+    ApiKeyRepository,
+    ChangeLog,
+    FollowError,
+    FollowRepository,
+    HealthCheck,
+    Limits,
+    LoggedEvent,
+    MockSnapRepository,
+    memory::Image,
+    ModerationError,
+    ModerationRepository,
+    NotificationError,
+    NotificationRepository,
+    ReactionError,
+    SnapAppState,
+    SnapCreationError,
+    SnapUpdateError,
+    StorageError,
+    TimelineCursor,
+    UserCreationError,
+    UserRepository,
+    Visibility,
+    WebhookError,
+    WebhookRepository,
+};
+
+/// One line of the journal file.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum JournalEntry {
+    SnapCreated { snap: Snap },
+    SnapEdited { snap: Snap },
+    /// Snap stored as it came in a dump, see [SnapAppState::restore].
+    SnapRestored { snap: Snap },
+    SnapDeleted {
+        id: String,
+        /// Missing in journals written before tombstones existed.
+        #[serde(default)]
+        at: Option<chrono::DateTime<chrono::Utc>>,
+    },
+    UserCreated { user: User },
+    ApiKeyCreated { key: ApiKey },
+    ApiKeyRevoked { key: ApiKey },
+    ApiKeyUsed { id: String, at: chrono::DateTime<chrono::Utc> },
+    FollowCreated { follower_id: String, followee_id: String },
+    FollowRemoved { follower_id: String, followee_id: String },
+    ReactionAdded {
+        snap_id: String,
+        user_id: String,
+        kind: ReactionKind,
+        at: chrono::DateTime<chrono::Utc>,
+    },
+    ReactionRemoved { snap_id: String, user_id: String, kind: ReactionKind },
+    NotificationCreated { notification: Notification },
+    NotificationsRead {
+        user_id: String,
+        /// `None` when every notification was marked.
+        ids: Option<Vec<String>>,
+        at: chrono::DateTime<chrono::Utc>,
+    },
+    NotificationPreferencesSet { user_id: String, preferences: NotificationPreferences },
+    WebhookCreated { webhook: Webhook },
+    WebhookUpdated { webhook: Webhook },
+    WebhookDeleted { id: String },
+    DeliveryEnqueued { delivery: Delivery },
+    DeliveryUpdated { delivery: Delivery },
+    ReportFiled { report: Report },
+    /// Moderation action applied to its snap and added to the audit log.
+    SnapModerated { entry: AuditEntry },
+    /// Audit entry stored as it is, in snapshots where its snap already
+    /// carries the change.
+    AuditLogged { entry: AuditEntry },
+}
+
+/// Uses of an API key closer than this to the journaled one
+/// only update memory, so busy keys don't flood the journal.
+const LAST_USED_RESOLUTION: chrono::TimeDelta = chrono::TimeDelta::seconds(60);
+
+/// Changes between two marks of [Journal::marks].
+const MARK_EVERY: u64 = 1024;
+
+/// Repository that keeps snaps in memory and appends every
+/// change to a journal file, one JSON entry per line.
+/// Opening an existing journal replays it to rebuild the state.
+/// Writes are buffered until [SnapAppState::flush] is called.
+/// The journal is also its [ChangeLog], read back from the file.
+#[derive(Clone)]
+pub struct JournalSnapRepository {
+    snaps: MockSnapRepository,
+    journal_mtx: Arc<Mutex<Journal>>,
+    path: PathBuf,
+}
+
+/// End of the journal file, where entries are appended.
+struct Journal {
+    writer: BufWriter<File>,
+    /// Bytes in the file, buffered ones included.
+    len: u64,
+    /// Entries in the file, the offset of the next change.
+    count: u64,
+    /// Position in the file of every [MARK_EVERY]th entry, so reading
+    /// the changes from an offset doesn't start from the beginning.
+    marks: Vec<u64>,
+}
+
+impl Journal {
+    /// Account for a line of `bytes` bytes, line break included,
+    /// appended to the file.
+    fn add_line(&mut self, bytes: usize, blank: bool) {
+        if !blank {
+            if self.count == self.marks.len() as u64 * MARK_EVERY {
+                self.marks.push(self.len);
+            }
+            self.count += 1;
+        }
+        self.len += bytes as u64;
+    }
+
+    /// Append `line`, an entry without its line break.
+    fn append_line(&mut self, line: &[u8]) -> Result<(), StorageError> {
+        self.writer.write_all(line)?;
+        self.writer.write_all(b"\n")?;
+        self.add_line(line.len() + 1, false);
+        Ok(())
+    }
+}
+
+impl JournalSnapRepository {
+    /// Open the journal at `path`, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> Result<JournalSnapRepository, StorageError> {
+        Self::open_with_fan_out(path, FanOut::default())
+    }
+
+    /// Same as [JournalSnapRepository::open], building timelines with `fan_out`.
+    pub fn open_with_fan_out(
+        path: impl AsRef<Path>,
+        fan_out: FanOut,
+    ) -> Result<JournalSnapRepository, StorageError> {
+        let path = path.as_ref();
+        // The journal keeps the changes, memory doesn't need to.
+        let snaps = MockSnapRepository::with_fan_out(fan_out).with_change_buffer(0);
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut journal = Journal { writer: BufWriter::new(file), len: 0, count: 0, marks: Vec::new() };
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut line = Vec::new();
+        for number in 1.. {
+            line.clear();
+            if reader.read_until(b'\n', &mut line)? == 0 {
+                break;
+            }
+            let text = String::from_utf8_lossy(&line);
+            replay_line(&snaps, &text, number)?;
+            journal.add_line(line.len(), text.trim().is_empty());
+        }
+
+        Ok(JournalSnapRepository {
+            snaps,
+            journal_mtx: Arc::new(Mutex::new(journal)),
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Journal and store a new snap, see [SnapAppState::reply].
+    fn create(
+        &self,
+        author_id: &str,
+        parent_id: Option<&str>,
+        message: &str,
+        attachments: Vec<Attachment>,
+    ) -> Result<Snap, SnapCreationError> {
+        // Holding the journal lock keeps the file in the same order as the state.
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        let snap = self.snaps.new_snap(author_id, parent_id, message)?.with_attachments(attachments);
+        if self.snaps.contains(&snap.id()) {
+            return Err(SnapCreationError::IdCollisionError)
+        }
+        if let Some(parent) = snap.in_reply_to() {
+            match self.snaps.get_snap(&parent) {
+                Some(parent) if !parent.is_deleted() && !parent.is_hidden() => {},
+                _ => return Err(SnapCreationError::ParentNotFound),
+            }
+        }
+        if self.snaps.over_limit(&snap) {
+            return Err(SnapCreationError::QuotaExceeded)
+        }
+
+        Self::append(&mut journal, &JournalEntry::SnapCreated { snap: snap.clone() })
+            .map_err(SnapCreationError::StorageError)?;
+        self.snaps.insert(snap.clone())?;
+        Ok(snap)
+    }
+
+    fn append(journal: &mut Journal, entry: &JournalEntry) -> Result<(), StorageError> {
+        let line = serde_json::to_vec(entry)
+            .expect("journal entries are always serializable");
+        journal.append_line(&line)
+    }
+}
+
+/// Decode line `number` of a journal and apply it to `snaps`.
+/// Blank lines are skipped and replaying the same entry twice is harmless.
+pub(crate) fn replay_line(snaps: &MockSnapRepository, line: &str, number: usize) -> Result<(), StorageError> {
+    if line.trim().is_empty() {
+        return Ok(())
+    }
+    let entry: JournalEntry = serde_json::from_str(line)
+        .map_err(|e| StorageError::Corrupt { line: number, reason: e.to_string() })?;
+    match entry {
+        JournalEntry::SnapCreated { snap } => {
+            let _ = snaps.insert(snap);
+        }
+        JournalEntry::SnapEdited { snap } => {
+            let _ = snaps.replace(snap);
+        }
+        JournalEntry::SnapRestored { snap } => {
+            let _ = snaps.put(snap);
+        }
+        JournalEntry::SnapDeleted { id, at } => {
+            let _ = snaps.remove(&id, at.unwrap_or_else(chrono::Utc::now));
+        }
+        JournalEntry::UserCreated { user } => {
+            let _ = snaps.insert_user(user);
+        }
+        JournalEntry::ApiKeyCreated { key } | JournalEntry::ApiKeyRevoked { key } => {
+            snaps.put_api_key(key);
+        }
+        JournalEntry::ApiKeyUsed { id, at } => {
+            if let Some(mut key) = snaps.get_api_key(&id) {
+                key.touch(at);
+                snaps.put_api_key(key);
+            }
+        }
+        JournalEntry::FollowCreated { follower_id, followee_id } => {
+            snaps.add_follow(&follower_id, &followee_id);
+        }
+        JournalEntry::FollowRemoved { follower_id, followee_id } => {
+            snaps.remove_follow(&follower_id, &followee_id);
+        }
+        JournalEntry::ReactionAdded { snap_id, user_id, kind, at } => {
+            let _ = snaps.add_reaction(&snap_id, &user_id, kind, at);
+        }
+        JournalEntry::ReactionRemoved { snap_id, user_id, kind } => {
+            let _ = snaps.remove_reaction(&snap_id, &user_id, kind);
+        }
+        JournalEntry::NotificationCreated { notification } => {
+            snaps.put_notification(notification);
+        }
+        JournalEntry::NotificationsRead { user_id, ids, at } => {
+            snaps.mark_read_at(&user_id, ids.as_deref(), at);
+        }
+        JournalEntry::NotificationPreferencesSet { user_id, preferences } => {
+            snaps.put_preferences(&user_id, preferences);
+        }
+        JournalEntry::WebhookCreated { webhook } | JournalEntry::WebhookUpdated { webhook } => {
+            snaps.put_webhook(webhook);
+        }
+        JournalEntry::WebhookDeleted { id } => {
+            let _ = snaps.remove_webhook(&id);
+        }
+        JournalEntry::DeliveryEnqueued { delivery } | JournalEntry::DeliveryUpdated { delivery } => {
+            let _ = snaps.put_delivery(delivery);
+        }
+        JournalEntry::ReportFiled { report } => {
+            snaps.put_report(report);
+        }
+        JournalEntry::SnapModerated { entry } => {
+            let _ = snaps.moderate_at(entry);
+        }
+        JournalEntry::AuditLogged { entry } => {
+            snaps.put_audit_entry(entry);
+        }
+    }
+    Ok(())
+}
+
+/// Write `image` as journal entries to `out`, in an order [replay_line]
+/// can rebuild it from, and return the number of entries.
+pub(crate) fn write_image(image: &Image, out: &mut dyn Write) -> Result<usize, StorageError> {
+    let mut entries = Vec::new();
+    entries.extend(image.users.iter().map(|user| JournalEntry::UserCreated { user: user.clone() }));
+    entries.extend(image.api_keys.iter().map(|key| JournalEntry::ApiKeyCreated { key: key.clone() }));
+    entries.extend(image.follows.iter().map(|(follower_id, followee_id)| JournalEntry::FollowCreated {
+        follower_id: follower_id.clone(),
+        followee_id: followee_id.clone(),
+    }));
+    entries.extend(image.snaps.iter().map(|snap| JournalEntry::SnapRestored { snap: snap.clone() }));
+    entries.extend(image.reactions.iter().map(|(snap_id, user_id, kind, at)| JournalEntry::ReactionAdded {
+        snap_id: snap_id.clone(),
+        user_id: user_id.clone(),
+        kind: *kind,
+        at: *at,
+    }));
+    entries.extend(image.notifications.iter().map(|notification| JournalEntry::NotificationCreated {
+        notification: notification.clone(),
+    }));
+    entries.extend(image.preferences.iter().map(|(user_id, preferences)| JournalEntry::NotificationPreferencesSet {
+        user_id: user_id.clone(),
+        preferences: *preferences,
+    }));
+    entries.extend(image.webhooks.iter().map(|webhook| JournalEntry::WebhookCreated { webhook: webhook.clone() }));
+    entries.extend(image.deliveries.iter().map(|delivery| JournalEntry::DeliveryEnqueued { delivery: delivery.clone() }));
+    entries.extend(image.reports.iter().map(|report| JournalEntry::ReportFiled { report: report.clone() }));
+    entries.extend(image.audit.iter().map(|entry| JournalEntry::AuditLogged { entry: entry.clone() }));
+
+    let mut writer = BufWriter::new(out);
+    for entry in &entries {
+        serde_json::to_writer(&mut writer, entry).expect("journal entries are always serializable");
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(entries.len())
+}
+
+impl SnapAppState for JournalSnapRepository {
+    fn post(&mut self, author_id: &str, message: &str) -> Result<Snap, SnapCreationError> {
+        self.create(author_id, None, message, Vec::new())
+    }
+
+    fn reply(&mut self, author_id: &str, parent_id: &str, message: &str) -> Result<Snap, SnapCreationError> {
+        self.create(author_id, Some(parent_id), message, Vec::new())
+    }
+
+    fn post_with_attachments(
+        &mut self,
+        author_id: &str,
+        parent_id: Option<&str>,
+        message: &str,
+        attachments: Vec<Attachment>,
+    ) -> Result<Snap, SnapCreationError> {
+        self.create(author_id, parent_id, message, attachments)
+    }
+
+    fn get_snap(&self, id: &str) -> Option<Snap> {
+        self.snaps.get_snap(id)
+    }
+
+    fn edit(&mut self, id: &str, message: &str) -> Result<Snap, SnapUpdateError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        let mut snap = match self.snaps.get_snap(id) {
+            Some(snap) if !snap.is_deleted() && !snap.is_hidden() => snap,
+            _ => return Err(SnapUpdateError::NotFound),
+        };
+        snap.edit(message.to_string());
+        Self::append(&mut journal, &JournalEntry::SnapEdited { snap: snap.clone() })
+            .map_err(SnapUpdateError::StorageError)?;
+        self.snaps.replace(snap.clone())?;
+        Ok(snap)
+    }
+
+    fn delete(&mut self, id: &str) -> Result<Snap, SnapUpdateError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        match self.snaps.get_snap(id) {
+            Some(snap) if !snap.is_deleted() => {},
+            _ => return Err(SnapUpdateError::NotFound),
+        }
+        let at = chrono::Utc::now();
+        Self::append(&mut journal, &JournalEntry::SnapDeleted { id: id.to_string(), at: Some(at) })
+            .map_err(SnapUpdateError::StorageError)?;
+        self.snaps.remove(id, at)
+    }
+
+    fn get(&self, visibility: Visibility) -> Vec<Snap> {
+        self.snaps.get(visibility)
+    }
+
+    fn all_snaps(&self) -> Vec<Snap> {
+        self.snaps.all_snaps()
+    }
+
+    fn restore(&mut self, snap: Snap) -> Result<bool, SnapCreationError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if let Some(parent) = snap.in_reply_to() {
+            if self.snaps.get_snap(&parent).is_none() {
+                return Err(SnapCreationError::ParentNotFound)
+            }
+        }
+        if self.snaps.over_limit(&snap) {
+            return Err(SnapCreationError::QuotaExceeded)
+        }
+        Self::append(&mut journal, &JournalEntry::SnapRestored { snap: snap.clone() })
+            .map_err(SnapCreationError::StorageError)?;
+        self.snaps.put(snap)
+    }
+
+    fn get_by_author(&self, author_id: &str) -> Vec<Snap> {
+        self.snaps.get_by_author(author_id)
+    }
+
+    fn replies(&self, id: &str) -> Vec<Snap> {
+        self.snaps.replies(id)
+    }
+
+    fn reply_count(&self, id: &str) -> usize {
+        self.snaps.reply_count(id)
+    }
+
+    fn get_by_tag(&self, tag: &str, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<Snap> {
+        self.snaps.get_by_tag(tag, before, limit, visibility)
+    }
+
+    fn trending_tags(&self, since: chrono::DateTime<chrono::Utc>, limit: usize) -> Vec<(String, usize)> {
+        self.snaps.trending_tags(since, limit)
+    }
+
+    fn snap_count(&self) -> usize {
+        self.snaps.snap_count()
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        self.snaps.set_limits(limits);
+    }
+
+    fn limits(&self) -> Limits {
+        self.snaps.limits()
+    }
+
+    fn react(&mut self, snap_id: &str, user_id: &str, kind: ReactionKind) -> Result<bool, ReactionError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_user(user_id).is_none() {
+            return Err(ReactionError::UserNotFound)
+        }
+        match self.snaps.get_snap(snap_id) {
+            Some(snap) if !snap.is_deleted() && !snap.is_hidden() => {},
+            _ => return Err(ReactionError::SnapNotFound),
+        }
+        if self.snaps.has_reaction(snap_id, user_id, kind) {
+            return Ok(false)
+        }
+        let at = chrono::Utc::now();
+        let entry = JournalEntry::ReactionAdded {
+            snap_id: snap_id.to_string(),
+            user_id: user_id.to_string(),
+            kind,
+            at,
+        };
+        Self::append(&mut journal, &entry).map_err(ReactionError::StorageError)?;
+        self.snaps.add_reaction(snap_id, user_id, kind, at)
+    }
+
+    fn unreact(&mut self, snap_id: &str, user_id: &str, kind: ReactionKind) -> Result<bool, ReactionError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        match self.snaps.get_snap(snap_id) {
+            Some(snap) if !snap.is_deleted() => {},
+            _ => return Err(ReactionError::SnapNotFound),
+        }
+        if !self.snaps.has_reaction(snap_id, user_id, kind) {
+            return Ok(false)
+        }
+        let entry = JournalEntry::ReactionRemoved {
+            snap_id: snap_id.to_string(),
+            user_id: user_id.to_string(),
+            kind,
+        };
+        Self::append(&mut journal, &entry).map_err(ReactionError::StorageError)?;
+        self.snaps.remove_reaction(snap_id, user_id, kind)
+    }
+
+    fn reaction_counts(&self, snap_id: &str) -> BTreeMap<ReactionKind, usize> {
+        self.snaps.reaction_counts(snap_id)
+    }
+
+    fn reactors(&self, snap_id: &str, kind: ReactionKind) -> Vec<String> {
+        self.snaps.reactors(snap_id, kind)
+    }
+
+    fn events(&self, offset: u64, limit: usize) -> Vec<LoggedEvent> {
+        self.snaps.events(offset, limit)
+    }
+
+    fn event_count(&self) -> u64 {
+        self.snaps.event_count()
+    }
+
+    fn flush(&self) -> Result<(), StorageError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+        journal.writer.flush()?;
+        journal.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    fn snapshot(&self, out: &mut dyn Write) -> Result<usize, StorageError> {
+        // Writers wait for the copy, so it ends on a whole entry.
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+        journal.writer.flush()?;
+        let mut entries = 0;
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            out.write_all(line.as_bytes())?;
+            out.write_all(b"\n")?;
+            entries += 1;
+        }
+        Ok(entries)
+    }
+
+    fn health(&self) -> Vec<HealthCheck> {
+        let journal = HealthCheck::run("journal_writable", || {
+            let mut journal = self.journal_mtx
+                .lock()
+                .map_err(|_| "journal lock poisoned by a panicking thread".to_string())?;
+            // Pushing buffered entries to the file fails if it isn't writable anymore.
+            journal.writer.flush().map_err(|e| e.to_string())
+        });
+        vec![self.snaps.lock_check(), journal]
+    }
+}
+
+impl ChangeLog for JournalSnapRepository {
+    fn changes(&self, offset: u64, limit: usize) -> Result<Vec<String>, StorageError> {
+        let (start, skip, count) = {
+            let mut journal = self.journal_mtx
+                .lock()
+                .unwrap();
+            if offset >= journal.count {
+                return Ok(Vec::new())
+            }
+            // Entries before `count` are whole in the file once flushed,
+            // so they can be read while others are appended.
+            journal.writer.flush()?;
+            let mark = (offset / MARK_EVERY) as usize;
+            (journal.marks[mark], offset - mark as u64 * MARK_EVERY, journal.count - offset)
+        };
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let wanted = usize::try_from(count).unwrap_or(usize::MAX).min(limit);
+        let mut changes = Vec::with_capacity(wanted);
+        let mut lines = BufReader::new(file)
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .skip(skip as usize);
+        while changes.len() < wanted {
+            match lines.next() {
+                Some(line) => changes.push(line?),
+                None => break,
+            }
+        }
+        Ok(changes)
+    }
+
+    fn first_change(&self) -> u64 {
+        0
+    }
+
+    fn change_count(&self) -> u64 {
+        self.journal_mtx
+            .lock()
+            .unwrap()
+            .count
+    }
+
+    fn apply_change(&mut self, offset: u64, line: &str) -> Result<bool, StorageError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        let count = journal.count;
+        if offset < count {
+            return Ok(false)
+        }
+        if offset > count {
+            return Err(StorageError::OutOfOrder { expected: count, offset })
+        }
+        // Checked before journaling, so a bad line doesn't end up in the file.
+        serde_json::from_str::<JournalEntry>(line)
+            .map_err(|e| StorageError::Corrupt { line: offset as usize + 1, reason: e.to_string() })?;
+        journal.append_line(line.trim_end().as_bytes())?;
+        replay_line(&self.snaps, line, offset as usize + 1)?;
+        Ok(true)
+    }
+}
+
+impl UserRepository for JournalSnapRepository {
+    fn create_user(&mut self, user: User) -> Result<User, UserCreationError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_user_by_username(user.username()).is_some() {
+            return Err(UserCreationError::UsernameTaken)
+        }
+        if self.snaps.get_user(&user.id()).is_some() {
+            return Err(UserCreationError::IdCollisionError)
+        }
+        if self.snaps.limits().max_users.is_some_and(|max| self.snaps.user_count() >= max) {
+            return Err(UserCreationError::QuotaExceeded)
+        }
+
+        Self::append(&mut journal, &JournalEntry::UserCreated { user: user.clone() })
+            .map_err(UserCreationError::StorageError)?;
+        self.snaps.insert_user(user.clone())?;
+        Ok(user)
+    }
+
+    fn get_user(&self, id: &str) -> Option<User> {
+        self.snaps.get_user(id)
+    }
+
+    fn get_user_by_username(&self, username: &str) -> Option<User> {
+        self.snaps.get_user_by_username(username)
+    }
+
+    fn user_count(&self) -> usize {
+        self.snaps.user_count()
+    }
+}
+
+impl ApiKeyRepository for JournalSnapRepository {
+    fn create_api_key(&mut self, key: ApiKey) -> Result<ApiKey, ApiKeyError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_api_key(&key.id()).is_some() {
+            return Err(ApiKeyError::IdCollisionError)
+        }
+
+        Self::append(&mut journal, &JournalEntry::ApiKeyCreated { key: key.clone() })
+            .map_err(ApiKeyError::StorageError)?;
+        self.snaps.put_api_key(key.clone());
+        Ok(key)
+    }
+
+    fn get_api_key(&self, id: &str) -> Option<ApiKey> {
+        self.snaps.get_api_key(id)
+    }
+
+    fn get_api_keys_by_owner(&self, owner_id: &str) -> Vec<ApiKey> {
+        self.snaps.get_api_keys_by_owner(owner_id)
+    }
+
+    fn revoke_api_key(&mut self, id: &str) -> Result<ApiKey, ApiKeyError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        let mut key = self.snaps.get_api_key(id).ok_or(ApiKeyError::NotFound)?;
+        key.revoke();
+        Self::append(&mut journal, &JournalEntry::ApiKeyRevoked { key: key.clone() })
+            .map_err(ApiKeyError::StorageError)?;
+        self.snaps.put_api_key(key.clone());
+        Ok(key)
+    }
+
+    fn touch_api_key(&mut self, id: &str) -> Result<(), ApiKeyError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        let mut key = self.snaps.get_api_key(id).ok_or(ApiKeyError::NotFound)?;
+        let now = chrono::Utc::now();
+        let stale = match key.last_used_at() {
+            Some(last) => now - *last >= LAST_USED_RESOLUTION,
+            None => true,
+        };
+        if stale {
+            Self::append(&mut journal, &JournalEntry::ApiKeyUsed { id: key.id(), at: now })
+                .map_err(ApiKeyError::StorageError)?;
+        }
+        key.touch(now);
+        self.snaps.put_api_key(key);
+        Ok(())
+    }
+}
+
+impl FollowRepository for JournalSnapRepository {
+    fn follow(&mut self, follower_id: &str, followee_id: &str) -> Result<bool, FollowError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        self.snaps.check_follow(follower_id, followee_id)?;
+        if self.snaps.following(follower_id).iter().any(|id| id == followee_id) {
+            return Ok(false)
+        }
+        let entry = JournalEntry::FollowCreated {
+            follower_id: follower_id.to_string(),
+            followee_id: followee_id.to_string(),
+        };
+        Self::append(&mut journal, &entry).map_err(FollowError::StorageError)?;
+        Ok(self.snaps.add_follow(follower_id, followee_id))
+    }
+
+    fn unfollow(&mut self, follower_id: &str, followee_id: &str) -> Result<bool, FollowError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        self.snaps.check_follow(follower_id, followee_id)?;
+        if !self.snaps.following(follower_id).iter().any(|id| id == followee_id) {
+            return Ok(false)
+        }
+        let entry = JournalEntry::FollowRemoved {
+            follower_id: follower_id.to_string(),
+            followee_id: followee_id.to_string(),
+        };
+        Self::append(&mut journal, &entry).map_err(FollowError::StorageError)?;
+        Ok(self.snaps.remove_follow(follower_id, followee_id))
+    }
+
+    fn followers(&self, user_id: &str) -> Vec<String> {
+        self.snaps.followers(user_id)
+    }
+
+    fn following(&self, user_id: &str) -> Vec<String> {
+        self.snaps.following(user_id)
+    }
+
+    fn timeline(&self, user_id: &str, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<Snap> {
+        self.snaps.timeline(user_id, before, limit, visibility)
+    }
+}
+
+impl NotificationRepository for JournalSnapRepository {
+    fn notify(&mut self, notification: Notification) -> Result<bool, NotificationError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if !self.snaps.check_notification(&notification)? {
+            return Ok(false)
+        }
+        let entry = JournalEntry::NotificationCreated { notification: notification.clone() };
+        Self::append(&mut journal, &entry).map_err(NotificationError::StorageError)?;
+        self.snaps.put_notification(notification);
+        Ok(true)
+    }
+
+    fn notifications(
+        &self,
+        user_id: &str,
+        before: Option<&TimelineCursor>,
+        limit: usize,
+        unread_only: bool,
+    ) -> Vec<Notification> {
+        self.snaps.notifications(user_id, before, limit, unread_only)
+    }
+
+    fn unread_count(&self, user_id: &str) -> usize {
+        self.snaps.unread_count(user_id)
+    }
+
+    fn mark_read(&mut self, user_id: &str, ids: Option<&[String]>) -> Result<usize, NotificationError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        // Only journal reads that change something.
+        let unread = self.snaps.notifications(user_id, None, usize::MAX, true)
+            .iter()
+            .filter(|notification| match ids {
+                Some(ids) => ids.contains(&notification.id()),
+                None => true,
+            })
+            .count();
+        if unread == 0 {
+            return Ok(0)
+        }
+        let at = chrono::Utc::now();
+        let entry = JournalEntry::NotificationsRead {
+            user_id: user_id.to_string(),
+            ids: ids.map(<[String]>::to_vec),
+            at,
+        };
+        Self::append(&mut journal, &entry).map_err(NotificationError::StorageError)?;
+        Ok(self.snaps.mark_read_at(user_id, ids, at))
+    }
+
+    fn notification_preferences(&self, user_id: &str) -> NotificationPreferences {
+        self.snaps.notification_preferences(user_id)
+    }
+
+    fn set_notification_preferences(
+        &mut self,
+        user_id: &str,
+        preferences: NotificationPreferences,
+    ) -> Result<(), NotificationError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_user(user_id).is_none() {
+            return Err(NotificationError::UserNotFound)
+        }
+        let entry = JournalEntry::NotificationPreferencesSet {
+            user_id: user_id.to_string(),
+            preferences,
+        };
+        Self::append(&mut journal, &entry).map_err(NotificationError::StorageError)?;
+        self.snaps.put_preferences(user_id, preferences);
+        Ok(())
+    }
+}
+
+impl WebhookRepository for JournalSnapRepository {
+    fn create_webhook(&mut self, webhook: Webhook) -> Result<Webhook, WebhookError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_user(&webhook.owner_id()).is_none() {
+            return Err(WebhookError::OwnerNotFound)
+        }
+        if self.snaps.get_webhook(&webhook.id()).is_some() {
+            return Err(WebhookError::IdCollisionError)
+        }
+        Self::append(&mut journal, &JournalEntry::WebhookCreated { webhook: webhook.clone() })
+            .map_err(WebhookError::StorageError)?;
+        self.snaps.put_webhook(webhook.clone());
+        Ok(webhook)
+    }
+
+    fn get_webhook(&self, id: &str) -> Option<Webhook> {
+        self.snaps.get_webhook(id)
+    }
+
+    fn get_webhooks_by_owner(&self, owner_id: &str) -> Vec<Webhook> {
+        self.snaps.get_webhooks_by_owner(owner_id)
+    }
+
+    fn webhooks_for(&self, event: WebhookEvent) -> Vec<Webhook> {
+        self.snaps.webhooks_for(event)
+    }
+
+    fn update_webhook(&mut self, webhook: Webhook) -> Result<Webhook, WebhookError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_webhook(&webhook.id()).is_none() {
+            return Err(WebhookError::NotFound)
+        }
+        Self::append(&mut journal, &JournalEntry::WebhookUpdated { webhook: webhook.clone() })
+            .map_err(WebhookError::StorageError)?;
+        self.snaps.put_webhook(webhook.clone());
+        Ok(webhook)
+    }
+
+    fn delete_webhook(&mut self, id: &str) -> Result<Webhook, WebhookError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_webhook(id).is_none() {
+            return Err(WebhookError::NotFound)
+        }
+        Self::append(&mut journal, &JournalEntry::WebhookDeleted { id: id.to_string() })
+            .map_err(WebhookError::StorageError)?;
+        self.snaps.remove_webhook(id)
+    }
+
+    fn enqueue_delivery(&mut self, delivery: Delivery) -> Result<(), WebhookError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_webhook(&delivery.webhook_id()).is_none() {
+            return Err(WebhookError::NotFound)
+        }
+        if self.snaps.get_delivery(&delivery.id()).is_some() {
+            return Err(WebhookError::IdCollisionError)
+        }
+        Self::append(&mut journal, &JournalEntry::DeliveryEnqueued { delivery: delivery.clone() })
+            .map_err(WebhookError::StorageError)?;
+        self.snaps.put_delivery(delivery)
+    }
+
+    fn update_delivery(&mut self, delivery: Delivery) -> Result<(), WebhookError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if self.snaps.get_delivery(&delivery.id()).is_none() {
+            return Err(WebhookError::NotFound)
+        }
+        Self::append(&mut journal, &JournalEntry::DeliveryUpdated { delivery: delivery.clone() })
+            .map_err(WebhookError::StorageError)?;
+        self.snaps.put_delivery(delivery)
+    }
+
+    fn get_delivery(&self, id: &str) -> Option<Delivery> {
+        self.snaps.get_delivery(id)
+    }
+
+    fn due_deliveries(&self, now: chrono::DateTime<chrono::Utc>, limit: usize) -> Vec<Delivery> {
+        self.snaps.due_deliveries(now, limit)
+    }
+
+    fn deliveries(&self, webhook_id: &str, status: Option<DeliveryStatus>) -> Vec<Delivery> {
+        self.snaps.deliveries(webhook_id, status)
+    }
+}
+
+impl ModerationRepository for JournalSnapRepository {
+    fn report(&mut self, report: Report) -> Result<bool, ModerationError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        if !self.snaps.check_report(&report)? {
+            return Ok(false)
+        }
+        Self::append(&mut journal, &JournalEntry::ReportFiled { report: report.clone() })
+            .map_err(ModerationError::StorageError)?;
+        self.snaps.put_report(report);
+        Ok(true)
+    }
+
+    fn reports(&self, snap_id: &str) -> Vec<Report> {
+        self.snaps.reports(snap_id)
+    }
+
+    fn report_queue(&self, limit: usize) -> Vec<(String, usize)> {
+        self.snaps.report_queue(limit)
+    }
+
+    fn moderate(&mut self, entry: AuditEntry) -> Result<AuditEntry, ModerationError> {
+        let mut journal = self.journal_mtx
+            .lock()
+            .unwrap();
+
+        match self.snaps.get_snap(&entry.snap_id()) {
+            Some(snap) if !snap.is_deleted() => {},
+            _ => return Err(ModerationError::SnapNotFound),
+        }
+        let open = self.snaps.reports(&entry.snap_id()).len();
+        let entry = entry.with_reports(open);
+        Self::append(&mut journal, &JournalEntry::SnapModerated { entry: entry.clone() })
+            .map_err(ModerationError::StorageError)?;
+        self.snaps.moderate_at(entry)
+    }
+
+    fn audit_log(&self, before: Option<&TimelineCursor>, limit: usize) -> Vec<AuditEntry> {
+        self.snaps.audit_log(before, limit)
+    }
+}
+
+#[cfg(test)]
+mod journal_repo_test {
+    use super::*;
+    use std::collections::BTreeSet;
+    use crate::models::{DeliveryAttempt, ModerationAction, NotificationKind, Scope, Thumbnail};
+
+    #[test]
+    fn reopening_replays_the_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        let snap_a = repo.post(&author.id(), "A").unwrap();
+        let snap_b = repo.post(&author.id(), "B").unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        let user = repo.get_user(&author.id()).unwrap();
+        assert_eq!(user.username(), "author");
+        assert!(user.verify_password("password"));
+        assert_eq!(repo.get_by_author(&author.id()).len(), 2);
+        let snaps = repo.get(Visibility::Public);
+        assert_eq!(snaps.len(), 2);
+        assert_eq!(snaps[0].id(), snap_b.id());
+        assert_eq!(snaps[1].id(), snap_a.id());
+        assert_eq!(snaps[1].timestamp(), snap_a.timestamp());
+    }
+
+    #[test]
+    fn limits_are_checked_before_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        repo.set_limits(Limits { max_snaps: Some(1), max_users: Some(1) });
+        repo.post(&author.id(), "A").unwrap();
+        assert!(matches!(repo.post(&author.id(), "B"), Err(SnapCreationError::QuotaExceeded)));
+        let copy = Snap::new("C".to_string(), uuid::Uuid::new_v4());
+        assert!(matches!(repo.restore(copy), Err(SnapCreationError::QuotaExceeded)));
+        let user = User::new("other".to_string(), "password");
+        assert!(matches!(repo.create_user(user), Err(UserCreationError::QuotaExceeded)));
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        assert_eq!((repo.snap_count(), repo.user_count()), (1, 1));
+    }
+
+    #[test]
+    fn replay_applies_edits_and_deletes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        let kept = repo.post(&author.id(), "A").unwrap();
+        let deleted = repo.post(&author.id(), "B").unwrap();
+        let edited = repo.edit(&kept.id(), "A edited").unwrap();
+        repo.delete(&deleted.id()).unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        assert_eq!(repo.snap_count(), 1);
+        let snap = repo.get_snap(&kept.id()).unwrap();
+        assert_eq!(snap.message(), "A edited");
+        assert_eq!(snap.edited_at(), edited.edited_at());
+    }
+
+    #[test]
+    fn replay_keeps_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap().id();
+        let parent = repo.post(&author, "Parent").unwrap();
+        let reply = repo.reply(&author, &parent.id(), "Reply").unwrap();
+        repo.delete(&parent.id()).unwrap();
+        let deleted_at = repo.get_snap(&parent.id()).unwrap().deleted_at().copied();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        let tombstone = repo.get_snap(&parent.id()).unwrap();
+        assert_eq!(tombstone.deleted_at().copied(), deleted_at);
+        assert_eq!(repo.replies(&parent.id())[0].id(), reply.id());
+        assert_eq!(repo.snap_count(), 1);
+    }
+
+    #[test]
+    fn replay_keeps_restored_snaps() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = MockSnapRepository::new();
+        let mut users = source.clone();
+        let author = users.create_user(User::new("author".to_string(), "password")).unwrap().id();
+        let parent = users.post(&author, "Parent").unwrap();
+        let reply = users.reply(&author, &parent.id(), "Reply").unwrap();
+        users.delete(&parent.id()).unwrap();
+
+        let path = dir.path().join("snaps.journal");
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        for snap in source.all_snaps() {
+            assert!(!repo.restore(snap).unwrap());
+        }
+        repo.flush().unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        let snaps = repo.all_snaps();
+        assert_eq!(snaps.len(), 2);
+        assert!(snaps[0].is_deleted());
+        assert_eq!(snaps[1].timestamp(), reply.timestamp());
+        assert_eq!(repo.replies(&parent.id())[0].id(), reply.id());
+    }
+
+    #[test]
+    fn replay_restores_reactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap().id();
+        let snap = repo.post(&author, "A").unwrap();
+        repo.react(&snap.id(), &author, ReactionKind::Like).unwrap();
+        repo.react(&snap.id(), &author, ReactionKind::Love).unwrap();
+        repo.unreact(&snap.id(), &author, ReactionKind::Love).unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        assert_eq!(repo.reaction_counts(&snap.id()), BTreeMap::from([(ReactionKind::Like, 1)]));
+        assert_eq!(repo.reactors(&snap.id(), ReactionKind::Like), vec![author]);
+    }
+
+    #[test]
+    fn replay_restores_notifications() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let alice = repo.create_user(User::new("alice".to_string(), "password")).unwrap();
+        let bob = repo.create_user(User::new("bob".to_string(), "password")).unwrap();
+        let snap = repo.post(&bob.id(), "Hi @alice").unwrap();
+        let mention = Notification::new(NotificationKind::Mention, alice.uuid(), bob.uuid(), snap.uuid());
+        let reaction = Notification::reaction(alice.uuid(), bob.uuid(), snap.uuid(), ReactionKind::Like);
+        assert!(repo.notify(mention.clone()).unwrap());
+        assert!(repo.notify(reaction).unwrap());
+        assert_eq!(repo.mark_read(&alice.id(), Some(&[mention.id()])).unwrap(), 1);
+        let preferences = NotificationPreferences { reactions: false, ..Default::default() };
+        repo.set_notification_preferences(&alice.id(), preferences).unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        let notifications = repo.notifications(&alice.id(), None, 10, false);
+        assert_eq!(notifications.len(), 2);
+        assert_eq!(notifications[1].id(), mention.id());
+        assert!(notifications[1].is_read());
+        assert_eq!(repo.unread_count(&alice.id()), 1);
+        assert_eq!(repo.notification_preferences(&alice.id()), preferences);
+    }
+
+    #[test]
+    fn replay_keeps_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let alice = repo.create_user(User::new("alice".to_string(), "password")).unwrap();
+        let attachment = Attachment {
+            hash: "ab".repeat(32),
+            content_type: "image/png".to_string(),
+            size: 42,
+            width: 8,
+            height: 4,
+            thumbnails: vec![Thumbnail {
+                max_dimension: 2,
+                hash: "cd".repeat(32),
+                content_type: "image/png".to_string(),
+                width: 2,
+                height: 1,
+            }],
+        };
+        let snap = repo.post_with_attachments(&alice.id(), None, "Look", vec![attachment.clone()]).unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        assert_eq!(repo.get_snap(&snap.id()).unwrap().attachments(), [attachment]);
+    }
+
+    #[test]
+    fn replay_restores_webhooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let alice = repo.create_user(User::new("alice".to_string(), "password")).unwrap();
+        let events = BTreeSet::from([WebhookEvent::SnapCreated]);
+        let mut webhook = repo.create_webhook(Webhook::new(alice.uuid(), "http://localhost/a".to_string(), events)).unwrap();
+        webhook.set_url("http://localhost/b".to_string());
+        repo.update_webhook(webhook.clone()).unwrap();
+        let gone = repo.create_webhook(Webhook::new(alice.uuid(), "http://localhost/c".to_string(), BTreeSet::from(WebhookEvent::ALL))).unwrap();
+        repo.delete_webhook(&gone.id()).unwrap();
+        let mut delivery = Delivery::new(webhook.uuid(), WebhookEvent::SnapCreated, "{}".to_string());
+        repo.enqueue_delivery(delivery.clone()).unwrap();
+        let now = chrono::Utc::now();
+        delivery.fail(DeliveryAttempt { at: now, status: None, error: Some("refused".to_string()) }, None);
+        repo.update_delivery(delivery.clone()).unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        let webhooks = repo.get_webhooks_by_owner(&alice.id());
+        assert_eq!(webhooks.len(), 1);
+        assert_eq!(webhooks[0].url(), "http://localhost/b");
+        assert_eq!(webhooks[0].secret(), webhook.secret());
+        let restored = repo.get_delivery(&delivery.id()).unwrap();
+        assert_eq!(restored.status(), DeliveryStatus::Dead);
+        assert_eq!(restored.attempts().len(), 1);
+    }
+
+    #[test]
+    fn replay_restores_moderation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let alice = repo.create_user(User::new("alice".to_string(), "password")).unwrap();
+        let hidden = repo.post(&alice.id(), "Rude").unwrap();
+        let reported = repo.post(&alice.id(), "Borderline").unwrap();
+        repo.report(Report::new(hidden.uuid(), alice.uuid(), "rude".to_string())).unwrap();
+        repo.report(Report::new(reported.uuid(), alice.uuid(), "maybe".to_string())).unwrap();
+        let entry = AuditEntry::new(alice.uuid(), hidden.uuid(), ModerationAction::Hide, "rude".to_string());
+        let entry = repo.moderate(entry).unwrap();
+        assert_eq!(entry.reports(), 1);
+        let mut image = Vec::new();
+        repo.snapshot(&mut image).unwrap();
+        drop(repo);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        let restored = MockSnapRepository::new();
+        for (index, line) in String::from_utf8(image).unwrap().lines().enumerate() {
+            replay_line(&restored, line, index + 1).unwrap();
+        }
+        for snaps in [&repo.snaps, &restored] {
+            assert!(snaps.get_snap(&hidden.id()).unwrap().is_hidden());
+            assert_eq!(snaps.get(Visibility::Public).len(), 1);
+            assert_eq!(snaps.report_queue(10), vec![(reported.id(), 1)]);
+            let log = snaps.audit_log(None, 10);
+            assert_eq!(log.len(), 1);
+            assert_eq!((log[0].id(), log[0].reports()), (entry.id(), 1));
+        }
+    }
+
+    #[test]
+    fn replay_rebuilds_timelines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open_with_fan_out(&path, FanOut::Write).unwrap();
+        let alice = repo.create_user(User::new("alice".to_string(), "password")).unwrap().id();
+        let bob = repo.create_user(User::new("bob".to_string(), "password")).unwrap().id();
+        let carol = repo.create_user(User::new("carol".to_string(), "password")).unwrap().id();
+        repo.follow(&alice, &bob).unwrap();
+        repo.follow(&alice, &carol).unwrap();
+        let snap = repo.post(&bob, "A").unwrap();
+        repo.post(&carol, "B").unwrap();
+        repo.unfollow(&alice, &carol).unwrap();
+        drop(repo);
+
+        for fan_out in [FanOut::Read, FanOut::Write] {
+            let repo = JournalSnapRepository::open_with_fan_out(&path, fan_out).unwrap();
+            assert_eq!(repo.following(&alice), vec![bob.clone()]);
+            let timeline = repo.timeline(&alice, None, 10, Visibility::Public);
+            assert_eq!(timeline.iter().map(Snap::id).collect::<Vec<String>>(), vec![snap.id()]);
+        }
+    }
+
+    #[test]
+    fn replay_restores_api_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let owner = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        let (used, token) = ApiKey::new(owner.uuid(), "used".to_string(), Scope::Write);
+        let (revoked, _) = ApiKey::new(owner.uuid(), "revoked".to_string(), Scope::Read);
+        repo.create_api_key(used.clone()).unwrap();
+        repo.create_api_key(revoked.clone()).unwrap();
+        repo.touch_api_key(&used.id()).unwrap();
+        repo.touch_api_key(&used.id()).unwrap();
+        repo.revoke_api_key(&revoked.id()).unwrap();
+        drop(repo);
+
+        // Only the first of two close uses is journaled.
+        let journal = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(journal.matches("api_key_used").count(), 1);
+
+        let repo = JournalSnapRepository::open(&path).unwrap();
+        let key = repo.get_api_key(&used.id()).unwrap();
+        assert!(key.last_used_at().is_some());
+        assert!(key.verify_secret(ApiKey::parse_token(&token).unwrap().1));
+        assert!(repo.get_api_key(&revoked.id()).unwrap().revoked_at().is_some());
+    }
+
+    #[test]
+    fn the_change_log_is_read_from_the_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let owner = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        let (key, _) = ApiKey::new(owner.uuid(), "key".to_string(), Scope::Write);
+        repo.create_api_key(key.clone()).unwrap();
+        repo.touch_api_key(&key.id()).unwrap();
+        for i in 0..MARK_EVERY + 10 {
+            repo.post(&owner.id(), &format!("Snap {i}")).unwrap();
+        }
+        let count = MARK_EVERY + 13;
+        assert_eq!(repo.change_count(), count);
+
+        // Every line of the journal is a change, uses of API keys included.
+        let all = repo.changes(0, usize::MAX).unwrap();
+        let journal = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(all, journal.lines().map(str::to_string).collect::<Vec<String>>());
+        assert!(all[2].contains("api_key_used"));
+        let offset = MARK_EVERY + 1;
+        assert_eq!(repo.changes(offset, 3).unwrap(), all[offset as usize..offset as usize + 3]);
+        assert!(repo.changes(count, 3).unwrap().is_empty());
+
+        let follower_path = dir.path().join("follower.journal");
+        let mut follower = JournalSnapRepository::open(&follower_path).unwrap();
+        for (offset, change) in all.iter().enumerate() {
+            assert!(follower.apply_change(offset as u64, change).unwrap());
+        }
+        assert!(follower.get_api_key(&key.id()).unwrap().last_used_at().is_some());
+        follower.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&follower_path).unwrap(), journal);
+
+        drop(repo);
+        std::fs::write(&path, format!("\n{journal}")).unwrap();
+        let reopened = JournalSnapRepository::open(&path).unwrap();
+        assert_eq!(reopened.change_count(), count);
+        assert_eq!(reopened.changes(offset, 3).unwrap(), all[offset as usize..offset as usize + 3]);
+    }
+
+    #[test]
+    fn flush_writes_the_journal() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+
+        let mut repo = JournalSnapRepository::open(&path).unwrap();
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        repo.post(&author.id(), "A").unwrap();
+        repo.flush().unwrap();
+
+        // The repository is still open, so only flushed entries are visible.
+        let reopened = JournalSnapRepository::open(&path).unwrap();
+        assert_eq!(reopened.snap_count(), 1);
+    }
+
+    #[test]
+    fn corrupt_journal_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("snaps.journal");
+        std::fs::write(&path, "{\"op\":\"snap_created\"}\n").unwrap();
+
+        match JournalSnapRepository::open(&path) {
+            Err(StorageError::Corrupt { line, .. }) => assert_eq!(line, 1),
+            other => panic!("expected a corrupt journal error, got {:?}", other.err()),
+        }
+    }
+}
@@ -0,0 +1,2098 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use crate::config::{FanOut, ReplicationConfig};
+use crate::entities;
+use crate::models::{
+    ApiKey,
+    Attachment,
+    AuditEntry,
+    Delivery,
+    DeliveryStatus,
+    Notification,
+    NotificationPreferences,
+    ModerationAction,
+    ReactionKind,
+    Report,
+    Snap,
+    User,
+    Webhook,
+    WebhookEvent,
+};
+use super::{
+    ApiKeyError,
+    ApiKeyRepository,
+    ChangeLog,
+    FollowError,
+    FollowRepository,
+    HealthCheck,
+    Limits,
+    LoggedEvent,
+    ModerationError,
+    ModerationRepository,
+    NotificationError,
+    NotificationRepository,
+    ReactionError,
+    SnapAppState,
+    SnapCreationError,
+    SnapEvent,
+    SnapUpdateError,
+    StorageError,
+    TimelineCursor,
+    UserCreationError,
+    UserRepository,
+    Visibility,
+    WebhookError,
+    WebhookRepository,
+};
+use super::journal::JournalEntry;
+
+/// Simple repository for snaps in memory.
+#[derive(Clone, Default)]
+pub struct MockSnapRepository {
+    snaps_mtx: Arc<Mutex<Snaps>>,
+    users_mtx: Arc<Mutex<Users>>,
+    api_keys_mtx: Arc<Mutex<HashMap<String, ApiKey>>>,
+    follows_mtx: Arc<Mutex<Follows>>,
+    /// Home timeline of each user, only filled with [FanOut::Write].
+    inboxes_mtx: Arc<Mutex<HashMap<String, BTreeSet<TimelineCursor>>>>,
+    /// Reactions to each snap, with the time each user reacted.
+    reactions_mtx: Arc<Mutex<HashMap<String, Reactions>>>,
+    notifications_mtx: Arc<Mutex<Notifications>>,
+    webhooks_mtx: Arc<Mutex<Webhooks>>,
+    moderation_mtx: Arc<Mutex<Moderation>>,
+    /// Latest changes in the journal format, see [ChangeLog].
+    changes_mtx: Arc<Mutex<Changes>>,
+    /// See [SnapAppState::set_limits].
+    limits_mtx: Arc<Mutex<Limits>>,
+    fan_out: FanOut,
+}
+
+type DateTime = chrono::DateTime<chrono::Utc>;
+
+/// Users who reacted to a snap by kind, with the time of the reaction.
+type Reactions = BTreeMap<ReactionKind, HashMap<String, DateTime>>;
+
+/// Snaps indexed by id, alongside the replies to each one.
+#[derive(Default)]
+struct Snaps {
+    by_id: HashMap<String, Snap>,
+    replies: HashMap<String, BTreeSet<TimelineCursor>>,
+    /// Snaps using each hashtag, tombstones left out.
+    tags: HashMap<String, BTreeSet<TimelineCursor>>,
+    /// Every change to the snaps, see [SnapAppState::events].
+    log: Vec<LoggedEvent>,
+}
+
+impl Snaps {
+    /// Snaps that aren't tombstones.
+    fn live(&self) -> impl Iterator<Item = &Snap> {
+        self.by_id.values().filter(|snap| !snap.is_deleted())
+    }
+
+    /// Whether storing `snap` would make more than `max` live snaps.
+    fn over(&self, snap: &Snap, max: Option<usize>) -> bool {
+        let Some(max) = max else {
+            return false
+        };
+        let adds = !snap.is_deleted() && match self.by_id.get(&snap.id()) {
+            Some(old) => old.is_deleted(),
+            None => true,
+        };
+        adds && self.live().count() >= max
+    }
+
+    /// Live snaps shown to `visibility`.
+    fn visible(&self, visibility: Visibility) -> impl Iterator<Item = &Snap> {
+        self.live().filter(move |snap| shown(snap, visibility))
+    }
+
+    /// Add `snap` to the index of each of its hashtags.
+    fn index_tags(&mut self, snap: &Snap) {
+        for tag in entities::hashtags(snap.message()) {
+            self.tags.entry(tag).or_default().insert(TimelineCursor::from(snap));
+        }
+    }
+
+    /// Append `event` to the log.
+    fn record(&mut self, event: SnapEvent) {
+        let offset = self.log.len() as u64;
+        self.log.push(LoggedEvent { offset, event });
+    }
+
+    /// Remove `snap` from the index of each of its hashtags.
+    fn unindex_tags(&mut self, snap: &Snap) {
+        for tag in entities::hashtags(snap.message()) {
+            if let Some(cursors) = self.tags.get_mut(&tag) {
+                cursors.remove(&TimelineCursor::from(snap));
+                if cursors.is_empty() {
+                    self.tags.remove(&tag);
+                }
+            }
+        }
+    }
+}
+
+/// Whether `snap` shows to `visibility`, tombstones aside.
+fn shown(snap: &Snap, visibility: Visibility) -> bool {
+    visibility == Visibility::Moderator || !snap.is_hidden()
+}
+
+/// Whole state of a [MockSnapRepository], see [MockSnapRepository::image].
+pub(crate) struct Image {
+    pub users: Vec<User>,
+    pub api_keys: Vec<ApiKey>,
+    /// Follower and followee of each follow.
+    pub follows: Vec<(String, String)>,
+    /// Tombstones included, oldest first.
+    pub snaps: Vec<Snap>,
+    /// Snap, user, kind and time of each reaction.
+    pub reactions: Vec<(String, String, ReactionKind, DateTime)>,
+    pub notifications: Vec<Notification>,
+    pub preferences: Vec<(String, NotificationPreferences)>,
+    pub webhooks: Vec<Webhook>,
+    pub deliveries: Vec<Delivery>,
+    /// Open and resolved, oldest first.
+    pub reports: Vec<Report>,
+    /// Oldest first.
+    pub audit: Vec<AuditEntry>,
+}
+
+/// Users indexed by id and by lowercase username.
+#[derive(Default)]
+struct Users {
+    by_id: HashMap<String, User>,
+    ids_by_username: HashMap<String, String>,
+}
+
+/// Follow graph indexed in both directions.
+#[derive(Default)]
+struct Follows {
+    following: HashMap<String, BTreeSet<String>>,
+    followers: HashMap<String, BTreeSet<String>>,
+}
+
+/// Notification inbox of each user, alongside their preferences.
+#[derive(Default)]
+struct Notifications {
+    inboxes: HashMap<String, BTreeMap<TimelineCursor, Notification>>,
+    preferences: HashMap<String, NotificationPreferences>,
+}
+
+/// Webhooks by id, alongside every delivery queued for them.
+#[derive(Default)]
+struct Webhooks {
+    by_id: HashMap<String, Webhook>,
+    deliveries: HashMap<String, Delivery>,
+}
+
+/// Latest changes of the log, dropping the oldest past `capacity`.
+struct Changes {
+    /// Offset of the first line.
+    first: u64,
+    lines: VecDeque<String>,
+    capacity: usize,
+}
+
+impl Changes {
+    /// Offset of the next change.
+    fn count(&self) -> u64 {
+        self.first + self.lines.len() as u64
+    }
+
+    fn push(&mut self, line: String) {
+        self.lines.push_back(line);
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        while self.lines.len() > self.capacity {
+            self.lines.pop_front();
+            self.first += 1;
+        }
+    }
+}
+
+impl Default for Changes {
+    fn default() -> Self {
+        Changes {
+            first: 0,
+            lines: VecDeque::new(),
+            capacity: ReplicationConfig::default().change_buffer,
+        }
+    }
+}
+
+/// Reports on each snap, alongside the audit log of the moderators.
+#[derive(Default)]
+struct Moderation {
+    /// Open and resolved, oldest first.
+    reports: HashMap<String, Vec<Report>>,
+    audit: BTreeMap<TimelineCursor, AuditEntry>,
+}
+
+impl MockSnapRepository {
+    /// Create a new empty repository.
+    pub fn new() -> MockSnapRepository {
+        MockSnapRepository::default()
+    }
+
+    /// Create a new empty repository building timelines with `fan_out`.
+    pub fn with_fan_out(fan_out: FanOut) -> MockSnapRepository {
+        MockSnapRepository { fan_out, ..MockSnapRepository::default() }
+    }
+
+    /// Keep only the latest `capacity` changes for the followers.
+    pub fn with_change_buffer(self, capacity: usize) -> MockSnapRepository {
+        let mut changes = self.changes_mtx.lock().unwrap();
+        changes.capacity = capacity;
+        changes.trim();
+        drop(changes);
+        self
+    }
+
+    /// Store an already built snap, keeping its id and timestamp.
+    /// The snap it replies to, if any, must be stored and not deleted.
+    pub(crate) fn insert(&self, snap: Snap) -> Result<(), SnapCreationError> {
+        self.insert_within(snap, None)
+    }
+
+    /// Same as [MockSnapRepository::insert], failing when `max` live
+    /// snaps are stored already.
+    fn insert_within(&self, snap: Snap, max: Option<usize>) -> Result<(), SnapCreationError> {
+        let mut snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+
+        if snaps.by_id.contains_key(&snap.id()) {
+            return Err(SnapCreationError::IdCollisionError)
+        }
+        if snaps.over(&snap, max) {
+            return Err(SnapCreationError::QuotaExceeded)
+        }
+        if let Some(parent) = snap.in_reply_to() {
+            match snaps.by_id.get(&parent) {
+                Some(parent) if !parent.is_deleted() && !parent.is_hidden() => {},
+                _ => return Err(SnapCreationError::ParentNotFound),
+            }
+            snaps.replies.entry(parent).or_default().insert(TimelineCursor::from(&snap));
+        }
+
+        snaps.index_tags(&snap);
+        snaps.by_id.insert(snap.id(), snap.clone());
+        snaps.record(SnapEvent::SnapCreated { snap: snap.clone() });
+        self.log_change(&JournalEntry::SnapCreated { snap: snap.clone() });
+        drop(snaps);
+        if self.fan_out == FanOut::Write {
+            let cursor = TimelineCursor::from(&snap);
+            let mut inboxes = self.inboxes_mtx
+                .lock()
+                .unwrap();
+            for user in self.audience(&snap.author_id()) {
+                inboxes.entry(user).or_default().insert(cursor.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// Store `snap` as it is in place of any snap with the same id,
+    /// see [SnapAppState::restore].
+    pub(crate) fn put(&self, snap: Snap) -> Result<bool, SnapCreationError> {
+        self.put_within(snap, None)
+    }
+
+    /// Same as [MockSnapRepository::put], failing when `snap` is new and
+    /// `max` live snaps are stored already.
+    fn put_within(&self, snap: Snap, max: Option<usize>) -> Result<bool, SnapCreationError> {
+        let mut snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+
+        if let Some(parent) = snap.in_reply_to() {
+            if !snaps.by_id.contains_key(&parent) {
+                return Err(SnapCreationError::ParentNotFound)
+            }
+        }
+        if snaps.over(&snap, max) {
+            return Err(SnapCreationError::QuotaExceeded)
+        }
+        let old = snaps.by_id.remove(&snap.id());
+        if let Some(old) = &old {
+            snaps.unindex_tags(old);
+            if let Some(parent) = old.in_reply_to() {
+                if let Some(replies) = snaps.replies.get_mut(&parent) {
+                    replies.remove(&TimelineCursor::from(old));
+                    if replies.is_empty() {
+                        snaps.replies.remove(&parent);
+                    }
+                }
+            }
+        }
+        if let Some(parent) = snap.in_reply_to() {
+            snaps.replies.entry(parent).or_default().insert(TimelineCursor::from(&snap));
+        }
+        if !snap.is_deleted() {
+            snaps.index_tags(&snap);
+        }
+        snaps.by_id.insert(snap.id(), snap.clone());
+        snaps.record(SnapEvent::SnapRestored { snap: snap.clone() });
+        self.log_change(&JournalEntry::SnapRestored { snap: snap.clone() });
+        drop(snaps);
+
+        if self.fan_out == FanOut::Write {
+            let mut inboxes = self.inboxes_mtx
+                .lock()
+                .unwrap();
+            if let Some(old) = &old {
+                for user in self.audience(&old.author_id()) {
+                    if let Some(inbox) = inboxes.get_mut(&user) {
+                        inbox.remove(&TimelineCursor::from(old));
+                    }
+                }
+            }
+            if !snap.is_deleted() {
+                for user in self.audience(&snap.author_id()) {
+                    inboxes.entry(user).or_default().insert(TimelineCursor::from(&snap));
+                }
+            }
+        }
+        Ok(old.is_some())
+    }
+
+    /// Whether storing `snap` would go over [Limits::max_snaps].
+    pub(crate) fn over_limit(&self, snap: &Snap) -> bool {
+        let max = self.limits().max_snaps;
+        self.snaps_mtx.lock().unwrap().over(snap, max)
+    }
+
+    /// Users whose timeline shows the snaps of `author_id`.
+    fn audience(&self, author_id: &str) -> Vec<String> {
+        let mut users = self.followers(author_id);
+        users.push(author_id.to_string());
+        users
+    }
+
+    /// Store a follow, without checking the users.
+    pub(crate) fn add_follow(&self, follower_id: &str, followee_id: &str) -> bool {
+        let mut follows = self.follows_mtx
+            .lock()
+            .unwrap();
+        let added = follows.following
+            .entry(follower_id.to_string())
+            .or_default()
+            .insert(followee_id.to_string());
+        follows.followers
+            .entry(followee_id.to_string())
+            .or_default()
+            .insert(follower_id.to_string());
+        if added {
+            self.log_change(&JournalEntry::FollowCreated {
+                follower_id: follower_id.to_string(),
+                followee_id: followee_id.to_string(),
+            });
+        }
+        drop(follows);
+
+        if added && self.fan_out == FanOut::Write {
+            let backfill = self.get_by_author(followee_id);
+            let mut inboxes = self.inboxes_mtx
+                .lock()
+                .unwrap();
+            inboxes.entry(follower_id.to_string())
+                .or_default()
+                .extend(backfill.iter().map(TimelineCursor::from));
+        }
+        added
+    }
+
+    /// Remove a follow, without checking the users.
+    pub(crate) fn remove_follow(&self, follower_id: &str, followee_id: &str) -> bool {
+        let mut follows = self.follows_mtx
+            .lock()
+            .unwrap();
+        let removed = follows.following
+            .get_mut(follower_id)
+            .is_some_and(|followees| followees.remove(followee_id));
+        if let Some(followers) = follows.followers.get_mut(followee_id) {
+            followers.remove(follower_id);
+        }
+        if removed {
+            self.log_change(&JournalEntry::FollowRemoved {
+                follower_id: follower_id.to_string(),
+                followee_id: followee_id.to_string(),
+            });
+        }
+        drop(follows);
+
+        if removed && self.fan_out == FanOut::Write {
+            let gone = self.get_by_author(followee_id);
+            let mut inboxes = self.inboxes_mtx
+                .lock()
+                .unwrap();
+            if let Some(inbox) = inboxes.get_mut(follower_id) {
+                for snap in &gone {
+                    inbox.remove(&TimelineCursor::from(snap));
+                }
+            }
+        }
+        removed
+    }
+
+    /// Check both users of a follow exist and are different.
+    pub(crate) fn check_follow(&self, follower_id: &str, followee_id: &str) -> Result<(), FollowError> {
+        let users = self.users_mtx
+            .lock()
+            .unwrap();
+        if !users.by_id.contains_key(follower_id) || !users.by_id.contains_key(followee_id) {
+            return Err(FollowError::UserNotFound)
+        }
+        if follower_id == followee_id {
+            return Err(FollowError::SelfFollow)
+        }
+        Ok(())
+    }
+
+    /// Store an already built user, keeping its id and password hash.
+    pub(crate) fn insert_user(&self, user: User) -> Result<(), UserCreationError> {
+        self.insert_user_within(user, None)
+    }
+
+    /// Same as [MockSnapRepository::insert_user], failing when `max`
+    /// users are stored already.
+    fn insert_user_within(&self, user: User, max: Option<usize>) -> Result<(), UserCreationError> {
+        let mut users = self.users_mtx
+            .lock()
+            .unwrap();
+
+        let username = user.username().to_lowercase();
+        if users.ids_by_username.contains_key(&username) {
+            return Err(UserCreationError::UsernameTaken)
+        }
+        if users.by_id.contains_key(&user.id()) {
+            return Err(UserCreationError::IdCollisionError)
+        }
+        if max.is_some_and(|max| users.by_id.len() >= max) {
+            return Err(UserCreationError::QuotaExceeded)
+        }
+
+        self.log_change(&JournalEntry::UserCreated { user: user.clone() });
+        users.ids_by_username.insert(username, user.id());
+        users.by_id.insert(user.id(), user);
+        Ok(())
+    }
+
+    /// Store an API key, replacing a previous version of it.
+    pub(crate) fn put_api_key(&self, key: ApiKey) {
+        let mut keys = self.api_keys_mtx
+            .lock()
+            .unwrap();
+        // Uses only move `last_used_at`, which isn't worth a change.
+        match keys.get(&key.id()) {
+            None => self.log_change(&JournalEntry::ApiKeyCreated { key: key.clone() }),
+            Some(old) if old.revoked_at() != key.revoked_at() => {
+                self.log_change(&JournalEntry::ApiKeyRevoked { key: key.clone() });
+            }
+            Some(_) => {}
+        }
+        keys.insert(key.id(), key);
+    }
+
+    /// Build a snap for `author_id` after checking the author exists,
+    /// replying to `parent_id` if given.
+    pub(crate) fn new_snap(
+        &self,
+        author_id: &str,
+        parent_id: Option<&str>,
+        message: &str,
+    ) -> Result<Snap, SnapCreationError> {
+        let author = match Uuid::parse_str(author_id) {
+            Ok(id) if self.users_mtx.lock().unwrap().by_id.contains_key(&id.to_string()) => id,
+            _ => return Err(SnapCreationError::AuthorNotFound),
+        };
+        match parent_id.map(Uuid::parse_str) {
+            None => Ok(Snap::new(String::from(message), author)),
+            Some(Ok(parent)) => Ok(Snap::reply(String::from(message), author, parent)),
+            Some(Err(_)) => Err(SnapCreationError::ParentNotFound),
+        }
+    }
+
+    /// Replace a stored snap with a newer version of itself.
+    pub(crate) fn replace(&self, snap: Snap) -> Result<(), SnapUpdateError> {
+        let mut snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let old = match snaps.by_id.get(&snap.id()) {
+            Some(old) => old.clone(),
+            None => return Err(SnapUpdateError::NotFound),
+        };
+        snaps.unindex_tags(&old);
+        if !snap.is_deleted() {
+            snaps.index_tags(&snap);
+        }
+        snaps.record(SnapEvent::SnapEdited {
+            id: snap.id(),
+            message: snap.message().to_string(),
+            at: snap.edited_at().copied().unwrap_or_else(chrono::Utc::now),
+        });
+        self.log_change(&JournalEntry::SnapEdited { snap: snap.clone() });
+        snaps.by_id.insert(snap.id(), snap);
+        Ok(())
+    }
+
+    /// Remove a stored snap deleted at `at`, leaving a tombstone
+    /// if it has replies. Returns the snap as it was.
+    pub(crate) fn remove(&self, id: &str, at: DateTime) -> Result<Snap, SnapUpdateError> {
+        self.remove_logging(id, at, &JournalEntry::SnapDeleted { id: id.to_string(), at: Some(at) })
+    }
+
+    /// Same as [MockSnapRepository::remove], logging `change` as the change made.
+    fn remove_logging(&self, id: &str, at: DateTime, change: &JournalEntry) -> Result<Snap, SnapUpdateError> {
+        let mut snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let snap = match snaps.by_id.get(id) {
+            Some(snap) if !snap.is_deleted() => snap.clone(),
+            _ => return Err(SnapUpdateError::NotFound),
+        };
+        snaps.unindex_tags(&snap);
+        if snaps.replies.contains_key(id) {
+            snaps.by_id.get_mut(id).unwrap().tombstone(at);
+        } else {
+            // Tombstones only exist for their replies, prune the ones left without any.
+            let mut removed = snaps.by_id.remove(id);
+            while let Some(child) = removed.take() {
+                let Some(parent) = child.in_reply_to() else { break };
+                let replies = snaps.replies.entry(parent.clone()).or_default();
+                replies.remove(&TimelineCursor::from(&child));
+                if !replies.is_empty() {
+                    break
+                }
+                snaps.replies.remove(&parent);
+                if snaps.by_id.get(&parent).is_some_and(Snap::is_deleted) {
+                    removed = snaps.by_id.remove(&parent);
+                }
+            }
+        }
+        snaps.record(SnapEvent::SnapDeleted { id: id.to_string(), at });
+        self.log_change(change);
+        // Still under the snaps lock, so nobody reacts to the snap in between.
+        self.reactions_mtx
+            .lock()
+            .unwrap()
+            .remove(id);
+        drop(snaps);
+
+        if self.fan_out == FanOut::Write {
+            let cursor = TimelineCursor::from(&snap);
+            let mut inboxes = self.inboxes_mtx
+                .lock()
+                .unwrap();
+            for user in self.audience(&snap.author_id()) {
+                if let Some(inbox) = inboxes.get_mut(&user) {
+                    inbox.remove(&cursor);
+                }
+            }
+        }
+        Ok(snap)
+    }
+
+    /// Hide a stored snap at `at`, or show it again with `None`, logging
+    /// `change` as the change made. Hiding a hidden snap keeps the first time.
+    fn set_hidden(&self, id: &str, at: Option<DateTime>, change: &JournalEntry) -> Result<Snap, SnapUpdateError> {
+        let mut snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let mut snap = match snaps.by_id.get(id) {
+            Some(snap) if !snap.is_deleted() => snap.clone(),
+            _ => return Err(SnapUpdateError::NotFound),
+        };
+        match (at, snap.is_hidden()) {
+            (Some(at), false) => {
+                snap.set_hidden(Some(at));
+                snaps.record(SnapEvent::SnapHidden { id: id.to_string(), at });
+            }
+            (None, true) => {
+                snap.set_hidden(None);
+                snaps.record(SnapEvent::SnapUnhidden { snap: snap.clone() });
+            }
+            _ => {}
+        }
+        snaps.by_id.insert(snap.id(), snap.clone());
+        self.log_change(change);
+        Ok(snap)
+    }
+
+    /// Check the reporter of `report` exists and its snap can be reported.
+    /// Returns whether the report is new, see [ModerationRepository::report].
+    pub(crate) fn check_report(&self, report: &Report) -> Result<bool, ModerationError> {
+        if self.get_user(&report.reporter_id()).is_none() {
+            return Err(ModerationError::UserNotFound)
+        }
+        match self.get_snap(&report.snap_id()) {
+            Some(snap) if !snap.is_deleted() && !snap.is_hidden() => {},
+            _ => return Err(ModerationError::SnapNotFound),
+        }
+        let duplicate = self.reports(&report.snap_id())
+            .iter()
+            .any(|open| open.reporter_id() == report.reporter_id());
+        Ok(!duplicate)
+    }
+
+    /// Store an already built report, keeping its id and resolution.
+    pub(crate) fn put_report(&self, report: Report) {
+        let mut moderation = self.moderation_mtx
+            .lock()
+            .unwrap();
+        self.log_change(&JournalEntry::ReportFiled { report: report.clone() });
+        moderation.reports
+            .entry(report.snap_id())
+            .or_default()
+            .push(report);
+    }
+
+    /// Apply the action of `entry`, resolve the open reports on its snap
+    /// at the time of the action and add it to the audit log, see
+    /// [ModerationRepository::moderate].
+    pub(crate) fn moderate_at(&self, entry: AuditEntry) -> Result<AuditEntry, ModerationError> {
+        // Held throughout so no report lands between the count and the resolution.
+        let mut moderation = self.moderation_mtx
+            .lock()
+            .unwrap();
+        let id = entry.snap_id();
+        let at = *entry.at();
+        let reports = moderation.reports.entry(id.clone()).or_default();
+        let open = reports.iter().filter(|report| report.is_open()).count();
+        let entry = entry.with_reports(open);
+
+        let change = JournalEntry::SnapModerated { entry: entry.clone() };
+        let applied = match entry.action() {
+            ModerationAction::Hide => self.set_hidden(&id, Some(at), &change),
+            ModerationAction::Restore => self.set_hidden(&id, None, &change),
+            ModerationAction::Delete => self.remove_logging(&id, at, &change),
+        };
+        if applied.is_err() {
+            if reports.is_empty() {
+                moderation.reports.remove(&id);
+            }
+            return Err(ModerationError::SnapNotFound)
+        }
+        for report in reports.iter_mut() {
+            report.resolve(at);
+        }
+        moderation.audit.insert(TimelineCursor::from(&entry), entry.clone());
+        Ok(entry)
+    }
+
+    /// Store an already built audit entry without touching its snap.
+    pub(crate) fn put_audit_entry(&self, entry: AuditEntry) {
+        let mut moderation = self.moderation_mtx
+            .lock()
+            .unwrap();
+        self.log_change(&JournalEntry::AuditLogged { entry: entry.clone() });
+        moderation.audit.insert(TimelineCursor::from(&entry), entry);
+    }
+
+    /// Store a reaction made at `at` after checking the user and the snap exist.
+    pub(crate) fn add_reaction(
+        &self,
+        snap_id: &str,
+        user_id: &str,
+        kind: ReactionKind,
+        at: DateTime,
+    ) -> Result<bool, ReactionError> {
+        if self.get_user(user_id).is_none() {
+            return Err(ReactionError::UserNotFound)
+        }
+        // The snaps lock is held so the snap can't be deleted meanwhile.
+        let snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        match snaps.by_id.get(snap_id) {
+            Some(snap) if !snap.is_deleted() && !snap.is_hidden() => {},
+            _ => return Err(ReactionError::SnapNotFound),
+        }
+        let mut reactions = self.reactions_mtx
+            .lock()
+            .unwrap();
+        let users = reactions.entry(snap_id.to_string())
+            .or_default()
+            .entry(kind)
+            .or_default();
+        if users.contains_key(user_id) {
+            return Ok(false)
+        }
+        users.insert(user_id.to_string(), at);
+        self.log_change(&JournalEntry::ReactionAdded {
+            snap_id: snap_id.to_string(),
+            user_id: user_id.to_string(),
+            kind,
+            at,
+        });
+        Ok(true)
+    }
+
+    /// Remove a reaction after checking the snap exists.
+    pub(crate) fn remove_reaction(
+        &self,
+        snap_id: &str,
+        user_id: &str,
+        kind: ReactionKind,
+    ) -> Result<bool, ReactionError> {
+        let snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        match snaps.by_id.get(snap_id) {
+            Some(snap) if !snap.is_deleted() => {},
+            _ => return Err(ReactionError::SnapNotFound),
+        }
+        let mut reactions = self.reactions_mtx
+            .lock()
+            .unwrap();
+        let Some(by_kind) = reactions.get_mut(snap_id) else {
+            return Ok(false)
+        };
+        let removed = by_kind.get_mut(&kind)
+            .is_some_and(|users| users.remove(user_id).is_some());
+        by_kind.retain(|_, users| !users.is_empty());
+        if by_kind.is_empty() {
+            reactions.remove(snap_id);
+        }
+        if removed {
+            self.log_change(&JournalEntry::ReactionRemoved {
+                snap_id: snap_id.to_string(),
+                user_id: user_id.to_string(),
+                kind,
+            });
+        }
+        Ok(removed)
+    }
+
+    /// Whether `user_id` reacted with `kind` to the snap `snap_id`.
+    pub(crate) fn has_reaction(&self, snap_id: &str, user_id: &str, kind: ReactionKind) -> bool {
+        self.reactions_mtx
+            .lock()
+            .unwrap()
+            .get(snap_id)
+            .and_then(|by_kind| by_kind.get(&kind))
+            .is_some_and(|users| users.contains_key(user_id))
+    }
+
+    /// Whether `notification` should be stored, after checking its
+    /// recipient exists.
+    pub(crate) fn check_notification(&self, notification: &Notification) -> Result<bool, NotificationError> {
+        let user_id = notification.user_id();
+        if self.get_user(&user_id).is_none() {
+            return Err(NotificationError::UserNotFound)
+        }
+        Ok(self.notification_preferences(&user_id).allows(notification.kind()))
+    }
+
+    /// Store an already built notification, keeping its id and read state.
+    pub(crate) fn put_notification(&self, notification: Notification) {
+        let mut notifications = self.notifications_mtx
+            .lock()
+            .unwrap();
+        self.log_change(&JournalEntry::NotificationCreated { notification: notification.clone() });
+        notifications.inboxes
+            .entry(notification.user_id())
+            .or_default()
+            .insert(TimelineCursor::from(&notification), notification);
+    }
+
+    /// Mark notifications of `user_id` as read at `at`, see
+    /// [NotificationRepository::mark_read].
+    pub(crate) fn mark_read_at(&self, user_id: &str, ids: Option<&[String]>, at: DateTime) -> usize {
+        let mut notifications = self.notifications_mtx
+            .lock()
+            .unwrap();
+        let Some(inbox) = notifications.inboxes.get_mut(user_id) else {
+            return 0
+        };
+        let mut marked = 0;
+        for notification in inbox.values_mut() {
+            let selected = match ids {
+                Some(ids) => ids.contains(&notification.id()),
+                None => true,
+            };
+            if selected && !notification.is_read() {
+                notification.mark_read(at);
+                marked += 1;
+            }
+        }
+        if marked > 0 {
+            self.log_change(&JournalEntry::NotificationsRead {
+                user_id: user_id.to_string(),
+                ids: ids.map(<[String]>::to_vec),
+                at,
+            });
+        }
+        marked
+    }
+
+    /// Store the preferences of `user_id` without checking the user exists.
+    pub(crate) fn put_preferences(&self, user_id: &str, preferences: NotificationPreferences) {
+        let mut notifications = self.notifications_mtx
+            .lock()
+            .unwrap();
+        self.log_change(&JournalEntry::NotificationPreferencesSet { user_id: user_id.to_string(), preferences });
+        notifications.preferences.insert(user_id.to_string(), preferences);
+    }
+
+    /// Store a webhook, replacing a previous version of it.
+    pub(crate) fn put_webhook(&self, webhook: Webhook) {
+        let mut webhooks = self.webhooks_mtx
+            .lock()
+            .unwrap();
+        if webhooks.by_id.contains_key(&webhook.id()) {
+            self.log_change(&JournalEntry::WebhookUpdated { webhook: webhook.clone() });
+        } else {
+            self.log_change(&JournalEntry::WebhookCreated { webhook: webhook.clone() });
+        }
+        webhooks.by_id.insert(webhook.id(), webhook);
+    }
+
+    /// Remove a webhook and its deliveries.
+    pub(crate) fn remove_webhook(&self, id: &str) -> Result<Webhook, WebhookError> {
+        let mut webhooks = self.webhooks_mtx
+            .lock()
+            .unwrap();
+        let webhook = webhooks.by_id.remove(id).ok_or(WebhookError::NotFound)?;
+        webhooks.deliveries.retain(|_, delivery| delivery.webhook_id() != id);
+        self.log_change(&JournalEntry::WebhookDeleted { id: id.to_string() });
+        Ok(webhook)
+    }
+
+    /// Store a delivery of a stored webhook, replacing a previous version of it.
+    pub(crate) fn put_delivery(&self, delivery: Delivery) -> Result<(), WebhookError> {
+        let mut webhooks = self.webhooks_mtx
+            .lock()
+            .unwrap();
+        if !webhooks.by_id.contains_key(&delivery.webhook_id()) {
+            return Err(WebhookError::NotFound)
+        }
+        if webhooks.deliveries.contains_key(&delivery.id()) {
+            self.log_change(&JournalEntry::DeliveryUpdated { delivery: delivery.clone() });
+        } else {
+            self.log_change(&JournalEntry::DeliveryEnqueued { delivery: delivery.clone() });
+        }
+        webhooks.deliveries.insert(delivery.id(), delivery);
+        Ok(())
+    }
+
+    /// Copy of the whole state taken with every lock held at once, so
+    /// no change lands halfway. Home timelines are left out as they
+    /// follow from the follows and the snaps.
+    pub(crate) fn image(&self) -> Image {
+        // Same order as the nested locks elsewhere: follows or moderation,
+        // then snaps, then reactions.
+        let users = self.users_mtx.lock().unwrap();
+        let api_keys = self.api_keys_mtx.lock().unwrap();
+        let follows = self.follows_mtx.lock().unwrap();
+        let moderation = self.moderation_mtx.lock().unwrap();
+        let snaps = self.snaps_mtx.lock().unwrap();
+        let reactions = self.reactions_mtx.lock().unwrap();
+        let notifications = self.notifications_mtx.lock().unwrap();
+        let webhooks = self.webhooks_mtx.lock().unwrap();
+
+        let mut image = Image {
+            users: users.by_id.values().cloned().collect(),
+            api_keys: api_keys.values().cloned().collect(),
+            follows: follows.following
+                .iter()
+                .flat_map(|(follower, followees)| {
+                    followees.iter().map(|followee| (follower.clone(), followee.clone()))
+                })
+                .collect(),
+            snaps: snaps.by_id.values().cloned().collect(),
+            reactions: reactions
+                .iter()
+                .flat_map(|(snap_id, by_kind)| by_kind.iter().flat_map(move |(kind, users)| {
+                    users.iter().map(move |(user_id, at)| (snap_id.clone(), user_id.clone(), *kind, *at))
+                }))
+                .collect(),
+            notifications: notifications.inboxes.values().flat_map(|inbox| inbox.values().cloned()).collect(),
+            preferences: notifications.preferences
+                .iter()
+                .map(|(user_id, preferences)| (user_id.clone(), *preferences))
+                .collect(),
+            webhooks: webhooks.by_id.values().cloned().collect(),
+            deliveries: webhooks.deliveries.values().cloned().collect(),
+            reports: moderation.reports.values().flatten().cloned().collect(),
+            audit: moderation.audit.values().cloned().collect(),
+        };
+        // Parents before replies and a stable order for the rest.
+        image.users.sort_by_key(|user| (*user.created_at(), user.id()));
+        image.api_keys.sort_by_key(|key| (*key.created_at(), key.id()));
+        image.follows.sort();
+        image.snaps.sort_by_key(|snap| TimelineCursor::from(snap));
+        image.reactions.sort_by(|a, b| (a.3, &a.0, &a.1).cmp(&(b.3, &b.0, &b.1)));
+        image.notifications.sort_by_key(|notification| TimelineCursor::from(notification));
+        image.preferences.sort_by(|a, b| a.0.cmp(&b.0));
+        image.webhooks.sort_by_key(|webhook| (*webhook.created_at(), webhook.id()));
+        image.deliveries.sort_by_key(|delivery| (*delivery.created_at(), delivery.id()));
+        image.reports.sort_by_key(|report| (*report.created_at(), report.id()));
+        image
+    }
+
+    /// Append `entry` to the change log. Called with the lock of what
+    /// changed held, so the log keeps the order of the changes.
+    fn log_change(&self, entry: &JournalEntry) {
+        let line = serde_json::to_string(entry)
+            .expect("journal entries are always serializable");
+        self.changes_mtx
+            .lock()
+            .unwrap()
+            .push(line);
+    }
+
+    /// Check that the locks can still be used.
+    pub(crate) fn lock_check(&self) -> HealthCheck {
+        HealthCheck::run("locks", || {
+            if self.snaps_mtx.is_poisoned()
+                || self.users_mtx.is_poisoned()
+                || self.api_keys_mtx.is_poisoned()
+                || self.follows_mtx.is_poisoned()
+                || self.inboxes_mtx.is_poisoned()
+                || self.reactions_mtx.is_poisoned()
+                || self.notifications_mtx.is_poisoned()
+                || self.webhooks_mtx.is_poisoned()
+                || self.moderation_mtx.is_poisoned()
+                || self.changes_mtx.is_poisoned()
+            {
+                return Err("lock poisoned by a panicking thread".to_string());
+            }
+            Ok(())
+        })
+    }
+
+    /// Whether a snap with this id is stored.
+    pub(crate) fn contains(&self, id: &str) -> bool {
+        self.snaps_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .contains_key(id)
+    }
+}
+
+impl SnapAppState for MockSnapRepository {
+    fn post(&mut self, author_id: &str, message: &str) -> Result<Snap, SnapCreationError> {
+        let snap = self.new_snap(author_id, None, message)?;
+        self.insert_within(snap.clone(), self.limits().max_snaps)?;
+        Ok(snap)
+    }
+
+    fn post_with_attachments(
+        &mut self,
+        author_id: &str,
+        parent_id: Option<&str>,
+        message: &str,
+        attachments: Vec<Attachment>,
+    ) -> Result<Snap, SnapCreationError> {
+        let snap = self.new_snap(author_id, parent_id, message)?.with_attachments(attachments);
+        self.insert_within(snap.clone(), self.limits().max_snaps)?;
+        Ok(snap)
+    }
+
+    fn reply(&mut self, author_id: &str, parent_id: &str, message: &str) -> Result<Snap, SnapCreationError> {
+        let snap = self.new_snap(author_id, Some(parent_id), message)?;
+        self.insert_within(snap.clone(), self.limits().max_snaps)?;
+        Ok(snap)
+    }
+
+    fn get_snap(&self, id: &str) -> Option<Snap> {
+        self.snaps_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .get(id)
+            .cloned()
+    }
+
+    fn edit(&mut self, id: &str, message: &str) -> Result<Snap, SnapUpdateError> {
+        let mut snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let mut snap = match snaps.by_id.get(id) {
+            Some(snap) if !snap.is_deleted() && !snap.is_hidden() => snap.clone(),
+            _ => return Err(SnapUpdateError::NotFound),
+        };
+        snaps.unindex_tags(&snap);
+        snap.edit(String::from(message));
+        snaps.index_tags(&snap);
+        snaps.by_id.insert(snap.id(), snap.clone());
+        snaps.record(SnapEvent::SnapEdited {
+            id: snap.id(),
+            message: snap.message().to_string(),
+            at: snap.edited_at().copied().unwrap_or_else(chrono::Utc::now),
+        });
+        self.log_change(&JournalEntry::SnapEdited { snap: snap.clone() });
+        Ok(snap)
+    }
+
+    fn delete(&mut self, id: &str) -> Result<Snap, SnapUpdateError> {
+        self.remove(id, chrono::Utc::now())
+    }
+
+    fn get(&self, visibility: Visibility) -> Vec<Snap> {
+        let mut vec = self.snaps_mtx
+            .lock()
+            .unwrap()
+            .visible(visibility)
+            .cloned()
+            .collect::<Vec<Snap>>();
+
+        vec.sort_by(|a, b| b.timestamp().cmp(a.timestamp()));
+        vec
+    }
+
+    fn all_snaps(&self) -> Vec<Snap> {
+        let mut vec = self.snaps_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .values()
+            .cloned()
+            .collect::<Vec<Snap>>();
+
+        vec.sort_by_key(|snap| TimelineCursor::from(snap));
+        vec
+    }
+
+    fn restore(&mut self, snap: Snap) -> Result<bool, SnapCreationError> {
+        self.put_within(snap, self.limits().max_snaps)
+    }
+
+    fn get_by_author(&self, author_id: &str) -> Vec<Snap> {
+        let mut vec = self.snaps_mtx
+            .lock()
+            .unwrap()
+            .visible(Visibility::Public)
+            .filter(|snap| snap.author_id() == author_id)
+            .cloned()
+            .collect::<Vec<Snap>>();
+
+        vec.sort_by(|a, b| b.timestamp().cmp(a.timestamp()));
+        vec
+    }
+
+    fn replies(&self, id: &str) -> Vec<Snap> {
+        let snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        snaps.replies
+            .get(id)
+            .into_iter()
+            .flatten()
+            .filter_map(|reply| snaps.by_id.get(reply.id()))
+            .cloned()
+            .collect()
+    }
+
+    fn get_by_tag(&self, tag: &str, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<Snap> {
+        let snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let Some(tagged) = snaps.tags.get(&tag.to_lowercase()) else {
+            return Vec::new()
+        };
+        let newer = match before {
+            Some(before) => tagged.range(..before),
+            None => tagged.range(..),
+        };
+        newer.rev()
+            .filter_map(|cursor| snaps.by_id.get(cursor.id()))
+            .filter(|snap| shown(snap, visibility))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    fn trending_tags(&self, since: DateTime, limit: usize) -> Vec<(String, usize)> {
+        let snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let mut vec = snaps.tags
+            .iter()
+            .map(|(tag, tagged)| {
+                let recent = tagged.iter()
+                    .rev()
+                    .take_while(|cursor| cursor.timestamp() >= &since)
+                    .filter(|cursor| snaps.by_id.get(cursor.id()).is_some_and(|snap| !snap.is_hidden()))
+                    .count();
+                (tag.clone(), recent)
+            })
+            .filter(|(_, recent)| *recent > 0)
+            .collect::<Vec<(String, usize)>>();
+
+        vec.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        vec.truncate(limit);
+        vec
+    }
+
+    fn reply_count(&self, id: &str) -> usize {
+        self.snaps_mtx
+            .lock()
+            .unwrap()
+            .replies
+            .get(id)
+            .map_or(0, BTreeSet::len)
+    }
+
+    fn snap_count(&self) -> usize {
+        self.snaps_mtx
+            .lock()
+            .unwrap()
+            .live()
+            .count()
+    }
+
+    fn set_limits(&mut self, limits: Limits) {
+        *self.limits_mtx.lock().unwrap() = limits;
+    }
+
+    fn limits(&self) -> Limits {
+        *self.limits_mtx.lock().unwrap()
+    }
+
+    fn react(&mut self, snap_id: &str, user_id: &str, kind: ReactionKind) -> Result<bool, ReactionError> {
+        self.add_reaction(snap_id, user_id, kind, chrono::Utc::now())
+    }
+
+    fn unreact(&mut self, snap_id: &str, user_id: &str, kind: ReactionKind) -> Result<bool, ReactionError> {
+        self.remove_reaction(snap_id, user_id, kind)
+    }
+
+    fn reaction_counts(&self, snap_id: &str) -> BTreeMap<ReactionKind, usize> {
+        self.reactions_mtx
+            .lock()
+            .unwrap()
+            .get(snap_id)
+            .map(|by_kind| by_kind.iter().map(|(kind, users)| (*kind, users.len())).collect())
+            .unwrap_or_default()
+    }
+
+    fn reactors(&self, snap_id: &str, kind: ReactionKind) -> Vec<String> {
+        let mut vec = self.reactions_mtx
+            .lock()
+            .unwrap()
+            .get(snap_id)
+            .and_then(|by_kind| by_kind.get(&kind))
+            .map(|users| users.iter().map(|(user, at)| (*at, user.clone())).collect::<Vec<_>>())
+            .unwrap_or_default();
+
+        vec.sort();
+        vec.into_iter().map(|(_, user)| user).collect()
+    }
+
+    fn events(&self, offset: u64, limit: usize) -> Vec<LoggedEvent> {
+        let snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(snaps.log.len());
+        snaps.log[start..].iter().take(limit).cloned().collect()
+    }
+
+    fn event_count(&self) -> u64 {
+        self.snaps_mtx
+            .lock()
+            .unwrap()
+            .log
+            .len() as u64
+    }
+
+    fn snapshot(&self, out: &mut dyn std::io::Write) -> Result<usize, StorageError> {
+        super::journal::write_image(&self.image(), out)
+    }
+
+    fn health(&self) -> Vec<HealthCheck> {
+        vec![self.lock_check()]
+    }
+}
+
+impl ChangeLog for MockSnapRepository {
+    fn changes(&self, offset: u64, limit: usize) -> Result<Vec<String>, StorageError> {
+        let changes = self.changes_mtx
+            .lock()
+            .unwrap();
+        if offset < changes.first {
+            return Err(StorageError::Compacted { first: changes.first, offset })
+        }
+        let start = usize::try_from(offset - changes.first).unwrap_or(usize::MAX);
+        Ok(changes.lines.iter().skip(start).take(limit).cloned().collect())
+    }
+
+    fn first_change(&self) -> u64 {
+        self.changes_mtx
+            .lock()
+            .unwrap()
+            .first
+    }
+
+    fn change_count(&self) -> u64 {
+        self.changes_mtx
+            .lock()
+            .unwrap()
+            .count()
+    }
+
+    fn apply_change(&mut self, offset: u64, line: &str) -> Result<bool, StorageError> {
+        let count = self.change_count();
+        if offset < count {
+            return Ok(false)
+        }
+        if offset > count {
+            return Err(StorageError::OutOfOrder { expected: count, offset })
+        }
+        super::journal::replay_line(self, line, offset as usize + 1)?;
+        // A change that turns out to change nothing here still takes its offset.
+        let mut changes = self.changes_mtx
+            .lock()
+            .unwrap();
+        if changes.count() == count {
+            changes.push(line.to_string());
+        }
+        Ok(true)
+    }
+}
+
+impl UserRepository for MockSnapRepository {
+    fn create_user(&mut self, user: User) -> Result<User, UserCreationError> {
+        self.insert_user_within(user.clone(), self.limits().max_users)?;
+        Ok(user)
+    }
+
+    fn get_user(&self, id: &str) -> Option<User> {
+        self.users_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .get(id)
+            .cloned()
+    }
+
+    fn get_user_by_username(&self, username: &str) -> Option<User> {
+        let users = self.users_mtx
+            .lock()
+            .unwrap();
+        users.ids_by_username
+            .get(&username.to_lowercase())
+            .and_then(|id| users.by_id.get(id))
+            .cloned()
+    }
+
+    fn user_count(&self) -> usize {
+        self.users_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .len()
+    }
+}
+
+impl ApiKeyRepository for MockSnapRepository {
+    fn create_api_key(&mut self, key: ApiKey) -> Result<ApiKey, ApiKeyError> {
+        let mut keys = self.api_keys_mtx
+            .lock()
+            .unwrap();
+
+        if keys.contains_key(&key.id()) {
+            return Err(ApiKeyError::IdCollisionError)
+        }
+
+        self.log_change(&JournalEntry::ApiKeyCreated { key: key.clone() });
+        keys.insert(key.id(), key.clone());
+        Ok(key)
+    }
+
+    fn get_api_key(&self, id: &str) -> Option<ApiKey> {
+        self.api_keys_mtx
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+    }
+
+    fn get_api_keys_by_owner(&self, owner_id: &str) -> Vec<ApiKey> {
+        let mut vec = self.api_keys_mtx
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|key| key.owner_id() == owner_id)
+            .cloned()
+            .collect::<Vec<ApiKey>>();
+
+        vec.sort_by(|a, b| b.created_at().cmp(a.created_at()));
+        vec
+    }
+
+    fn revoke_api_key(&mut self, id: &str) -> Result<ApiKey, ApiKeyError> {
+        let mut keys = self.api_keys_mtx
+            .lock()
+            .unwrap();
+        let key = keys.get_mut(id).ok_or(ApiKeyError::NotFound)?;
+        if key.revoked_at().is_none() {
+            key.revoke();
+            self.log_change(&JournalEntry::ApiKeyRevoked { key: key.clone() });
+        }
+        Ok(key.clone())
+    }
+
+    fn touch_api_key(&mut self, id: &str) -> Result<(), ApiKeyError> {
+        let mut keys = self.api_keys_mtx
+            .lock()
+            .unwrap();
+        let key = keys.get_mut(id).ok_or(ApiKeyError::NotFound)?;
+        key.touch(chrono::Utc::now());
+        Ok(())
+    }
+}
+
+impl FollowRepository for MockSnapRepository {
+    fn follow(&mut self, follower_id: &str, followee_id: &str) -> Result<bool, FollowError> {
+        self.check_follow(follower_id, followee_id)?;
+        Ok(self.add_follow(follower_id, followee_id))
+    }
+
+    fn unfollow(&mut self, follower_id: &str, followee_id: &str) -> Result<bool, FollowError> {
+        self.check_follow(follower_id, followee_id)?;
+        Ok(self.remove_follow(follower_id, followee_id))
+    }
+
+    fn followers(&self, user_id: &str) -> Vec<String> {
+        self.follows_mtx
+            .lock()
+            .unwrap()
+            .followers
+            .get(user_id)
+            .map(|followers| followers.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn following(&self, user_id: &str) -> Vec<String> {
+        self.follows_mtx
+            .lock()
+            .unwrap()
+            .following
+            .get(user_id)
+            .map(|followees| followees.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn timeline(&self, user_id: &str, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<Snap> {
+        match self.fan_out {
+            // Merge the snaps of every followed author on each request.
+            FanOut::Read => {
+                let mut authors = self.following(user_id)
+                    .into_iter()
+                    .collect::<HashSet<String>>();
+                authors.insert(user_id.to_string());
+
+                let mut vec = self.snaps_mtx
+                    .lock()
+                    .unwrap()
+                    .visible(visibility)
+                    .filter(|snap| authors.contains(&snap.author_id()))
+                    .filter(|snap| match before {
+                        Some(before) => &TimelineCursor::from(*snap) < before,
+                        None => true,
+                    })
+                    .cloned()
+                    .collect::<Vec<Snap>>();
+
+                vec.sort_by_key(|snap| std::cmp::Reverse(TimelineCursor::from(snap)));
+                vec.truncate(limit);
+                vec
+            }
+            // Read the inbox filled when the snaps were posted.
+            FanOut::Write => {
+                let inboxes = self.inboxes_mtx
+                    .lock()
+                    .unwrap();
+                let Some(inbox) = inboxes.get(user_id) else {
+                    return Vec::new()
+                };
+                let snaps = self.snaps_mtx
+                    .lock()
+                    .unwrap();
+                let newer = match before {
+                    Some(before) => inbox.range(..before),
+                    None => inbox.range(..),
+                };
+                newer.rev()
+                    .filter_map(|cursor| snaps.by_id.get(cursor.id()))
+                    .filter(|snap| !snap.is_deleted() && shown(snap, visibility))
+                    .take(limit)
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+}
+
+impl NotificationRepository for MockSnapRepository {
+    fn notify(&mut self, notification: Notification) -> Result<bool, NotificationError> {
+        if !self.check_notification(&notification)? {
+            return Ok(false)
+        }
+        self.put_notification(notification);
+        Ok(true)
+    }
+
+    fn notifications(
+        &self,
+        user_id: &str,
+        before: Option<&TimelineCursor>,
+        limit: usize,
+        unread_only: bool,
+    ) -> Vec<Notification> {
+        let notifications = self.notifications_mtx
+            .lock()
+            .unwrap();
+        let Some(inbox) = notifications.inboxes.get(user_id) else {
+            return Vec::new()
+        };
+        let newer = match before {
+            Some(before) => inbox.range(..before),
+            None => inbox.range(..),
+        };
+        newer.rev()
+            .map(|(_, notification)| notification)
+            .filter(|notification| !unread_only || !notification.is_read())
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    fn unread_count(&self, user_id: &str) -> usize {
+        self.notifications_mtx
+            .lock()
+            .unwrap()
+            .inboxes
+            .get(user_id)
+            .map(|inbox| inbox.values().filter(|notification| !notification.is_read()).count())
+            .unwrap_or_default()
+    }
+
+    fn mark_read(&mut self, user_id: &str, ids: Option<&[String]>) -> Result<usize, NotificationError> {
+        Ok(self.mark_read_at(user_id, ids, chrono::Utc::now()))
+    }
+
+    fn notification_preferences(&self, user_id: &str) -> NotificationPreferences {
+        self.notifications_mtx
+            .lock()
+            .unwrap()
+            .preferences
+            .get(user_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn set_notification_preferences(
+        &mut self,
+        user_id: &str,
+        preferences: NotificationPreferences,
+    ) -> Result<(), NotificationError> {
+        if self.get_user(user_id).is_none() {
+            return Err(NotificationError::UserNotFound)
+        }
+        self.put_preferences(user_id, preferences);
+        Ok(())
+    }
+}
+
+impl ModerationRepository for MockSnapRepository {
+    fn report(&mut self, report: Report) -> Result<bool, ModerationError> {
+        if !self.check_report(&report)? {
+            return Ok(false)
+        }
+        self.put_report(report);
+        Ok(true)
+    }
+
+    fn reports(&self, snap_id: &str) -> Vec<Report> {
+        self.moderation_mtx
+            .lock()
+            .unwrap()
+            .reports
+            .get(snap_id)
+            .map(|reports| reports.iter().filter(|report| report.is_open()).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn report_queue(&self, limit: usize) -> Vec<(String, usize)> {
+        let moderation = self.moderation_mtx
+            .lock()
+            .unwrap();
+        let snaps = self.snaps_mtx
+            .lock()
+            .unwrap();
+        let mut queue = moderation.reports
+            .iter()
+            .filter(|(id, _)| snaps.by_id.get(*id).is_some_and(|snap| !snap.is_deleted()))
+            .filter_map(|(id, reports)| {
+                let mut open = reports.iter().filter(|report| report.is_open());
+                let oldest = *open.next()?.created_at();
+                Some((id.clone(), open.count() + 1, oldest))
+            })
+            .collect::<Vec<(String, usize, DateTime)>>();
+
+        queue.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.2.cmp(&b.2)).then_with(|| a.0.cmp(&b.0)));
+        queue.into_iter()
+            .take(limit)
+            .map(|(id, count, _)| (id, count))
+            .collect()
+    }
+
+    fn moderate(&mut self, entry: AuditEntry) -> Result<AuditEntry, ModerationError> {
+        self.moderate_at(entry)
+    }
+
+    fn audit_log(&self, before: Option<&TimelineCursor>, limit: usize) -> Vec<AuditEntry> {
+        let moderation = self.moderation_mtx
+            .lock()
+            .unwrap();
+        let newer = match before {
+            Some(before) => moderation.audit.range(..before),
+            None => moderation.audit.range(..),
+        };
+        newer.rev()
+            .take(limit)
+            .map(|(_, entry)| entry.clone())
+            .collect()
+    }
+}
+
+impl WebhookRepository for MockSnapRepository {
+    fn create_webhook(&mut self, webhook: Webhook) -> Result<Webhook, WebhookError> {
+        if self.get_user(&webhook.owner_id()).is_none() {
+            return Err(WebhookError::OwnerNotFound)
+        }
+        if self.get_webhook(&webhook.id()).is_some() {
+            return Err(WebhookError::IdCollisionError)
+        }
+        self.put_webhook(webhook.clone());
+        Ok(webhook)
+    }
+
+    fn get_webhook(&self, id: &str) -> Option<Webhook> {
+        self.webhooks_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .get(id)
+            .cloned()
+    }
+
+    fn get_webhooks_by_owner(&self, owner_id: &str) -> Vec<Webhook> {
+        let mut vec = self.webhooks_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|webhook| webhook.owner_id() == owner_id)
+            .cloned()
+            .collect::<Vec<Webhook>>();
+        vec.sort_by_key(|webhook| *webhook.created_at());
+        vec
+    }
+
+    fn webhooks_for(&self, event: WebhookEvent) -> Vec<Webhook> {
+        self.webhooks_mtx
+            .lock()
+            .unwrap()
+            .by_id
+            .values()
+            .filter(|webhook| webhook.subscribes_to(event))
+            .cloned()
+            .collect()
+    }
+
+    fn update_webhook(&mut self, webhook: Webhook) -> Result<Webhook, WebhookError> {
+        if self.get_webhook(&webhook.id()).is_none() {
+            return Err(WebhookError::NotFound)
+        }
+        self.put_webhook(webhook.clone());
+        Ok(webhook)
+    }
+
+    fn delete_webhook(&mut self, id: &str) -> Result<Webhook, WebhookError> {
+        self.remove_webhook(id)
+    }
+
+    fn enqueue_delivery(&mut self, delivery: Delivery) -> Result<(), WebhookError> {
+        if self.get_delivery(&delivery.id()).is_some() {
+            return Err(WebhookError::IdCollisionError)
+        }
+        self.put_delivery(delivery)
+    }
+
+    fn update_delivery(&mut self, delivery: Delivery) -> Result<(), WebhookError> {
+        if self.get_delivery(&delivery.id()).is_none() {
+            return Err(WebhookError::NotFound)
+        }
+        self.put_delivery(delivery)
+    }
+
+    fn get_delivery(&self, id: &str) -> Option<Delivery> {
+        self.webhooks_mtx
+            .lock()
+            .unwrap()
+            .deliveries
+            .get(id)
+            .cloned()
+    }
+
+    fn due_deliveries(&self, now: DateTime, limit: usize) -> Vec<Delivery> {
+        let mut vec = self.webhooks_mtx
+            .lock()
+            .unwrap()
+            .deliveries
+            .values()
+            .filter(|delivery| delivery.status() == DeliveryStatus::Pending)
+            .filter(|delivery| delivery.next_attempt_at().is_some_and(|at| *at <= now))
+            .cloned()
+            .collect::<Vec<Delivery>>();
+        vec.sort_by_key(|delivery| delivery.next_attempt_at().copied());
+        vec.truncate(limit);
+        vec
+    }
+
+    fn deliveries(&self, webhook_id: &str, status: Option<DeliveryStatus>) -> Vec<Delivery> {
+        let mut vec = self.webhooks_mtx
+            .lock()
+            .unwrap()
+            .deliveries
+            .values()
+            .filter(|delivery| delivery.webhook_id() == webhook_id)
+            .filter(|delivery| match status {
+                Some(status) => delivery.status() == status,
+                None => true,
+            })
+            .cloned()
+            .collect::<Vec<Delivery>>();
+        vec.sort_by_key(|delivery| std::cmp::Reverse(*delivery.created_at()));
+        vec
+    }
+}
+
+#[cfg(test)]
+mod mock_repo_test {
+    use super::*;
+    use std::collections::BTreeSet;
+    use crate::models::{DeliveryAttempt, NotificationKind, Scope};
+
+    /// Repository with one registered user, returned alongside.
+    fn repo_with_author() -> (MockSnapRepository, String) {
+        let mut repo = MockSnapRepository::new();
+        let user = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+        (repo, user.id())
+    }
+
+    #[test]
+    fn posting_snaps() {
+        let (mut repo, author) = repo_with_author();
+        assert_eq!(repo.snap_count(), 0);
+
+        repo.post(&author, "A").unwrap();
+        assert_eq!(repo.snap_count(), 1);
+
+        repo.post(&author, "B").unwrap();
+        repo.post(&author, "C").unwrap();
+        assert_eq!(repo.snap_count(), 3);
+    }
+
+    #[test]
+    fn get_snaps_is_sorted() {
+        let (mut repo, author) = repo_with_author();
+        let snap_a = repo.post(&author, "A").unwrap();
+        let snap_b = repo.post(&author, "B").unwrap();
+
+        assert!(snap_a.timestamp() <= snap_b.timestamp());
+        assert_ne!(snap_a.id(), snap_b.id());
+
+        let snaps = repo.get(Visibility::Public);
+        assert_eq!(snaps.len(), 2);
+        // snap_b got posted last so it should be returned first
+        assert_eq!(snaps[0].id(), snap_b.id());
+        assert_eq!(snaps[1].id(), snap_a.id());
+    }
+
+    #[test]
+    fn posting_needs_an_existing_author() {
+        let mut repo = MockSnapRepository::new();
+        let unknown = Uuid::new_v4().to_string();
+
+        assert!(matches!(repo.post(&unknown, "A"), Err(SnapCreationError::AuthorNotFound)));
+        assert!(matches!(repo.post("not-an-id", "A"), Err(SnapCreationError::AuthorNotFound)));
+        assert_eq!(repo.snap_count(), 0);
+    }
+
+    #[test]
+    fn snaps_by_author() {
+        let (mut repo, alice) = repo_with_author();
+        let bob = repo.create_user(User::new("bob".to_string(), "password")).unwrap().id();
+        repo.post(&alice, "A").unwrap();
+        repo.post(&bob, "B").unwrap();
+
+        let snaps = repo.get_by_author(&bob);
+        assert_eq!(snaps.len(), 1);
+        assert_eq!(snaps[0].message(), "B");
+    }
+
+    #[test]
+    fn edit_and_delete_snaps() {
+        let (mut repo, author) = repo_with_author();
+        let snap = repo.post(&author, "A").unwrap();
+
+        let edited = repo.edit(&snap.id(), "B").unwrap();
+        assert_eq!(edited.message(), "B");
+        assert_eq!(repo.get_snap(&snap.id()).unwrap().message(), "B");
+
+        assert_eq!(repo.delete(&snap.id()).unwrap().id(), snap.id());
+        assert!(repo.get_snap(&snap.id()).is_none());
+        assert!(matches!(repo.edit(&snap.id(), "C"), Err(SnapUpdateError::NotFound)));
+        assert!(matches!(repo.delete(&snap.id()), Err(SnapUpdateError::NotFound)));
+    }
+
+    #[test]
+    fn limits_are_checked_with_each_insert() {
+        let (mut repo, author) = repo_with_author();
+        repo.set_limits(Limits { max_snaps: Some(2), max_users: Some(1) });
+        let first = repo.post(&author, "First").unwrap();
+        repo.reply(&author, &first.id(), "Reply").unwrap();
+        assert!(matches!(repo.post(&author, "Third"), Err(SnapCreationError::QuotaExceeded)));
+        let copy = Snap::new("Imported".to_string(), Uuid::new_v4());
+        assert!(matches!(repo.restore(copy.clone()), Err(SnapCreationError::QuotaExceeded)));
+        // Overwriting a live snap doesn't add one.
+        assert!(repo.restore(first.clone()).unwrap());
+
+        repo.delete(&first.id()).unwrap();
+        assert!(!repo.restore(copy).unwrap());
+        let user = User::new("other".to_string(), "password");
+        assert!(matches!(repo.create_user(user.clone()), Err(UserCreationError::QuotaExceeded)));
+        // Replays aren't limited.
+        repo.insert_user(user).unwrap();
+        assert_eq!(repo.user_count(), 2);
+    }
+
+    #[test]
+    fn replies_and_tombstones() {
+        let (mut repo, author) = repo_with_author();
+        let parent = repo.post(&author, "Parent").unwrap();
+        let reply = repo.reply(&author, &parent.id(), "Reply").unwrap();
+        let nested = repo.reply(&author, &reply.id(), "Nested").unwrap();
+        assert_eq!(repo.reply_count(&parent.id()), 1);
+        assert!(matches!(repo.reply(&author, "missing", "A"), Err(SnapCreationError::ParentNotFound)));
+
+        // Snaps with replies stay as tombstones.
+        repo.delete(&parent.id()).unwrap();
+        repo.delete(&reply.id()).unwrap();
+        assert!(repo.get_snap(&parent.id()).unwrap().is_deleted());
+        assert_eq!(repo.replies(&reply.id())[0].id(), nested.id());
+        assert_eq!(repo.snap_count(), 1);
+        assert!(matches!(repo.edit(&parent.id(), "B"), Err(SnapUpdateError::NotFound)));
+        assert!(matches!(repo.reply(&author, &parent.id(), "A"), Err(SnapCreationError::ParentNotFound)));
+
+        // Removing the last reply prunes the tombstones above it.
+        repo.delete(&nested.id()).unwrap();
+        assert!(repo.get_snap(&reply.id()).is_none());
+        assert!(repo.get_snap(&parent.id()).is_none());
+    }
+
+    #[test]
+    fn restoring_keeps_ids_and_replaces() {
+        let (mut repo, author) = repo_with_author();
+        let parent = repo.post(&author, "Parent #old").unwrap();
+        let reply = repo.reply(&author, &parent.id(), "Reply").unwrap();
+        repo.delete(&parent.id()).unwrap();
+        let snaps = repo.all_snaps();
+        assert_eq!(snaps.iter().map(Snap::id).collect::<Vec<String>>(), vec![parent.id(), reply.id()]);
+
+        // Authors don't need to exist, parents do.
+        let mut copy = MockSnapRepository::new();
+        assert!(matches!(copy.restore(snaps[1].clone()), Err(SnapCreationError::ParentNotFound)));
+        for snap in snaps.clone() {
+            assert!(!copy.restore(snap).unwrap());
+        }
+        assert_eq!(copy.snap_count(), 1);
+        assert_eq!(copy.replies(&parent.id())[0].id(), reply.id());
+        assert_eq!(copy.get_snap(&reply.id()).unwrap().timestamp(), reply.timestamp());
+        assert!(copy.get_by_tag("old", None, 10, Visibility::Public).is_empty());
+
+        let mut edited = snaps[1].clone();
+        edited.edit("Reply #new".to_string());
+        assert!(copy.restore(edited).unwrap());
+        assert_eq!(copy.get_by_tag("new", None, 10, Visibility::Public)[0].id(), reply.id());
+        assert_eq!(copy.reply_count(&parent.id()), 1);
+    }
+
+    #[test]
+    fn tags_follow_edits_and_deletes() {
+        let (mut repo, author) = repo_with_author();
+        let old = repo.post(&author, "#Rust and #go").unwrap();
+        let new = repo.post(&author, "More #rust").unwrap();
+
+        let tagged = repo.get_by_tag("RUST", None, 10, Visibility::Public);
+        assert_eq!(tagged.iter().map(Snap::id).collect::<Vec<String>>(), vec![new.id(), old.id()]);
+        assert_eq!(repo.get_by_tag("rust", Some(&TimelineCursor::from(&new)), 10, Visibility::Public)[0].id(), old.id());
+        assert_eq!(repo.trending_tags(*old.timestamp(), 10), vec![
+            ("rust".to_string(), 2),
+            ("go".to_string(), 1),
+        ]);
+        assert_eq!(repo.trending_tags(*new.timestamp(), 10), vec![("rust".to_string(), 1)]);
+
+        repo.edit(&old.id(), "Just #go").unwrap();
+        repo.delete(&new.id()).unwrap();
+        assert!(repo.get_by_tag("rust", None, 10, Visibility::Public).is_empty());
+        assert_eq!(repo.get_by_tag("go", None, 10, Visibility::Public)[0].message(), "Just #go");
+    }
+
+    #[test]
+    fn moderation_hides_and_closes_reports() {
+        let (mut repo, author) = repo_with_author();
+        let reporter = repo.create_user(User::new("reporter".to_string(), "password")).unwrap();
+        let calm = repo.post(&author, "Calm #news").unwrap();
+        let rude = repo.post(&author, "Rude #news").unwrap();
+        let report = |snap: &Snap, user: &User| Report::new(snap.uuid(), user.uuid(), "spam".to_string());
+
+        assert!(repo.report(report(&rude, &reporter)).unwrap());
+        assert!(!repo.report(report(&rude, &reporter)).unwrap());
+        assert!(repo.report(report(&rude, &repo.get_user(&author).unwrap())).unwrap());
+        assert!(repo.report(report(&calm, &reporter)).unwrap());
+        assert!(matches!(repo.report(Report::new(Uuid::new_v4(), reporter.uuid(), "spam".to_string())), Err(ModerationError::SnapNotFound)));
+        assert_eq!(repo.report_queue(10), vec![(rude.id(), 2), (calm.id(), 1)]);
+
+        let moderator = Uuid::new_v4();
+        let hide = AuditEntry::new(moderator, rude.uuid(), ModerationAction::Hide, "insults".to_string());
+        assert_eq!(repo.moderate(hide).unwrap().reports(), 2);
+        assert!(repo.reports(&rude.id()).is_empty());
+        assert_eq!(repo.report_queue(10), vec![(calm.id(), 1)]);
+        assert_eq!(repo.get(Visibility::Public).iter().map(Snap::id).collect::<Vec<String>>(), vec![calm.id()]);
+        assert_eq!(repo.get(Visibility::Moderator).len(), 2);
+        assert_eq!(repo.get_by_tag("news", None, 10, Visibility::Public).len(), 1);
+        assert_eq!(repo.get_by_tag("news", None, 10, Visibility::Moderator).len(), 2);
+        assert_eq!(repo.trending_tags(*calm.timestamp(), 10), vec![("news".to_string(), 1)]);
+        assert_eq!(repo.timeline(&author, None, 10, Visibility::Public).len(), 1);
+        assert_eq!(repo.timeline(&author, None, 10, Visibility::Moderator).len(), 2);
+        assert_eq!(repo.snap_count(), 2);
+        assert!(matches!(repo.react(&rude.id(), &author, ReactionKind::Like), Err(ReactionError::SnapNotFound)));
+        assert!(matches!(repo.reply(&author, &rude.id(), "Reply"), Err(SnapCreationError::ParentNotFound)));
+        assert!(matches!(repo.edit(&rude.id(), "Still rude"), Err(SnapUpdateError::NotFound)));
+        assert!(matches!(repo.report(report(&rude, &reporter)), Err(ModerationError::SnapNotFound)));
+
+        let restore = AuditEntry::new(moderator, rude.uuid(), ModerationAction::Restore, "apologized".to_string());
+        assert_eq!(repo.moderate(restore).unwrap().reports(), 0);
+        assert_eq!(repo.get(Visibility::Public).len(), 2);
+        assert_eq!(repo.get_by_tag("news", None, 10, Visibility::Public).len(), 2);
+
+        let delete = AuditEntry::new(moderator, calm.uuid(), ModerationAction::Delete, "spam".to_string());
+        repo.moderate(delete.clone()).unwrap();
+        assert!(repo.get_snap(&calm.id()).is_none());
+        assert!(repo.report_queue(10).is_empty());
+        assert!(matches!(repo.moderate(delete), Err(ModerationError::SnapNotFound)));
+
+        let log = repo.audit_log(None, 10);
+        let actions = log.iter().map(AuditEntry::action).collect::<Vec<ModerationAction>>();
+        assert_eq!(actions, vec![ModerationAction::Delete, ModerationAction::Restore, ModerationAction::Hide]);
+        assert_eq!(repo.audit_log(Some(&TimelineCursor::from(&log[0])), 1)[0].id(), log[1].id());
+    }
+
+    #[test]
+    fn reactions_are_idempotent() {
+        let (mut repo, author) = repo_with_author();
+        let snap = repo.post(&author, "A").unwrap();
+
+        assert!(repo.react(&snap.id(), &author, ReactionKind::Like).unwrap());
+        assert!(!repo.react(&snap.id(), &author, ReactionKind::Like).unwrap());
+        assert!(repo.react(&snap.id(), &author, ReactionKind::Wow).unwrap());
+        assert_eq!(repo.reaction_counts(&snap.id()), BTreeMap::from([
+            (ReactionKind::Like, 1),
+            (ReactionKind::Wow, 1),
+        ]));
+        assert_eq!(repo.reactors(&snap.id(), ReactionKind::Like), vec![author.clone()]);
+
+        assert!(repo.unreact(&snap.id(), &author, ReactionKind::Like).unwrap());
+        assert!(!repo.unreact(&snap.id(), &author, ReactionKind::Like).unwrap());
+        assert!(matches!(repo.react("missing", &author, ReactionKind::Sad), Err(ReactionError::SnapNotFound)));
+        assert!(matches!(repo.react(&snap.id(), "missing", ReactionKind::Sad), Err(ReactionError::UserNotFound)));
+
+        repo.delete(&snap.id()).unwrap();
+        assert!(repo.reaction_counts(&snap.id()).is_empty());
+    }
+
+    #[test]
+    fn usernames_are_unique_ignoring_case() {
+        let (mut repo, author) = repo_with_author();
+
+        let result = repo.create_user(User::new("AUTHOR".to_string(), "password"));
+        assert!(matches!(result, Err(UserCreationError::UsernameTaken)));
+        assert_eq!(repo.get_user_by_username("Author").unwrap().id(), author);
+        assert_eq!(repo.user_count(), 1);
+    }
+
+    #[test]
+    fn api_keys_by_owner() {
+        let (mut repo, author) = repo_with_author();
+        let owner = Uuid::parse_str(&author).unwrap();
+        let (old, _) = ApiKey::new(owner, "old".to_string(), Scope::Read);
+        let (new, _) = ApiKey::new(owner, "new".to_string(), Scope::Write);
+        let (other, _) = ApiKey::new(Uuid::new_v4(), "other".to_string(), Scope::Admin);
+        for key in [old.clone(), new.clone(), other] {
+            repo.create_api_key(key).unwrap();
+        }
+
+        let keys = repo.get_api_keys_by_owner(&author);
+        assert_eq!(keys.iter().map(ApiKey::name).collect::<Vec<&str>>(), vec!["new", "old"]);
+
+        repo.touch_api_key(&new.id()).unwrap();
+        assert!(repo.get_api_key(&new.id()).unwrap().last_used_at().is_some());
+        assert!(repo.revoke_api_key(&old.id()).unwrap().revoked_at().is_some());
+        assert!(matches!(repo.revoke_api_key("missing"), Err(ApiKeyError::NotFound)));
+    }
+
+    #[test]
+    fn follows() {
+        let (mut repo, alice) = repo_with_author();
+        let bob = repo.create_user(User::new("bob".to_string(), "password")).unwrap().id();
+
+        assert!(repo.follow(&alice, &bob).unwrap());
+        assert!(!repo.follow(&alice, &bob).unwrap());
+        assert_eq!(repo.following(&alice), vec![bob.clone()]);
+        assert_eq!(repo.followers(&bob), vec![alice.clone()]);
+        assert!(matches!(repo.follow(&alice, &alice), Err(FollowError::SelfFollow)));
+        assert!(matches!(repo.follow(&alice, "missing"), Err(FollowError::UserNotFound)));
+
+        assert!(repo.unfollow(&alice, &bob).unwrap());
+        assert!(!repo.unfollow(&alice, &bob).unwrap());
+        assert!(repo.followers(&bob).is_empty());
+    }
+
+    #[test]
+    fn notifications_follow_preferences() {
+        let (mut repo, alice) = repo_with_author();
+        let bob = repo.create_user(User::new("bob".to_string(), "password")).unwrap();
+        let alice = repo.get_user(&alice).unwrap();
+        let snap = repo.post(&alice.id(), "A").unwrap();
+        let reply = Notification::new(NotificationKind::Reply, alice.uuid(), bob.uuid(), snap.uuid());
+        let like = Notification::reaction(alice.uuid(), bob.uuid(), snap.uuid(), ReactionKind::Like);
+
+        assert!(repo.notify(reply.clone()).unwrap());
+        assert!(repo.notify(like.clone()).unwrap());
+        assert_eq!(repo.unread_count(&alice.id()), 2);
+        let newest = repo.notifications(&alice.id(), None, 1, false);
+        assert_eq!(newest[0].id(), like.id());
+        let older = repo.notifications(&alice.id(), Some(&TimelineCursor::from(&newest[0])), 10, false);
+        assert_eq!(older[0].id(), reply.id());
+
+        assert_eq!(repo.mark_read(&alice.id(), Some(&[like.id()])).unwrap(), 1);
+        assert_eq!(repo.mark_read(&alice.id(), Some(&[like.id()])).unwrap(), 0);
+        assert_eq!(repo.notifications(&alice.id(), None, 10, true)[0].id(), reply.id());
+        assert_eq!(repo.mark_read(&alice.id(), None).unwrap(), 1);
+        assert_eq!(repo.unread_count(&alice.id()), 0);
+
+        let preferences = NotificationPreferences { reactions: false, ..Default::default() };
+        repo.set_notification_preferences(&alice.id(), preferences).unwrap();
+        let like = Notification::reaction(alice.uuid(), bob.uuid(), snap.uuid(), ReactionKind::Love);
+        assert!(!repo.notify(like).unwrap());
+        assert_eq!(repo.notifications(&alice.id(), None, 10, false).len(), 2);
+
+        let unknown = Notification::new(NotificationKind::Mention, Uuid::new_v4(), bob.uuid(), snap.uuid());
+        assert!(matches!(repo.notify(unknown), Err(NotificationError::UserNotFound)));
+    }
+
+    #[test]
+    fn webhook_deliveries_queue() {
+        let (mut repo, author) = repo_with_author();
+        let owner = repo.get_user(&author).unwrap().uuid();
+        let events = BTreeSet::from([WebhookEvent::SnapCreated]);
+        let webhook = repo.create_webhook(Webhook::new(owner, "http://localhost/hook".to_string(), events)).unwrap();
+        assert_eq!(repo.webhooks_for(WebhookEvent::SnapCreated).len(), 1);
+        assert!(repo.webhooks_for(WebhookEvent::SnapDeleted).is_empty());
+        assert_eq!(repo.get_webhooks_by_owner(&author).len(), 1);
+
+        let first = Delivery::new(webhook.uuid(), WebhookEvent::SnapCreated, "{}".to_string());
+        let second = Delivery::new(webhook.uuid(), WebhookEvent::SnapCreated, "{}".to_string());
+        let now = chrono::Utc::now();
+        repo.enqueue_delivery(first.clone()).unwrap();
+        repo.enqueue_delivery(second.clone()).unwrap();
+        assert!(matches!(repo.enqueue_delivery(first.clone()), Err(WebhookError::IdCollisionError)));
+        assert_eq!(repo.due_deliveries(now, 10).len(), 2);
+        assert_eq!(repo.due_deliveries(now, 1).len(), 1);
+
+        let mut retried = first.clone();
+        let attempt = DeliveryAttempt { at: now, status: Some(500), error: Some("boom".to_string()) };
+        retried.fail(attempt.clone(), Some(now + chrono::TimeDelta::seconds(60)));
+        repo.update_delivery(retried).unwrap();
+        let mut dead = second.clone();
+        dead.fail(attempt, None);
+        repo.update_delivery(dead).unwrap();
+        assert!(repo.due_deliveries(now, 10).is_empty());
+        assert_eq!(repo.due_deliveries(now + chrono::TimeDelta::seconds(60), 10)[0].id(), first.id());
+        let dead = repo.deliveries(&webhook.id(), Some(DeliveryStatus::Dead));
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].id(), second.id());
+
+        repo.delete_webhook(&webhook.id()).unwrap();
+        assert!(repo.get_delivery(&first.id()).is_none());
+        assert!(matches!(repo.enqueue_delivery(first), Err(WebhookError::NotFound)));
+    }
+
+    #[test]
+    fn timeline_strategies_agree() {
+        for fan_out in [FanOut::Read, FanOut::Write] {
+            let mut repo = MockSnapRepository::with_fan_out(fan_out);
+            let alice = repo.create_user(User::new("alice".to_string(), "password")).unwrap().id();
+            let bob = repo.create_user(User::new("bob".to_string(), "password")).unwrap().id();
+            let carol = repo.create_user(User::new("carol".to_string(), "password")).unwrap().id();
+
+            let old = repo.post(&bob, "Before the follow").unwrap();
+            repo.follow(&alice, &bob).unwrap();
+            repo.post(&carol, "Not followed").unwrap();
+            let own = repo.post(&alice, "Own snap").unwrap();
+            let deleted = repo.post(&bob, "Deleted").unwrap();
+            let new = repo.post(&bob, "After the follow").unwrap();
+            repo.delete(&deleted.id()).unwrap();
+
+            let timeline = repo.timeline(&alice, None, 10, Visibility::Public);
+            let ids = timeline.iter().map(Snap::id).collect::<Vec<String>>();
+            assert_eq!(ids, vec![new.id(), own.id(), old.id()], "{fan_out:?}");
+
+            let page = repo.timeline(&alice, Some(&TimelineCursor::from(&new)), 1, Visibility::Public);
+            assert_eq!(page[0].id(), own.id(), "{fan_out:?}");
+
+            repo.unfollow(&alice, &bob).unwrap();
+            let ids = repo.timeline(&alice, None, 10, Visibility::Public).iter().map(Snap::id).collect::<Vec<String>>();
+            assert_eq!(ids, vec![own.id()], "{fan_out:?}");
+        }
+    }
+
+    #[test]
+    fn poisoned_lock_fails_health() {
+        let repo = MockSnapRepository::new();
+        assert!(repo.health().iter().all(HealthCheck::ok));
+
+        let poisoner = repo.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.snaps_mtx.lock().unwrap();
+            panic!("poison the lock");
+        }).join();
+
+        assert!(!repo.health()[0].ok());
+    }
+
+    #[test]
+    fn change_buffer_keeps_the_latest_changes() {
+        let mut repo = MockSnapRepository::new().with_change_buffer(2);
+        let author = repo.create_user(User::new("author".to_string(), "password")).unwrap().id();
+        let first = repo.post(&author, "First").unwrap();
+        repo.post(&author, "Second").unwrap();
+
+        assert_eq!((repo.first_change(), repo.change_count()), (1, 3));
+        assert!(matches!(repo.changes(0, 10), Err(StorageError::Compacted { first: 1, offset: 0 })));
+        let changes = repo.changes(1, 10).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert!(changes[0].contains(&first.id()));
+        assert!(repo.changes(3, 10).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,631 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use crate::models::{
+    ApiKey,
+    Attachment,
+    AuditEntry,
+    Delivery,
+    DeliveryStatus,
+    Notification,
+    NotificationPreferences,
+    ReactionKind,
+    Report,
+    Snap,
+    User,
+    Webhook,
+    WebhookEvent,
+};
+
+mod memory;
+mod journal;
+
+pub use memory::MockSnapRepository;
+pub use journal::JournalSnapRepository;
+pub(crate) use journal::replay_line;
+
+/// Trait for the application state.
+#[allow(dead_code)]
+pub trait SnapAppState {
+    /// Create a [Snap] with a message written by the user `author_id`.
+    /// [Snap::timestamp] will be the time of creation.
+    /// [Snap::id] will be a random UUID.
+    /// Returns a copy of the snap on success,
+    /// or an error if it can't create it.
+    fn post(&mut self, author_id: &str, message: &str) -> Result<Snap, SnapCreationError>;
+
+    /// Same as [SnapAppState::post] for a reply to the snap `parent_id`,
+    /// which must exist and not be deleted.
+    fn reply(&mut self, author_id: &str, parent_id: &str, message: &str) -> Result<Snap, SnapCreationError>;
+
+    /// Same as [SnapAppState::post], or [SnapAppState::reply] when `parent_id`
+    /// is given, for a snap carrying media `attachments` already in a blob store.
+    fn post_with_attachments(
+        &mut self,
+        author_id: &str,
+        parent_id: Option<&str>,
+        message: &str,
+        attachments: Vec<Attachment>,
+    ) -> Result<Snap, SnapCreationError>;
+
+    /// Return a copy of the snap with this id, if any.
+    /// Deleted snaps kept as tombstones are returned too, see [Snap::is_deleted].
+    fn get_snap(&self, id: &str) -> Option<Snap>;
+
+    /// Replace the message of the snap with this id, unless it's hidden.
+    /// Returns a copy of the edited snap.
+    fn edit(&mut self, id: &str, message: &str) -> Result<Snap, SnapUpdateError>;
+
+    /// Remove the snap with this id. Snaps with replies are kept as
+    /// tombstones so the conversation stays whole, and tombstones left
+    /// without replies are removed too.
+    /// Returns the snap as it was before the removal.
+    fn delete(&mut self, id: &str) -> Result<Snap, SnapUpdateError>;
+
+    /// Return a vector with the copy of all snaps
+    /// at the time, ordered from the most recent to the oldest.
+    /// Tombstones are left out, and so are hidden snaps unless
+    /// `visibility` is [Visibility::Moderator].
+    fn get(&self, visibility: Visibility) -> Vec<Snap>;
+
+    /// Return every snap, tombstones included, from the oldest to the
+    /// most recent, so replies come after the snaps they reply to.
+    fn all_snaps(&self) -> Vec<Snap>;
+
+    /// Store `snap` as it is, keeping its id, timestamps and tombstone,
+    /// in place of any stored snap with the same id. Meant to restore
+    /// dumps: the author doesn't need to exist, but the snap replied to,
+    /// if any, must be stored, even as a tombstone.
+    /// Returns whether a snap was replaced.
+    fn restore(&mut self, snap: Snap) -> Result<bool, SnapCreationError>;
+
+    /// Same as [SnapAppState::get] with [Visibility::Public] but only the
+    /// snaps written by `author_id`.
+    fn get_by_author(&self, author_id: &str) -> Vec<Snap>;
+
+    /// Return the direct replies to the snap `id`, tombstones included,
+    /// from the oldest to the most recent.
+    fn replies(&self, id: &str) -> Vec<Snap>;
+
+    /// Return the amount of direct replies to the snap `id`.
+    fn reply_count(&self, id: &str) -> usize;
+
+    /// Return up to `limit` snaps using the hashtag `tag`, matched ignoring
+    /// case, from the most recent to the oldest, starting right after `before`.
+    /// Hidden snaps are left out unless `visibility` is [Visibility::Moderator].
+    fn get_by_tag(&self, tag: &str, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<Snap>;
+
+    /// Return up to `limit` hashtags used by the most snaps posted since
+    /// `since`, with the amount of those snaps, the most used first.
+    /// Hidden snaps are left out.
+    fn trending_tags(&self, since: chrono::DateTime<chrono::Utc>, limit: usize) -> Vec<(String, usize)>;
+
+    /// Return the amount of snaps currently, tombstones left out
+    /// and hidden snaps counted.
+    fn snap_count(&self) -> usize;
+
+    /// Most snaps and users the store takes from now on. Checked under
+    /// the same lock as each insert, so writes at the same time can't go
+    /// over. Replaying a journal or a leader's changes isn't limited.
+    fn set_limits(&mut self, limits: Limits);
+
+    /// Limits set with [SnapAppState::set_limits], none by default.
+    fn limits(&self) -> Limits;
+
+    /// Record that `user_id` reacted with `kind` to the snap `snap_id`.
+    /// Returns whether the reaction is new.
+    fn react(&mut self, snap_id: &str, user_id: &str, kind: ReactionKind) -> Result<bool, ReactionError>;
+
+    /// Remove the reaction `kind` of `user_id` to the snap `snap_id`.
+    /// Returns whether there was a reaction to remove.
+    fn unreact(&mut self, snap_id: &str, user_id: &str, kind: ReactionKind) -> Result<bool, ReactionError>;
+
+    /// Return the amount of users who reacted to the snap `snap_id`
+    /// with each kind, leaving out kinds nobody used.
+    fn reaction_counts(&self, snap_id: &str) -> BTreeMap<ReactionKind, usize>;
+
+    /// Return the ids of the users who reacted to the snap `snap_id`
+    /// with `kind`, from the first to react to the last.
+    fn reactors(&self, snap_id: &str, kind: ReactionKind) -> Vec<String>;
+
+    /// Up to `limit` events of the snap log, from the one at `offset`.
+    /// Every change to a snap appends one, in the order they were applied.
+    fn events(&self, offset: u64, limit: usize) -> Vec<LoggedEvent>;
+
+    /// Number of events in the snap log, which is the offset of the next one.
+    fn event_count(&self) -> u64;
+
+    /// Make sure every change so far is on durable storage.
+    /// Backends that keep nothing on disk have nothing to do.
+    fn flush(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Write a consistent image of the whole store to `out` in the journal
+    /// format, one entry per line, while writes go on. Returns the number
+    /// of entries. In-memory stores write their state, journals copy their file.
+    fn snapshot(&self, out: &mut dyn std::io::Write) -> Result<usize, StorageError>;
+
+    /// Run the backend's self checks, e.g. whether its locks are usable
+    /// or its files writable.
+    fn health(&self) -> Vec<HealthCheck>;
+}
+
+/// Who is looking at the snaps, which decides whether hidden ones show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// Anybody, hidden snaps are left out.
+    Public,
+    /// A moderator, who still sees hidden snaps.
+    Moderator,
+}
+
+/// Result of one backend self check.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    /// Why the check failed, `None` when it passed.
+    pub error: Option<String>,
+    pub latency: Duration,
+}
+
+impl HealthCheck {
+    /// Time `check` and record its outcome under `name`.
+    pub fn run<F>(name: &'static str, check: F) -> HealthCheck
+    where
+        F: FnOnce() -> Result<(), String>,
+    {
+        let start = Instant::now();
+        let result = check();
+        HealthCheck {
+            name,
+            error: result.err(),
+            latency: start.elapsed(),
+        }
+    }
+
+    pub fn ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Trait for the registered users, kept next to [SnapAppState].
+pub trait UserRepository {
+    /// Store a new user. Usernames are unique ignoring case.
+    /// Returns a copy of the user on success.
+    fn create_user(&mut self, user: User) -> Result<User, UserCreationError>;
+
+    /// Return a copy of the user with this id, if any.
+    fn get_user(&self, id: &str) -> Option<User>;
+
+    /// Return a copy of the user with this username, ignoring case, if any.
+    fn get_user_by_username(&self, username: &str) -> Option<User>;
+
+    /// Return the amount of users currently.
+    fn user_count(&self) -> usize;
+}
+
+/// Trait for the API keys of the users.
+pub trait ApiKeyRepository {
+    /// Store a new key. Returns a copy of it on success.
+    fn create_api_key(&mut self, key: ApiKey) -> Result<ApiKey, ApiKeyError>;
+
+    /// Return a copy of the key with this id, revoked or not, if any.
+    fn get_api_key(&self, id: &str) -> Option<ApiKey>;
+
+    /// Return the keys owned by `owner_id`, from the newest to the oldest.
+    fn get_api_keys_by_owner(&self, owner_id: &str) -> Vec<ApiKey>;
+
+    /// Revoke the key with this id. Returns a copy of the revoked key.
+    fn revoke_api_key(&mut self, id: &str) -> Result<ApiKey, ApiKeyError>;
+
+    /// Record that the key with this id was just used.
+    fn touch_api_key(&mut self, id: &str) -> Result<(), ApiKeyError>;
+}
+
+/// Trait for who follows whom, and the home timelines built from it.
+pub trait FollowRepository {
+    /// Make `follower_id` follow `followee_id`.
+    /// Returns whether the follow is new.
+    fn follow(&mut self, follower_id: &str, followee_id: &str) -> Result<bool, FollowError>;
+
+    /// Make `follower_id` stop following `followee_id`.
+    /// Returns whether there was a follow to remove.
+    fn unfollow(&mut self, follower_id: &str, followee_id: &str) -> Result<bool, FollowError>;
+
+    /// Return the ids of the users following `user_id`.
+    fn followers(&self, user_id: &str) -> Vec<String>;
+
+    /// Return the ids of the users `user_id` follows.
+    fn following(&self, user_id: &str) -> Vec<String>;
+
+    /// Return up to `limit` snaps of `user_id` and the users they follow,
+    /// from the most recent to the oldest, starting right after `before`.
+    /// Hidden snaps are left out unless `visibility` is [Visibility::Moderator].
+    fn timeline(&self, user_id: &str, before: Option<&TimelineCursor>, limit: usize, visibility: Visibility) -> Vec<Snap>;
+}
+
+/// Trait for the notification inbox of each user and what they want in it.
+pub trait NotificationRepository {
+    /// Store `notification` in the inbox of its recipient, unless they
+    /// turned its kind off. Returns whether it was stored.
+    fn notify(&mut self, notification: Notification) -> Result<bool, NotificationError>;
+
+    /// Return up to `limit` notifications of `user_id`, from the most
+    /// recent to the oldest, starting right after `before`.
+    /// With `unread_only`, notifications already read are skipped.
+    fn notifications(
+        &self,
+        user_id: &str,
+        before: Option<&TimelineCursor>,
+        limit: usize,
+        unread_only: bool,
+    ) -> Vec<Notification>;
+
+    /// Return how many notifications of `user_id` are unread.
+    fn unread_count(&self, user_id: &str) -> usize;
+
+    /// Mark as read the notifications of `user_id` with these ids, or all
+    /// of them with `None`. Unknown ids are ignored.
+    /// Returns how many notifications were unread.
+    fn mark_read(&mut self, user_id: &str, ids: Option<&[String]>) -> Result<usize, NotificationError>;
+
+    /// Return the preferences of `user_id`, the defaults if never set.
+    fn notification_preferences(&self, user_id: &str) -> NotificationPreferences;
+
+    /// Replace the preferences of `user_id`.
+    fn set_notification_preferences(
+        &mut self,
+        user_id: &str,
+        preferences: NotificationPreferences,
+    ) -> Result<(), NotificationError>;
+}
+
+/// Trait for webhook subscriptions and the queue of their deliveries.
+pub trait WebhookRepository {
+    /// Store a new webhook after checking its owner exists.
+    fn create_webhook(&mut self, webhook: Webhook) -> Result<Webhook, WebhookError>;
+
+    /// Return the webhook with this id, if any.
+    fn get_webhook(&self, id: &str) -> Option<Webhook>;
+
+    /// Return the webhooks of `owner_id`, oldest first.
+    fn get_webhooks_by_owner(&self, owner_id: &str) -> Vec<Webhook>;
+
+    /// Return the webhooks subscribed to `event`.
+    fn webhooks_for(&self, event: WebhookEvent) -> Vec<Webhook>;
+
+    /// Replace a stored webhook with `webhook`.
+    fn update_webhook(&mut self, webhook: Webhook) -> Result<Webhook, WebhookError>;
+
+    /// Remove the webhook with this id, and its deliveries.
+    /// Returns a copy of the removed webhook.
+    fn delete_webhook(&mut self, id: &str) -> Result<Webhook, WebhookError>;
+
+    /// Queue a new delivery for its webhook.
+    fn enqueue_delivery(&mut self, delivery: Delivery) -> Result<(), WebhookError>;
+
+    /// Replace a stored delivery with `delivery`, after an attempt or a requeue.
+    fn update_delivery(&mut self, delivery: Delivery) -> Result<(), WebhookError>;
+
+    /// Return the delivery with this id, if any.
+    fn get_delivery(&self, id: &str) -> Option<Delivery>;
+
+    /// Return up to `limit` pending deliveries due at `now`, the most overdue first.
+    fn due_deliveries(&self, now: chrono::DateTime<chrono::Utc>, limit: usize) -> Vec<Delivery>;
+
+    /// Return the deliveries of `webhook_id`, the most recent first,
+    /// only the ones with `status` if given.
+    fn deliveries(&self, webhook_id: &str, status: Option<DeliveryStatus>) -> Vec<Delivery>;
+}
+
+/// Trait for the reports of abusive snaps and what moderators did about them.
+pub trait ModerationRepository {
+    /// Store `report` after checking its snap is live and not hidden and
+    /// its reporter exists. A user reporting a snap they already reported,
+    /// and nobody acted on yet, stores nothing.
+    /// Returns whether the report is new.
+    fn report(&mut self, report: Report) -> Result<bool, ModerationError>;
+
+    /// Return the open reports on the snap `snap_id`, the oldest first.
+    fn reports(&self, snap_id: &str) -> Vec<Report>;
+
+    /// Return up to `limit` live snaps with open reports and how many
+    /// they have, the most reported first, then the longest waiting.
+    fn report_queue(&self, limit: usize) -> Vec<(String, usize)>;
+
+    /// Hide, restore or delete the snap of `entry`, close its open
+    /// reports and add `entry` to the audit log, all as one change.
+    /// Returns the entry with the number of reports closed.
+    fn moderate(&mut self, entry: AuditEntry) -> Result<AuditEntry, ModerationError>;
+
+    /// Return up to `limit` entries of the audit log, from the most
+    /// recent to the oldest, starting right after `before`.
+    fn audit_log(&self, before: Option<&TimelineCursor>, limit: usize) -> Vec<AuditEntry>;
+}
+
+/// Position of a snap in a timeline. Orders like the snaps do,
+/// by timestamp, with the id breaking ties. Notifications are paginated
+/// the same way, by creation time, and audit entries by the time of the action.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimelineCursor {
+    timestamp: chrono::DateTime<chrono::Utc>,
+    id: String,
+}
+
+impl TimelineCursor {
+    /// Id of the snap the cursor points at.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Timestamp of the snap the cursor points at.
+    pub fn timestamp(&self) -> &chrono::DateTime<chrono::Utc> {
+        &self.timestamp
+    }
+}
+
+impl From<&Snap> for TimelineCursor {
+    fn from(snap: &Snap) -> Self {
+        TimelineCursor { timestamp: *snap.timestamp(), id: snap.id() }
+    }
+}
+
+impl From<&Notification> for TimelineCursor {
+    fn from(notification: &Notification) -> Self {
+        TimelineCursor { timestamp: *notification.created_at(), id: notification.id() }
+    }
+}
+
+impl From<&AuditEntry> for TimelineCursor {
+    fn from(entry: &AuditEntry) -> Self {
+        TimelineCursor { timestamp: *entry.at(), id: entry.id() }
+    }
+}
+
+/// Formats as `<nanoseconds since the epoch>_<snap id>`.
+impl fmt::Display for TimelineCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nanos = self.timestamp.timestamp_nanos_opt().unwrap_or_default();
+        write!(f, "{nanos}_{}", self.id)
+    }
+}
+
+impl FromStr for TimelineCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (nanos, id) = s.split_once('_').ok_or("expected <nanoseconds>_<id>")?;
+        let nanos = nanos.parse::<i64>().map_err(|e| e.to_string())?;
+        let id = uuid::Uuid::parse_str(id).map_err(|e| e.to_string())?;
+        Ok(TimelineCursor {
+            timestamp: chrono::DateTime::from_timestamp_nanos(nanos),
+            id: id.to_string(),
+        })
+    }
+}
+
+/// Change to a snap, as recorded in the snap log.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SnapEvent {
+    SnapCreated { snap: Snap },
+    SnapEdited {
+        id: String,
+        message: String,
+        at: chrono::DateTime<chrono::Utc>,
+    },
+    SnapDeleted {
+        id: String,
+        at: chrono::DateTime<chrono::Utc>,
+    },
+    /// Snap stored as it came in a dump or a snapshot, in place of
+    /// any snap with the same id.
+    SnapRestored { snap: Snap },
+    /// A moderator hid the snap from everyone else.
+    SnapHidden {
+        id: String,
+        at: chrono::DateTime<chrono::Utc>,
+    },
+    /// A moderator showed a hidden snap again.
+    SnapUnhidden { snap: Snap },
+}
+
+impl SnapEvent {
+    /// Id of the snap changed.
+    pub fn snap_id(&self) -> String {
+        match self {
+            SnapEvent::SnapCreated { snap }
+            | SnapEvent::SnapRestored { snap }
+            | SnapEvent::SnapUnhidden { snap } => snap.id(),
+            SnapEvent::SnapEdited { id, .. }
+            | SnapEvent::SnapDeleted { id, .. }
+            | SnapEvent::SnapHidden { id, .. } => id.clone(),
+        }
+    }
+}
+
+/// [SnapEvent] at its position in the snap log.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct LoggedEvent {
+    pub offset: u64,
+    #[serde(flatten)]
+    pub event: SnapEvent,
+}
+
+/// Log of every change to a store, one journal entry per change in the
+/// order they were applied, so other stores can replicate it.
+pub trait ChangeLog {
+    /// Up to `limit` changes from the one at `offset`, each a journal line.
+    /// Fails with [StorageError::Compacted] for changes no longer kept.
+    fn changes(&self, offset: u64, limit: usize) -> Result<Vec<String>, StorageError>;
+
+    /// Offset of the oldest change still kept. Stores that need older
+    /// ones have to start over from a snapshot.
+    fn first_change(&self) -> u64;
+
+    /// Number of changes logged, which is the offset of the next one.
+    fn change_count(&self) -> u64;
+
+    /// Apply the change at `offset` of the log of another store. Changes
+    /// already applied are skipped, returning false, and skipping one
+    /// is an error, so the same changes can be sent again safely.
+    fn apply_change(&mut self, offset: u64, line: &str) -> Result<bool, StorageError>;
+}
+
+/// Most live snaps and users a store takes, none when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub max_snaps: Option<usize>,
+    pub max_users: Option<usize>,
+}
+
+/// Everything the router needs from a storage backend.
+pub trait Repository:
+    SnapAppState
+    + ChangeLog
+    + UserRepository
+    + ApiKeyRepository
+    + FollowRepository
+    + NotificationRepository
+    + WebhookRepository
+    + ModerationRepository
+{}
+
+impl<T> Repository for T
+where
+    T: SnapAppState
+        + ChangeLog
+        + UserRepository
+        + ApiKeyRepository
+        + FollowRepository
+        + NotificationRepository
+        + WebhookRepository
+        + ModerationRepository,
+{}
+
+#[derive(Debug)]
+pub enum SnapCreationError {
+    IdCollisionError,
+    /// No user has the given author id.
+    AuthorNotFound,
+    /// The snap replied to doesn't exist or was deleted.
+    ParentNotFound,
+    /// The store holds [Limits::max_snaps] snaps already.
+    QuotaExceeded,
+    /// The backend couldn't persist the snap.
+    StorageError(StorageError),
+}
+
+/// Errors changing an existing snap.
+#[derive(Debug)]
+pub enum SnapUpdateError {
+    NotFound,
+    /// The backend couldn't persist the change.
+    StorageError(StorageError),
+}
+
+/// Errors reacting to a snap.
+#[derive(Debug)]
+pub enum ReactionError {
+    /// The snap doesn't exist or was deleted.
+    SnapNotFound,
+    UserNotFound,
+    /// The backend couldn't persist the change.
+    StorageError(StorageError),
+}
+
+#[derive(Debug)]
+pub enum UserCreationError {
+    UsernameTaken,
+    IdCollisionError,
+    /// The store holds [Limits::max_users] users already.
+    QuotaExceeded,
+    /// The backend couldn't persist the user.
+    StorageError(StorageError),
+}
+
+#[derive(Debug)]
+pub enum ApiKeyError {
+    NotFound,
+    IdCollisionError,
+    /// The backend couldn't persist the change.
+    StorageError(StorageError),
+}
+
+#[derive(Debug)]
+pub enum FollowError {
+    /// One of the users doesn't exist.
+    UserNotFound,
+    /// Users can't follow themselves.
+    SelfFollow,
+    /// The backend couldn't persist the change.
+    StorageError(StorageError),
+}
+
+#[derive(Debug)]
+pub enum NotificationError {
+    /// The recipient doesn't exist.
+    UserNotFound,
+    /// The backend couldn't persist the change.
+    StorageError(StorageError),
+}
+
+#[derive(Debug)]
+pub enum WebhookError {
+    /// No webhook or delivery has the given id.
+    NotFound,
+    /// No user has the owner id.
+    OwnerNotFound,
+    IdCollisionError,
+    /// The backend couldn't persist the change.
+    StorageError(StorageError),
+}
+
+#[derive(Debug)]
+pub enum ModerationError {
+    /// The snap doesn't exist, was deleted, or is hidden from the reporter.
+    SnapNotFound,
+    /// No user has the reporter id.
+    UserNotFound,
+    /// The backend couldn't persist the change.
+    StorageError(StorageError),
+}
+
+/// Errors coming from a persistent storage backend.
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    /// A journal line couldn't be decoded.
+    Corrupt { line: usize, reason: String },
+    /// A replicated change came after a gap in the log.
+    OutOfOrder { expected: u64, offset: u64 },
+    /// A change was asked for after the log dropped it.
+    Compacted { first: u64, offset: u64 },
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage I/O error: {e}"),
+            StorageError::Corrupt { line, reason } => {
+                write!(f, "corrupt journal at line {line}: {reason}")
+            }
+            StorageError::OutOfOrder { expected, offset } => {
+                write!(f, "change {offset} received while expecting change {expected}")
+            }
+            StorageError::Compacted { first, offset } => {
+                write!(f, "change {offset} was dropped, the change log starts at {first}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
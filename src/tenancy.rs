@@ -0,0 +1,460 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use axum::http::Method;
+use crate::config::{BucketConfig, Config, ConfigError, RateLimitConfig, TenantQuotas};
+use crate::context::AppContext;
+use crate::rate_limit::{ClientKey, Decision, RateLimiter};
+use crate::router;
+use crate::state::{Limits, Repository, StorageError};
+use crate::webhooks::Dispatcher;
+
+/// Header naming the tenant of a request.
+pub const TENANT_HEADER: &str = "X-Snap-Tenant";
+
+/// Header carrying `tenancy.admin_token` on the `/admin/tenants` requests.
+pub const ADMIN_TOKEN_HEADER: &str = "X-Snap-Admin-Token";
+
+/// Longest tenant id, the longest DNS label.
+const MAX_ID_LENGTH: usize = 63;
+
+/// Team with its own snaps, users and settings on a shared server.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, utoipa::ToSchema)]
+pub struct Tenant {
+    /// Name of the tenant in hosts and paths, e.g. `acme` in `acme.snaps.example.com`.
+    pub id: String,
+    pub name: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    /// Suspended tenants answer every request with Forbidden (403).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suspended_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub quotas: TenantQuotas,
+}
+
+impl Tenant {
+    pub fn is_suspended(&self) -> bool {
+        self.suspended_at.is_some()
+    }
+}
+
+/// Changes to a [Tenant], fields left unset stay as they are.
+#[derive(Debug, Default, serde::Deserialize, utoipa::ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct TenantUpdate {
+    pub name: Option<String>,
+    pub quotas: Option<TenantQuotas>,
+}
+
+/// What a tenant stores, to compare with its quotas.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct TenantUsage {
+    pub snaps: usize,
+    pub users: usize,
+}
+
+/// A [Tenant] and its usage.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct TenantInfo {
+    #[serde(flatten)]
+    pub tenant: Tenant,
+    pub usage: TenantUsage,
+}
+
+/// Errors while managing the tenants.
+#[derive(Debug)]
+pub enum TenantError {
+    /// The id can't name a tenant in hosts and paths.
+    InvalidId(String),
+    /// The quotas make no sense.
+    InvalidQuotas(ConfigError),
+    Exists(String),
+    NotFound(String),
+    /// The services of the tenant couldn't be set up.
+    Config(ConfigError),
+    /// The store of the tenant or the list of tenants couldn't be opened or saved.
+    Storage(StorageError),
+}
+
+impl fmt::Display for TenantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TenantError::InvalidId(id) => write!(
+                f,
+                "invalid tenant id {id:?}, expected up to {MAX_ID_LENGTH} lowercase letters, digits and dashes",
+            ),
+            TenantError::InvalidQuotas(e) => write!(f, "{e}"),
+            TenantError::Exists(id) => write!(f, "tenant {id} already exists"),
+            TenantError::NotFound(id) => write!(f, "no tenant {id}"),
+            TenantError::Config(e) => write!(f, "{e}"),
+            TenantError::Storage(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for TenantError {}
+
+impl From<StorageError> for TenantError {
+    fn from(e: StorageError) -> Self {
+        TenantError::Storage(e)
+    }
+}
+
+/// Whether `id` can name a tenant: a DNS label, also fine as a file name.
+pub fn valid_id(id: &str) -> bool {
+    (1..=MAX_ID_LENGTH).contains(&id.len())
+        && id.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-')
+        && !id.starts_with('-')
+        && !id.ends_with('-')
+}
+
+/// Store and services of one tenant.
+#[derive(Clone)]
+pub struct Scope<S> {
+    pub repo: S,
+    pub context: AppContext,
+    /// Routes of the app over `repo` and `context`.
+    pub app: axum::Router,
+    pub dispatcher: Dispatcher<S>,
+    /// Budgets shared by the clients of the tenant.
+    limiter: RateLimiter,
+    /// Budgets of each client of the tenant, by API key or IP.
+    clients: RateLimiter,
+}
+
+impl<S: Repository> Scope<S> {
+    /// Charge a request with `method` to the budgets of the tenant with
+    /// `quotas`. Budgets left unset are never charged.
+    pub fn charge(&self, quotas: &TenantQuotas, method: &Method, tenant_id: &str) -> Result<(), Decision> {
+        let read = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+        let limited = if read { quotas.read.is_some() } else { quotas.write.is_some() };
+        if !limited {
+            return Ok(())
+        }
+        let decision = self.limiter.check(&ClientKey::Tenant(tenant_id.to_string()), method);
+        if decision.allowed { Ok(()) } else { Err(decision) }
+    }
+
+    pub fn usage(&self) -> TenantUsage {
+        TenantUsage {
+            snaps: self.repo.snap_count(),
+            users: self.repo.user_count(),
+        }
+    }
+
+    /// Forget the budgets that refilled completely.
+    pub fn prune(&self) {
+        self.limiter.prune();
+        self.clients.prune();
+    }
+}
+
+struct Entry<S> {
+    tenant: Tenant,
+    scope: Scope<S>,
+}
+
+type Opener<S> = dyn Fn(&str) -> Result<S, StorageError> + Send + Sync;
+
+/// Every tenant of the server, with its [Scope]. Cheaply clonable.
+#[derive(Clone)]
+pub struct Tenants<S> {
+    config: Arc<Config>,
+    /// Services shared by every tenant: metrics and readiness.
+    shared: AppContext,
+    open: Arc<Opener<S>>,
+    /// File listing the tenants, they only live in memory when unset.
+    registry: Option<PathBuf>,
+    entries_mtx: Arc<RwLock<BTreeMap<String, Entry<S>>>>,
+}
+
+impl<S: Repository + Clone + Send + Sync + 'static> Tenants<S> {
+    /// Tenants of a server configured with `config`, sharing the metrics
+    /// and readiness of `shared`. The store of each tenant is opened with
+    /// `open`, given the tenant id, and the tenants listed in `registry`
+    /// are opened right away.
+    pub fn new<F>(config: Config, shared: AppContext, registry: Option<PathBuf>, open: F) -> Result<Tenants<S>, TenantError>
+    where
+        F: Fn(&str) -> Result<S, StorageError> + Send + Sync + 'static,
+    {
+        let tenants = Tenants {
+            config: Arc::new(config),
+            shared,
+            open: Arc::new(open),
+            registry,
+            entries_mtx: Arc::new(RwLock::new(BTreeMap::new())),
+        };
+        let listed = match &tenants.registry {
+            Some(path) => read_registry(path)?,
+            None => Vec::new(),
+        };
+        let mut entries = tenants.entries_mtx.write().unwrap();
+        for tenant in listed {
+            let scope = tenants.open_scope(&tenant)?;
+            entries.insert(tenant.id.clone(), Entry { tenant, scope });
+        }
+        drop(entries);
+        Ok(tenants)
+    }
+
+    /// Settings of the server, not of a tenant.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Create the tenant `id` with an empty store. Quotas default to
+    /// `tenancy.default_quotas`.
+    pub fn create(&self, id: &str, name: Option<String>, quotas: Option<TenantQuotas>) -> Result<Tenant, TenantError> {
+        if !valid_id(id) {
+            return Err(TenantError::InvalidId(id.to_string()))
+        }
+        let quotas = quotas.unwrap_or_else(|| self.config.tenancy.default_quotas.clone());
+        quotas.validate("quotas").map_err(TenantError::InvalidQuotas)?;
+        if self.entries_mtx.read().unwrap().contains_key(id) {
+            return Err(TenantError::Exists(id.to_string()))
+        }
+        let tenant = Tenant {
+            id: id.to_string(),
+            name: name.unwrap_or_else(|| id.to_string()),
+            created_at: chrono::Utc::now(),
+            suspended_at: None,
+            quotas,
+        };
+        // Opening may replay a journal, requests to other tenants go on meanwhile.
+        let scope = self.open_scope(&tenant)?;
+        let mut entries = self.entries_mtx.write().unwrap();
+        if entries.contains_key(id) {
+            return Err(TenantError::Exists(id.to_string()))
+        }
+        entries.insert(tenant.id.clone(), Entry { tenant: tenant.clone(), scope });
+        if let Err(e) = self.save(&entries) {
+            entries.remove(id);
+            return Err(e)
+        }
+        Ok(tenant)
+    }
+
+    /// Apply `update` to the tenant `id`.
+    pub fn update(&self, id: &str, update: TenantUpdate) -> Result<Tenant, TenantError> {
+        if let Some(quotas) = &update.quotas {
+            quotas.validate("quotas").map_err(TenantError::InvalidQuotas)?;
+        }
+        self.modify(id, |entry| {
+            if let Some(name) = update.name {
+                entry.tenant.name = name;
+            }
+            if let Some(quotas) = update.quotas {
+                // Budgets start over with the new quotas.
+                entry.scope.limiter = limiter(&quotas);
+                entry.scope.repo.set_limits(store_limits(&quotas));
+                entry.tenant.quotas = quotas;
+            }
+        })
+    }
+
+    /// Turn away every request to the tenant `id`, keeping its store.
+    pub fn suspend(&self, id: &str) -> Result<Tenant, TenantError> {
+        self.modify(id, |entry| {
+            entry.tenant.suspended_at.get_or_insert_with(chrono::Utc::now);
+        })
+    }
+
+    /// Serve the tenant `id` again after [Tenants::suspend].
+    pub fn resume(&self, id: &str) -> Result<Tenant, TenantError> {
+        self.modify(id, |entry| entry.tenant.suspended_at = None)
+    }
+
+    /// The tenant `id` and its scope.
+    pub fn get(&self, id: &str) -> Option<(Tenant, Scope<S>)> {
+        self.entries_mtx
+            .read()
+            .unwrap()
+            .get(id)
+            .map(|entry| (entry.tenant.clone(), entry.scope.clone()))
+    }
+
+    /// Every tenant and its scope, ordered by id.
+    pub fn all(&self) -> Vec<(Tenant, Scope<S>)> {
+        self.entries_mtx
+            .read()
+            .unwrap()
+            .values()
+            .map(|entry| (entry.tenant.clone(), entry.scope.clone()))
+            .collect()
+    }
+
+    fn modify(&self, id: &str, change: impl FnOnce(&mut Entry<S>)) -> Result<Tenant, TenantError> {
+        let mut entries = self.entries_mtx.write().unwrap();
+        let entry = entries.get_mut(id).ok_or_else(|| TenantError::NotFound(id.to_string()))?;
+        let (tenant, limiter) = (entry.tenant.clone(), entry.scope.limiter.clone());
+        change(entry);
+        let changed = entry.tenant.clone();
+        if let Err(e) = self.save(&entries) {
+            let entry = entries.get_mut(id).unwrap();
+            entry.scope.repo.set_limits(store_limits(&tenant.quotas));
+            entry.tenant = tenant;
+            entry.scope.limiter = limiter;
+            return Err(e)
+        }
+        Ok(changed)
+    }
+
+    /// Open the store of `tenant` and set up its services.
+    fn open_scope(&self, tenant: &Tenant) -> Result<Scope<S>, TenantError> {
+        let config = tenant_config(&self.config, &tenant.id);
+        let mut repo = (self.open)(&tenant.id)?;
+        repo.set_limits(store_limits(&tenant.quotas));
+        let context = AppContext {
+            metrics: self.shared.metrics.clone(),
+            readiness: self.shared.readiness.clone(),
+            ..AppContext::new(config).map_err(TenantError::Config)?
+        };
+        let status = context.projections.replay(&repo);
+        tracing::info!(tenant = %tenant.id, events = status.offset, snaps = status.snaps, "tenant opened");
+        let dispatcher = Dispatcher::new(repo.clone(), context.config.webhooks.clone(), context.metrics.clone());
+        let clients = RateLimiter::new(context.config.rate_limit.clone()).with_api_keys(repo.clone());
+        let per_client = context.config.rate_limit.enabled.then(|| clients.clone());
+        let app = router::get_router_with_limiter(context.clone(), per_client).with_state(repo.clone());
+        Ok(Scope {
+            app,
+            repo,
+            context,
+            dispatcher,
+            limiter: limiter(&tenant.quotas),
+            clients,
+        })
+    }
+
+    /// Write the list of tenants to the registry, if any, replacing it
+    /// only once completely written.
+    fn save(&self, entries: &BTreeMap<String, Entry<S>>) -> Result<(), TenantError> {
+        let Some(path) = &self.registry else {
+            return Ok(())
+        };
+        let tenants = entries.values().map(|entry| &entry.tenant).collect::<Vec<_>>();
+        let json = serde_json::to_vec_pretty(&tenants).expect("tenants are always serializable");
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, json).map_err(StorageError::Io)?;
+        std::fs::rename(&partial, path).map_err(StorageError::Io)?;
+        Ok(())
+    }
+}
+
+/// Settings of the tenant `id`: the ones of the server with its own
+/// token issuer, so tokens only work for the tenant they were issued by,
+/// and its own media and snapshot directories.
+pub fn tenant_config(config: &Config, id: &str) -> Config {
+    let mut config = config.clone();
+    config.auth.issuer = format!("{}/{id}", config.auth.issuer);
+    config.media.path = config.media.path.map(|path| path.join(id));
+    config.snapshots.path = config.snapshots.path.map(|path| path.join(id));
+    config
+}
+
+/// Limits of a store holding the snaps and users of a tenant with `quotas`.
+fn store_limits(quotas: &TenantQuotas) -> Limits {
+    Limits {
+        max_snaps: quotas.max_snaps,
+        max_users: quotas.max_users,
+    }
+}
+
+/// Limiter of the budgets in `quotas`.
+fn limiter(quotas: &TenantQuotas) -> RateLimiter {
+    // Stands in for the budgets left unset, which are never charged.
+    let unlimited = BucketConfig { burst: 1, per_second: 1.0 };
+    RateLimiter::new(RateLimitConfig {
+        enabled: true,
+        read: quotas.read.clone().unwrap_or_else(|| unlimited.clone()),
+        write: quotas.write.clone().unwrap_or(unlimited),
+    })
+}
+
+fn read_registry(path: &Path) -> Result<Vec<Tenant>, TenantError> {
+    let json = match std::fs::read(path) {
+        Ok(json) => json,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(StorageError::Io(e).into()),
+    };
+    serde_json::from_slice(&json).map_err(|e| TenantError::Storage(StorageError::Corrupt {
+        line: e.line(),
+        reason: e.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tenancy_test {
+    use super::*;
+    use crate::state::{MockSnapRepository, SnapAppState};
+
+    fn tenants(registry: Option<PathBuf>) -> Tenants<MockSnapRepository> {
+        Tenants::new(Config::default(), AppContext::default(), registry, |_| Ok(MockSnapRepository::new())).unwrap()
+    }
+
+    #[test]
+    fn ids_are_dns_labels() {
+        for id in ["acme", "team-42", "a"] {
+            assert!(valid_id(id), "{id}");
+        }
+        for id in ["", "Acme", "-acme", "acme-", "a.b", "a/b", "..", &"a".repeat(64)] {
+            assert!(!valid_id(id), "{id}");
+        }
+    }
+
+    #[test]
+    fn tenants_are_created_suspended_and_resumed() {
+        let tenants = tenants(None);
+        let tenant = tenants.create("acme", Some("Acme".to_string()), None).unwrap();
+        assert_eq!(tenant.name, "Acme");
+        assert!(matches!(tenants.create("acme", None, None), Err(TenantError::Exists(_))));
+        assert!(matches!(tenants.create("Acme", None, None), Err(TenantError::InvalidId(_))));
+
+        assert!(tenants.suspend("acme").unwrap().is_suspended());
+        assert!(tenants.get("acme").unwrap().0.is_suspended());
+        assert!(!tenants.resume("acme").unwrap().is_suspended());
+        assert!(matches!(tenants.suspend("nobody"), Err(TenantError::NotFound(_))));
+    }
+
+    #[test]
+    fn every_tenant_has_its_own_store() {
+        let tenants = tenants(None);
+        tenants.create("acme", None, None).unwrap();
+        tenants.create("globex", None, None).unwrap();
+        let (_, mut acme) = tenants.get("acme").unwrap();
+        acme.repo.restore(crate::models::Snap::new("hi".to_string(), uuid::Uuid::new_v4())).unwrap();
+        assert_eq!(tenants.get("acme").unwrap().1.usage().snaps, 1);
+        assert_eq!(tenants.get("globex").unwrap().1.usage().snaps, 0);
+        assert_ne!(acme.context.config.auth.issuer, tenants.get("globex").unwrap().1.context.config.auth.issuer);
+    }
+
+    #[test]
+    fn the_registry_survives_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = dir.path().join("tenants.json");
+        let first = tenants(Some(registry.clone()));
+        first.create("acme", None, None).unwrap();
+        first.suspend("acme").unwrap();
+        let quotas = TenantQuotas { max_snaps: Some(10), ..TenantQuotas::default() };
+        first.update("acme", TenantUpdate { quotas: Some(quotas.clone()), ..TenantUpdate::default() }).unwrap();
+
+        let tenant = tenants(Some(registry)).get("acme").unwrap().0;
+        assert!(tenant.is_suspended());
+        assert_eq!(tenant.quotas, quotas);
+    }
+
+    #[test]
+    fn only_limited_budgets_are_charged() {
+        let tenants = tenants(None);
+        let quotas = TenantQuotas {
+            write: Some(BucketConfig { burst: 1, per_second: 0.001 }),
+            ..TenantQuotas::default()
+        };
+        tenants.create("acme", None, Some(quotas.clone())).unwrap();
+        let (_, scope) = tenants.get("acme").unwrap();
+        for _ in 0..5 {
+            assert!(scope.charge(&quotas, &Method::GET, "acme").is_ok());
+        }
+        assert!(scope.charge(&quotas, &Method::POST, "acme").is_ok());
+        assert!(scope.charge(&quotas, &Method::POST, "acme").is_err());
+    }
+}
@@ -0,0 +1,400 @@
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use axum::http::{header, Uri};
+use hmac::{Hmac, Mac};
+use http_body_util::Full;
+use hyper::body::Bytes;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::connect::dns::Name;
+use hyper_util::rt::TokioExecutor;
+use sha2::Sha256;
+use crate::config::WebhooksConfig;
+use crate::metrics::Metrics;
+use crate::models::{self, Delivery, DeliveryAttempt, Snap, Webhook, WebhookEvent};
+use crate::state::Repository;
+
+/// Body of every delivery.
+#[derive(Debug, serde::Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    /// Time the event happened.
+    created_at: String,
+    snap: SnapPayload<'a>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct SnapPayload<'a> {
+    id: String,
+    author_id: String,
+    message: &'a str,
+    timestamp: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    edited_at: Option<String>,
+}
+
+/// Queue a delivery of `event` about `snap` for every webhook subscribed
+/// to it. Failures are only logged, the snap already changed.
+pub fn enqueue<S: Repository>(repo: &mut S, event: WebhookEvent, snap: &Snap) {
+    let webhooks = repo.webhooks_for(event);
+    if webhooks.is_empty() {
+        return
+    }
+    let payload = Payload {
+        event,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        snap: SnapPayload {
+            id: snap.id(),
+            author_id: snap.author_id(),
+            message: snap.message(),
+            timestamp: snap.timestamp().to_rfc3339(),
+            in_reply_to: snap.in_reply_to(),
+            edited_at: snap.edited_at().map(|time| time.to_rfc3339()),
+        },
+    };
+    let payload = serde_json::to_string(&payload).expect("payloads are always serializable");
+    for webhook in webhooks {
+        let delivery = Delivery::new(webhook.uuid(), event, payload.clone());
+        if let Err(e) = repo.enqueue_delivery(delivery) {
+            tracing::warn!("can't queue {event} for webhook {}: {e:?}", webhook.id());
+        }
+    }
+}
+
+/// Value of the `X-Snap-Signature` header: `sha256=` and the hex
+/// HMAC-SHA256 with `secret` of `<timestamp>.<body>`, where `timestamp`
+/// is the value of the `X-Snap-Timestamp` header.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let message = format!("{timestamp}.{body}");
+    format!("sha256={}", models::hex(&hmac_sha256(secret.as_bytes(), message.as_bytes())))
+}
+
+/// HMAC (RFC 2104) with SHA-256.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().into()
+}
+
+/// Wait before the next attempt of a delivery after `failed` failed ones.
+/// Starts at the initial backoff and doubles up to the maximum.
+pub fn backoff(config: &WebhooksConfig, failed: usize) -> Duration {
+    let doublings = failed.saturating_sub(1).min(63) as u32;
+    let ms = config.initial_backoff_ms.saturating_mul(1 << doublings);
+    Duration::from_millis(ms.min(config.max_backoff_ms))
+}
+
+/// Sends the due deliveries of the queue kept by a repository.
+#[derive(Clone)]
+pub struct Dispatcher<S> {
+    repo: S,
+    config: WebhooksConfig,
+    metrics: Metrics,
+    client: Client<HttpsConnector<HttpConnector<PublicResolver>>, Full<Bytes>>,
+}
+
+impl<S: Repository> Dispatcher<S> {
+    pub fn new(repo: S, config: WebhooksConfig, metrics: Metrics) -> Dispatcher<S> {
+        Dispatcher {
+            repo,
+            metrics,
+            client: Client::builder(TokioExecutor::new()).build(connector(config.allow_private_hosts)),
+            config,
+        }
+    }
+
+    /// Attempt the deliveries due now, up to the batch size, one after
+    /// the other. Failed ones are scheduled for a retry with exponential
+    /// backoff, or become dead letters after the last attempt.
+    /// Returns how many deliveries were attempted.
+    pub async fn run_once(&mut self) -> usize {
+        let due = self.repo.due_deliveries(chrono::Utc::now(), self.config.batch_size);
+        let count = due.len();
+        for mut delivery in due {
+            // Deleting a webhook removes its deliveries, this one raced it.
+            let Some(webhook) = self.repo.get_webhook(&delivery.webhook_id()) else {
+                continue
+            };
+            let attempt = self.send(&webhook, &delivery).await;
+            let failed = delivery.round_attempts() + 1;
+            let outcome = if attempt.error.is_none() {
+                delivery.succeed(attempt);
+                "delivered"
+            } else if failed >= self.config.max_attempts as usize {
+                tracing::warn!("webhook delivery {} is dead after {failed} attempts", delivery.id());
+                delivery.fail(attempt, None);
+                "dead"
+            } else {
+                let wait = chrono::TimeDelta::milliseconds(backoff(&self.config, failed).as_millis() as i64);
+                delivery.fail(attempt, Some(chrono::Utc::now() + wait));
+                "retried"
+            };
+            self.metrics.webhook_attempted(outcome);
+            if let Err(e) = self.repo.update_delivery(delivery) {
+                tracing::error!("can't record webhook delivery attempt: {e:?}");
+            }
+        }
+        count
+    }
+
+    /// POST `delivery` to `webhook`, signed with its secret.
+    async fn send(&self, webhook: &Webhook, delivery: &Delivery) -> DeliveryAttempt {
+        let at = chrono::Utc::now();
+        let timestamp = at.timestamp();
+        let failure = |status, error: String| DeliveryAttempt { at, status, error: Some(error) };
+        let request = hyper::Request::post(webhook.url())
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Snap-Event", delivery.event().as_str())
+            .header("X-Snap-Delivery", delivery.id())
+            .header("X-Snap-Timestamp", timestamp)
+            .header("X-Snap-Signature", sign(webhook.secret(), timestamp, delivery.payload()))
+            .body(Full::new(Bytes::from(delivery.payload().to_string())));
+        let request = match request {
+            Ok(request) => request,
+            Err(_) => return failure(None, "invalid request".to_string()),
+        };
+        // Names are checked by the resolver, addresses never reach it.
+        let literal = request.uri().host().and_then(ip_address);
+        if literal.is_some_and(|ip| !self.config.allow_private_hosts && !is_public(ip)) {
+            return failure(None, HostError::Private.to_string());
+        }
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        match tokio::time::timeout(timeout, self.client.request(request)).await {
+            Err(_) => failure(None, format!("no response after {}s", self.config.timeout_secs)),
+            Ok(Err(e)) => {
+                tracing::warn!("webhook delivery {} failed: {}", delivery.id(), describe(&e));
+                failure(None, error_kind(&e))
+            }
+            Ok(Ok(response)) if response.status().is_success() => DeliveryAttempt {
+                at,
+                status: Some(response.status().as_u16()),
+                error: None,
+            },
+            Ok(Ok(response)) => {
+                let status = response.status();
+                failure(Some(status.as_u16()), format!("receiver answered {status}"))
+            }
+        }
+    }
+}
+
+/// `error` followed by its causes, which tell what went wrong when
+/// connecting.
+fn describe(error: &dyn std::error::Error) -> String {
+    let mut text = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        text.push_str(&format!(": {cause}"));
+        source = cause.source();
+    }
+    text
+}
+
+/// What the delivery log tells about a failed request. The causes stay
+/// in the server logs, they could reveal the network behind the host.
+fn error_kind(error: &hyper_util::client::legacy::Error) -> String {
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        if let Some(e) = cause.downcast_ref::<HostError>() {
+            return e.to_string();
+        }
+        source = cause.source();
+    }
+    let kind = if error.is_connect() { "connection failed" } else { "request failed" };
+    kind.to_string()
+}
+
+/// Why deliveries to a host are refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostError {
+    /// The host has no address.
+    Unresolved,
+    /// The host has an address that isn't public.
+    Private,
+}
+
+impl fmt::Display for HostError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostError::Unresolved => write!(f, "host can't be resolved"),
+            HostError::Private => write!(f, "host not allowed"),
+        }
+    }
+}
+
+impl std::error::Error for HostError {}
+
+/// Check that every address of the host of `uri` is public, unless
+/// `allow_private` is set.
+pub async fn check_host(uri: &Uri, allow_private: bool) -> Result<(), HostError> {
+    let Some(host) = uri.host() else {
+        return Err(HostError::Unresolved)
+    };
+    if allow_private {
+        return Ok(())
+    }
+    if let Some(ip) = ip_address(host) {
+        return if is_public(ip) { Ok(()) } else { Err(HostError::Private) }
+    }
+    let addresses = tokio::net::lookup_host((host, 0)).await
+        .map_err(|_| HostError::Unresolved)?
+        .collect::<Vec<_>>();
+    public_addresses(addresses).map(|_| ())
+}
+
+/// `addresses` if there are some and all of them are public.
+fn public_addresses(addresses: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, HostError> {
+    if addresses.is_empty() {
+        Err(HostError::Unresolved)
+    } else if addresses.iter().all(|address| is_public(address.ip())) {
+        Ok(addresses)
+    } else {
+        Err(HostError::Private)
+    }
+}
+
+/// `host` of a URI if it's an IP address, IPv6 ones being in brackets.
+fn ip_address(host: &str) -> Option<IpAddr> {
+    host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+/// Whether `ip` can be reached from the internet: not loopback, private,
+/// link-local, shared, multicast, documentation or unspecified.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // 100.64.0.0/10, shared by carrier-grade NATs.
+                || (a == 100 && (64..128).contains(&b))
+                || a >= 240)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // fc00::/7, unique local.
+                    || (first & 0xfe00) == 0xfc00
+                    // fe80::/10, link-local.
+                    || (first & 0xffc0) == 0xfe80
+                    // 2001:db8::/32, documentation.
+                    || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+            }
+        },
+    }
+}
+
+/// Resolver of the delivery client, turning away hosts with addresses
+/// that aren't public unless private hosts are allowed. Checking here
+/// rather than before the request covers names resolving elsewhere by
+/// the time of the connection.
+#[derive(Debug, Clone, Copy)]
+pub struct PublicResolver {
+    allow_private: bool,
+}
+
+impl tower_service::Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let allow_private = self.allow_private;
+        Box::pin(async move {
+            let addresses = tokio::net::lookup_host((name.as_str(), 0)).await?.collect::<Vec<_>>();
+            if allow_private {
+                return Ok(addresses.into_iter())
+            }
+            Ok(public_addresses(addresses)?.into_iter())
+        })
+    }
+}
+
+/// Connector for `http` and `https` URLs, trusting the Mozilla roots.
+fn connector(allow_private: bool) -> HttpsConnector<HttpConnector<PublicResolver>> {
+    let mut http = HttpConnector::new_with_resolver(PublicResolver { allow_private });
+    http.enforce_http(false);
+    HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http)
+}
+
+#[cfg(test)]
+mod webhooks_test {
+    use super::*;
+
+    #[test]
+    fn hmac_matches_rfc_4231() {
+        let mac = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(
+            models::hex(&mac),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+        );
+        let mac = hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(
+            models::hex(&mac),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+        );
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let config = WebhooksConfig {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            ..WebhooksConfig::default()
+        };
+        let waits = (1..=6).map(|failed| backoff(&config, failed).as_millis()).collect::<Vec<_>>();
+        assert_eq!(waits, vec![100, 200, 400, 800, 1000, 1000]);
+        assert_eq!(backoff(&config, 500).as_millis(), 1000);
+    }
+
+    #[test]
+    fn only_public_addresses_are_public() {
+        let public = ["93.184.215.14", "8.8.8.8", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"];
+        for ip in public {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+        let private = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+            "100.64.0.1", "0.0.0.0", "255.255.255.255", "::1", "::", "fd00::1",
+            "fe80::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+        ];
+        for ip in private {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[tokio::test]
+    async fn private_hosts_are_refused_unless_allowed() {
+        let uri = "http://127.0.0.1:8080/hook".parse::<Uri>().unwrap();
+        assert_eq!(check_host(&uri, false).await, Err(HostError::Private));
+        assert_eq!(check_host(&uri, true).await, Ok(()));
+        let uri = "http://[::1]/hook".parse::<Uri>().unwrap();
+        assert_eq!(check_host(&uri, false).await, Err(HostError::Private));
+        let uri = "http://localhost/hook".parse::<Uri>().unwrap();
+        assert_eq!(check_host(&uri, false).await, Err(HostError::Private));
+    }
+}
@@ -0,0 +1,209 @@
+use std::sync::Arc;
+use snap_app_demo::{config, context, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::{SnapAppState, UserRepository, Visibility};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, StatusCode},
+    response::Response,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the administrator root and the user alice, alongside their
+/// `Authorization` values.
+struct TestApp {
+    app: axum::Router,
+    repo: state::MockSnapRepository,
+    root: String,
+    alice: String,
+}
+
+fn test_app() -> TestApp {
+    test_app_with(config::Config::default())
+}
+
+fn test_app_with(mut config: config::Config) -> TestApp {
+    let mut repo = state::MockSnapRepository::new();
+    let root = repo.create_user(User::new("root".to_string(), "a long password")).unwrap().id();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    config.admin.users = vec![root.clone()];
+    let context = context::AppContext { config: Arc::new(config), ..context::AppContext::default() };
+    TestApp {
+        root: format!("Bearer {}", context.auth.issue(&root).access_token),
+        alice: format!("Bearer {}", context.auth.issue(&alice).access_token),
+        app: router::get_router_with_context(context).with_state(repo.clone()),
+        repo,
+    }
+}
+
+async fn send(app: &axum::Router, method: &str, uri: &str, authorization: &str, body: String) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization)
+        .header("Content-Type", "application/x-ndjson")
+        .body(Body::from(body))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_text(response: Response) -> String {
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    serde_json::from_str(&body_text(response).await).unwrap()
+}
+
+/// Dump of the snaps of `app`, as exported by root.
+async fn export(app: &TestApp) -> String {
+    let response = send(&app.app, "GET", "/admin/export", &app.root, String::new()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+    body_text(response).await
+}
+
+#[tokio::test]
+async fn only_administrators() {
+    let app = test_app();
+    let response = send(&app.app, "GET", "/admin/export", &app.alice, String::new()).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app.app, "POST", "/admin/import", &app.alice, String::new()).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app.app, "GET", "/admin/export", "", String::new()).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn export_then_import_elsewhere() {
+    let mut source = test_app();
+    let author = source.repo.get_user_by_username("alice").unwrap().id();
+    let first = source.repo.post(&author, "First").unwrap();
+    source.repo.reply(&author, &first.id(), "Reply").unwrap();
+    let dump = export(&source).await;
+    let lines = dump.lines().collect::<Vec<&str>>();
+    assert_eq!(lines.len(), 4);
+    let header = serde_json::from_str::<Value>(lines[0]).unwrap();
+    assert_eq!((header["format"].as_str(), header["snaps"].as_u64()), (Some("snap-dump"), Some(2)));
+
+    let target = test_app();
+    let response = send(&target.app, "POST", "/admin/import?dry_run=true", &target.root, dump.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let report = body_json(response).await;
+    assert_eq!((report["data"]["created"].as_u64(), report["data"]["dry_run"].as_bool()), (Some(2), Some(true)));
+    assert!(target.repo.all_snaps().is_empty());
+
+    let response = send(&target.app, "POST", "/admin/import", &target.root, dump.clone()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"]["created"], 2);
+    let reply_id = serde_json::from_str::<Value>(lines[2]).unwrap()["snap"]["id"].as_str().unwrap().to_string();
+    let reply = target.repo.get_snap(&reply_id).unwrap();
+    assert_eq!(reply.in_reply_to(), Some(first.id()));
+    assert_eq!(reply.timestamp(), source.repo.get_snap(&reply.id()).unwrap().timestamp());
+}
+
+#[tokio::test]
+async fn conflicts_and_damaged_dumps() {
+    let mut app = test_app();
+    let author = app.repo.get_user_by_username("alice").unwrap().id();
+    app.repo.post(&author, "Only").unwrap();
+    let dump = export(&app).await;
+
+    let response = send(&app.app, "POST", "/admin/import", &app.root, dump.clone()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = send(&app.app, "POST", "/admin/import?on_conflict=skip", &app.root, dump.clone()).await;
+    assert_eq!(body_json(response).await["data"]["skipped"], 1);
+    let response = send(&app.app, "POST", "/admin/import?on_conflict=overwrite", &app.root, dump.clone()).await;
+    assert_eq!(body_json(response).await["data"]["overwritten"], 1);
+    let response = send(&app.app, "POST", "/admin/import?on_conflict=maybe", &app.root, dump.clone()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let forged = dump.replace("Only", "Forged");
+    let response = send(&app.app, "POST", "/admin/import?on_conflict=overwrite", &app.root, forged).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(body_json(response).await["detail"].as_str().unwrap().contains("checksum"));
+    assert_eq!(app.repo.get(Visibility::Public).first().unwrap().message(), "Only");
+
+    let cut = dump.lines().take(2).collect::<Vec<&str>>().join("\n");
+    let response = send(&app.app, "POST", "/admin/import?on_conflict=skip", &app.root, cut).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn snapshots_are_taken_listed_and_downloaded() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = config::Config::default();
+    config.snapshots.path = Some(dir.path().to_path_buf());
+    config.snapshots.keep = 2;
+    let mut app = test_app_with(config);
+    let author = app.repo.get_user_by_username("alice").unwrap().id();
+    app.repo.post(&author, "Kept").unwrap();
+
+    let response = send(&app.app, "POST", "/admin/snapshots", &app.alice, String::new()).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let mut names = Vec::new();
+    for _ in 0..3 {
+        let response = send(&app.app, "POST", "/admin/snapshots", &app.root, String::new()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let info = body_json(response).await["data"].clone();
+        // Both users and the snap.
+        assert_eq!(info["entries"], 3);
+        names.push(info["name"].as_str().unwrap().to_string());
+    }
+
+    let response = send(&app.app, "GET", "/admin/snapshots", &app.root, String::new()).await;
+    let listed = body_json(response).await["data"].as_array().unwrap()
+        .iter()
+        .map(|info| info["name"].as_str().unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(listed, vec![names[2].clone(), names[1].clone()]);
+
+    let response = send(&app.app, "GET", &format!("/admin/snapshots/{}", names[2]), &app.root, String::new()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+    let path = dir.path().join("downloaded.ndjson");
+    std::fs::write(&path, body_text(response).await).unwrap();
+    let (info, restored) = snap_app_demo::snapshot::verify(&path, config::FanOut::default()).unwrap();
+    assert_eq!(info.entries, 3);
+    assert_eq!(restored.get(Visibility::Public).first().unwrap().message(), "Kept");
+
+    let response = send(&app.app, "GET", &format!("/admin/snapshots/{}", names[0]), &app.root, String::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app.app, "GET", "/admin/snapshots/..%2Fsecret", &app.root, String::new()).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn snapshots_need_a_directory() {
+    let app = test_app();
+    let response = send(&app.app, "POST", "/admin/snapshots", &app.root, String::new()).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(body_json(response).await["title"], "Snapshots disabled");
+}
+
+#[tokio::test]
+async fn projections_are_replayed_from_the_log() {
+    let mut app = test_app();
+    let author = app.repo.get_user_by_username("alice").unwrap().id();
+    let kept = app.repo.post(&author, "Kept #snap").unwrap();
+    let gone = app.repo.reply(&author, &kept.id(), "Gone").unwrap();
+    app.repo.delete(&gone.id()).unwrap();
+
+    let response = send(&app.app, "POST", "/admin/projections/replay", &app.alice, String::new()).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = send(&app.app, "GET", "/admin/projections", &app.root, String::new()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let status = body_json(response).await["data"].clone();
+    assert_eq!(status, serde_json::json!({ "offset": 3, "snaps": 1, "authors": 1, "terms": 2 }));
+
+    let response = send(&app.app, "POST", "/admin/projections/replay", &app.root, String::new()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"], status);
+    app.repo.post(&author, "After the replay").unwrap();
+    let response = send(&app.app, "GET", "/admin/projections", &app.root, String::new()).await;
+    assert_eq!(body_json(response).await["data"]["offset"], 4);
+}
@@ -0,0 +1,227 @@
+use std::net::SocketAddr;
+use snap_app_demo::{config, context, rate_limit, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the users alice and bob, alongside their access tokens.
+struct TestApp {
+    app: axum::Router,
+    repo: state::MockSnapRepository,
+    alice: String,
+    bob: String,
+}
+
+fn test_app() -> TestApp {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let bob = repo.create_user(User::new("bob".to_string(), "a long password")).unwrap().id();
+    let context = context::AppContext::default();
+    TestApp {
+        alice: format!("Bearer {}", context.auth.issue(&alice).access_token),
+        bob: format!("Bearer {}", context.auth.issue(&bob).access_token),
+        app: router::get_router_with_context(context).with_state(repo.clone()),
+        repo,
+    }
+}
+
+/// Send `body` with `method` to `uri`, with `authorization` as is.
+async fn send(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    authorization: &str,
+    body: Option<Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization);
+    let request = match body {
+        Some(body) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&body).unwrap())),
+        None => request.body(Body::empty()),
+    };
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Create a key with `scope` and return its id and `Authorization` value.
+async fn create_key(app: &axum::Router, authorization: &str, scope: &str) -> (String, String) {
+    let response = send(app, "POST", "/api-keys", authorization, Some(json!({
+        "name": format!("{scope} bot"),
+        "scope": scope,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let body = body_json(response).await;
+    assert_eq!(body["data"]["scope"], json!(scope));
+    let key = body["data"]["key"].as_str().unwrap();
+    (body["data"]["id"].as_str().unwrap().to_string(), format!("ApiKey {key}"))
+}
+
+#[tokio::test]
+async fn keys_post_as_their_owner() {
+    let test = test_app();
+    let (_, key) = create_key(&test.app, &test.alice, "write").await;
+
+    let response = send(&test.app, "POST", "/snaps", &key, Some(json!({
+        "message": "Posted by a bot",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let author = body_json(response).await["data"]["author_id"].clone();
+
+    let response = send(&test.app, "GET", "/api-keys", &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    let keys = body["data"].as_array().unwrap();
+    assert_eq!(keys.len(), 1);
+    assert!(keys[0].get("key").is_none());
+    assert!(keys[0]["last_used_at"].is_string());
+    assert!(keys[0]["revoked_at"].is_null());
+
+    let response = send(&test.app, "GET", "/api-keys", &test.bob, None).await;
+    assert_eq!(body_json(response).await["data"], json!([]));
+
+    let snaps = send(&test.app, "GET", "/snaps", &key, None).await;
+    assert_eq!(body_json(snaps).await["data"][0]["author_id"], author);
+}
+
+#[tokio::test]
+async fn scopes_limit_keys() {
+    let test = test_app();
+    let (_, read) = create_key(&test.app, &test.alice, "read").await;
+    let (_, write) = create_key(&test.app, &test.alice, "write").await;
+    let (_, admin) = create_key(&test.app, &test.alice, "admin").await;
+
+    let response = send(&test.app, "POST", "/snaps", &read, Some(json!({
+        "message": "Not allowed",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(response.headers()["www-authenticate"].to_str().unwrap().contains("insufficient_scope"));
+
+    let response = send(&test.app, "GET", "/api-keys", &write, None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&test.app, "GET", "/api-keys", &admin, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"].as_array().unwrap().len(), 3);
+}
+
+#[tokio::test]
+async fn revoked_keys_stop_working() {
+    let test = test_app();
+    let (id, key) = create_key(&test.app, &test.alice, "write").await;
+    let uri = format!("/api-keys/{id}");
+
+    let response = send(&test.app, "DELETE", &uri, &test.bob, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(&test.app, "DELETE", &uri, &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send(&test.app, "POST", "/snaps", &key, Some(json!({
+        "message": "Too late",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(body_json(response).await["detail"], json!("unknown or revoked API key"));
+
+    let response = send(&test.app, "GET", "/api-keys", &test.alice, None).await;
+    assert!(body_json(response).await["data"][0]["revoked_at"].is_string());
+}
+
+#[tokio::test]
+async fn unknown_keys_are_rejected() {
+    let test = test_app();
+    let (_, key) = create_key(&test.app, &test.alice, "write").await;
+    // Same id, different secret.
+    let forged = format!("{key}0");
+
+    for authorization in [forged.as_str(), "ApiKey snap_garbage"] {
+        let response = send(&test.app, "POST", "/snaps", authorization, Some(json!({
+            "message": "Forged",
+        }))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[tokio::test]
+async fn invalid_key_names() {
+    let test = test_app();
+    let response = send(&test.app, "POST", "/api-keys", &test.alice, Some(json!({
+        "name": " ",
+        "scope": "read",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn each_key_has_its_own_budget() {
+    let test = test_app();
+    let (_, first) = create_key(&test.app, &test.alice, "write").await;
+    let (_, second) = create_key(&test.app, &test.alice, "write").await;
+    let limits = config::RateLimitConfig {
+        enabled: true,
+        read: config::BucketConfig { burst: 10, per_second: 1.0 },
+        write: config::BucketConfig { burst: 1, per_second: 0.1 },
+    };
+    let limiter = rate_limit::RateLimiter::new(limits).with_api_keys(test.repo.clone());
+    let app = test.app.layer(rate_limit::RateLimitLayer::new(limiter));
+    let post = |key: &str| {
+        let app = app.clone();
+        let key = key.to_string();
+        async move {
+            send(&app, "POST", "/snaps", &key, Some(json!({ "message": "Hi" }))).await.status()
+        }
+    };
+
+    assert_eq!(post(&first).await, StatusCode::CREATED);
+    assert_eq!(post(&first).await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(post(&second).await, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn unverified_keys_are_charged_to_the_ip() {
+    let test = test_app();
+    let (id, _) = create_key(&test.app, &test.alice, "write").await;
+    let limits = config::RateLimitConfig {
+        enabled: true,
+        read: config::BucketConfig { burst: 2, per_second: 0.1 },
+        write: config::BucketConfig { burst: 1, per_second: 0.1 },
+    };
+    let limiter = rate_limit::RateLimiter::new(limits).with_api_keys(test.repo.clone());
+    let app = test.app.layer(rate_limit::RateLimitLayer::new(limiter));
+    let get = |authorization: String| {
+        let request = Request::builder()
+            .uri("/snaps")
+            .header("Authorization", authorization)
+            .extension(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 4000))))
+            .body(Body::empty())
+            .unwrap();
+        let app = app.clone();
+        async move { app.oneshot(request).await.unwrap().status() }
+    };
+
+    // A random key each time, or a known id with a wrong secret.
+    let random = || format!("ApiKey snap_{}_secret", uuid::Uuid::new_v4().simple());
+    assert_eq!(get(random()).await, StatusCode::OK);
+    assert_eq!(get(format!("ApiKey snap_{}_wrong", id.replace('-', ""))).await, StatusCode::OK);
+    assert_eq!(get(random()).await, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(get(format!("ApiKey snap_{}_wrong", id.replace('-', ""))).await, StatusCode::TOO_MANY_REQUESTS);
+}
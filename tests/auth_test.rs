@@ -0,0 +1,203 @@
+use snap_app_demo::{context, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the users alice and bob, alongside their ids and access tokens.
+struct TestApp {
+    app: axum::Router,
+    context: context::AppContext,
+    alice: (String, String),
+    bob: (String, String),
+}
+
+fn test_app() -> TestApp {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let bob = repo.create_user(User::new("bob".to_string(), "a long password")).unwrap().id();
+    let context = context::AppContext::default();
+    let alice_token = context.auth.issue(&alice).access_token;
+    let bob_token = context.auth.issue(&bob).access_token;
+    TestApp {
+        app: router::get_router_with_context(context.clone()).with_state(repo),
+        context,
+        alice: (alice, alice_token),
+        bob: (bob, bob_token),
+    }
+}
+
+/// Send `body` with `method` to `uri`, with `authorization` as is, if any.
+async fn send(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    authorization: Option<&str>,
+    body: Option<Value>,
+) -> Response {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri);
+    if let Some(authorization) = authorization {
+        request = request.header("Authorization", authorization);
+    }
+    let body = match body {
+        Some(body) => {
+            request = request.header("Content-Type", "application/json");
+            Body::from(serde_json::to_string(&body).unwrap())
+        }
+        None => Body::empty(),
+    };
+    app.clone().oneshot(request.body(body).unwrap()).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+fn bearer(token: &str) -> String {
+    format!("Bearer {token}")
+}
+
+/// Post a snap as the owner of `token` and return its id.
+async fn post_snap(app: &axum::Router, token: &str, message: &str) -> String {
+    let response = send(app, "POST", "/snaps", Some(&bearer(token)), Some(json!({
+        "message": message,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["data"]["id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn posting_needs_a_token() {
+    let test = test_app();
+
+    let response = send(&test.app, "POST", "/snaps", None, Some(json!({
+        "message": "Anonymous",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(response.headers()["www-authenticate"], r#"Bearer realm="snap""#);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+
+    let response = send(&test.app, "POST", "/snaps", Some("Basic YWxpY2U6cGFzcw=="), Some(json!({
+        "message": "Wrong scheme",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers()["www-authenticate"].to_str().unwrap().contains(r#"error="invalid_request""#));
+
+    let response = send(&test.app, "POST", "/snaps", Some("Bearer not.a.token"), Some(json!({
+        "message": "Bad token",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(response.headers()["www-authenticate"].to_str().unwrap().contains(r#"error="invalid_token""#));
+}
+
+#[tokio::test]
+async fn token_identifies_the_author() {
+    let test = test_app();
+    let (alice, token) = &test.alice;
+
+    // An author id in the body is ignored in favour of the token.
+    let response = send(&test.app, "POST", "/snaps", Some(&bearer(token)), Some(json!({
+        "author_id": test.bob.0,
+        "message": "Hi",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(body_json(response).await["data"]["author_id"], json!(alice));
+}
+
+#[tokio::test]
+async fn reads_stay_public() {
+    let test = test_app();
+    let id = post_snap(&test.app, &test.alice.1, "Public").await;
+
+    let response = send(&test.app, "GET", &format!("/snaps/{id}"), None, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"]["message"], json!("Public"));
+
+    let response = send(&test.app, "GET", "/snaps", None, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn refresh_tokens() {
+    let test = test_app();
+    let tokens = test.context.auth.issue(&test.alice.0);
+
+    let response = send(&test.app, "POST", "/login/refresh", None, Some(json!({
+        "refresh_token": tokens.refresh_token,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["data"]["user"]["id"], json!(test.alice.0));
+    let access = body["data"]["access_token"].as_str().unwrap();
+    post_snap(&test.app, access, "Refreshed").await;
+
+    // Access tokens can't be refreshed and refresh tokens can't authenticate.
+    let response = send(&test.app, "POST", "/login/refresh", None, Some(json!({
+        "refresh_token": tokens.access_token,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(&test.app, "POST", "/snaps", Some(&bearer(&tokens.refresh_token)), Some(json!({
+        "message": "Nope",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn only_the_author_edits() {
+    let test = test_app();
+    let id = post_snap(&test.app, &test.alice.1, "Original").await;
+    let uri = format!("/snaps/{id}");
+
+    let response = send(&test.app, "PATCH", &uri, None, Some(json!({ "message": "Anon" }))).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&test.app, "PATCH", &uri, Some(&bearer(&test.bob.1)), Some(json!({
+        "message": "Bob was here",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&test.app, "PATCH", &uri, Some(&bearer(&test.alice.1)), Some(json!({
+        "message": "Edited",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["data"]["message"], json!("Edited"));
+    assert!(body["data"]["edited_at"].is_string());
+
+    let response = send(&test.app, "PATCH", "/snaps/missing", Some(&bearer(&test.alice.1)), Some(json!({
+        "message": "Edited",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn only_the_author_deletes() {
+    let test = test_app();
+    let id = post_snap(&test.app, &test.alice.1, "Short lived").await;
+    let uri = format!("/snaps/{id}");
+
+    let response = send(&test.app, "DELETE", &uri, Some(&bearer(&test.bob.1)), None).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = send(&test.app, "DELETE", &uri, Some(&bearer(&test.alice.1)), None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send(&test.app, "GET", &uri, None, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&test.app, "DELETE", &uri, Some(&bearer(&test.alice.1)), None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
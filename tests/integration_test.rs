@@ -1,4 +1,4 @@
-use snap_app_demo::{router, state};
+use snap_app_demo::{config, context, rate_limit, router, state};
 use axum::{
     body::Body,
     extract::Request,
@@ -9,11 +9,27 @@ use tower::ServiceExt;
 use http_body_util::BodyExt;
 use tokio::net::TcpListener;
 use uuid::Uuid;
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+
+/// Repository with one registered user, returned alongside its id.
+fn repo_with_author() -> (state::MockSnapRepository, String) {
+    let mut repo = state::MockSnapRepository::new();
+    let user = repo.create_user(User::new("author".to_string(), "password")).unwrap();
+    (repo, user.id())
+}
+
+/// `Authorization` header value for `user_id` signed with the keys of `context`.
+fn bearer(context: &context::AppContext, user_id: &str) -> String {
+    format!("Bearer {}", context.auth.issue(user_id).access_token)
+}
 
 #[tokio::test]
 async fn post_snap_logic() {
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let message = "Test Snap";
     let response = app
@@ -21,6 +37,7 @@ async fn post_snap_logic() {
             Request::builder()
                 .method("POST")
                 .uri("/snaps")
+                .header("Authorization", &token)
                 .header("Content-Type", "application/json")
                 .body(Body::from(
                     serde_json::to_string(&json!({
@@ -45,8 +62,10 @@ async fn post_snap_logic() {
 
 #[tokio::test]
 async fn post_snap() {
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let listener = TcpListener::bind("localhost:0")
         .await
@@ -69,6 +88,7 @@ async fn post_snap() {
             Request::builder()
                 .method("POST")
                 .uri(format!("http://{addr}/snaps"))
+                .header("Authorization", &token)
                 .header("Content-Type", "application/json")
                 .body(Body::from(
                     serde_json::to_string(&json!({
@@ -92,8 +112,10 @@ async fn post_snap() {
 
 #[tokio::test]
 async fn get_snaps() {
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let listener = TcpListener::bind("localhost:0")
         .await
@@ -115,6 +137,7 @@ async fn get_snaps() {
         Request::builder()
             .method("POST")
             .uri(format!("http://{addr}/snaps"))
+            .header("Authorization", &token)
             .header("Content-Type", "application/json")
             .body(Body::from(
                 serde_json::to_string(&json!({
@@ -127,6 +150,7 @@ async fn get_snaps() {
         Request::builder()
             .method("POST")
             .uri(format!("http://{addr}/snaps"))
+            .header("Authorization", &token)
             .header("Content-Type", "application/json")
             .body(Body::from(
                 serde_json::to_string(&json!({
@@ -200,8 +224,10 @@ async fn get_zero_snaps() {
 
 #[tokio::test]
 async fn post_invalid_request_no_message_field() {
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let listener = TcpListener::bind("localhost:0")
         .await
@@ -223,6 +249,7 @@ async fn post_invalid_request_no_message_field() {
             Request::builder()
                 .method("POST")
                 .uri(format!("http://{addr}/snaps"))
+                .header("Authorization", &token)
                 .header("Content-Type", "application/json")
                 .body(Body::from(
                     serde_json::to_string(&json!({
@@ -244,8 +271,10 @@ async fn post_invalid_request_no_message_field() {
 
 #[tokio::test]
 async fn post_invalid_request_empty_json() {
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let listener = TcpListener::bind("localhost:0")
         .await
@@ -267,6 +296,7 @@ async fn post_invalid_request_empty_json() {
             Request::builder()
                 .method("POST")
                 .uri(format!("http://{addr}/snaps"))
+                .header("Authorization", &token)
                 .header("Content-Type", "application/json")
                 .body(Body::from(
                     serde_json::to_string(&json!({})).unwrap()
@@ -286,8 +316,10 @@ async fn post_invalid_request_empty_json() {
 
 #[tokio::test]
 async fn post_invalid_request_empty_body() {
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let listener = TcpListener::bind("localhost:0")
         .await
@@ -309,6 +341,7 @@ async fn post_invalid_request_empty_body() {
             Request::builder()
                 .method("POST")
                 .uri(format!("http://{addr}/snaps"))
+                .header("Authorization", &token)
                 .header("Content-Type", "application/json")
                 .body(Body::empty())
                 .unwrap(),
@@ -326,8 +359,10 @@ async fn post_invalid_request_empty_body() {
 
 #[tokio::test]
 async fn post_invalid_request_missing_header() {
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let listener = TcpListener::bind("localhost:0")
         .await
@@ -349,6 +384,7 @@ async fn post_invalid_request_missing_header() {
             Request::builder()
                 .method("POST")
                 .uri(format!("http://{addr}/snaps"))
+                .header("Authorization", &token)
                 .body(Body::from(
                     serde_json::to_string(&json!({
                         "message": "Test Snap",
@@ -369,8 +405,10 @@ async fn post_invalid_request_missing_header() {
 #[tokio::test]
 async fn post_snap_extended_json() {
     // What happens if json has more values than used?
-    let state = state::MockSnapRepository::new();
-    let app = router::get_router().with_state(state);
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
 
     let listener = TcpListener::bind("localhost:0")
         .await
@@ -393,6 +431,7 @@ async fn post_snap_extended_json() {
             Request::builder()
                 .method("POST")
                 .uri(format!("http://{addr}/snaps"))
+                .header("Authorization", &token)
                 .header("Content-Type", "application/json")
                 .body(Body::from(
                     serde_json::to_string(&json!({
@@ -415,3 +454,231 @@ async fn post_snap_extended_json() {
     let id = body["data"]["id"].as_str().unwrap();
     assert!(Uuid::parse_str(id).is_ok());
 }
+
+#[tokio::test]
+async fn post_snap_message_too_long() {
+    let (state, author) = repo_with_author();
+    let mut config = config::Config::default();
+    config.limits.max_message_length = 5;
+    let context = context::AppContext::new(config).unwrap();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context)
+        .with_state(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/snaps")
+                .header("Authorization", &token)
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&json!({
+                        "message": "Longer than five",
+                    })).unwrap()
+                ))
+                .unwrap(),
+        ).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["title"], json!("Message too long"));
+}
+
+#[tokio::test]
+async fn healthz_is_ok() {
+    let state = state::MockSnapRepository::new();
+    let app = router::get_router().with_state(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/healthz")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn readyz_reports_checks() {
+    let state = state::MockSnapRepository::new();
+    let app = router::get_router().with_state(state);
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/readyz")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["phase"], json!("ready"));
+    assert_eq!(body["checks"][0]["name"], json!("locks"));
+    assert_eq!(body["checks"][0]["ok"], json!(true));
+    assert!(body["checks"][0]["latency_ms"].is_number());
+}
+
+#[tokio::test]
+async fn readyz_unavailable_while_shutting_down() {
+    let state = state::MockSnapRepository::new();
+    let context = context::AppContext::default();
+    let app = router::get_router_with_context(context.clone()).with_state(state);
+
+    context.readiness.set(context::Phase::ShuttingDown);
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/readyz")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    let body: Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["phase"], json!("shutting_down"));
+}
+
+#[tokio::test]
+async fn metrics_count_routes_and_problems() {
+    let (state, author) = repo_with_author();
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let app = router::get_router_with_context(context).with_state(state);
+
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/snaps")
+                .header("Authorization", &token)
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    serde_json::to_string(&json!({
+                        "message": "Test Snap",
+                    })).unwrap()
+                ))
+                .unwrap(),
+        ).await.unwrap();
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri("/nowhere")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .method("BREW")
+                .uri("/nowhere")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains(r#"http_requests_total{method="POST",route="/snaps",status="201"} 1"#));
+    assert!(body.contains(r#"http_requests_total{method="GET",route="<unmatched>",status="404"} 1"#));
+    assert!(body.contains(r#"http_requests_total{method="other",route="<unmatched>",status="404"} 1"#));
+    assert!(!body.contains("BREW"));
+    assert!(body.contains(r#"problem_responses_total{kind="not_found",status="404"} 2"#));
+    assert!(body.contains("http_request_duration_seconds_bucket"));
+    assert!(body.contains("snaps 1"));
+    assert!(body.contains("open_streams 0"));
+}
+
+#[tokio::test]
+async fn rate_limited_writes() {
+    let (state, author) = repo_with_author();
+    let limits = config::RateLimitConfig {
+        enabled: true,
+        read: config::BucketConfig { burst: 10, per_second: 1.0 },
+        write: config::BucketConfig { burst: 1, per_second: 0.1 },
+    };
+    let context = context::AppContext::default();
+    let token = bearer(&context, &author);
+    let limiter = rate_limit::RateLimiter::new(limits);
+    let app = router::get_router_with_limiter(context, Some(limiter)).with_state(state);
+
+    let post = || Request::builder()
+        .method("POST")
+        .uri("/snaps")
+        .header("Authorization", &token)
+        .header("Content-Type", "application/json")
+        .body(Body::from(
+            serde_json::to_string(&json!({
+                "message": "Test Snap",
+            })).unwrap()
+        ))
+        .unwrap();
+
+    let response = app.clone().oneshot(post()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(response.headers()["ratelimit-limit"], "1");
+    assert_eq!(response.headers()["ratelimit-remaining"], "0");
+
+    let response = app.clone().oneshot(post()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(response.headers()["retry-after"], "10");
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+
+    // Reads have their own budget.
+    let response = app.clone()
+        .oneshot(
+            Request::builder()
+                .uri("/snaps")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["ratelimit-remaining"], "9");
+
+    // Refused requests are counted too.
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/metrics")
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap();
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(body.contains(r#"http_requests_total{method="POST",route="/snaps",status="429"} 1"#));
+    assert!(body.contains(r#"problem_responses_total{kind="rate_limited",status="429"} 1"#));
+}
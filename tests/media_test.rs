@@ -0,0 +1,255 @@
+use snap_app_demo::{config, context, media, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use serde_json::{json, Value};
+use std::io::Cursor;
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+const BOUNDARY: &str = "snap-boundary";
+
+/// Gradient image of `width` by `height` pixels encoded as `format`.
+fn image(format: ImageFormat, width: u32, height: u32) -> Vec<u8> {
+    let image = RgbImage::from_fn(width, height, |x, y| Rgb([x as u8, y as u8, 200]));
+    let mut bytes = Vec::new();
+    DynamicImage::ImageRgb8(image).write_to(Cursor::new(&mut bytes), format).unwrap();
+    bytes
+}
+
+/// App whose snaps take up to two attachments of 4 KiB and 10000
+/// pixels, with 16 pixel thumbnails, alongside the `Authorization`
+/// value of its user.
+fn test_app() -> (axum::Router, String) {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let mut config = config::Config::default();
+    config.media.max_attachments = 2;
+    config.media.max_bytes = 4096;
+    config.media.max_pixels = 10_000;
+    config.media.thumbnail_sizes = vec![16];
+    let context = context::AppContext::new(config).unwrap();
+    let authorization = format!("Bearer {}", context.auth.issue(&alice).access_token);
+    (router::get_router_with_context(context).with_state(repo), authorization)
+}
+
+/// `multipart/form-data` body with the text `fields` and the `files`,
+/// each sent as `attachments` with a made up type and name.
+fn multipart(fields: &[(&str, &str)], files: &[&[u8]]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend(format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"\r\n\r\n{value}\r\n"
+        ).bytes());
+    }
+    for file in files {
+        body.extend(format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"attachments\"; filename=\"cat.png\"\r\n\
+             Content-Type: image/png\r\n\r\n"
+        ).bytes());
+        body.extend_from_slice(file);
+        body.extend(b"\r\n");
+    }
+    body.extend(format!("--{BOUNDARY}--\r\n").bytes());
+    body
+}
+
+async fn post_multipart(app: &axum::Router, authorization: &str, body: Vec<u8>) -> Response {
+    let request = Request::builder()
+        .method("POST")
+        .uri("/snaps")
+        .header("Authorization", authorization)
+        .header("Content-Type", format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from(body))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn get(app: &axum::Router, uri: &str, headers: &[(&str, &str)]) -> Response {
+    let mut request = Request::builder().uri(uri);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+}
+
+async fn body_bytes(response: Response) -> Vec<u8> {
+    response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes()
+        .to_vec()
+}
+
+async fn body_json(response: Response) -> Value {
+    serde_json::from_slice(&body_bytes(response).await).unwrap()
+}
+
+#[tokio::test]
+async fn snaps_carry_attachments() {
+    let (app, alice) = test_app();
+    let (png, gif) = (image(ImageFormat::Png, 40, 20), image(ImageFormat::Gif, 8, 8));
+    let body = multipart(&[("message", "Look #cats")], &[&png, &gif]);
+    let response = post_multipart(&app, &alice, body).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let created = body_json(response).await["data"].clone();
+    assert_eq!(created["message"], "Look #cats");
+    assert_eq!(created["attachments"][0]["content_type"], "image/png");
+    assert_eq!(created["attachments"][1]["content_type"], "image/gif");
+    assert_eq!(created["attachments"][0]["size"], png.len());
+    assert_eq!(created["attachments"][0]["width"], 40);
+    assert_eq!(created["attachments"][0]["height"], 20);
+
+    let uri = format!("/snaps/{}", created["id"].as_str().unwrap());
+    let snap = body_json(get(&app, &uri, &[]).await).await;
+    assert_eq!(snap["data"]["attachments"], created["attachments"]);
+    let url = created["attachments"][0]["url"].as_str().unwrap();
+    let response = get(&app, url, &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    assert!(response.headers()["cache-control"].to_str().unwrap().contains("immutable"));
+    assert_eq!(body_bytes(response).await, png);
+
+    // Smaller than the thumbnail size, the GIF has none.
+    assert_eq!(created["attachments"][1]["thumbnails"], json!([]));
+    let thumbnail = &created["attachments"][0]["thumbnails"][0];
+    assert_eq!(thumbnail["max_dimension"], 16);
+    assert_eq!((thumbnail["width"].as_u64(), thumbnail["height"].as_u64()), (Some(16), Some(8)));
+    let response = get(&app, thumbnail["url"].as_str().unwrap(), &[]).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let thumbnail = image::load_from_memory(&body_bytes(response).await).unwrap();
+    assert_eq!((thumbnail.width(), thumbnail.height()), (16, 8));
+
+    let reply = multipart(&[("message", "Same cat"), ("in_reply_to", created["id"].as_str().unwrap())], &[&png]);
+    let response = post_multipart(&app, &alice, reply).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let reply = body_json(response).await["data"].clone();
+    assert_eq!(reply["in_reply_to"], created["id"]);
+    // Same contents, same blob.
+    assert_eq!(reply["attachments"][0]["hash"], created["attachments"][0]["hash"]);
+}
+
+#[tokio::test]
+async fn media_supports_ranges_and_caching() {
+    let (app, alice) = test_app();
+    let png = image(ImageFormat::Png, 4, 4);
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Range")], &[&png])).await;
+    let created = body_json(response).await["data"].clone();
+    let url = created["attachments"][0]["url"].as_str().unwrap();
+    let len = png.len();
+
+    let response = get(&app, url, &[("Range", "bytes=0-3")]).await;
+    assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(response.headers()["content-range"], format!("bytes 0-3/{len}").as_str());
+    assert_eq!(body_bytes(response).await, &png[..4]);
+
+    let response = get(&app, url, &[("Range", "bytes=-2")]).await;
+    assert_eq!(body_bytes(response).await, &png[len - 2..]);
+
+    let response = get(&app, url, &[("Range", &format!("bytes={len}-"))]).await;
+    assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    assert_eq!(response.headers()["content-range"], format!("bytes */{len}").as_str());
+
+    let etag = get(&app, url, &[]).await.headers()["etag"].to_str().unwrap().to_string();
+    let response = get(&app, url, &[("If-None-Match", &etag)]).await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert!(body_bytes(response).await.is_empty());
+
+    assert_eq!(get(&app, &format!("/media/{}", "0".repeat(64)), &[]).await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(get(&app, "/media/not-a-hash", &[]).await.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn invalid_attachments_are_rejected() {
+    let (app, alice) = test_app();
+    let png = image(ImageFormat::Png, 4, 4);
+    // Claims to be a PNG in its part headers, but isn't.
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Fake")], &[b"<html></html>"])).await;
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Many")], &[&png, &png, &png])).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let large = [&png[..], &[0; 4096]].concat();
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Large")], &[&large])).await;
+    assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    let truncated = &png[..png.len() / 2];
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Broken")], &[truncated])).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], "Invalid image");
+
+    let response = post_multipart(&app, &alice, multipart(&[], &[&png])).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = post_multipart(&app, &alice, multipart(&[("title", "Hi"), ("message", "Hi")], &[])).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn failed_posts_leave_no_attachments_behind() {
+    let (app, alice) = test_app();
+    let png = image(ImageFormat::Png, 40, 20);
+    let url = format!("/media/{}", media::hash(&png));
+    let orphan = multipart(&[("message", "Hi"), ("in_reply_to", "missing")], &[&png]);
+    let response = post_multipart(&app, &alice, orphan.clone()).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(get(&app, &url, &[]).await.status(), StatusCode::NOT_FOUND);
+
+    // Blobs already carried by another snap stay.
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Hi")], &[&png])).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let thumbnail = body_json(response).await["data"]["attachments"][0]["thumbnails"][0]["url"]
+        .as_str()
+        .unwrap()
+        .to_string();
+    let response = post_multipart(&app, &alice, orphan).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(get(&app, &url, &[]).await.status(), StatusCode::OK);
+    assert_eq!(get(&app, &thumbnail, &[]).await.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn json_snaps_have_no_attachments() {
+    let (app, alice) = test_app();
+    let request = Request::builder()
+        .method("POST")
+        .uri("/snaps")
+        .header("Authorization", &alice)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "message": "Text only" }).to_string()))
+        .unwrap();
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CREATED);
+    assert_eq!(body_json(response).await["data"]["attachments"], json!([]));
+}
+
+#[tokio::test]
+async fn images_are_cleaned_up() {
+    let (app, alice) = test_app();
+    // A JPEG carrying an EXIF segment and a comment.
+    let jpeg = image(ImageFormat::Jpeg, 30, 30);
+    let mut tagged = jpeg[..2].to_vec();
+    tagged.extend(b"\xff\xe1\x00\x15Exif\x00\x00GPSLatitude\x00\x00");
+    tagged.extend(b"\xff\xfe\x00\x0bCanon EOS");
+    tagged.extend(&jpeg[2..]);
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Holidays")], &[&tagged])).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let attachment = body_json(response).await["data"]["attachments"][0].clone();
+    assert_eq!(attachment["size"], jpeg.len());
+    let stored = body_bytes(get(&app, attachment["url"].as_str().unwrap(), &[]).await).await;
+    assert_eq!(stored, jpeg);
+
+    // 101 by 100 pixels is over the limit, whatever the file size.
+    let bomb = image(ImageFormat::Png, 101, 100);
+    let response = post_multipart(&app, &alice, multipart(&[("message", "Boom")], &[&bomb])).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], "Image too large");
+}
@@ -0,0 +1,261 @@
+use std::sync::Arc;
+use snap_app_demo::{config, context, router, state};
+use snap_app_demo::models::{Snap, User, Webhook, WebhookEvent};
+use snap_app_demo::state::{SnapAppState, UserRepository, Visibility, WebhookRepository};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App where root administers, mod moderates, alice posts and bob reports,
+/// alongside the `Authorization` values of those who call the API.
+struct TestApp {
+    app: axum::Router,
+    repo: state::MockSnapRepository,
+    alice_id: String,
+    alice: String,
+    root: String,
+    moderator: String,
+    bob: String,
+}
+
+fn test_app() -> TestApp {
+    let mut repo = state::MockSnapRepository::new();
+    let mut create = |name: &str| repo.create_user(User::new(name.to_string(), "a long password")).unwrap().id();
+    let (root, moderator, alice, bob) = (create("root"), create("mod"), create("alice"), create("bob"));
+    let mut config = config::Config::default();
+    config.admin.users = vec![root.clone()];
+    config.moderation.moderators = vec![moderator.clone()];
+    config.moderation.max_reason_length = 20;
+    let context = context::AppContext { config: Arc::new(config), ..context::AppContext::default() };
+    let bearer = |id: &str| format!("Bearer {}", context.auth.issue(id).access_token);
+    TestApp {
+        root: bearer(&root),
+        moderator: bearer(&moderator),
+        bob: bearer(&bob),
+        alice: bearer(&alice),
+        alice_id: alice,
+        app: router::get_router_with_context(context).with_state(repo.clone()),
+        repo,
+    }
+}
+
+async fn send(app: &axum::Router, method: &str, uri: &str, authorization: &str, body: Option<Value>) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization)
+        .header("Content-Type", "application/json")
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn report(app: &TestApp, authorization: &str, snap: &Snap, reason: &str) -> Response {
+    let uri = format!("/snaps/{}/reports", snap.id());
+    send(&app.app, "POST", &uri, authorization, Some(json!({ "reason": reason }))).await
+}
+
+async fn moderate(app: &TestApp, authorization: &str, snap: &Snap, action: &str, justification: &str) -> Response {
+    let uri = format!("/moderation/snaps/{}/{action}", snap.id());
+    send(&app.app, "POST", &uri, authorization, Some(json!({ "justification": justification }))).await
+}
+
+#[tokio::test]
+async fn reports_are_filed_once() {
+    let mut app = test_app();
+    let snap = app.repo.post(&app.alice_id, "Buy cheap watches").unwrap();
+
+    let response = report(&app, &app.bob, &snap, "spam").await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let filed = body_json(response).await["data"].clone();
+    assert_eq!(filed["snap_id"], json!(snap.id()));
+    assert_eq!(filed["reason"], "spam");
+
+    let response = report(&app, &app.bob, &snap, "still spam").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let again = body_json(response).await["data"].clone();
+    assert_eq!((&again["id"], &again["reason"]), (&filed["id"], &json!("spam")));
+
+    let response = report(&app, &app.bob, &snap, "").await;
+    assert_eq!(body_json(response).await["title"], "Invalid reason");
+    let response = report(&app, &app.bob, &snap, "far too long to be a reason").await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = send(&app.app, "POST", "/snaps/missing/reports", &app.bob, Some(json!({ "reason": "spam" }))).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = report(&app, "", &snap, "spam").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn only_moderators_review() {
+    let mut app = test_app();
+    let snap = app.repo.post(&app.alice_id, "Hello").unwrap();
+
+    for uri in ["/moderation/queue", "/moderation/audit"] {
+        let response = send(&app.app, "GET", uri, &app.bob, None).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(body_json(response).await["title"], "Not a moderator");
+        for moderator in [&app.moderator, &app.root] {
+            let response = send(&app.app, "GET", uri, moderator, None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+    let response = moderate(&app, &app.bob, &snap, "hide", "rude").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = moderate(&app, &app.moderator, &snap, "ban", "rude").await;
+    assert_eq!(body_json(response).await["title"], "Unknown action");
+    let response = moderate(&app, &app.moderator, &snap, "hide", " ").await;
+    assert_eq!(body_json(response).await["title"], "Invalid justification");
+    assert!(!app.repo.get_snap(&snap.id()).unwrap().is_hidden());
+}
+
+#[tokio::test]
+async fn queue_puts_the_most_reported_first() {
+    let mut app = test_app();
+    let once = app.repo.post(&app.alice_id, "Reported once").unwrap();
+    let twice = app.repo.post(&app.alice_id, "Reported twice").unwrap();
+    app.repo.post(&app.alice_id, "Never reported").unwrap();
+    report(&app, &app.bob, &once, "spam").await;
+    report(&app, &app.bob, &twice, "spam").await;
+    report(&app, &app.root, &twice, "rude").await;
+
+    let response = send(&app.app, "GET", "/moderation/queue", &app.moderator, None).await;
+    let queue = body_json(response).await["data"].clone();
+    assert_eq!(queue.as_array().unwrap().len(), 2);
+    assert_eq!((&queue[0]["snap"]["id"], &queue[0]["report_count"]), (&json!(twice.id()), &json!(2)));
+    assert_eq!(queue[0]["reports"][0]["reason"], "spam");
+    assert_eq!(queue[1]["snap"]["id"], json!(once.id()));
+
+    let response = send(&app.app, "GET", "/moderation/queue?limit=1", &app.moderator, None).await;
+    assert_eq!(body_json(response).await["data"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn hidden_snaps_stay_visible_to_moderators() {
+    let mut app = test_app();
+    let root = app.repo.post(&app.alice_id, "Question").unwrap();
+    let rude = app.repo.reply(&app.alice_id, &root.id(), "Rude #answer").unwrap();
+    report(&app, &app.bob, &rude, "rude").await;
+
+    let response = moderate(&app, &app.moderator, &rude, "hide", "insults").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let entry = body_json(response).await["data"].clone();
+    assert_eq!((&entry["action"], &entry["reports"]), (&json!("hide"), &json!(1)));
+    assert_eq!(app.repo.get(Visibility::Public).len(), 1);
+    assert_eq!(app.repo.get(Visibility::Moderator).len(), 2);
+
+    let uri = format!("/snaps/{}", rude.id());
+    let response = send(&app.app, "GET", &uri, &app.bob, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app.app, "GET", &uri, &app.moderator, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let snap = body_json(response).await["data"].clone();
+    assert_eq!(snap["message"], "Rude #answer");
+    assert!(snap["hidden_at"].is_string());
+
+    let lists = [
+        "/snaps".to_string(),
+        "/snaps?limit=10".to_string(),
+        "/tags/answer/snaps".to_string(),
+        "/search?q=rude".to_string(),
+        format!("/users/{}/snaps", app.alice_id),
+    ];
+    for uri in &lists {
+        for (authorization, shown) in [(&app.bob, false), (&app.moderator, true)] {
+            let response = send(&app.app, "GET", uri, authorization, None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let listed = body_json(response).await["data"].clone();
+            let found = listed.as_array().unwrap().iter().find(|snap| snap["id"] == json!(rude.id())).cloned();
+            assert_eq!(found.is_some(), shown, "{uri}");
+            if let Some(snap) = found {
+                assert!(snap["hidden_at"].is_string());
+            }
+        }
+    }
+    let response = send(&app.app, "GET", &format!("/snaps/{}/thread", root.id()), &app.bob, None).await;
+    let thread = body_json(response).await["data"].clone();
+    assert_eq!(thread["replies"][0]["message"], "");
+    let response = send(&app.app, "GET", &format!("/snaps/{}/thread", rude.id()), &app.bob, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = report(&app, &app.bob, &rude, "still rude").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = moderate(&app, &app.root, &rude, "restore", "apologized").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = send(&app.app, "GET", &uri, &app.bob, None).await;
+    assert_eq!(body_json(response).await["data"]["message"], "Rude #answer");
+}
+
+#[tokio::test]
+async fn reactors_of_hidden_snaps_are_only_listed_to_moderators() {
+    let mut app = test_app();
+    let rude = app.repo.post(&app.alice_id, "Rude").unwrap();
+    let uri = format!("/snaps/{}/reactions/like", rude.id());
+    let response = send(&app.app, "PUT", &uri, &app.bob, None).await;
+    assert!(response.status().is_success());
+    moderate(&app, &app.moderator, &rude, "hide", "insults").await;
+
+    let response = send(&app.app, "GET", &uri, &app.bob, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app.app, "GET", &uri, &app.moderator, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"].as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn hidden_snaps_cant_be_edited() {
+    let mut app = test_app();
+    let watcher = app.repo.get_user(&app.alice_id).unwrap().uuid();
+    let events = [WebhookEvent::SnapEdited].into_iter().collect();
+    app.repo.create_webhook(Webhook::new(watcher, "http://example.com/hook".to_string(), events)).unwrap();
+    let rude = app.repo.post(&app.alice_id, "Rude").unwrap();
+    moderate(&app, &app.moderator, &rude, "hide", "insults").await;
+
+    let uri = format!("/snaps/{}", rude.id());
+    let response = send(&app.app, "PATCH", &uri, &app.alice, Some(json!({ "message": "Still rude" }))).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(app.repo.get_snap(&rude.id()).unwrap().message(), "Rude");
+    // Subscribers don't get the hidden contents.
+    assert!(app.repo.due_deliveries(chrono::Utc::now(), 10).is_empty());
+}
+
+#[tokio::test]
+async fn deletions_are_audited() {
+    let mut app = test_app();
+    let first = app.repo.post(&app.alice_id, "First").unwrap();
+    let second = app.repo.post(&app.alice_id, "Second").unwrap();
+    moderate(&app, &app.moderator, &first, "hide", "spam").await;
+    moderate(&app, &app.moderator, &first, "delete", "spam again").await;
+    moderate(&app, &app.root, &second, "restore", "nothing wrong").await;
+
+    let response = send(&app.app, "GET", &format!("/snaps/{}", first.id()), &app.moderator, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = moderate(&app, &app.moderator, &first, "hide", "spam").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(&app.app, "GET", "/moderation/audit?limit=2", &app.moderator, None).await;
+    let page = body_json(response).await;
+    let actions = page["data"].as_array().unwrap()
+        .iter()
+        .map(|entry| entry["action"].as_str().unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(actions, vec!["restore", "delete"]);
+    assert_eq!(page["data"][1]["justification"], "spam again");
+
+    let uri = format!("/moderation/audit?limit=2&cursor={}", page["next_cursor"].as_str().unwrap());
+    let response = send(&app.app, "GET", &uri, &app.moderator, None).await;
+    let page = body_json(response).await;
+    assert_eq!(page["data"][0]["action"], "hide");
+    assert!(page["next_cursor"].is_null());
+}
@@ -0,0 +1,195 @@
+use std::time::Duration;
+use snap_app_demo::{context, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the users alice and bob, alongside their `Authorization` values.
+struct TestApp {
+    app: axum::Router,
+    context: context::AppContext,
+    alice: String,
+    bob: String,
+}
+
+fn test_app() -> TestApp {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let bob = repo.create_user(User::new("Bob".to_string(), "a long password")).unwrap().id();
+    let context = context::AppContext::default();
+    TestApp {
+        alice: format!("Bearer {}", context.auth.issue(&alice).access_token),
+        bob: format!("Bearer {}", context.auth.issue(&bob).access_token),
+        app: router::get_router_with_context(context.clone()).with_state(repo),
+        context,
+    }
+}
+
+/// Send `body` with `method` to `uri`, with `authorization` as is.
+async fn send(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    authorization: &str,
+    body: Option<Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization);
+    let request = match body {
+        Some(body) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&body).unwrap())),
+        None => request.body(Body::empty()),
+    };
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Post `message`, replying to `in_reply_to` if given, and return its id.
+async fn post_snap(app: &axum::Router, authorization: &str, message: &str, in_reply_to: Option<&str>) -> String {
+    let response = send(app, "POST", "/snaps", authorization, Some(json!({
+        "message": message,
+        "in_reply_to": in_reply_to,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["data"]["id"].as_str().unwrap().to_string()
+}
+
+fn kinds(page: &Value) -> Vec<&str> {
+    page["data"].as_array().unwrap()
+        .iter()
+        .map(|notification| notification["kind"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn interactions_fill_the_inbox() {
+    let test = test_app();
+    let snap = post_snap(&test.app, &test.alice, "Hello @bob and @alice", None).await;
+    post_snap(&test.app, &test.bob, "Hi @alice", Some(&snap)).await;
+    let response = send(&test.app, "PUT", &format!("/snaps/{snap}/reactions/like"), &test.bob, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    send(&test.app, "PUT", &format!("/snaps/{snap}/reactions/like"), &test.bob, None).await;
+    send(&test.app, "PUT", &format!("/snaps/{snap}/reactions/love"), &test.alice, None).await;
+
+    // A reply that also mentions the parent author only notifies once.
+    let response = send(&test.app, "GET", "/notifications", &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = body_json(response).await;
+    assert_eq!(kinds(&page), vec!["reaction", "reply"]);
+    assert_eq!(page["data"][0]["reaction"], json!("like"));
+    assert_eq!(page["data"][1]["read"], json!(false));
+    assert_eq!(page["unread_count"], json!(2));
+
+    let response = send(&test.app, "GET", "/notifications", &test.bob, None).await;
+    let page = body_json(response).await;
+    assert_eq!(kinds(&page), vec!["mention"]);
+    assert_eq!(page["data"][0]["snap_id"], json!(snap));
+}
+
+#[tokio::test]
+async fn notifications_are_marked_read() {
+    let test = test_app();
+    for i in 0..3 {
+        post_snap(&test.app, &test.bob, &format!("@alice number {i}"), None).await;
+    }
+
+    let response = send(&test.app, "GET", "/notifications?limit=2", &test.alice, None).await;
+    let page = body_json(response).await;
+    assert_eq!(page["data"].as_array().unwrap().len(), 2);
+    let newest = page["data"][0]["id"].clone();
+    let cursor = page["next_cursor"].as_str().unwrap();
+    let response = send(&test.app, "GET", &format!("/notifications?limit=2&cursor={cursor}"), &test.alice, None).await;
+    let page = body_json(response).await;
+    assert_eq!(page["data"].as_array().unwrap().len(), 1);
+    assert_eq!(page["next_cursor"], Value::Null);
+
+    let response = send(&test.app, "POST", "/notifications/read", &test.alice, Some(json!({ "ids": [newest] }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"], json!({ "marked": 1, "unread_count": 2 }));
+
+    let response = send(&test.app, "GET", "/notifications?unread=true", &test.alice, None).await;
+    let page = body_json(response).await;
+    assert_eq!(page["data"].as_array().unwrap().len(), 2);
+    assert!(page["data"].as_array().unwrap().iter().all(|notification| notification["id"] != newest));
+
+    let response = send(&test.app, "POST", "/notifications/read", &test.alice, Some(json!({}))).await;
+    assert_eq!(body_json(response).await["data"], json!({ "marked": 2, "unread_count": 0 }));
+    let response = send(&test.app, "GET", "/notifications", &test.alice, None).await;
+    assert!(body_json(response).await["data"][0]["read_at"].is_string());
+}
+
+#[tokio::test]
+async fn preferences_turn_kinds_off() {
+    let test = test_app();
+    let response = send(&test.app, "GET", "/notifications/preferences", &test.alice, None).await;
+    assert_eq!(body_json(response).await["data"], json!({
+        "mentions": true,
+        "replies": true,
+        "reactions": true,
+    }));
+
+    let response = send(&test.app, "PATCH", "/notifications/preferences", &test.alice, Some(json!({
+        "mentions": false,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"]["mentions"], json!(false));
+
+    let snap = post_snap(&test.app, &test.alice, "Mine", None).await;
+    post_snap(&test.app, &test.bob, "Hey @alice", None).await;
+    post_snap(&test.app, &test.bob, "Reply", Some(&snap)).await;
+    let response = send(&test.app, "GET", "/notifications", &test.alice, None).await;
+    assert_eq!(kinds(&body_json(response).await), vec!["reply"]);
+
+    let response = send(&test.app, "GET", "/notifications", "Bearer nope", None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn stream_pushes_own_notifications() {
+    let test = test_app();
+    let response = send(&test.app, "GET", "/notifications/stream", &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let mut body = response.into_body();
+
+    post_snap(&test.app, &test.alice, "Talking to @bob", None).await;
+    post_snap(&test.app, &test.bob, "Talking to @alice", None).await;
+
+    let frame = tokio::time::timeout(Duration::from_secs(5), body.frame())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    let text = String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap();
+    assert!(text.starts_with("event: notification\n"), "{text}");
+    let data = text.lines().find_map(|line| line.strip_prefix("data: ")).unwrap();
+    assert_eq!(serde_json::from_str::<Value>(data).unwrap()["kind"], json!("mention"));
+
+    let metrics = send(&test.app, "GET", "/metrics", &test.alice, None).await;
+    let metrics = String::from_utf8(metrics.into_body().collect().await.unwrap().to_bytes().to_vec()).unwrap();
+    assert!(metrics.contains("open_streams 1"));
+
+    // Closing the notifier ends the stream.
+    test.context.notifier.close();
+    let end = tokio::time::timeout(Duration::from_secs(5), body.frame()).await.unwrap();
+    assert!(end.is_none());
+}
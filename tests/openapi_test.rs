@@ -0,0 +1,123 @@
+use snap_app_demo::{router, state};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// Source of `get_router`, where every route is declared.
+const ROUTER_SOURCE: &str = include_str!("../src/router/mod.rs");
+
+const METHODS: [&str; 5] = ["get", "post", "put", "patch", "delete"];
+
+async fn get(uri: &str) -> Response {
+    let app = router::get_router().with_state(state::MockSnapRepository::new());
+    let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    app.oneshot(request).await.unwrap()
+}
+
+async fn body_bytes(response: Response) -> Vec<u8> {
+    response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes()
+        .to_vec()
+}
+
+async fn spec() -> Value {
+    let response = get("/openapi.json").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_slice(&body_bytes(response).await).unwrap()
+}
+
+/// Path and methods of each `.route(...)` call in `get_router`, with
+/// the axum `:param` segments written as OpenAPI `{param}`.
+fn declared_routes() -> Vec<(String, Vec<&'static str>)> {
+    ROUTER_SOURCE.split(".route(")
+        .skip(1)
+        .map(|call| {
+            // The call ends at the parenthesis closing `.route(`.
+            let mut depth = 1;
+            let end = call.find(|c| {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => {},
+                }
+                depth == 0
+            }).unwrap();
+            let call = &call[..end];
+            let path = call.split('"').nth(1).unwrap();
+            let path = path.split('/')
+                .map(|segment| match segment.strip_prefix(':') {
+                    Some(param) => format!("{{{param}}}"),
+                    None => segment.to_string(),
+                })
+                .collect::<Vec<String>>()
+                .join("/");
+            let methods = METHODS.into_iter()
+                .filter(|method| {
+                    call.contains(&format!("routing::{method}(")) || call.contains(&format!(".{method}("))
+                })
+                .collect();
+            (path, methods)
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn every_route_is_documented() {
+    let spec = spec().await;
+    let routes = declared_routes();
+    assert!(routes.len() > 20, "only found {} routes", routes.len());
+    for (path, methods) in routes {
+        assert!(!methods.is_empty(), "no method found for {path}");
+        for method in methods {
+            assert!(
+                spec["paths"][&path][method].is_object(),
+                "{} {path} is missing from the OpenAPI document",
+                method.to_uppercase(),
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn spec_describes_payloads() {
+    let spec = spec().await;
+    assert_eq!(spec["openapi"], "3.1.0");
+    let schemas = &spec["components"]["schemas"];
+    for name in ["CreateSnap", "SnapInfo", "SnapCreated", "ProblemResponse", "SnapForm"] {
+        assert!(schemas[name].is_object(), "{name} is missing from the schemas");
+    }
+    assert_eq!(schemas["CreateSnap"]["required"], serde_json::json!(["message"]));
+
+    let create = &spec["paths"]["/snaps"]["post"];
+    assert!(create["requestBody"]["content"]["multipart/form-data"].is_object());
+    let created = &create["responses"]["201"]["content"]["application/json"]["schema"];
+    assert_eq!(created["$ref"], "#/components/schemas/ApiResponse_SnapCreated");
+    assert!(schemas["ApiResponse_SnapCreated"]["properties"]["data"]["properties"]["id"].is_object());
+    let problem = &create["responses"]["422"]["content"]["application/problem+json"]["schema"];
+    assert_eq!(problem["$ref"], "#/components/schemas/ProblemResponse");
+    assert!(create["security"].is_array());
+    assert!(spec["components"]["securitySchemes"]["bearer"].is_object());
+}
+
+#[tokio::test]
+async fn docs_are_served() {
+    let response = get("/docs/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let page = String::from_utf8(body_bytes(response).await).unwrap();
+    assert!(page.contains("swagger-ui"));
+
+    let response = get("/docs/swagger-initializer.js").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let script = String::from_utf8(body_bytes(response).await).unwrap();
+    assert!(script.contains("/openapi.json"));
+}
+
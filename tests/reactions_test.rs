@@ -0,0 +1,160 @@
+use snap_app_demo::{context, router, state};
+use snap_app_demo::models::{ReactionKind, User};
+use snap_app_demo::state::{SnapAppState, UserRepository, Visibility};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the users alice and bob, a snap by alice, and their `Authorization` values.
+struct TestApp {
+    app: axum::Router,
+    snap: String,
+    alice: String,
+    bob: (String, String),
+}
+
+fn test_app() -> TestApp {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let bob = repo.create_user(User::new("bob".to_string(), "a long password")).unwrap().id();
+    let snap = repo.post(&alice, "React to me").unwrap().id();
+    let context = context::AppContext::default();
+    TestApp {
+        snap,
+        alice: format!("Bearer {}", context.auth.issue(&alice).access_token),
+        bob: (bob.clone(), format!("Bearer {}", context.auth.issue(&bob).access_token)),
+        app: router::get_router_with_context(context).with_state(repo),
+    }
+}
+
+/// Send an empty `method` request to `uri`, with `authorization` as is.
+async fn send(app: &axum::Router, method: &str, uri: &str, authorization: &str) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization)
+        .body(Body::empty())
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn reactions_are_counted_per_user() {
+    let test = test_app();
+    let like = format!("/snaps/{}/reactions/like", test.snap);
+
+    for authorization in [&test.alice, &test.bob.1, &test.bob.1] {
+        let response = send(&test.app, "PUT", &like, authorization).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+    let response = send(&test.app, "PUT", &format!("/snaps/{}/reactions/love", test.snap), &test.bob.1).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send(&test.app, "GET", &format!("/snaps/{}", test.snap), &test.alice).await;
+    assert_eq!(body_json(response).await["data"]["reactions"], json!({ "like": 2, "love": 1 }));
+
+    let response = send(&test.app, "DELETE", &like, &test.alice).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send(&test.app, "DELETE", &like, &test.alice).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = send(&test.app, "GET", &like, &test.alice).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(body["data"][0]["id"], json!(test.bob.0));
+}
+
+#[tokio::test]
+async fn invalid_reactions() {
+    let test = test_app();
+
+    let response = send(&test.app, "PUT", &format!("/snaps/{}/reactions/meh", test.snap), &test.alice).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], json!("Unknown reaction"));
+
+    let response = send(&test.app, "PUT", "/snaps/missing/reactions/like", &test.alice).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = test.app.clone()
+        .oneshot(Request::builder()
+            .method("PUT")
+            .uri(format!("/snaps/{}/reactions/like", test.snap))
+            .body(Body::empty())
+            .unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+/// Toggle reactions from many threads at once, ending with every user
+/// reacting once, and check no update got lost or doubled.
+fn concurrent_toggling<S>(mut repo: S)
+where
+    S: SnapAppState + UserRepository + Clone + Send + 'static,
+{
+    let users = (0..8)
+        .map(|i| repo.create_user(User::new(format!("user{i}"), "password")).unwrap().id())
+        .collect::<Vec<String>>();
+    let snap = repo.post(&users[0], "Busy snap").unwrap().id();
+
+    let threads = users.iter()
+        .flat_map(|user| [user.clone(), user.clone()])
+        .map(|user| {
+            let mut repo = repo.clone();
+            let snap = snap.clone();
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    repo.react(&snap, &user, ReactionKind::Like).unwrap();
+                    repo.unreact(&snap, &user, ReactionKind::Like).unwrap();
+                }
+                repo.react(&snap, &user, ReactionKind::Like).unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Each user has two threads, the last one to finish may see its
+    // reaction removed by the other, so make the final state explicit.
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let count = repo.reaction_counts(&snap).get(&ReactionKind::Like).copied().unwrap_or(0);
+    assert_eq!(count, repo.reactors(&snap, ReactionKind::Like).len());
+    for user in &users {
+        repo.react(&snap, user, ReactionKind::Like).unwrap();
+    }
+
+    assert_eq!(repo.reaction_counts(&snap)[&ReactionKind::Like], users.len());
+    assert_eq!(repo.reactors(&snap, ReactionKind::Like).len(), users.len());
+}
+
+#[test]
+fn concurrent_toggling_in_memory() {
+    concurrent_toggling(state::MockSnapRepository::new());
+}
+
+#[test]
+fn concurrent_toggling_in_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("snaps.journal");
+    concurrent_toggling(state::JournalSnapRepository::open(&path).unwrap());
+
+    let repo = state::JournalSnapRepository::open(&path).unwrap();
+    let snap = repo.get(Visibility::Public)[0].id();
+    assert_eq!(repo.reaction_counts(&snap)[&ReactionKind::Like], 8);
+}
@@ -0,0 +1,197 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use snap_app_demo::{config, context, replication, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::{ChangeLog, UserRepository};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, StatusCode},
+};
+use http_body_util::BodyExt;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+const AUTH_SECRET: &str = "a secret shared by every server of the test";
+const REPLICATION_SECRET: &str = "another secret to stream the change log";
+
+/// Server listening on a localhost port.
+struct Server {
+    url: String,
+    repo: state::MockSnapRepository,
+    context: context::AppContext,
+}
+
+/// Start a server with `repo`, following `leader` if any.
+async fn start(repo: state::MockSnapRepository, leader: Option<&str>) -> Server {
+    let mut config = config::Config::default();
+    config.auth.secret = Some(AUTH_SECRET.to_string());
+    config.rate_limit.enabled = false;
+    config.replication.secret = Some(REPLICATION_SECRET.to_string());
+    config.replication.leader = leader.map(str::to_string);
+    config.replication.poll_interval_ms = 10;
+    let context = context::AppContext::new(config.clone()).unwrap();
+    let app = router::get_router_with_context(context.clone()).with_state(repo.clone());
+
+    let listener = TcpListener::bind("localhost:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+    if leader.is_some() {
+        tokio::spawn(replication::follow(repo.clone(), config.replication, context.replication.clone()));
+    }
+    Server { url: format!("http://{addr}"), repo, context }
+}
+
+fn client() -> Client<HttpConnector, Body> {
+    hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new()).build_http()
+}
+
+/// Send `body` with `method` to `url`, returning the status, the
+/// `Location` header and the JSON body.
+async fn send(method: &str, url: &str, authorization: &str, body: Option<Value>) -> (StatusCode, Option<String>, Value) {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json");
+    if !authorization.is_empty() {
+        request = request.header(header::AUTHORIZATION, authorization);
+    }
+    let request = request
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .unwrap();
+    let response = client().request(request).await.unwrap();
+    let status = response.status();
+    let location = response.headers()
+        .get(header::LOCATION)
+        .map(|value| value.to_str().unwrap().to_string());
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, location, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+/// Replication status of `server`.
+async fn replication_status(server: &Server) -> Value {
+    let (status, _, body) = send("GET", &format!("{}/replication/status", server.url), "", None).await;
+    assert_eq!(status, StatusCode::OK);
+    body["data"].clone()
+}
+
+/// Wait until `follower` has every change of `leader`.
+async fn caught_up(follower: &Server, leader: &Server) -> Value {
+    let head = leader.repo.change_count();
+    for _ in 0..500 {
+        let status = replication_status(follower).await;
+        if status["offset"] == json!(head) && status["lag"] == json!(0) {
+            return status
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the follower didn't catch up: {}", replication_status(follower).await);
+}
+
+#[tokio::test]
+async fn followers_replicate_the_leader() {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let leader = start(repo, None).await;
+    let token = format!("Bearer {}", leader.context.auth.issue(&alice).access_token);
+    let first = start(state::MockSnapRepository::new(), Some(&leader.url)).await;
+
+    let (status, _, created) = send("POST", &format!("{}/snaps", leader.url), &token, Some(json!({ "message": "hello #rust" }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let id = created["data"]["id"].as_str().unwrap().to_string();
+
+    let replicated = caught_up(&first, &leader).await;
+    assert_eq!(replicated["role"], "follower");
+    assert_eq!(replicated["leader"], json!(leader.url));
+    assert_eq!(replicated["connected"], true);
+    let (status, _, snap) = send("GET", &format!("{}/snaps/{id}", first.url), "", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(snap["data"]["message"], "hello #rust");
+    // Read models of the follower are built from the replicated changes.
+    let (_, _, found) = send("GET", &format!("{}/search?q=rust", first.url), "", None).await;
+    assert_eq!(found["data"][0]["id"], json!(id));
+
+    // A follower started late catches up from the start of the log.
+    let second = start(state::MockSnapRepository::new(), Some(&leader.url)).await;
+    caught_up(&second, &leader).await;
+    assert!(second.repo.get_user(&alice).is_some());
+
+    // Writes to a follower are sent to the leader, tokens work on both.
+    let url = format!("{}/snaps/{id}", second.url);
+    let (status, location, _) = send("DELETE", &url, &token, None).await;
+    assert_eq!(status, StatusCode::TEMPORARY_REDIRECT);
+    let location = location.unwrap();
+    assert_eq!(location, format!("{}/snaps/{id}", leader.url));
+    let (status, _, _) = send("DELETE", &location, &token, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    for follower in [&first, &second] {
+        caught_up(follower, &leader).await;
+        let (status, _, _) = send("GET", &url.replace(&second.url, &follower.url), "", None).await;
+        assert_ne!(status, StatusCode::OK);
+    }
+    assert_eq!(first.repo.changes(0, 100).unwrap(), leader.repo.changes(0, 100).unwrap());
+
+    let leader_status = replication_status(&leader).await;
+    assert_eq!(leader_status["role"], "leader");
+    assert_eq!(leader_status["offset"], json!(leader.repo.change_count()));
+}
+
+#[tokio::test]
+async fn the_change_log_needs_the_secret() {
+    let leader = start(state::MockSnapRepository::new(), None).await;
+    let url = format!("{}/replication/changes", leader.url);
+    let request = |secret: &str| Request::get(&url)
+        .header(replication::SECRET_HEADER, secret)
+        .body(Body::empty())
+        .unwrap();
+
+    let response = client().request(request("not the secret")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Followers with changes the leader doesn't have are turned away.
+    let response = client().request(Request::get(format!("{url}?from=5"))
+        .header(replication::SECRET_HEADER, REPLICATION_SECRET)
+        .body(Body::empty())
+        .unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    let response = client().request(request(REPLICATION_SECRET)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut body = response.into_body();
+    let frame = body.frame().await.unwrap().unwrap().into_data().unwrap();
+    let frame: Value = serde_json::from_slice(&frame).unwrap();
+    assert_eq!(frame, json!({ "type": "head", "offset": 0 }));
+}
+
+#[tokio::test]
+async fn followers_behind_the_change_buffer_are_turned_away() {
+    let mut repo = state::MockSnapRepository::new().with_change_buffer(1);
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let leader = start(repo, None).await;
+    let token = format!("Bearer {}", leader.context.auth.issue(&alice).access_token);
+    let (status, _, _) = send("POST", &format!("{}/snaps", leader.url), &token, Some(json!({ "message": "hello" }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let response = client().request(Request::get(format!("{}/replication/changes?from=0", leader.url))
+        .header(replication::SECRET_HEADER, REPLICATION_SECRET)
+        .body(Body::empty())
+        .unwrap()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::GONE);
+
+    let follower = start(state::MockSnapRepository::new(), Some(&leader.url)).await;
+    for _ in 0..500 {
+        let status = replication_status(&follower).await;
+        if let Some(error) = status["error"].as_str() {
+            assert!(error.contains("fell behind"), "{error}");
+            assert_eq!(follower.repo.change_count(), 0);
+            return
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("the follower wasn't turned away: {}", replication_status(&follower).await);
+}
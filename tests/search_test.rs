@@ -0,0 +1,85 @@
+use snap_app_demo::{context, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::{SnapAppState, UserRepository, Visibility};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App where alice posted `messages`, in order, alongside its repository.
+fn test_app(messages: &[&str]) -> (axum::Router, state::MockSnapRepository, String) {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    for message in messages {
+        repo.post(&alice, message).unwrap();
+    }
+    let app = router::get_router_with_context(context::AppContext::default()).with_state(repo.clone());
+    (app, repo, alice)
+}
+
+async fn get(app: &axum::Router, uri: &str) -> Response {
+    let request = Request::builder()
+        .uri(uri)
+        .body(Body::empty())
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn messages(app: &axum::Router, uri: &str) -> Vec<String> {
+    let response = get(app, uri).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await["data"].as_array().unwrap()
+        .iter()
+        .map(|snap| snap["message"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn search_matches_every_word() {
+    let (app, _, _) = test_app(&["Learning Rust today", "rust, again!", "Nothing here", "More #RUST today"]);
+
+    assert_eq!(messages(&app, "/search?q=rust").await, vec!["More #RUST today", "rust, again!", "Learning Rust today"]);
+    assert_eq!(messages(&app, "/search?q=TODAY%20rust").await, vec!["More #RUST today", "Learning Rust today"]);
+    assert!(messages(&app, "/search?q=python").await.is_empty());
+
+    let response = get(&app, "/search?q=rust&limit=2").await;
+    let body = body_json(response).await;
+    let cursor = body["next_cursor"].as_str().unwrap();
+    assert_eq!(messages(&app, &format!("/search?q=rust&limit=2&cursor={cursor}")).await, vec!["Learning Rust today"]);
+
+    let response = get(&app, "/search?q=%23%21").await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = get(&app, "/search").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn reads_see_every_change_so_far() {
+    let (app, mut repo, alice) = test_app(&["First draft"]);
+    assert_eq!(messages(&app, "/search?q=draft").await, vec!["First draft"]);
+
+    let first = repo.get(Visibility::Public).pop().unwrap();
+    repo.edit(&first.id(), "First version").unwrap();
+    let second = repo.post(&alice, "Second draft").unwrap();
+    assert_eq!(messages(&app, "/search?q=draft").await, vec!["Second draft"]);
+    assert_eq!(messages(&app, "/snaps").await, vec!["Second draft", "First version"]);
+
+    repo.delete(&second.id()).unwrap();
+    assert!(messages(&app, "/search?q=draft").await.is_empty());
+    assert_eq!(messages(&app, "/snaps?limit=5").await, vec!["First version"]);
+    assert_eq!(messages(&app, &format!("/users/{alice}/snaps")).await, vec!["First version"]);
+}
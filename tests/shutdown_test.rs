@@ -0,0 +1,180 @@
+use snap_app_demo::{context, router, state};
+use snap_app_demo::context::Phase;
+use snap_app_demo::shutdown::{self, BackgroundTasks, ServeOutcome};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    routing,
+};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+/// Router with a single route that answers after `delay`.
+fn slow_app(delay: Duration) -> axum::Router {
+    axum::Router::new().route(
+        "/slow",
+        routing::get(move || async move {
+            tokio::time::sleep(delay).await;
+            "done"
+        }),
+    )
+}
+
+#[tokio::test]
+async fn in_flight_request_finishes_after_shutdown() {
+    let listener = TcpListener::bind("localhost:0")
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+    let server = tokio::spawn(shutdown::serve_with_drain(
+        listener,
+        slow_app(Duration::from_millis(300)),
+        async move { let _ = stop_rx.await; },
+        Duration::ZERO,
+        Duration::from_secs(5),
+    ));
+
+    let client =
+        hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new())
+            .build_http();
+    let request = tokio::spawn(client.request(
+        Request::builder()
+            .uri(format!("http://{addr}/slow"))
+            .body(Body::empty())
+            .unwrap(),
+    ));
+
+    // Ask for shutdown while the request is being handled.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    stop_tx.send(()).unwrap();
+
+    let response = request.await.unwrap().unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(server.await.unwrap(), ServeOutcome::Drained);
+}
+
+#[tokio::test]
+async fn drain_timeout_drops_slow_requests() {
+    let listener = TcpListener::bind("localhost:0")
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+
+    let server = tokio::spawn(shutdown::serve_with_drain(
+        listener,
+        slow_app(Duration::from_secs(30)),
+        async move { let _ = stop_rx.await; },
+        Duration::ZERO,
+        Duration::from_millis(200),
+    ));
+
+    let client =
+        hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new())
+            .build_http();
+    let _request = tokio::spawn(client.request(
+        Request::builder()
+            .uri(format!("http://{addr}/slow"))
+            .body(Body::empty())
+            .unwrap(),
+    ));
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    stop_tx.send(()).unwrap();
+
+    let outcome = tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .expect("serve_with_drain should give up after the drain timeout");
+    assert_eq!(outcome.unwrap(), ServeOutcome::TimedOut);
+}
+
+#[tokio::test]
+async fn readiness_fails_before_connections_are_refused() {
+    let listener = TcpListener::bind("localhost:0")
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let context = context::AppContext::default();
+    let readiness = context.readiness.clone();
+    let app = router::get_router_with_context(context).with_state(state::MockSnapRepository::new());
+
+    let server = tokio::spawn(shutdown::serve_with_drain(
+        listener,
+        app,
+        async move {
+            let _ = stop_rx.await;
+            readiness.set(Phase::ShuttingDown);
+        },
+        Duration::from_millis(500),
+        Duration::from_secs(5),
+    ));
+    // A new client each time, so every request opens a new connection.
+    let get = |path: &str| {
+        let client =
+            hyper_util::client::legacy::Builder::new(hyper_util::rt::TokioExecutor::new())
+                .build_http::<Body>();
+        client.request(
+            Request::builder()
+                .uri(format!("http://{addr}{path}"))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    assert_eq!(get("/readyz").await.unwrap().status(), StatusCode::OK);
+    stop_tx.send(()).unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(get("/readyz").await.unwrap().status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(get("/healthz").await.unwrap().status(), StatusCode::OK);
+
+    assert_eq!(server.await.unwrap(), ServeOutcome::Drained);
+    assert!(get("/healthz").await.is_err());
+}
+
+#[tokio::test]
+async fn background_tasks_stop_in_order() {
+    let runs = Arc::new(AtomicUsize::new(0));
+    let stopped = Arc::new(Mutex::new(Vec::new()));
+    let mut background = BackgroundTasks::new();
+
+    let counter = runs.clone();
+    background.spawn_periodic("counter", Duration::from_millis(10), move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    for name in ["first", "second"] {
+        let guard = StopRecorder { name, stopped: stopped.clone() };
+        background.spawn_periodic(name, Duration::from_millis(10), move || {
+            let _ = &guard;
+        });
+    }
+
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    background.shutdown().await;
+
+    let runs_at_shutdown = runs.load(Ordering::SeqCst);
+    assert!(runs_at_shutdown > 0);
+    assert_eq!(*stopped.lock().unwrap(), vec!["first", "second"]);
+
+    // Nothing runs after shutdown returned.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(runs.load(Ordering::SeqCst), runs_at_shutdown);
+}
+
+/// Records its name when the task owning it is dropped.
+struct StopRecorder {
+    name: &'static str,
+    stopped: Arc<Mutex<Vec<&'static str>>>,
+}
+
+impl Drop for StopRecorder {
+    fn drop(&mut self) {
+        self.stopped.lock().unwrap().push(self.name);
+    }
+}
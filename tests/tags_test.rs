@@ -0,0 +1,103 @@
+use snap_app_demo::{context, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::{SnapAppState, UserRepository};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App where alice posted `messages`, in order.
+fn test_app(messages: &[&str]) -> axum::Router {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    for message in messages {
+        repo.post(&alice, message).unwrap();
+    }
+    router::get_router_with_context(context::AppContext::default()).with_state(repo)
+}
+
+async fn get(app: &axum::Router, uri: &str) -> Response {
+    let request = Request::builder()
+        .uri(uri)
+        .body(Body::empty())
+        .unwrap();
+    app.clone().oneshot(request).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+#[tokio::test]
+async fn snaps_show_their_entities() {
+    let app = test_app(&["Hi @Bob, see #Rust"]);
+
+    let response = get(&app, "/snaps").await;
+    assert_eq!(body_json(response).await["data"][0]["entities"], json!([
+        { "kind": "mention", "text": "bob", "start": 3, "end": 7 },
+        { "kind": "hashtag", "text": "rust", "start": 13, "end": 18 },
+    ]));
+}
+
+#[tokio::test]
+async fn tag_feed_is_paginated() {
+    let app = test_app(&["One #rust", "Two #go", "Three #Rust", "Four #rust #RUST"]);
+
+    let response = get(&app, "/tags/%23RUST/snaps?limit=2").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    let messages = body["data"].as_array().unwrap()
+        .iter()
+        .map(|snap| snap["message"].as_str().unwrap())
+        .collect::<Vec<&str>>();
+    assert_eq!(messages, vec!["Four #rust #RUST", "Three #Rust"]);
+
+    let cursor = body["next_cursor"].as_str().unwrap();
+    let response = get(&app, &format!("/tags/rust/snaps?limit=2&cursor={cursor}")).await;
+    let body = body_json(response).await;
+    assert_eq!(body["data"][0]["message"], json!("One #rust"));
+    assert_eq!(body["next_cursor"], Value::Null);
+
+    let response = get(&app, "/tags/unused/snaps").await;
+    assert_eq!(body_json(response).await["data"], json!([]));
+}
+
+#[tokio::test]
+async fn trending_counts_snaps_per_tag() {
+    let app = test_app(&["#go", "#rust", "#rust #go", "#Rust #rust", "#zig"]);
+
+    let response = get(&app, "/tags/trending").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"], json!([
+        { "tag": "rust", "count": 3 },
+        { "tag": "go", "count": 2 },
+        { "tag": "zig", "count": 1 },
+    ]));
+
+    let response = get(&app, "/tags/trending?limit=1").await;
+    assert_eq!(body_json(response).await["data"], json!([{ "tag": "rust", "count": 3 }]));
+
+    let response = get(&app, "/tags/trending?limit=0").await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn invalid_tags() {
+    let app = test_app(&[]);
+
+    for tag in ["123", "two%20words", "a-b"] {
+        let response = get(&app, &format!("/tags/{tag}/snaps")).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(body_json(response).await["title"], json!("Invalid tag"));
+    }
+}
@@ -0,0 +1,346 @@
+use snap_app_demo::{config, context, dump, metrics, router, state, tenancy};
+use snap_app_demo::config::{BucketConfig, TenantQuotas};
+use snap_app_demo::models::User;
+use snap_app_demo::state::{SnapAppState, UserRepository};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, StatusCode},
+};
+use http_body_util::BodyExt;
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+const ADMIN_TOKEN: &str = "a token long enough to manage the tenants";
+
+/// Router of a server hosting the tenants `acme` and `globex`, on the
+/// domain `snaps.test`.
+fn test_app() -> axum::Router {
+    test_app_with(Vec::new(), |_| Ok(state::MockSnapRepository::new()))
+}
+
+/// Router of a server opening the stores of the tenants with `open`, and
+/// where the users in `admins` administer them.
+fn test_app_with<F>(admins: Vec<String>, open: F) -> axum::Router
+where
+    F: Fn(&str) -> Result<state::MockSnapRepository, state::StorageError> + Send + Sync + 'static,
+{
+    let mut config = test_config();
+    config.admin.users = admins;
+    let tenants = tenancy::Tenants::new(config, context::AppContext::default(), None, open).unwrap();
+    router::get_tenant_router(tenants)
+}
+
+/// Settings of a server on the domain `snaps.test`, managed with [ADMIN_TOKEN].
+fn test_config() -> config::Config {
+    let mut config = config::Config::default();
+    // Shared by every tenant, only the issuer tells their tokens apart.
+    config.auth.secret = Some("a secret shared by every tenant of the test".to_string());
+    config.tenancy.enabled = true;
+    config.tenancy.domain = Some("snaps.test".to_string());
+    config.tenancy.admin_token = Some(ADMIN_TOKEN.to_string());
+    config
+}
+
+/// Request for `uri` with the headers in `headers`.
+fn request(method: &str, uri: &str, headers: &[(&str, &str)], body: Option<Value>) -> Request {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::CONTENT_TYPE, "application/json");
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+        .unwrap()
+}
+
+async fn send(app: &axum::Router, request: Request) -> (StatusCode, Value) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+async fn admin(app: &axum::Router, method: &str, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+    send(app, request(method, uri, &[(tenancy::ADMIN_TOKEN_HEADER, ADMIN_TOKEN)], body)).await
+}
+
+/// Register `username` on `tenant` and return its id and `Authorization` value.
+async fn sign_up(app: &axum::Router, tenant: &str, username: &str) -> (String, String) {
+    let credentials = json!({ "username": username, "password": "a long password" });
+    let (status, created) = send(app, request("POST", &format!("/t/{tenant}/users"), &[], Some(credentials.clone()))).await;
+    assert_eq!(status, StatusCode::CREATED, "{created}");
+    let (status, login) = send(app, request("POST", &format!("/t/{tenant}/login"), &[], Some(credentials))).await;
+    assert_eq!(status, StatusCode::OK, "{login}");
+    (
+        created["data"]["id"].as_str().unwrap().to_string(),
+        format!("Bearer {}", login["data"]["access_token"].as_str().unwrap()),
+    )
+}
+
+async fn post_snap(app: &axum::Router, tenant: &str, token: &str, message: &str) -> (StatusCode, Value) {
+    let headers = [(header::AUTHORIZATION.as_str(), token)];
+    send(app, request("POST", &format!("/t/{tenant}/snaps"), &headers, Some(json!({ "message": message })))).await
+}
+
+async fn create_tenants(app: &axum::Router) {
+    for id in ["acme", "globex"] {
+        let (status, body) = admin(app, "POST", "/admin/tenants", Some(json!({ "id": id }))).await;
+        assert_eq!(status, StatusCode::CREATED, "{body}");
+    }
+}
+
+#[tokio::test]
+async fn tenants_cant_read_each_others_snaps() {
+    let app = test_app();
+    create_tenants(&app).await;
+    let (alice, alice_token) = sign_up(&app, "acme", "alice").await;
+    let (status, created) = post_snap(&app, "acme", &alice_token, "secret plans #launch").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let id = created["data"]["id"].as_str().unwrap();
+
+    let (status, snap) = send(&app, request("GET", &format!("/t/acme/snaps/{id}"), &[], None)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(snap["data"]["message"], "secret plans #launch");
+
+    for uri in [format!("/snaps/{id}"), format!("/users/{alice}"), format!("/snaps/{id}/thread")] {
+        let (status, _) = send(&app, request("GET", &format!("/t/globex{uri}"), &[], None)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri}");
+    }
+    for uri in ["/snaps", "/search?q=plans", "/tags/launch/snaps", &format!("/users/{alice}/snaps")] {
+        let (_, found) = send(&app, request("GET", &format!("/t/globex{uri}"), &[], None)).await;
+        assert!(!found.to_string().contains("secret plans"), "{uri}: {found}");
+    }
+    let (_, trending) = send(&app, request("GET", "/t/globex/tags/trending", &[], None)).await;
+    assert!(!trending.to_string().contains("launch"), "{trending}");
+
+    // Tokens only work on the tenant that issued them.
+    let (status, _) = post_snap(&app, "globex", &alice_token, "hello").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let headers = [(header::AUTHORIZATION.as_str(), alice_token.as_str())];
+    let (status, _) = send(&app, request("DELETE", &format!("/t/globex/snaps/{id}"), &headers, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (_, usage) = admin(&app, "GET", "/admin/tenants", None).await;
+    assert_eq!(usage["data"][0]["id"], "acme");
+    assert_eq!(usage["data"][0]["usage"], json!({ "snaps": 1, "users": 1 }));
+    assert_eq!(usage["data"][1]["usage"], json!({ "snaps": 0, "users": 0 }));
+}
+
+#[tokio::test]
+async fn tenants_are_named_by_path_header_or_subdomain() {
+    let app = test_app();
+    create_tenants(&app).await;
+    let (_, token) = sign_up(&app, "globex", "bob").await;
+    let (_, created) = post_snap(&app, "globex", &token, "hi").await;
+    let uri = format!("/snaps/{}", created["data"]["id"].as_str().unwrap());
+
+    for headers in [
+        vec![(tenancy::TENANT_HEADER, "globex")],
+        vec![(header::HOST.as_str(), "globex.snaps.test")],
+        vec![(header::HOST.as_str(), "globex.snaps.test:8080")],
+    ] {
+        let (status, snap) = send(&app, request("GET", &uri, &headers, None)).await;
+        assert_eq!(status, StatusCode::OK, "{headers:?}");
+        assert_eq!(snap["data"]["message"], "hi");
+    }
+    // The path prefix wins over the header.
+    let headers = [(tenancy::TENANT_HEADER, "globex")];
+    let (status, _) = send(&app, request("GET", &format!("/t/acme{uri}"), &headers, None)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    for (uri, headers) in [
+        (uri.clone(), vec![]),
+        (uri.clone(), vec![(header::HOST.as_str(), "snaps.test")]),
+        (uri.clone(), vec![(header::HOST.as_str(), "globex.elsewhere.test")]),
+        (format!("/t/initech{uri}"), vec![]),
+    ] {
+        let (status, problem) = send(&app, request("GET", &uri, &headers, None)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{uri} {headers:?}");
+        assert_eq!(problem["title"], "Unknown tenant");
+    }
+}
+
+#[tokio::test]
+async fn suspended_tenants_are_turned_away() {
+    let app = test_app();
+    create_tenants(&app).await;
+    let (status, tenant) = admin(&app, "POST", "/admin/tenants/acme/suspend", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(tenant["data"]["suspended_at"].is_string());
+
+    let (status, problem) = send(&app, request("GET", "/t/acme/snaps", &[], None)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem["title"], "Tenant suspended");
+    let (status, _) = send(&app, request("GET", "/t/globex/snaps", &[], None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = admin(&app, "POST", "/admin/tenants/acme/resume", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, request("GET", "/t/acme/snaps", &[], None)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn quotas_and_budgets_are_per_tenant() {
+    let app = test_app();
+    create_tenants(&app).await;
+    let quotas = TenantQuotas {
+        max_snaps: Some(1),
+        write: Some(BucketConfig { burst: 4, per_second: 0.001 }),
+        ..TenantQuotas::default()
+    };
+    let (status, _) = admin(&app, "PATCH", "/admin/tenants/acme", Some(json!({ "quotas": quotas }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Signing up and logging in take two writes of the budget.
+    let (_, token) = sign_up(&app, "acme", "alice").await;
+    let (status, _) = post_snap(&app, "acme", &token, "first").await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, problem) = post_snap(&app, "acme", &token, "second").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem["title"], "Quota exceeded");
+    let (status, _) = post_snap(&app, "acme", &token, "third").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = send(&app, request("GET", "/t/acme/snaps", &[], None)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, token) = sign_up(&app, "globex", "bob").await;
+    for message in ["one", "two", "three"] {
+        let (status, _) = post_snap(&app, "globex", &token, message).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_posts_stop_at_the_snap_quota() {
+    let app = test_app();
+    let quotas = TenantQuotas { max_snaps: Some(3), ..TenantQuotas::default() };
+    let (status, _) = admin(&app, "POST", "/admin/tenants", Some(json!({ "id": "acme", "quotas": quotas }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, token) = sign_up(&app, "acme", "alice").await;
+
+    let posts = (0..12)
+        .map(|i| {
+            let (app, token) = (app.clone(), token.clone());
+            tokio::spawn(async move { post_snap(&app, "acme", &token, &format!("snap {i}")).await.0 })
+        })
+        .collect::<Vec<_>>();
+    let mut statuses = Vec::new();
+    for post in posts {
+        statuses.push(post.await.unwrap());
+    }
+    let created = statuses.iter().filter(|status| **status == StatusCode::CREATED).count();
+    let refused = statuses.iter().filter(|status| **status == StatusCode::FORBIDDEN).count();
+    assert_eq!((created, refused), (3, 9));
+    let (_, tenant) = admin(&app, "GET", "/admin/tenants/acme", None).await;
+    assert_eq!(tenant["data"]["usage"]["snaps"], 3);
+}
+
+#[tokio::test]
+async fn api_keys_have_their_own_budget_in_each_tenant() {
+    let mut config = test_config();
+    config.rate_limit.write = BucketConfig { burst: 3, per_second: 0.001 };
+    let shared = context::AppContext { metrics: metrics::Metrics::for_tenants(), ..Default::default() };
+    let tenants = tenancy::Tenants::new(config, shared, None, |_| {
+        Ok(state::MockSnapRepository::new())
+    }).unwrap();
+    let app = router::get_tenant_router(tenants);
+    create_tenants(&app).await;
+
+    // Signing up, logging in and creating the key take the budget of the client.
+    let (_, token) = sign_up(&app, "acme", "alice").await;
+    let headers = [(header::AUTHORIZATION.as_str(), token.as_str())];
+    let body = json!({ "name": "bot", "scope": "write" });
+    let (status, created) = send(&app, request("POST", "/t/acme/api-keys", &headers, Some(body))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let key = format!("ApiKey {}", created["data"]["key"].as_str().unwrap());
+    let (status, _) = post_snap(&app, "acme", &token, "as alice").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = post_snap(&app, "acme", &key, "as the bot").await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // Clients start with a full budget on every tenant.
+    sign_up(&app, "globex", "alice").await;
+
+    // Refused requests are counted, tenants don't overwrite each other's snap count.
+    let response = app.oneshot(request("GET", "/t/acme/metrics", &[], None)).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let metrics = String::from_utf8(body.to_vec()).unwrap();
+    assert!(metrics.contains(r#"http_requests_total{method="POST",route="/snaps",status="429"} 1"#));
+    assert!(!metrics.lines().any(|line| line.starts_with("snaps ")));
+}
+
+#[tokio::test]
+async fn imports_and_sign_ups_count_against_the_quotas() {
+    let mut source = state::MockSnapRepository::new();
+    let author = source.create_user(User::new("author".to_string(), "a long password")).unwrap().id();
+    for message in ["one", "two", "three"] {
+        source.post(&author, message).unwrap();
+    }
+    let dump = dump::export(&source).collect::<String>();
+    let mut repo = state::MockSnapRepository::new();
+    let root = repo.create_user(User::new("root".to_string(), "a long password")).unwrap().id();
+    let app = test_app_with(vec![root], move |_| Ok(repo.clone()));
+    let quotas = TenantQuotas { max_snaps: Some(2), max_users: Some(1), ..TenantQuotas::default() };
+    let (status, _) = admin(&app, "POST", "/admin/tenants", Some(json!({ "id": "acme", "quotas": quotas }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let credentials = json!({ "username": "root", "password": "a long password" });
+    let (_, login) = send(&app, request("POST", "/t/acme/login", &[], Some(credentials))).await;
+    let token = format!("Bearer {}", login["data"]["access_token"].as_str().unwrap());
+    let import = |query: &str| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("/t/acme/admin/import{query}"))
+            .header(header::AUTHORIZATION, &token)
+            .header(header::CONTENT_TYPE, "application/x-ndjson")
+            .body(Body::from(dump.clone()))
+            .unwrap()
+    };
+
+    for query in ["?dry_run=true", ""] {
+        let (status, problem) = send(&app, import(query)).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{query}");
+        assert_eq!(problem["title"], "Quota exceeded");
+    }
+    let (_, tenant) = admin(&app, "GET", "/admin/tenants/acme", None).await;
+    assert_eq!(tenant["data"]["usage"], json!({ "snaps": 2, "users": 1 }));
+
+    let quotas = TenantQuotas { max_snaps: Some(3), ..quotas };
+    let (status, _) = admin(&app, "PATCH", "/admin/tenants/acme", Some(json!({ "quotas": quotas }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, report) = send(&app, import("?on_conflict=skip")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((&report["data"]["created"], &report["data"]["skipped"]), (&json!(1), &json!(2)));
+
+    let credentials = json!({ "username": "alice", "password": "a long password" });
+    let (status, problem) = send(&app, request("POST", "/t/acme/users", &[], Some(credentials))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(problem["title"], "Quota exceeded");
+}
+
+#[tokio::test]
+async fn tenants_are_managed_with_the_admin_token() {
+    let app = test_app();
+    let body = Some(json!({ "id": "acme" }));
+    let (status, _) = send(&app, request("POST", "/admin/tenants", &[], body.clone())).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let headers = [(tenancy::ADMIN_TOKEN_HEADER, "not the token")];
+    let (status, _) = send(&app, request("GET", "/admin/tenants", &headers, None)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, created) = admin(&app, "POST", "/admin/tenants", Some(json!({ "id": "acme", "name": "Acme" }))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(created["data"]["name"], "Acme");
+    let (status, _) = admin(&app, "POST", "/admin/tenants", body).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = admin(&app, "POST", "/admin/tenants", Some(json!({ "id": "../acme" }))).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = admin(&app, "GET", "/admin/tenants/globex", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, tenant) = admin(&app, "GET", "/admin/tenants/acme", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(tenant["data"]["usage"]["snaps"], 0);
+}
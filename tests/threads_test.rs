@@ -0,0 +1,145 @@
+use snap_app_demo::{context, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the user alice, alongside her `Authorization` value.
+fn test_app() -> (axum::Router, String) {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let context = context::AppContext::default();
+    let authorization = format!("Bearer {}", context.auth.issue(&alice).access_token);
+    (router::get_router_with_context(context).with_state(repo), authorization)
+}
+
+/// Send `body` with `method` to `uri`, with `authorization` as is.
+async fn send(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    authorization: &str,
+    body: Option<Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization);
+    let request = match body {
+        Some(body) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&body).unwrap())),
+        None => request.body(Body::empty()),
+    };
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Post `message`, replying to `in_reply_to` if given, and return its id.
+async fn post_snap(app: &axum::Router, authorization: &str, message: &str, in_reply_to: Option<&str>) -> String {
+    let response = send(app, "POST", "/snaps", authorization, Some(json!({
+        "message": message,
+        "in_reply_to": in_reply_to,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["data"]["id"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn thread_starts_at_the_root() {
+    let (app, alice) = test_app();
+    let root = post_snap(&app, &alice, "Root", None).await;
+    let first = post_snap(&app, &alice, "First", Some(&root)).await;
+    post_snap(&app, &alice, "Second", Some(&root)).await;
+    let nested = post_snap(&app, &alice, "Nested", Some(&first)).await;
+
+    let response = send(&app, "GET", &format!("/snaps/{nested}/thread"), &alice, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let thread = &body_json(response).await["data"];
+    assert_eq!(thread["id"], json!(root));
+    assert_eq!(thread["reply_count"], json!(2));
+    assert_eq!(thread["replies"][0]["message"], json!("First"));
+    assert_eq!(thread["replies"][0]["replies"][0]["id"], json!(nested));
+    assert_eq!(thread["replies"][1]["message"], json!("Second"));
+
+    let response = send(&app, "GET", &format!("/snaps/{root}/thread?depth=1"), &alice, None).await;
+    let thread = &body_json(response).await["data"];
+    assert_eq!(thread["replies"][0]["reply_count"], json!(1));
+    assert_eq!(thread["replies"][0]["replies"], json!([]));
+
+    let response = send(&app, "GET", &format!("/snaps/{root}/thread?depth=0"), &alice, None).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn list_shows_reply_counts() {
+    let (app, alice) = test_app();
+    let root = post_snap(&app, &alice, "Root", None).await;
+    let reply = post_snap(&app, &alice, "Reply", Some(&root)).await;
+
+    let response = send(&app, "GET", "/snaps", &alice, None).await;
+    let body = body_json(response).await;
+    assert_eq!(body["data"][0]["id"], json!(reply));
+    assert_eq!(body["data"][0]["in_reply_to"], json!(root));
+    assert_eq!(body["data"][0]["reply_count"], json!(0));
+    assert_eq!(body["data"][1]["reply_count"], json!(1));
+}
+
+#[tokio::test]
+async fn replies_need_a_live_parent() {
+    let (app, alice) = test_app();
+    let response = send(&app, "POST", "/snaps", &alice, Some(json!({
+        "message": "To nowhere",
+        "in_reply_to": "missing",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], json!("Unknown parent"));
+}
+
+#[tokio::test]
+async fn deleted_parents_leave_tombstones() {
+    let (app, alice) = test_app();
+    let root = post_snap(&app, &alice, "Root", None).await;
+    let reply = post_snap(&app, &alice, "Reply", Some(&root)).await;
+
+    let response = send(&app, "DELETE", &format!("/snaps/{root}"), &alice, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send(&app, "GET", &format!("/snaps/{root}"), &alice, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&app, "GET", "/snaps", &alice, None).await;
+    assert_eq!(body_json(response).await["data"].as_array().unwrap().len(), 1);
+
+    let response = send(&app, "GET", &format!("/snaps/{reply}/thread"), &alice, None).await;
+    let thread = &body_json(response).await["data"];
+    assert_eq!(thread["id"], json!(root));
+    assert_eq!(thread["message"], json!(""));
+    assert!(thread["deleted_at"].is_string());
+    assert_eq!(thread["replies"][0]["id"], json!(reply));
+
+    let response = send(&app, "POST", "/snaps", &alice, Some(json!({
+        "message": "Too late",
+        "in_reply_to": root,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Without replies left, the tombstone goes away.
+    let response = send(&app, "DELETE", &format!("/snaps/{reply}"), &alice, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send(&app, "GET", &format!("/snaps/{root}/thread"), &alice, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
@@ -0,0 +1,186 @@
+use snap_app_demo::{config, context, router, state};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the users alice, bob and carol, alongside their ids and access tokens.
+struct TestApp {
+    app: axum::Router,
+    alice: (String, String),
+    bob: (String, String),
+    carol: (String, String),
+}
+
+fn test_app(fan_out: config::FanOut) -> TestApp {
+    let mut repo = state::MockSnapRepository::with_fan_out(fan_out);
+    let mut config = config::Config::default();
+    config.timeline.fan_out = fan_out;
+    config.timeline.default_page_size = 2;
+    let context = context::AppContext::new(config).unwrap();
+    let mut user = |username: &str| {
+        let id = repo.create_user(User::new(username.to_string(), "a long password")).unwrap().id();
+        let token = format!("Bearer {}", context.auth.issue(&id).access_token);
+        (id, token)
+    };
+    let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+    TestApp {
+        app: router::get_router_with_context(context).with_state(repo),
+        alice,
+        bob,
+        carol,
+    }
+}
+
+/// Send `body` with `method` to `uri`, with `authorization` as is.
+async fn send(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    authorization: &str,
+    body: Option<Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization);
+    let request = match body {
+        Some(body) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&body).unwrap())),
+        None => request.body(Body::empty()),
+    };
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+async fn post_snap(app: &axum::Router, authorization: &str, message: &str) {
+    let response = send(app, "POST", "/snaps", authorization, Some(json!({
+        "message": message,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+}
+
+/// Messages of every timeline page of `authorization`, following the cursors.
+async fn read_timeline(app: &axum::Router, authorization: &str) -> Vec<Vec<String>> {
+    let mut pages = Vec::new();
+    let mut uri = "/timeline".to_string();
+    loop {
+        let response = send(app, "GET", &uri, authorization, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = body_json(response).await;
+        pages.push(body["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|snap| snap["message"].as_str().unwrap().to_string())
+            .collect());
+        match body["next_cursor"].as_str() {
+            Some(cursor) => uri = format!("/timeline?cursor={cursor}"),
+            None => return pages,
+        }
+    }
+}
+
+#[tokio::test]
+async fn timeline_merges_followed_users() {
+    for fan_out in [config::FanOut::Read, config::FanOut::Write] {
+        let test = test_app(fan_out);
+        post_snap(&test.app, &test.bob.1, "Bob 1").await;
+        let response = send(&test.app, "PUT", &format!("/users/{}/follow", test.bob.0), &test.alice.1, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        post_snap(&test.app, &test.carol.1, "Carol").await;
+        post_snap(&test.app, &test.alice.1, "Alice").await;
+        post_snap(&test.app, &test.bob.1, "Bob 2").await;
+
+        let pages = read_timeline(&test.app, &test.alice.1).await;
+        assert_eq!(pages, vec![vec!["Bob 2", "Alice"], vec!["Bob 1"]], "{fan_out:?}");
+
+        let response = send(&test.app, "DELETE", &format!("/users/{}/follow", test.bob.0), &test.alice.1, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let pages = read_timeline(&test.app, &test.alice.1).await;
+        assert_eq!(pages, vec![vec!["Alice"]], "{fan_out:?}");
+    }
+}
+
+#[tokio::test]
+async fn followers_and_following() {
+    let test = test_app(config::FanOut::default());
+    for (follower, followee) in [(&test.alice, &test.bob), (&test.carol, &test.bob), (&test.bob, &test.alice)] {
+        let response = send(&test.app, "PUT", &format!("/users/{}/follow", followee.0), &follower.1, None).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+
+    let response = send(&test.app, "GET", &format!("/users/{}/followers", test.bob.0), &test.alice.1, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut followers = body_json(response).await["data"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["username"].as_str().unwrap().to_string())
+        .collect::<Vec<String>>();
+    followers.sort();
+    assert_eq!(followers, vec!["alice", "carol"]);
+
+    let response = send(&test.app, "GET", &format!("/users/{}/following", test.bob.0), &test.alice.1, None).await;
+    assert_eq!(body_json(response).await["data"][0]["id"], json!(test.alice.0));
+}
+
+#[tokio::test]
+async fn invalid_follows() {
+    let test = test_app(config::FanOut::default());
+
+    let response = send(&test.app, "PUT", &format!("/users/{}/follow", test.alice.0), &test.alice.1, None).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = send(&test.app, "PUT", "/users/missing/follow", &test.alice.1, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = send(&test.app, "GET", "/users/missing/followers", &test.alice.1, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn timeline_needs_a_user_and_valid_paging() {
+    let test = test_app(config::FanOut::default());
+
+    let response = test.app.clone()
+        .oneshot(Request::builder().uri("/timeline").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&test.app, "GET", "/timeline?cursor=garbage", &test.alice.1, None).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = send(&test.app, "GET", "/timeline?limit=1000", &test.alice.1, None).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn repository_latency_is_measured() {
+    let test = test_app(config::FanOut::Write);
+    post_snap(&test.app, &test.alice.1, "Hi").await;
+    read_timeline(&test.app, &test.alice.1).await;
+
+    let response = send(&test.app, "GET", "/metrics", &test.alice.1, None).await;
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let text = String::from_utf8(body.to_vec()).unwrap();
+    assert!(text.contains(r#"repository_operation_duration_seconds_count{fan_out="write",operation="post"} 1"#));
+    assert!(text.contains(r#"repository_operation_duration_seconds_count{fan_out="write",operation="timeline"} 1"#));
+}
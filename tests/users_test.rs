@@ -0,0 +1,213 @@
+use snap_app_demo::{context, router, state};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::StatusCode,
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+use uuid::Uuid;
+
+fn app() -> axum::Router {
+    router::get_router().with_state(state::MockSnapRepository::new())
+}
+
+/// Send a JSON `body` with `method` to `uri`.
+async fn send(app: &axum::Router, method: &str, uri: &str, body: Value) -> Response {
+    send_as(app, method, uri, None, body).await
+}
+
+/// Same as [send] with an optional access token.
+async fn send_as(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    token: Option<&str>,
+    body: Value,
+) -> Response {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Content-Type", "application/json");
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    }
+    app.clone()
+        .oneshot(request.body(Body::from(serde_json::to_string(&body).unwrap())).unwrap())
+        .await
+        .unwrap()
+}
+
+async fn get(app: &axum::Router, uri: &str) -> Response {
+    app.clone()
+        .oneshot(
+            Request::builder()
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap(),
+        ).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Register `username` and return its id.
+async fn register(app: &axum::Router, username: &str) -> String {
+    let response = send(app, "POST", "/users", json!({
+        "username": username,
+        "password": "a long password",
+    })).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["data"]["id"].as_str().unwrap().to_string()
+}
+
+/// Log in as `username` and return an access token.
+async fn login_as(app: &axum::Router, username: &str) -> String {
+    let response = send(app, "POST", "/login", json!({
+        "username": username,
+        "password": "a long password",
+    })).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await["data"]["access_token"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn register_and_get_user() {
+    let app = app();
+    let id = register(&app, "alice").await;
+    assert!(Uuid::parse_str(&id).is_ok());
+
+    let response = get(&app, &format!("/users/{id}")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["data"]["username"], json!("alice"));
+    assert!(body["data"].get("password").is_none());
+    assert!(body["data"].get("password_hash").is_none());
+}
+
+#[tokio::test]
+async fn register_taken_username() {
+    let app = app();
+    register(&app, "alice").await;
+
+    let response = send(&app, "POST", "/users", json!({
+        "username": "Alice",
+        "password": "another password",
+    })).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    assert_eq!(body_json(response).await["title"], json!("Username taken"));
+}
+
+#[tokio::test]
+async fn register_invalid_fields() {
+    let app = app();
+
+    let response = send(&app, "POST", "/users", json!({
+        "username": "no spaces allowed",
+        "password": "a long password",
+    })).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], json!("Invalid username"));
+
+    let response = send(&app, "POST", "/users", json!({
+        "username": "alice",
+        "password": "short",
+    })).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], json!("Invalid password"));
+
+    let response = send(&app, "POST", "/users", json!({
+        "username": "alice",
+        "password": "x".repeat(1025),
+    })).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], json!("Invalid password"));
+}
+
+#[tokio::test]
+async fn get_unknown_user() {
+    let response = get(&app(), &format!("/users/{}", Uuid::new_v4())).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(body_json(response).await["title"], json!("User not found"));
+}
+
+#[tokio::test]
+async fn login() {
+    let app = app();
+    let id = register(&app, "alice").await;
+
+    let response = send(&app, "POST", "/login", json!({
+        "username": "alice",
+        "password": "a long password",
+    })).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["data"]["user"]["id"], json!(id));
+    assert_eq!(body["data"]["token_type"], json!("Bearer"));
+    assert!(body["data"]["access_token"].is_string());
+    assert!(body["data"]["refresh_token"].is_string());
+
+    let response = send(&app, "POST", "/login", json!({
+        "username": "alice",
+        "password": "wrong password",
+    })).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = send(&app, "POST", "/login", json!({
+        "username": "nobody",
+        "password": "a long password",
+    })).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = send(&app, "POST", "/login", json!({
+        "username": "alice",
+        "password": "x".repeat(1025),
+    })).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn snaps_of_a_user() {
+    let app = app();
+    let alice = register(&app, "alice").await;
+    let bob = register(&app, "bob").await;
+
+    for (author, username, message) in [(&alice, "alice", "From alice"), (&bob, "bob", "From bob")] {
+        let token = login_as(&app, username).await;
+        let response = send_as(&app, "POST", "/snaps", Some(&token), json!({
+            "message": message,
+        })).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(body_json(response).await["data"]["author_id"], json!(author));
+    }
+
+    let response = get(&app, &format!("/users/{bob}/snaps")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = body_json(response).await;
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(body["data"][0]["message"], json!("From bob"));
+    assert_eq!(body["data"][0]["author_id"], json!(bob));
+
+    let response = get(&app, &format!("/users/{}/snaps", Uuid::new_v4())).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn post_snap_unknown_author() {
+    let context = context::AppContext::default();
+    let token = context.auth.issue(&Uuid::new_v4().to_string()).access_token;
+    let app = router::get_router_with_context(context).with_state(state::MockSnapRepository::new());
+
+    let response = send_as(&app, "POST", "/snaps", Some(&token), json!({
+        "message": "Nobody wrote this",
+    })).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body_json(response).await["title"], json!("Unknown author"));
+}
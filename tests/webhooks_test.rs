@@ -0,0 +1,323 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use snap_app_demo::{config, context, router, state, webhooks};
+use snap_app_demo::models::User;
+use snap_app_demo::state::UserRepository;
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+use http_body_util::BodyExt;
+
+/// App with the users alice and bob, alongside their `Authorization` values.
+struct TestApp {
+    app: axum::Router,
+    repo: state::MockSnapRepository,
+    context: context::AppContext,
+    alice: String,
+    bob: String,
+}
+
+/// App whose webhooks can point to the local receivers.
+fn test_app() -> TestApp {
+    test_app_with(true)
+}
+
+fn test_app_with(allow_private_hosts: bool) -> TestApp {
+    let mut repo = state::MockSnapRepository::new();
+    let alice = repo.create_user(User::new("alice".to_string(), "a long password")).unwrap().id();
+    let bob = repo.create_user(User::new("bob".to_string(), "a long password")).unwrap().id();
+    let mut config = config::Config::default();
+    config.webhooks.allow_private_hosts = allow_private_hosts;
+    let context = context::AppContext::new(config).unwrap();
+    TestApp {
+        alice: format!("Bearer {}", context.auth.issue(&alice).access_token),
+        bob: format!("Bearer {}", context.auth.issue(&bob).access_token),
+        app: router::get_router_with_context(context.clone()).with_state(repo.clone()),
+        repo,
+        context,
+    }
+}
+
+impl TestApp {
+    /// Dispatcher of the app's queue retrying after 1ms and giving up
+    /// after `max_attempts`.
+    fn dispatcher(&self, max_attempts: u32) -> webhooks::Dispatcher<state::MockSnapRepository> {
+        let config = config::WebhooksConfig {
+            max_attempts,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 1,
+            timeout_secs: 5,
+            ..self.context.config.webhooks.clone()
+        };
+        webhooks::Dispatcher::new(self.repo.clone(), config, self.context.metrics.clone())
+    }
+}
+
+/// Send `body` with `method` to `uri`, with `authorization` as is.
+async fn send(
+    app: &axum::Router,
+    method: &str,
+    uri: &str,
+    authorization: &str,
+    body: Option<Value>,
+) -> Response {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header("Authorization", authorization);
+    let request = match body {
+        Some(body) => request
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&body).unwrap())),
+        None => request.body(Body::empty()),
+    };
+    app.clone().oneshot(request.unwrap()).await.unwrap()
+}
+
+async fn body_json(response: Response) -> Value {
+    let body = response.into_body()
+        .collect()
+        .await
+        .unwrap()
+        .to_bytes();
+    serde_json::from_slice(&body).unwrap()
+}
+
+/// Requests received by a [receiver].
+type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+/// Local HTTP receiver answering with `statuses` in order, then 200.
+/// Returns its URL and the requests it received.
+async fn receiver(statuses: Vec<StatusCode>) -> (String, Received) {
+    let received = Received::default();
+    let statuses = Arc::new(Mutex::new(VecDeque::from(statuses)));
+    let app = axum::Router::new().route("/hook", axum::routing::post({
+        let received = received.clone();
+        move |headers: HeaderMap, body: String| async move {
+            received.lock().unwrap().push((headers, body));
+            statuses.lock().unwrap().pop_front().unwrap_or(StatusCode::OK)
+        }
+    }));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (format!("http://{address}/hook"), received)
+}
+
+/// Subscribe `url` to `events` for the user of `authorization`.
+async fn create_webhook(app: &axum::Router, authorization: &str, url: &str, events: &[&str]) -> Value {
+    let response = send(app, "POST", "/webhooks", authorization, Some(json!({
+        "url": url,
+        "events": events,
+    }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["data"].clone()
+}
+
+async fn post_snap(app: &axum::Router, authorization: &str, message: &str) -> String {
+    let response = send(app, "POST", "/snaps", authorization, Some(json!({ "message": message }))).await;
+    assert_eq!(response.status(), StatusCode::CREATED);
+    body_json(response).await["data"]["id"].as_str().unwrap().to_string()
+}
+
+async fn deliveries(app: &axum::Router, authorization: &str, webhook: &str, query: &str) -> Vec<Value> {
+    let uri = format!("/webhooks/{webhook}/deliveries{query}");
+    let response = send(app, "GET", &uri, authorization, None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    body_json(response).await["data"].as_array().unwrap().clone()
+}
+
+#[tokio::test]
+async fn webhooks_are_managed_by_their_owner() {
+    let test = test_app();
+    let webhook = create_webhook(&test.app, &test.alice, "http://localhost:9/hook", &["snap.created"]).await;
+    let id = webhook["id"].as_str().unwrap();
+    assert!(webhook["secret"].as_str().unwrap().starts_with("whsec_"));
+
+    let response = send(&test.app, "GET", "/webhooks", &test.alice, None).await;
+    let list = body_json(response).await;
+    assert_eq!(list["data"].as_array().unwrap().len(), 1);
+    assert!(list["data"][0].get("secret").is_none());
+
+    let uri = format!("/webhooks/{id}");
+    let response = send(&test.app, "PATCH", &uri, &test.alice, Some(json!({
+        "events": ["snap.edited", "snap.deleted"],
+    }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(body_json(response).await["data"]["events"], json!(["snap.edited", "snap.deleted"]));
+
+    let response = send(&test.app, "GET", &uri, &test.bob, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&test.app, "DELETE", &uri, &test.bob, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = send(&test.app, "DELETE", &uri, &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = send(&test.app, "GET", &uri, &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn invalid_webhooks_are_rejected() {
+    let test = test_app();
+    for (url, events) in [
+        ("ftp://example.com/hook", json!(["snap.created"])),
+        ("/hook", json!(["snap.created"])),
+        ("http://example.com/hook", json!([])),
+        ("http://example.com/hook", json!(["snap.liked"])),
+    ] {
+        let response = send(&test.app, "POST", "/webhooks", &test.alice, Some(json!({
+            "url": url,
+            "events": events,
+        }))).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY, "{url} {events}");
+    }
+}
+
+#[tokio::test]
+async fn deliveries_are_signed() {
+    let test = test_app();
+    let (url, received) = receiver(vec![]).await;
+    let webhook = create_webhook(&test.app, &test.alice, &url, &["snap.created", "snap.deleted"]).await;
+    let secret = webhook["secret"].as_str().unwrap();
+
+    let snap = post_snap(&test.app, &test.bob, "Hello hooks").await;
+    let response = send(&test.app, "PATCH", &format!("/snaps/{snap}"), &test.bob, Some(json!({
+        "message": "Edited",
+    }))).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(test.dispatcher(3).run_once().await, 1);
+
+    let received = received.lock().unwrap().clone();
+    assert_eq!(received.len(), 1);
+    let (headers, body) = &received[0];
+    assert_eq!(headers["X-Snap-Event"], "snap.created");
+    let timestamp = headers["X-Snap-Timestamp"].to_str().unwrap().parse::<i64>().unwrap();
+    let signature = webhooks::sign(secret, timestamp, body);
+    assert_eq!(headers["X-Snap-Signature"].to_str().unwrap(), signature);
+    let payload = serde_json::from_str::<Value>(body).unwrap();
+    assert_eq!(payload["event"], "snap.created");
+    assert_eq!(payload["snap"]["id"], snap.as_str());
+    assert_eq!(payload["snap"]["message"], "Hello hooks");
+
+    let log = deliveries(&test.app, &test.alice, webhook["id"].as_str().unwrap(), "").await;
+    assert_eq!(log[0]["status"], "delivered");
+    assert_eq!(log[0]["attempts"][0]["status"], 200);
+    assert_eq!(headers["X-Snap-Delivery"].to_str().unwrap(), log[0]["id"]);
+}
+
+#[tokio::test]
+async fn https_deliveries_go_over_tls() {
+    let test = test_app();
+    let (url, received) = receiver(vec![]).await;
+    let url = url.replace("http://", "https://");
+    let webhook = create_webhook(&test.app, &test.alice, &url, &["snap.created"]).await;
+    post_snap(&test.app, &test.bob, "Over TLS").await;
+
+    assert_eq!(test.dispatcher(3).run_once().await, 1);
+    // The receiver only speaks plain HTTP, so the handshake fails.
+    assert!(received.lock().unwrap().is_empty());
+    let log = deliveries(&test.app, &test.alice, webhook["id"].as_str().unwrap(), "").await;
+    assert_eq!(log[0]["status"], "pending");
+    assert!(log[0]["attempts"][0]["status"].is_null());
+    // Only the kind of error, the causes stay in the server logs.
+    assert_eq!(log[0]["attempts"][0]["error"], "connection failed");
+}
+
+#[tokio::test]
+async fn private_hosts_are_refused_by_default() {
+    let test = test_app_with(false);
+    let (url, received) = receiver(vec![]).await;
+    for url in [url.as_str(), "http://localhost:9/hook", "http://169.254.169.254/latest", "http://[::1]/hook"] {
+        let response = send(&test.app, "POST", "/webhooks", &test.alice, Some(json!({
+            "url": url,
+            "events": ["snap.created"],
+        }))).await;
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY, "{url}");
+    }
+
+    // A webhook registered while private hosts were allowed is checked
+    // again on delivery.
+    let open = test_app();
+    let webhook = create_webhook(&open.app, &open.alice, &url, &["snap.created"]).await;
+    post_snap(&open.app, &open.bob, "Inside job").await;
+    let config = config::WebhooksConfig {
+        allow_private_hosts: false,
+        ..config::WebhooksConfig::default()
+    };
+    let mut dispatcher = webhooks::Dispatcher::new(open.repo.clone(), config, open.context.metrics.clone());
+    assert_eq!(dispatcher.run_once().await, 1);
+    assert!(received.lock().unwrap().is_empty());
+    let log = deliveries(&open.app, &open.alice, webhook["id"].as_str().unwrap(), "").await;
+    assert_eq!(log[0]["attempts"][0]["error"], "host not allowed");
+
+    let webhook = create_webhook(&open.app, &open.alice, "http://localhost:9/hook", &["snap.created"]).await;
+    post_snap(&open.app, &open.bob, "Inside job by name").await;
+    dispatcher.run_once().await;
+    let log = deliveries(&open.app, &open.alice, webhook["id"].as_str().unwrap(), "").await;
+    assert_eq!(log[0]["attempts"][0]["error"], "host not allowed");
+}
+
+#[tokio::test]
+async fn failed_deliveries_are_retried() {
+    let test = test_app();
+    let (url, received) = receiver(vec![StatusCode::INTERNAL_SERVER_ERROR]).await;
+    let webhook = create_webhook(&test.app, &test.alice, &url, &["snap.created"]).await;
+    let id = webhook["id"].as_str().unwrap();
+    post_snap(&test.app, &test.bob, "Retry me").await;
+
+    let mut dispatcher = test.dispatcher(3);
+    assert_eq!(dispatcher.run_once().await, 1);
+    let log = deliveries(&test.app, &test.alice, id, "").await;
+    assert_eq!(log[0]["status"], "pending");
+    assert_eq!(log[0]["attempts"][0]["status"], 500);
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(dispatcher.run_once().await, 1);
+    let log = deliveries(&test.app, &test.alice, id, "").await;
+    assert_eq!(log[0]["status"], "delivered");
+    assert_eq!(log[0]["attempts"].as_array().unwrap().len(), 2);
+    assert_eq!(received.lock().unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn exhausted_deliveries_become_dead_letters() {
+    let test = test_app();
+    let failing = vec![StatusCode::BAD_GATEWAY; 3];
+    let (url, received) = receiver(failing).await;
+    let webhook = create_webhook(&test.app, &test.alice, &url, &["snap.created"]).await;
+    let id = webhook["id"].as_str().unwrap();
+    post_snap(&test.app, &test.bob, "Doomed").await;
+
+    let mut dispatcher = test.dispatcher(2);
+    dispatcher.run_once().await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    dispatcher.run_once().await;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(dispatcher.run_once().await, 0);
+
+    let dead = deliveries(&test.app, &test.alice, id, "?status=dead").await;
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0]["attempts"].as_array().unwrap().len(), 2);
+    assert!(deliveries(&test.app, &test.alice, id, "?status=pending").await.is_empty());
+    let response = send(&test.app, "GET", &format!("/webhooks/{id}/deliveries?status=lost"), &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let uri = format!("/webhooks/{id}/deliveries/{}/retry", dead[0]["id"].as_str().unwrap());
+    let response = send(&test.app, "POST", &uri, &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let response = send(&test.app, "POST", &uri, &test.alice, None).await;
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    // The requeued delivery gets a whole new round of attempts.
+    assert_eq!(dispatcher.run_once().await, 1);
+    assert_eq!(deliveries(&test.app, &test.alice, id, "").await[0]["status"], "pending");
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(dispatcher.run_once().await, 1);
+    assert_eq!(deliveries(&test.app, &test.alice, id, "").await[0]["status"], "delivered");
+    assert_eq!(received.lock().unwrap().len(), 4);
+}